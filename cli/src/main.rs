@@ -0,0 +1,734 @@
+//! Configures the keyboard from the command line, over the raw HID interface described in
+//! `key_ripper::raw_hid`: the keymap can be dumped to a text file and flashed back, the
+//! crash record, idle and panic settings read and changed, and the matrix and its counters
+//! watched while typing.
+//!
+//! Macros aren't part of the raw HID protocol, so they're read and written through the VIA
+//! interface instead, with the same buffer requests VIA itself makes (see `key_ripper::via`).
+//!
+//! `settings-uf2` needs no keyboard attached: it writes a keymap, macros and settings as a
+//! UF2 file for the bootloader to flash, for updating a keyboard without any tools.
+//!
+//! The CLI is built against the firmware crate, so the matrix size and key names come from
+//! the layout picked by its default features, like the simulator.
+
+mod uf2;
+
+use std::{error::Error, fmt::Write as _, fs, thread, time::Duration};
+
+use hidapi::{HidApi, HidDevice};
+use key_ripper::{
+    build_info::{BUILD_INFO, GIT_HASH_LEN},
+    config_block::{ConfigBlock, MAX_BLOCK_SIZE},
+    crash::PanicAction,
+    hid_descriptor::RAW_HID_USAGE_PAGE,
+    idle::IdleSettings,
+    key_codes::KeyCode,
+    key_mapping::NUM_LAYERS,
+    keymap::Keymap,
+    macros::{MacroBuffer, MACRO_BUFFER_SIZE, MACRO_COUNT},
+    raw_hid::{Command, Packet, Status, PROTOCOL_VERSION, RAW_REPORT_LEN},
+    settings::Settings,
+    via::VIA_REPORT_LEN,
+    NUM_COLS, NUM_ROWS, USB_PRODUCT_ID, USB_VENDOR_ID,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const USAGE: &str = "usage: key-ripper-cli <command>
+
+  version                         print the firmware and protocol versions
+  matrix                          print the keys held, as they change
+  stats                           print the ghost and stuck key counters, as they change
+  bounces                         print how much each key's presses bounced, and which chatter
+  latency [reset]                 print how long presses take to reach USB, or start over
+  crash                           print why the keyboard last restarted, and the saved panic
+  dump-keymap [file]              write every layer of the keymap to a file, or stdout
+  flash-keymap <file>             set the keys listed in a file, and save the keymap
+  macros                          print the macros
+  set-macro <index> <text>        replace a macro with text to type, and save the macros
+  idle [<minutes> [<macro>|none]] print or set the idle timeout and away macro
+  panic-action [reset|bootloader|halt]
+                                  print or set what the keyboard does after a panic
+  settings-uf2 <out.uf2> [--keymap <file>] [--macros <file>] [--reset-settings]
+                                  write a keymap, macros and default settings as a UF2 file,
+                                  to copy to the keyboard in BOOTSEL mode";
+
+/// The usage page of the VIA interface, which tells it apart from the raw HID interface and
+/// the keyboard's other HID interfaces.
+const VIA_USAGE_PAGE: u16 = 0xFF60;
+
+const VIA_MACRO_GET_BUFFER: u8 = 0x0E;
+const VIA_MACRO_SET_BUFFER: u8 = 0x0F;
+
+/// The most macro buffer one VIA request carries, after its four byte header.
+const VIA_MAX_CHUNK: usize = VIA_REPORT_LEN - 4;
+
+/// How long to wait for the keyboard to respond to a request.
+const RESPONSE_TIMEOUT_MS: i32 = 1000;
+
+/// How long to wait before sending a request the keyboard was too busy for again.
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How often `matrix` and `stats` ask the keyboard for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// One of the keyboard's raw HID interfaces, found by its usage page.
+struct Interface {
+    device: HidDevice,
+    sequence: u8,
+}
+
+impl Interface {
+    fn open(api: &HidApi, usage_page: u16) -> Result<Self> {
+        let info = api
+            .device_list()
+            .find(|info| {
+                info.vendor_id() == USB_VENDOR_ID
+                    && info.product_id() == USB_PRODUCT_ID
+                    && info.usage_page() == usage_page
+            })
+            .ok_or("no keyboard found, is it plugged in?")?;
+
+        Ok(Self { device: info.open_device(api)?, sequence: 0 })
+    }
+
+    /// Send a report, and wait for the keyboard's response to it.
+    fn exchange<const N: usize>(&mut self, report: &[u8; N]) -> Result<[u8; N]> {
+        self.send(report)?;
+        self.receive()
+    }
+
+    fn send<const N: usize>(&mut self, report: &[u8; N]) -> Result<()> {
+        // The interfaces don't use report IDs, which hidapi wants as a leading zero.
+        let mut out = vec![0];
+        out.extend_from_slice(report);
+        self.device.write(&out)?;
+        Ok(())
+    }
+
+    /// Wait for the next report from the keyboard.
+    fn receive<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut response = [0u8; N];
+        let len = self.device.read_timeout(&mut response, RESPONSE_TIMEOUT_MS)?;
+        if len != N {
+            return Err("no response from the keyboard".into());
+        }
+        Ok(response)
+    }
+
+    /// Make a raw HID request, returning the response's payload.
+    fn request(&mut self, command: Command, payload: &[u8]) -> Result<Vec<u8>> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let request = Packet::new(command.to_u8(), self.sequence, Status::Ok, payload);
+
+        loop {
+            self.send(&request.to_bytes())?;
+
+            // Anything else is a response to an earlier request which timed out. It's skipped
+            // by reading on, as sending the request again would repeat it.
+            let response = loop {
+                let response = self.receive::<RAW_REPORT_LEN>()?;
+                let response = Packet::parse(&response)
+                    .map_err(|status| format!("corrupted response: {status:?}"))?;
+                if response.command == request.command && response.sequence == request.sequence {
+                    break response;
+                }
+            };
+
+            return match response.status {
+                Status::Ok => Ok(response.payload().to_vec()),
+                // Another tool's request was being handled.
+                Status::Busy => {
+                    thread::sleep(BUSY_RETRY_DELAY);
+                    continue;
+                },
+                Status::Locked => Err("the configuration is locked, press Fn + L to unlock".into()),
+                status => Err(format!("{command:?} failed: {status:?}").into()),
+            };
+        }
+    }
+
+    /// Make a VIA macro buffer request, returning the response.
+    fn via_request(&mut self, id: u8, offset: usize, data: &[u8]) -> Result<Vec<u8>> {
+        let mut request = [0u8; VIA_REPORT_LEN];
+        request[0] = id;
+        request[1..3].copy_from_slice(&(offset as u16).to_be_bytes());
+        request[3] = data.len() as u8;
+        request[4..4 + data.len()].copy_from_slice(data);
+
+        let response = self.exchange(&request)?;
+        if response[0] != id {
+            return Err("the keyboard refused the macros, is the configuration locked?".into());
+        }
+        Ok(response[4..4 + data.len()].to_vec())
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    if let Err(err) = run(&args) {
+        eprintln!("error: {err}");
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[&str]) -> Result<()> {
+    let Some(command) = args.first() else {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    };
+
+    if let ["settings-uf2", path, options @ ..] = args {
+        return settings_uf2(path, options);
+    }
+
+    let api = HidApi::new()?;
+    if matches!(*command, "macros" | "set-macro") {
+        let mut via = Interface::open(&api, VIA_USAGE_PAGE)?;
+        return match args {
+            ["macros"] => print_macros(&mut via),
+            ["set-macro", index, text] => set_macro(&mut via, index, text),
+            _ => usage(),
+        };
+    }
+
+    let mut raw_hid = Interface::open(&api, RAW_HID_USAGE_PAGE)?;
+    match args {
+        ["version"] => print_version(&mut raw_hid),
+        ["matrix"] => watch_matrix(&mut raw_hid),
+        ["stats"] => watch_stats(&mut raw_hid),
+        ["bounces"] => print_bounces(&mut raw_hid),
+        ["latency"] => print_latency(&mut raw_hid),
+        ["latency", "reset"] => {
+            raw_hid.request(Command::ResetLatencyStats, &[])?;
+            Ok(())
+        },
+        ["crash"] => print_crash(&mut raw_hid),
+        ["dump-keymap" | "flash-keymap", ..] if !matches_keyboard(&mut raw_hid)? => {
+            Err("the keyboard's matrix doesn't match this tool's, build it with the keyboard's \
+                 layout"
+                .into())
+        },
+        ["dump-keymap"] => {
+            print!("{}", dump_keymap(&mut raw_hid)?);
+            Ok(())
+        },
+        ["dump-keymap", path] => Ok(fs::write(path, dump_keymap(&mut raw_hid)?)?),
+        ["flash-keymap", path] => flash_keymap(&mut raw_hid, &fs::read_to_string(path)?),
+        ["idle", settings @ ..] if settings.len() <= 2 => idle(&mut raw_hid, settings),
+        ["panic-action"] => {
+            let action = raw_hid.request(Command::GetPanicAction, &[])?;
+            let action = action.first().and_then(|action| PanicAction::from_u8(*action));
+            println!("{}", panic_action_name(action.ok_or("unknown panic action")?));
+            Ok(())
+        },
+        ["panic-action", name] => {
+            let action = [PanicAction::Reset, PanicAction::Bootloader, PanicAction::Halt]
+                .into_iter()
+                .find(|action| panic_action_name(*action) == *name)
+                .ok_or("the panic action is one of reset, bootloader or halt")?;
+            raw_hid.request(Command::SetPanicAction, &[action.to_u8()])?;
+            Ok(())
+        },
+        _ => usage(),
+    }
+}
+
+fn usage() -> Result<()> {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+/// What `Command::Identify` responds with, see `key_ripper::build_info::BuildInfo`.
+struct Identity {
+    rows: u8,
+    cols: u8,
+    layers: u8,
+    keymap_crc: u32,
+    build_time: u32,
+    git_hash: String,
+}
+
+impl Identity {
+    /// The keyboard's identity, or `None` for firmware too old to report one.
+    fn request(raw_hid: &mut Interface) -> Result<Option<Self>> {
+        if protocol_version(raw_hid)? < 7 {
+            return Ok(None);
+        }
+
+        let response = raw_hid.request(Command::Identify, &[])?;
+        if response.len() != 11 + GIT_HASH_LEN {
+            return Err("malformed identify response".into());
+        }
+        let word = |i: usize| u32::from_le_bytes(response[i..i + 4].try_into().unwrap());
+        let git_hash = String::from_utf8_lossy(&response[11..]).trim_end_matches('\0').to_string();
+        Ok(Some(Self {
+            rows: response[0],
+            cols: response[1],
+            layers: response[2],
+            keymap_crc: word(3),
+            build_time: word(7),
+            git_hash,
+        }))
+    }
+}
+
+fn protocol_version(raw_hid: &mut Interface) -> Result<u8> {
+    let response = raw_hid.request(Command::GetVersion, &[])?;
+    Ok(*response.first().ok_or("empty version response")?)
+}
+
+/// Whether the keyboard's matrix and layers are the size this tool was built for, so keys
+/// are written where they're meant to go. Firmware too old to say is trusted to match.
+fn matches_keyboard(raw_hid: &mut Interface) -> Result<bool> {
+    Ok(Identity::request(raw_hid)?.is_none_or(|identity| {
+        (identity.rows, identity.cols, identity.layers)
+            == (BUILD_INFO.rows, BUILD_INFO.cols, BUILD_INFO.layers)
+    }))
+}
+
+fn print_version(raw_hid: &mut Interface) -> Result<()> {
+    let response = raw_hid.request(Command::GetVersion, &[])?;
+    let (protocol, version) = response.split_first().ok_or("empty version response")?;
+    println!("firmware {}, protocol {protocol}", String::from_utf8_lossy(version));
+    if *protocol != PROTOCOL_VERSION {
+        println!("this tool speaks protocol {PROTOCOL_VERSION}, some commands may not work");
+    }
+
+    if let Some(identity) = Identity::request(raw_hid)? {
+        println!("built from {} at {} (Unix time)", identity.git_hash, identity.build_time);
+        println!(
+            "{}x{} matrix, {} layers, compiled-in keymap {:08x}",
+            identity.cols, identity.rows, identity.layers, identity.keymap_crc
+        );
+        if identity.keymap_crc != BUILD_INFO.keymap_crc {
+            println!("this tool was built with a different keymap ({:08x})", BUILD_INFO.keymap_crc);
+        }
+    }
+    Ok(())
+}
+
+/// Print the keys held each time they change, until interrupted.
+fn watch_matrix(raw_hid: &mut Interface) -> Result<()> {
+    let mut last = None;
+    loop {
+        let bitmap = raw_hid.request(Command::GetMatrix, &[])?;
+        if last.as_ref() != Some(&bitmap) {
+            let mut held = String::new();
+            for i in (0..NUM_COLS * NUM_ROWS).filter(|i| bitmap[i / 8] & (1 << (i % 8)) != 0) {
+                write!(held, " ({}, {})", i / NUM_ROWS, i % NUM_ROWS)?;
+            }
+            println!("held:{}", if held.is_empty() { " nothing" } else { &held });
+            last = Some(bitmap);
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Print the matrix counters each time they change, until interrupted.
+fn watch_stats(raw_hid: &mut Interface) -> Result<()> {
+    let mut last = None;
+    loop {
+        let stats = raw_hid.request(Command::GetMatrixStats, &[])?;
+        if last.as_ref() != Some(&stats) {
+            let [ghosts, stuck] = [0, 4].map(|i| {
+                stats.get(i..i + 4).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            });
+            println!("ghosts suppressed: {ghosts}, stuck keys: {stuck}");
+            last = Some(stats);
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Print the histogram of bounces per press of every key pressed since the keyboard started.
+fn print_bounces(raw_hid: &mut Interface) -> Result<()> {
+    println!("key       presses by bounces: 0 / 1 / 2 / 3-4 / 5+");
+    let mut chattering = 0;
+    for col in 0..NUM_COLS {
+        for row in 0..NUM_ROWS {
+            let stats = raw_hid.request(Command::GetBounceStats, &[col as u8, row as u8])?;
+            let Some((flag, histogram)) = stats.split_first() else {
+                return Err("the keyboard sent no bounce stats".into());
+            };
+            let histogram: Vec<u16> = histogram
+                .chunks_exact(2)
+                .map(|count| u16::from_le_bytes([count[0], count[1]]))
+                .collect();
+            if histogram.iter().all(|count| *count == 0) {
+                continue;
+            }
+
+            let counts: Vec<String> = histogram.iter().map(u16::to_string).collect();
+            let key = format!("({col}, {row})");
+            let note = if *flag != 0 { "  chattering" } else { "" };
+            println!("{key:<10}{}{note}", counts.join(" / "));
+            chattering += (*flag != 0) as usize;
+        }
+    }
+    if chattering > 0 {
+        println!("{chattering} key(s) chattering, their switches may be failing");
+    }
+    Ok(())
+}
+
+/// Print the time from scanning a press to sending the report with it, over every press
+/// since the keyboard started or the stats were reset.
+fn print_latency(raw_hid: &mut Interface) -> Result<()> {
+    let stats = raw_hid.request(Command::GetLatencyStats, &[])?;
+    let [samples, min, average, max] = [0, 4, 8, 12].map(|i| {
+        stats.get(i..i + 4).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    });
+    if samples == 0 {
+        println!("no presses timed yet");
+        return Ok(());
+    }
+
+    let ms = |us: u32| us as f64 / 1000.0;
+    println!(
+        "{samples} presses: min {:.3} ms, average {:.3} ms, max {:.3} ms",
+        ms(min),
+        ms(average),
+        ms(max)
+    );
+    Ok(())
+}
+
+fn print_crash(raw_hid: &mut Interface) -> Result<()> {
+    let crash = raw_hid.request(Command::GetLastCrash, &[])?;
+    match crash.as_slice() {
+        [0] => println!("last restart: normal"),
+        [2] => println!("last restart: watchdog"),
+        [1, panic @ ..] if panic.len() >= 4 => {
+            let (line, file) = panic.split_at(4);
+            let line = u32::from_le_bytes(line.try_into().unwrap());
+            println!("last restart: panic at {}:{line}", String::from_utf8_lossy(file));
+        },
+        _ => println!("last restart: unknown"),
+    }
+
+    // The saved panic is read from the start of its text until nothing more comes back.
+    let mut text = Vec::new();
+    loop {
+        let offset = u8::try_from(text.len()).map_err(|_| "the saved panic is too long")?;
+        let chunk = raw_hid.request(Command::GetPanicLog, &[offset])?;
+        if chunk.is_empty() {
+            break;
+        }
+        text.extend_from_slice(&chunk);
+    }
+
+    if text.is_empty() {
+        println!("saved panic: none");
+    } else {
+        println!("saved panic: {}", String::from_utf8_lossy(&text));
+    }
+    Ok(())
+}
+
+/// The keymap as text, one key per line as `layer col row key`, which `flash_keymap` reads
+/// back.
+fn dump_keymap(raw_hid: &mut Interface) -> Result<String> {
+    let mut text = String::new();
+    for layer in 0..NUM_LAYERS as u8 {
+        for col in 0..NUM_COLS as u8 {
+            for row in 0..NUM_ROWS as u8 {
+                let key = raw_hid.request(Command::GetKey, &[layer, col, row])?;
+                let key = match key.as_slice() {
+                    [low, high] => u16::from_le_bytes([*low, *high]),
+                    _ => return Err("malformed key response".into()),
+                };
+                match KeyCode::from_u16(key) {
+                    Some(key) => writeln!(text, "{layer} {col} {row} {key:?}")?,
+                    None => writeln!(text, "{layer} {col} {row} {key:#06x}")?,
+                }
+            }
+        }
+    }
+    Ok(text)
+}
+
+/// Set every key listed in `text`, as written by `dump_keymap`, then save the keymap. Keys
+/// are given by name or number, and blank lines and those starting with `#` are skipped.
+fn flash_keymap(raw_hid: &mut Interface, text: &str) -> Result<()> {
+    let keys = parse_keymap(text)?;
+    for ([layer, col, row], key) in &keys {
+        let [low, high] = (*key as u16).to_le_bytes();
+        raw_hid.request(Command::SetKey, &[*layer, *col, *row, low, high])?;
+    }
+    raw_hid.request(Command::SaveKeymap, &[])?;
+    println!("flashed {} keys", keys.len());
+    Ok(())
+}
+
+/// The keys listed in a keymap file, as `([layer, col, row], key)`.
+fn parse_keymap(text: &str) -> Result<Vec<([u8; 3], KeyCode)>> {
+    let mut keys = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let ([layer, col, row], key) = parse_keymap_line(line)
+            .ok_or_else(|| format!("line {}: expected `layer col row key`", number + 1))?;
+        if layer as usize >= NUM_LAYERS || col as usize >= NUM_COLS || row as usize >= NUM_ROWS {
+            return Err(format!("line {}: no such key", number + 1).into());
+        }
+        keys.push(([layer, col, row], key));
+    }
+    Ok(keys)
+}
+
+fn parse_keymap_line(line: &str) -> Option<([u8; 3], KeyCode)> {
+    let [layer, col, row, key] = line.split_whitespace().collect::<Vec<_>>().try_into().ok()?;
+    Some(([layer.parse().ok()?, col.parse().ok()?, row.parse().ok()?], parse_key(key)?))
+}
+
+/// A key by its `KeyCode` name, as `dump_keymap` writes them, or its number.
+fn parse_key(name: &str) -> Option<KeyCode> {
+    if let Some(hex) = name.strip_prefix("0x") {
+        return KeyCode::from_u16(u16::from_str_radix(hex, 16).ok()?);
+    }
+
+    (0..=KeyCode::MAX_VALUE)
+        .filter_map(KeyCode::from_u16)
+        .find(|key| format!("{key:?}").eq_ignore_ascii_case(name))
+}
+
+/// Read the whole macro buffer, split into its macros.
+fn read_macros(via: &mut Interface) -> Result<Vec<Vec<u8>>> {
+    let mut buffer = Vec::with_capacity(MACRO_BUFFER_SIZE);
+    while buffer.len() < MACRO_BUFFER_SIZE {
+        let len = VIA_MAX_CHUNK.min(MACRO_BUFFER_SIZE - buffer.len());
+        buffer.extend(via.via_request(VIA_MACRO_GET_BUFFER, buffer.len(), &vec![0; len])?);
+    }
+
+    let mut macros: Vec<Vec<u8>> =
+        buffer.split(|byte| *byte == 0).take(MACRO_COUNT).map(<[u8]>::to_vec).collect();
+    macros.resize(MACRO_COUNT, Vec::new());
+    Ok(macros)
+}
+
+fn print_macros(via: &mut Interface) -> Result<()> {
+    for (index, sequence) in read_macros(via)?.iter().enumerate() {
+        println!("{index:>2}: {}", sequence.escape_ascii());
+    }
+    Ok(())
+}
+
+/// Replace one macro, written with the same escapes `print_macros` uses, keeping the rest.
+fn set_macro(via: &mut Interface, index: &str, text: &str) -> Result<()> {
+    let index = parse_macro_index(index)?;
+    let mut macros = read_macros(via)?;
+    macros[index] = unescape(text)?;
+
+    let mut buffer: Vec<u8> =
+        macros.iter().flat_map(|sequence| sequence.iter().chain(&[0])).copied().collect();
+    if buffer.len() > MACRO_BUFFER_SIZE {
+        return Err(format!("the macros only have room for {MACRO_BUFFER_SIZE} bytes").into());
+    }
+    buffer.resize(MACRO_BUFFER_SIZE, 0);
+
+    for (i, chunk) in buffer.chunks(VIA_MAX_CHUNK).enumerate() {
+        via.via_request(VIA_MACRO_SET_BUFFER, i * VIA_MAX_CHUNK, chunk)?;
+    }
+    Ok(())
+}
+
+fn parse_macro_index(index: &str) -> Result<usize> {
+    let index = index.trim().parse().ok().filter(|index| *index < MACRO_COUNT);
+    Ok(index.ok_or_else(|| format!("the macro index is from 0 to {}", MACRO_COUNT - 1))?)
+}
+
+/// Undo `escape_ascii`: `\n`, `\r`, `\t`, `\\`, `\'`, `\"` and `\xNN`.
+fn unescape(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let byte = match c {
+            '\\' => match chars.next() {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some(c @ ('\\' | '\'' | '"')) => c as u8,
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    u8::from_str_radix(&hex, 16).map_err(|_| format!("bad escape `\\x{hex}`"))?
+                },
+                other => return Err(format!("bad escape `\\{}`", other.unwrap_or(' ')).into()),
+            },
+            c if c.is_ascii() => c as u8,
+            c => return Err(format!("`{c}` can't be typed by a macro, only ASCII").into()),
+        };
+        if byte == 0 {
+            return Err("a macro can't contain `\\x00`, which ends it".into());
+        }
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+/// Print the idle settings, or change them, keeping the away macro unless it's given.
+fn idle(raw_hid: &mut Interface, args: &[&str]) -> Result<()> {
+    let current = raw_hid.request(Command::GetIdleSettings, &[])?;
+    let mut settings = match current.as_slice() {
+        [timeout_min, away_macro] => IdleSettings::from_bytes([*timeout_min, *away_macro]),
+        _ => return Err("malformed idle settings response".into()),
+    };
+
+    if let [timeout_min, rest @ ..] = args {
+        settings.timeout_min =
+            timeout_min.parse().map_err(|_| "the timeout is 0 to 255 minutes")?;
+        if let [away_macro] = rest {
+            settings.away_macro = match *away_macro {
+                "none" => None,
+                index => Some(
+                    index.parse().ok().filter(|index| (*index as usize) < MACRO_COUNT).ok_or_else(
+                        || format!("the away macro is none, or 0 to {}", MACRO_COUNT - 1),
+                    )?,
+                ),
+            };
+        }
+        raw_hid.request(Command::SetIdleSettings, &settings.to_bytes())?;
+    }
+
+    match settings.timeout_min {
+        0 => print!("idle: never"),
+        minutes => print!("idle after: {minutes} min"),
+    }
+    match settings.away_macro {
+        Some(index) => println!(", away macro: {index}"),
+        None => println!(", away macro: none"),
+    }
+    Ok(())
+}
+
+fn panic_action_name(action: PanicAction) -> &'static str {
+    match action {
+        PanicAction::Reset => "reset",
+        PanicAction::Bootloader => "bootloader",
+        PanicAction::Halt => "halt",
+    }
+}
+
+/// Write the partitions picked by `options` as a UF2 file: the keymap listed in a keymap
+/// file, on top of the compiled-in one, the macros listed in a file as `macros` prints
+/// them, and the default settings.
+fn settings_uf2(path: &str, options: &[&str]) -> Result<()> {
+    let mut image = uf2::Image::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--keymap" => {
+                let file = options.next().ok_or("--keymap needs a keymap file")?;
+                let mut keymap = Keymap::default();
+                for ([layer, col, row], key) in parse_keymap(&fs::read_to_string(file)?)? {
+                    keymap.set_key(layer as usize, col as usize, row as usize, key);
+                }
+
+                // The rest of the partition is erased, so older saved keymaps are gone and
+                // this one is loaded.
+                add_partition(&mut image, "KEYMAP", &keymap.encode(0))?;
+            },
+            "--macros" => {
+                let file = options.next().ok_or("--macros needs a macros file")?;
+                let macros = parse_macros(&fs::read_to_string(file)?)?;
+                add_partition(&mut image, "MACROS", &encode_block(&macros))?;
+            },
+            "--reset-settings" => {
+                add_partition(&mut image, "SETTINGS", &encode_block(&Settings::default()))?;
+            },
+            option => return Err(format!("unknown option `{option}`").into()),
+        }
+    }
+
+    if image.is_empty() {
+        return Err("nothing to write, pick --keymap, --macros or --reset-settings".into());
+    }
+    fs::write(path, image.to_uf2())?;
+    Ok(())
+}
+
+/// Write `data` at the start of a partition, with the rest of it erased so nothing saved
+/// before is left.
+fn add_partition(image: &mut uf2::Image, name: &str, data: &[u8]) -> Result<()> {
+    let (address, size) =
+        uf2::partition(name).ok_or_else(|| format!("no {name} partition in memory.x"))?;
+    let mut data = data.to_vec();
+    data.resize(size, 0xFF);
+    image.add(address, &data);
+    Ok(())
+}
+
+fn encode_block(block: &impl ConfigBlock) -> Vec<u8> {
+    let mut buffer = [0xFFu8; MAX_BLOCK_SIZE];
+    let len = block.encode(&mut buffer);
+    buffer[..len].to_vec()
+}
+
+/// Macros listed as `index: text`, with the escapes `print_macros` uses. Macros which
+/// aren't listed are left empty.
+fn parse_macros(text: &str) -> Result<MacroBuffer> {
+    let mut macros = MacroBuffer::default();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (index, sequence) = line
+            .split_once(':')
+            .ok_or_else(|| format!("line {}: expected `index: text`", number + 1))?;
+        let sequence = unescape(sequence.strip_prefix(' ').unwrap_or(sequence))?;
+        if !macros.set_macro(parse_macro_index(index)?, &sequence) {
+            return Err(format!("the macros only have room for {MACRO_BUFFER_SIZE} bytes").into());
+        }
+    }
+    Ok(macros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keymap_file_parses_names_and_numbers() {
+        let text = "# layer col row key\n\n0 1 2 A\n  1 0 0 escape  \n0 0 1 0x2C\n";
+        let keys = parse_keymap(text).unwrap();
+        assert_eq!(
+            keys,
+            [([0, 1, 2], KeyCode::A), ([1, 0, 0], KeyCode::Escape), ([0, 0, 1], KeyCode::Space)]
+        );
+    }
+
+    #[test]
+    fn keymap_file_errors_name_the_line() {
+        let error = |text: &str| parse_keymap(text).unwrap_err().to_string();
+        assert_eq!(error("0 0 0 A\n0 0 A\n"), "line 2: expected `layer col row key`");
+        assert_eq!(error("0 0 0 NotAKey"), "line 1: expected `layer col row key`");
+        assert_eq!(error(&format!("{NUM_LAYERS} 0 0 A")), "line 1: no such key");
+        assert_eq!(error(&format!("0 {NUM_COLS} 0 A")), "line 1: no such key");
+        assert_eq!(error(&format!("0 0 {NUM_ROWS} A")), "line 1: no such key");
+    }
+
+    #[test]
+    fn macro_text_unescapes_like_escape_ascii() {
+        let bytes = b"Hi,\tthere\r\n\"it's\" \\ \x1B\x7F".to_vec();
+        let text = bytes.escape_ascii().to_string();
+        assert_eq!(unescape(&text).unwrap(), bytes);
+        assert_eq!(unescape(r"\x41\x62").unwrap(), b"Ab");
+    }
+
+    #[test]
+    fn macro_text_rejects_what_a_macro_cant_hold() {
+        let error = |text: &str| unescape(text).unwrap_err().to_string();
+        assert_eq!(error(r"\q"), r"bad escape `\q`");
+        assert_eq!(error("\\"), r"bad escape `\ `");
+        assert_eq!(error(r"\xZZ"), r"bad escape `\xZZ`");
+        assert_eq!(error(r"a\x00b"), r"a macro can't contain `\x00`, which ends it");
+        assert_eq!(error("é"), "`é` can't be typed by a macro, only ASCII");
+    }
+}
@@ -0,0 +1,133 @@
+//! Writing the keyboard's flash partitions as a UF2 file, which the RP2040's bootloader
+//! flashes when it's copied to the drive the board shows up as in BOOTSEL mode. Only the
+//! partitions in the file are written, leaving the firmware and everything else as it was.
+
+use std::collections::BTreeMap;
+
+use key_ripper::flash::SECTOR_SIZE;
+
+/// The partitions and where they are, from the firmware's linker script.
+const MEMORY_X: &str = include_str!("../../firmware/memory.x");
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// The `familyID` field is set, identifying the chip the file is for.
+const UF2_FLAG_FAMILY_ID: u32 = 0x0000_2000;
+const RP2040_FAMILY_ID: u32 = 0xE48B_FF56;
+
+const UF2_BLOCK_SIZE: usize = 512;
+
+/// The data each block carries, a flash page, as the bootloader expects.
+const UF2_PAYLOAD_SIZE: usize = 256;
+
+/// The start and size of a partition in `memory.x`, like `KEYMAP`.
+pub fn partition(name: &str) -> Option<(u32, usize)> {
+    let line = MEMORY_X.lines().find(|line| line.split_whitespace().next() == Some(name))?;
+    let (_, bounds) = line.split_once(':')?;
+    let (origin, length) = bounds.split_once(',')?;
+
+    let origin = origin.trim().strip_prefix("ORIGIN =")?.trim().strip_prefix("0x")?;
+    let length = length.trim().strip_prefix("LENGTH =")?.trim().strip_suffix('K')?;
+    Some((u32::from_str_radix(origin, 16).ok()?, length.parse::<usize>().ok()? * 1024))
+}
+
+/// The flash to write, by address.
+#[derive(Default)]
+pub struct Image {
+    regions: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Image {
+    /// Write `data` at `address`, padded with `0xFF` to whole sectors, as the bootloader
+    /// erases every sector it writes to.
+    pub fn add(&mut self, address: u32, data: &[u8]) {
+        let mut data = data.to_vec();
+        data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0xFF);
+        self.regions.insert(address, data);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn to_uf2(&self) -> Vec<u8> {
+        let pages: Vec<(u32, &[u8])> = self
+            .regions
+            .iter()
+            .flat_map(|(address, data)| {
+                data.chunks(UF2_PAYLOAD_SIZE)
+                    .enumerate()
+                    .map(move |(i, page)| (address + (i * UF2_PAYLOAD_SIZE) as u32, page))
+            })
+            .collect();
+
+        let mut uf2 = Vec::with_capacity(pages.len() * UF2_BLOCK_SIZE);
+        for (block_no, (address, page)) in pages.iter().enumerate() {
+            let header = [
+                UF2_MAGIC_START0,
+                UF2_MAGIC_START1,
+                UF2_FLAG_FAMILY_ID,
+                *address,
+                UF2_PAYLOAD_SIZE as u32,
+                block_no as u32,
+                pages.len() as u32,
+                RP2040_FAMILY_ID,
+            ];
+            let start = uf2.len();
+            uf2.extend(header.iter().flat_map(|word| word.to_le_bytes()));
+            uf2.extend_from_slice(page);
+            uf2.resize(start + UF2_BLOCK_SIZE - 4, 0);
+            uf2.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        }
+        uf2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(block: &[u8], index: usize) -> u32 {
+        u32::from_le_bytes(block[index * 4..index * 4 + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn partitions_come_from_memory_x() {
+        assert_eq!(partition("KEYMAP"), Some((0x101F_8000, 12 * 1024)));
+        assert_eq!(partition("SETTINGS"), Some((0x101F_F000, 4 * 1024)));
+        assert_eq!(partition("NOT_A_PARTITION"), None);
+    }
+
+    #[test]
+    fn image_writes_whole_sectors_a_page_per_block() {
+        let mut image = Image::default();
+        assert!(image.is_empty());
+        image.add(0x101F_F000, &[0xAB; 300]);
+        assert!(!image.is_empty());
+
+        let uf2 = image.to_uf2();
+        let blocks: Vec<&[u8]> = uf2.chunks(UF2_BLOCK_SIZE).collect();
+        let pages = SECTOR_SIZE / UF2_PAYLOAD_SIZE;
+        assert_eq!(uf2.len(), pages * UF2_BLOCK_SIZE);
+
+        for (block_no, block) in blocks.iter().enumerate() {
+            assert_eq!(word(block, 0), UF2_MAGIC_START0);
+            assert_eq!(word(block, 1), UF2_MAGIC_START1);
+            assert_eq!(word(block, 2), UF2_FLAG_FAMILY_ID);
+            assert_eq!(word(block, 3), 0x101F_F000 + (block_no * UF2_PAYLOAD_SIZE) as u32);
+            assert_eq!(word(block, 4), UF2_PAYLOAD_SIZE as u32);
+            assert_eq!(word(block, 5), block_no as u32);
+            assert_eq!(word(block, 6), pages as u32);
+            assert_eq!(word(block, 7), RP2040_FAMILY_ID);
+            assert_eq!(word(block, UF2_BLOCK_SIZE / 4 - 1), UF2_MAGIC_END);
+        }
+
+        // The data, then the rest of the sector as erased flash.
+        let payload: Vec<u8> =
+            blocks.iter().flat_map(|block| &block[32..32 + UF2_PAYLOAD_SIZE]).copied().collect();
+        assert!(payload[..300].iter().all(|byte| *byte == 0xAB));
+        assert!(payload[300..].iter().all(|byte| *byte == 0xFF));
+    }
+}
@@ -0,0 +1,219 @@
+// Firmware for the key ripper's wireless dongle: an RP2040 with an nRF24L01+ radio, which
+// receives the keyboard's reports over the air and passes them on to the host over USB.
+
+#![no_main]
+#![no_std]
+
+use core::cell::RefCell;
+use critical_section::Mutex;
+use defmt::{info, warn};
+use defmt_rtt as _;
+use fugit::RateExtU32;
+use panic_probe as _;
+use rp2040_hal::{
+    gpio::FunctionSpi,
+    pac::{self, interrupt},
+    usb::{self, UsbBus},
+    Clock, Spi, Watchdog,
+};
+use usb_device::{bus::UsbBusAllocator, device::UsbDeviceBuilder, prelude::*};
+use usbd_hid::{
+    descriptor::{KeyboardReport, MouseReport, SerializedDescriptor},
+    hid_class::{
+        HIDClass, HidClassSettings, HidCountryCode, HidProtocol, HidSubClass, ProtocolModeConfig,
+    },
+};
+
+use key_ripper::{
+    hid_descriptor,
+    nrf24::{Nrf24, Role},
+    wireless::Frame,
+};
+
+/// The rate of USB interrupt polling the dongle will ask of the host.
+const USB_POLL_RATE_MS: u8 = 1;
+
+const EXTERNAL_CRYSTAL_FREQUENCY_HZ: u32 = 12_000_000;
+
+/// The USB device and its interfaces, once they're set up. Only the USB interrupt uses them
+/// from then on.
+static USB: Mutex<RefCell<Option<Usb>>> = Mutex::new(RefCell::new(None));
+
+struct Usb {
+    device: UsbDevice<'static, usb::UsbBus>,
+    keyboard_hid: HIDClass<'static, usb::UsbBus>,
+    mouse_hid: HIDClass<'static, usb::UsbBus>,
+}
+
+/// The latest keyboard report received over the radio.
+static KEYBOARD_REPORT: Mutex<RefCell<KeyboardReport>> = Mutex::new(RefCell::new(KeyboardReport {
+    modifier: 0,
+    reserved: 0,
+    leds: 0,
+    keycodes: [0u8; 6],
+}));
+
+/// The next mouse report to send, which is taken once the host has received it.
+static MOUSE_REPORT: Mutex<RefCell<Option<MouseReport>>> = Mutex::new(RefCell::new(None));
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    info!("Start of main()");
+    let mut pac = pac::Peripherals::take().unwrap();
+    let core = pac::CorePeripherals::take().unwrap();
+
+    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+
+    let clocks = rp2040_hal::clocks::init_clocks_and_plls(
+        EXTERNAL_CRYSTAL_FREQUENCY_HZ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+
+    let sio = rp2040_hal::Sio::new(pac.SIO);
+    let pins =
+        rp2040_hal::gpio::Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+
+    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+
+    // The radio is wired the same way as on the keyboard.
+    let _spi_sclk = pins.gpio6.into_mode::<FunctionSpi>();
+    let _spi_mosi = pins.gpio7.into_mode::<FunctionSpi>();
+    let _spi_miso = pins.gpio4.into_mode::<FunctionSpi>();
+    let spi = Spi::<_, _, 8>::new(pac.SPI0).init(
+        &mut pac.RESETS,
+        clocks.peripheral_clock.freq(),
+        8.MHz(),
+        &embedded_hal::spi::MODE_0,
+    );
+    let csn = pins.gpio5.into_push_pull_output();
+    let ce = pins.gpio8.into_push_pull_output();
+    let mut radio = Nrf24::new(spi, csn, ce, Role::Receiver, &mut delay).unwrap();
+
+    info!("Initializing USB");
+    let force_vbus_detect_bit = true;
+    let usb_bus = UsbBus::new(
+        pac.USBCTRL_REGS,
+        pac.USBCTRL_DPRAM,
+        clocks.usb_clock,
+        force_vbus_detect_bit,
+        &mut pac.RESETS,
+    );
+    let bus_ref: &'static UsbBusAllocator<usb::UsbBus> =
+        cortex_m::singleton!(: UsbBusAllocator<usb::UsbBus> = UsbBusAllocator::new(usb_bus))
+            .unwrap();
+
+    let keyboard_hid = HIDClass::new_with_settings(
+        bus_ref,
+        hid_descriptor::KEYBOARD_REPORT_DESCRIPTOR,
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Keyboard,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::US,
+        },
+    );
+
+    // The keyboard can't be told about the host's Resolution Multiplier over the radio, so
+    // the dongle's mouse only has standard resolution scrolling.
+    let mouse_hid = HIDClass::new_with_settings(
+        bus_ref,
+        MouseReport::desc(),
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Mouse,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::NotSupported,
+        },
+    );
+
+    // https://github.com/obdev/v-usb/blob/7a28fdc685952412dad2b8842429127bc1cf9fa7/usbdrv/USB-IDs-for-free.txt#L128
+    let usb_device = UsbDeviceBuilder::new(bus_ref, UsbVidPid(0x16c0, 0x27db))
+        .manufacturer("bschwind")
+        .product("key ripper dongle")
+        .build();
+    critical_section::with(|cs| {
+        USB.replace(cs, Some(Usb { device: usb_device, keyboard_hid, mouse_hid }));
+    });
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ);
+    }
+
+    info!("Listening for the keyboard");
+    let mut last_sequence = None;
+    loop {
+        let Some(bytes) = radio.receive() else {
+            continue;
+        };
+
+        let Some((sequence, frame)) = Frame::from_bytes(&bytes) else {
+            warn!("Unknown frame kind {=u8}", bytes[0]);
+            continue;
+        };
+
+        // The keyboard sent this one again, not having heard it was received.
+        if last_sequence == Some(sequence) {
+            continue;
+        }
+        last_sequence = Some(sequence);
+
+        critical_section::with(|cs| match frame {
+            Frame::Keyboard(report) => {
+                KEYBOARD_REPORT.replace(cs, report);
+            },
+            Frame::Mouse(report) => {
+                let mut pending = MOUSE_REPORT.borrow_ref_mut(cs);
+                *pending = Some(match *pending {
+                    // Add to movement the host hasn't picked up yet, rather than losing it.
+                    Some(pending) if pending.buttons == report.buttons => MouseReport {
+                        buttons: report.buttons,
+                        x: pending.x.saturating_add(report.x),
+                        y: pending.y.saturating_add(report.y),
+                        wheel: pending.wheel.saturating_add(report.wheel),
+                        pan: pending.pan.saturating_add(report.pan),
+                    },
+                    _ => report,
+                });
+            },
+        });
+    }
+}
+
+/// Handle USB interrupts, used by the host to poll for new reports.
+#[allow(non_snake_case)]
+#[interrupt]
+fn USBCTRL_IRQ() {
+    critical_section::with(|cs| {
+        let mut usb = USB.borrow_ref_mut(cs);
+        let Some(Usb { device, keyboard_hid, mouse_hid }) = usb.as_mut() else {
+            return;
+        };
+
+        device.poll(&mut [keyboard_hid, mouse_hid]);
+
+        keyboard_hid.push_input(&*KEYBOARD_REPORT.borrow_ref(cs)).ok();
+
+        let mut mouse_report = MOUSE_REPORT.borrow_ref_mut(cs);
+        if let Some(report) = *mouse_report {
+            if mouse_hid.push_input(&report).is_ok() {
+                *mouse_report = None;
+            }
+        }
+
+        // macOS doesn't like it when you don't pull this, apparently.
+        keyboard_hid.pull_raw_output(&mut [0; 64]).ok();
+    });
+}
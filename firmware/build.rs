@@ -7,8 +7,27 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also turns `board.toml` into Rust: the matrix size in `board.rs`, macros setting up
+//! the board's pins in `board_pins.rs`, and each layout's keymaps in `layout_<name>.rs`,
+//! all in `OUT_DIR` for the crate to `include!`.
+//!
+//! Last, it passes the git commit and build time on to `build_info`, as environment
+//! variables.
 
-use std::{env, fs::File, io::Write, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -22,4 +41,581 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // Another board can point `KEY_RIPPER_BOARD` at its own board file, rather than changing
+    // this crate's. A relative path is from this crate's directory.
+    println!("cargo:rerun-if-env-changed=KEY_RIPPER_BOARD");
+    let board_path = env::var_os("KEY_RIPPER_BOARD").map_or("board.toml".into(), PathBuf::from);
+    println!("cargo:rerun-if-changed={}", board_path.display());
+    println!("cargo:rerun-if-changed=src/key_code_list.rs");
+    let key_codes = key_code_names();
+    let board = Board::parse(
+        &fs::read_to_string(&board_path)
+            .unwrap_or_else(|err| panic!("{}: {err}", board_path.display())),
+    );
+    let selected = board.selected_layout();
+    fs::write(out.join("board.rs"), board.constants(selected.as_deref())).unwrap();
+    fs::write(out.join("board_pins.rs"), board.pin_macros(selected.as_deref())).unwrap();
+    for name in board.layout_names() {
+        fs::write(out.join(format!("layout_{name}.rs")), board.layout(&name, &key_codes)).unwrap();
+    }
+
+    // The reflog changes with every commit and checkout, unlike `HEAD` itself.
+    if Path::new("../.git/logs/HEAD").exists() {
+        println!("cargo:rerun-if-changed=../.git/logs/HEAD");
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rustc-env=KEY_RIPPER_GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=KEY_RIPPER_BUILD_TIME={}", build_time());
+}
+
+/// The commit being built, abbreviated to 12 digits, or `unknown` outside a git checkout.
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| hash.len() == 12)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Now, in seconds since the Unix epoch, or `SOURCE_DATE_EPOCH` for reproducible builds.
+fn build_time() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs())
+}
+
+/// `board.toml`, read with `toml`. A value of the wrong type, or a key this doesn't know,
+/// stops the build with where it is in the file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Board {
+    matrix: Matrix,
+    pins: Pins,
+    #[serde(default)]
+    split: Split,
+    usb: Usb,
+    layouts: BTreeMap<String, Layout>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Matrix {
+    rows: usize,
+    cols: usize,
+    diode_direction: DiodeDirection,
+    #[serde(default)]
+    sense: Sense,
+}
+
+/// Named after the `key_scan::DiodeDirection` variants.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+enum DiodeDirection {
+    #[serde(rename = "col2row")]
+    ColToRow,
+    #[serde(rename = "row2col")]
+    RowToCol,
+}
+
+/// Named after the `key_scan::Sense` variants.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Sense {
+    #[default]
+    PullDown,
+    PullUp,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pins {
+    rows: Vec<u8>,
+    cols: Vec<u8>,
+    module_id: u8,
+    indicator_led: u8,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Split {
+    left_cols: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Usb {
+    poll_interval_ms: Option<u32>,
+    max_power_ma: Option<u32>,
+    #[serde(default)]
+    self_powered: bool,
+    vendor_id: u16,
+    product_id: u16,
+    manufacturer: String,
+    product: String,
+}
+
+/// A `[layouts.<name>]` table. Its layers are every other key in it, like `normal`, `fn`,
+/// `num` and the ones named in `bases`.
+#[derive(Deserialize)]
+struct Layout {
+    unpopulated: Vec<(usize, usize)>,
+    #[serde(default)]
+    bases: Vec<String>,
+
+    // In place of the board's, for a layout on a PCB with a different matrix.
+    rows: Option<usize>,
+    cols: Option<usize>,
+    row_pins: Option<Vec<u8>>,
+    col_pins: Option<Vec<u8>>,
+    diode_direction: Option<DiodeDirection>,
+    sense: Option<Sense>,
+    split_left_cols: Option<usize>,
+
+    #[serde(flatten)]
+    layers: BTreeMap<String, Layer>,
+}
+
+/// A layer is either a grid, a multi-line string with a line of keys for each row of the
+/// matrix, or an array of columns.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Layer {
+    Grid(String),
+    Columns(Vec<Vec<String>>),
+}
+
+/// The matrix a layout is built on, checked against itself.
+struct LayoutMatrix {
+    rows: usize,
+    cols: usize,
+    row_pins: Vec<u8>,
+    col_pins: Vec<u8>,
+    diode_direction: DiodeDirection,
+    sense: Sense,
+    split_left_cols: usize,
+}
+
+impl LayoutMatrix {
+    /// Whether the columns are driven, the same rule as
+    /// `key_scan::MatrixWiring::drives_columns`.
+    fn drives_columns(&self) -> bool {
+        matches!(
+            (self.diode_direction, self.sense),
+            (DiodeDirection::ColToRow, Sense::PullDown) | (DiodeDirection::RowToCol, Sense::PullUp)
+        )
+    }
+}
+
+/// `value` and its key in layout `name`'s table if it's set there, otherwise `default` and
+/// its key in the board's.
+fn layout_value<T: Clone>(
+    name: Option<&str>,
+    value: Option<&T>,
+    key: &str,
+    default: &T,
+    default_key: &str,
+) -> (T, String) {
+    match (name, value) {
+        (Some(name), Some(value)) => (value.clone(), format!("layouts.{name}.{key}")),
+        _ => (default.clone(), default_key.to_string()),
+    }
+}
+
+fn positive(size: usize, key: &str) -> usize {
+    if size == 0 {
+        panic!("board.toml: `{key}` should be positive");
+    }
+    size
+}
+
+fn pin(pin: u8, key: &str) -> u8 {
+    if pin >= 30 {
+        panic!("board.toml: `{key}` has a pin past GPIO29");
+    }
+    pin
+}
+
+/// The pins in `key`, which has to have `len` of them.
+fn pins(pins: Vec<u8>, key: &str, len: usize) -> Vec<u8> {
+    if pins.len() != len {
+        panic!("board.toml: `{key}` should have {len} pins, one for each in the matrix");
+    }
+    pins.into_iter().map(|gpio| pin(gpio, key)).collect()
+}
+
+impl Board {
+    fn parse(source: &str) -> Self {
+        toml::from_str(source).unwrap_or_else(|err| panic!("board.toml: {err}"))
+    }
+
+    /// The layout picked by a `layout-*` feature, if there's one for a layout in the file.
+    fn selected_layout(&self) -> Option<String> {
+        self.layouts
+            .keys()
+            .find(|name| {
+                let feature =
+                    format!("CARGO_FEATURE_LAYOUT_{}", name.to_uppercase().replace('-', "_"));
+                env::var_os(feature).is_some()
+            })
+            .cloned()
+    }
+
+    /// The board's matrix, with whatever layout `name`'s table sets in its place. Layouts on
+    /// a PCB with a different matrix set its size and pins this way.
+    fn matrix(&self, name: Option<&str>) -> LayoutMatrix {
+        let layout = name.map(|name| &self.layouts[name]);
+        let matrix = &self.matrix;
+
+        let (rows, key) = layout_value(
+            name,
+            layout.and_then(|l| l.rows.as_ref()),
+            "rows",
+            &matrix.rows,
+            "matrix.rows",
+        );
+        let rows = positive(rows, &key);
+        let (cols, key) = layout_value(
+            name,
+            layout.and_then(|l| l.cols.as_ref()),
+            "cols",
+            &matrix.cols,
+            "matrix.cols",
+        );
+        let cols = positive(cols, &key);
+
+        let row_pins = layout.and_then(|l| l.row_pins.as_ref());
+        let (row_pins, key) =
+            layout_value(name, row_pins, "row_pins", &self.pins.rows, "pins.rows");
+        let row_pins = pins(row_pins, &key, rows);
+        let col_pins = layout.and_then(|l| l.col_pins.as_ref());
+        let (col_pins, key) =
+            layout_value(name, col_pins, "col_pins", &self.pins.cols, "pins.cols");
+        let col_pins = pins(col_pins, &key, cols);
+
+        let diode_direction =
+            layout.and_then(|l| l.diode_direction).unwrap_or(matrix.diode_direction);
+        let sense = layout.and_then(|l| l.sense).unwrap_or(matrix.sense);
+
+        // Half of the columns on each side, if it's not set.
+        let left_cols = layout.and_then(|l| l.split_left_cols).map(Some);
+        let (left_cols, key) = layout_value(
+            name,
+            left_cols.as_ref(),
+            "split_left_cols",
+            &self.split.left_cols,
+            "split.left_cols",
+        );
+        let split_left_cols = left_cols.unwrap_or(cols.div_ceil(2));
+        if !(1..cols).contains(&split_left_cols) {
+            panic!("board.toml: `{key}` should be from 1 to {}", cols - 1);
+        }
+
+        LayoutMatrix { rows, cols, row_pins, col_pins, diode_direction, sense, split_left_cols }
+    }
+
+    /// `usb.poll_interval_ms`, or 1 ms if it's not set.
+    fn poll_interval_ms(&self) -> u32 {
+        let interval = self.usb.poll_interval_ms.unwrap_or(1);
+        if !(1..=8).contains(&interval) {
+            panic!("board.toml: `usb.poll_interval_ms` should be from 1 to 8");
+        }
+        interval
+    }
+
+    /// `usb.max_power_ma`, or 500 mA if it's not set.
+    fn max_power_ma(&self) -> u32 {
+        let max_power = self.usb.max_power_ma.unwrap_or(500);
+        if max_power > 500 {
+            panic!("board.toml: `usb.max_power_ma` should be at most 500");
+        }
+        max_power
+    }
+
+    /// `usb.manufacturer` or `usb.product`, which have to fit in a USB string descriptor.
+    fn usb_string<'a>(string: &'a str, key: &str) -> &'a str {
+        if string.is_empty() || string.encode_utf16().count() > 126 {
+            panic!("board.toml: `{key}` should be 1 to 126 characters");
+        }
+        string
+    }
+
+    fn constants(&self, layout: Option<&str>) -> String {
+        let LayoutMatrix {
+            rows,
+            cols,
+            row_pins,
+            col_pins,
+            diode_direction,
+            sense,
+            split_left_cols,
+        } = self.matrix(layout);
+        let poll_interval_ms = self.poll_interval_ms();
+        let max_power_ma = self.max_power_ma();
+        let self_powered = self.usb.self_powered;
+        let vendor_id = self.usb.vendor_id;
+        let product_id = self.usb.product_id;
+        let manufacturer = Self::usb_string(&self.usb.manufacturer, "usb.manufacturer");
+        let product = Self::usb_string(&self.usb.product, "usb.product");
+        format!(
+            "/// The number of columns in the matrix, from `board.toml`.\n\
+             pub const NUM_COLS: usize = {cols};\n\
+             /// The number of rows in the matrix, from `board.toml`.\n\
+             pub const NUM_ROWS: usize = {rows};\n\n\
+             /// The GPIO of each row, from `board.toml`.\n\
+             pub const ROW_PINS: [u8; NUM_ROWS] = {row_pins:?};\n\
+             /// The GPIO of each column, from `board.toml`.\n\
+             pub const COL_PINS: [u8; NUM_COLS] = {col_pins:?};\n\n\
+             /// Which lines of the matrix are driven and how the others are pulled, from\n\
+             /// `board.toml`.\n\
+             pub const MATRIX_WIRING: crate::key_scan::MatrixWiring = \
+                 crate::key_scan::MatrixWiring {{ \
+                     diode_direction: crate::key_scan::DiodeDirection::{diode_direction:?}, \
+                     sense: crate::key_scan::Sense::{sense:?} \
+                 }};\n\n\
+             /// The number of columns on the left half of a split keyboard, with the right\n\
+             /// half's after them, from `board.toml`.\n\
+             pub const SPLIT_LEFT_COLS: usize = {split_left_cols};\n\n\
+             /// The time from the start of one scan to the start of the next, which is also\n\
+             /// how often the host polls for reports, in milliseconds, from `board.toml`.\n\
+             pub const SCAN_PERIOD_MS: u32 = {poll_interval_ms};\n\n\
+             /// The most current the keyboard draws from USB, in milliamps, from `board.toml`.\n\
+             pub const USB_MAX_POWER_MA: usize = {max_power_ma};\n\
+             /// Whether the keyboard has its own power supply rather than running off USB, from\n\
+             /// `board.toml`.\n\
+             pub const USB_SELF_POWERED: bool = {self_powered};\n\n\
+             /// The USB vendor and product IDs, from `board.toml`.\n\
+             pub const USB_VENDOR_ID: u16 = {vendor_id:#06x};\n\
+             pub const USB_PRODUCT_ID: u16 = {product_id:#06x};\n\
+             /// The USB manufacturer and product strings, from `board.toml`.\n\
+             pub const USB_MANUFACTURER: &str = {manufacturer:?};\n\
+             pub const USB_PRODUCT: &str = {product:?};\n"
+        )
+    }
+
+    fn pin_macros(&self, layout: Option<&str>) -> String {
+        let matrix = self.matrix(layout);
+        let module_id = pin(self.pins.module_id, "pins.module_id");
+        let indicator_led = pin(self.pins.indicator_led, "pins.indicator_led");
+        if !matrix.row_pins.contains(&module_id) && !matrix.col_pins.contains(&module_id) {
+            panic!("board.toml: `pins.module_id` should be one of the matrix pins");
+        }
+
+        let drives_columns = matrix.drives_columns();
+        let pull = if matrix.sense == Sense::PullUp { "pull_up" } else { "pull_down" };
+        let (sense_pins, drive_pins) = if drives_columns {
+            (matrix.row_pins, matrix.col_pins)
+        } else {
+            (matrix.col_pins, matrix.row_pins)
+        };
+        let pin = |pin: u8| {
+            if pin == module_id {
+                "$module_id_pin".to_string()
+            } else {
+                format!("$pins.gpio{pin}")
+            }
+        };
+
+        let mut sense = String::new();
+        for gpio in sense_pins {
+            write!(sense, "&{}.into_{pull}_input(), ", pin(gpio)).unwrap();
+        }
+        let mut drive = String::new();
+        let mut pio_drive = String::new();
+        for gpio in drive_pins {
+            write!(drive, "&mut {}.into_push_pull_output(), ", pin(gpio)).unwrap();
+            write!(
+                pio_drive,
+                "let _: rp2040_hal::gpio::Pin<_, rp2040_hal::gpio::FunctionPio1> = \
+                 {}.into_mode(); ",
+                pin(gpio)
+            )
+            .unwrap();
+        }
+
+        format!(
+            "/// The expansion module's ID pin, from `board.toml`, as an input for the ADC.\n\
+             macro_rules! module_id_pin {{ ($pins:ident) => {{ \
+                 $pins.gpio{module_id}.into_floating_input() \
+             }}; }}\n\n\
+             /// The pins reading the switches, from `board.toml`, as inputs pulled the way\n\
+             /// `MATRIX_WIRING` says. The ID pin has already been taken for `module_id_pin`,\n\
+             /// so it's passed in. Capacitive boards set up the row pins their own way.\n\
+             #[allow(unused_macros)]\n\
+             macro_rules! sense_pins {{ ($pins:ident, $module_id_pin:ident) => {{ \
+                 &[{sense}] \
+             }}; }}\n\n\
+             /// The pins driven in turn, from `board.toml`, as outputs.\n\
+             #[allow(unused_macros)]\n\
+             macro_rules! drive_pins {{ ($pins:ident, $module_id_pin:ident) => {{ \
+                 &mut [{drive}] \
+             }}; }}\n\n\
+             /// The pins driven in turn, from `board.toml`, handed over to PIO1 for\n\
+             /// `pio_scan::PioScanner`.\n\
+             #[allow(unused_macros)]\n\
+             macro_rules! pio_drive_pins {{ ($pins:ident, $module_id_pin:ident) => {{ \
+                 {pio_drive} \
+             }}; }}\n\n\
+             /// The indicator LED's pin, from `board.toml`, as an output.\n\
+             macro_rules! indicator_led_pin {{ ($pins:ident) => {{ \
+                 $pins.gpio{indicator_led}.into_push_pull_output() \
+             }}; }}\n\n\
+             /// The GPIO number of the indicator LED, for the panic handler, which can't\n\
+             /// have the pin.\n\
+             #[allow(unused)]\n\
+             const INDICATOR_LED_GPIO: usize = {indicator_led};\n"
+        )
+    }
+
+    fn layout_names(&self) -> Vec<String> {
+        self.layouts.keys().cloned().collect()
+    }
+
+    fn layout(&self, name: &str, key_codes: &[&str]) -> String {
+        let LayoutMatrix { rows, cols, .. } = self.matrix(Some(name));
+        let layout = &self.layouts[name];
+        let mut code = String::new();
+
+        for (col, row) in &layout.unpopulated {
+            if *col >= cols || *row >= rows {
+                panic!(
+                    "board.toml: `layouts.{name}.unpopulated` has [{col}, {row}], which is \
+                     outside the {cols} column, {rows} row matrix"
+                );
+            }
+        }
+        let positions: Vec<String> =
+            layout.unpopulated.iter().map(|(col, row)| format!("({col}, {row})")).collect();
+        writeln!(
+            code,
+            "/// Matrix positions, as `(column, row)`, without a switch in this layout."
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "pub const UNPOPULATED_KEYS: &[(usize, usize)] = &[{}];",
+            positions.join(", ")
+        )
+        .unwrap();
+
+        for (layer, constant) in [
+            ("normal", "NORMAL_LAYER_MAPPING"),
+            ("fn", "FN_LAYER_MAPPING"),
+            ("num", "NUM_LAYER_MAPPING"),
+        ] {
+            let mapping = self.layer_mapping(name, layer, rows, cols, key_codes);
+            writeln!(code, "\n/// The `{layer}` layer of this layout in `board.toml`.").unwrap();
+            writeln!(code, "pub const {constant}: [[KeyCode; NUM_ROWS]; NUM_COLS] = {mapping};")
+                .unwrap();
+        }
+
+        // Other base layers, like Colemak or Dvorak, which can take the normal layer's place.
+        let bases: Vec<String> = layout
+            .bases
+            .iter()
+            .map(|base| self.layer_mapping(name, base, rows, cols, key_codes))
+            .collect();
+        writeln!(
+            code,
+            "\n/// The layers in `bases` of this layout in `board.toml`, which can take the \
+             normal\n/// layer's place.\n\
+             pub const EXTRA_BASE_LAYERS: [[[KeyCode; NUM_ROWS]; NUM_COLS]; {}] = [{}];",
+            bases.len(),
+            bases.join(", ")
+        )
+        .unwrap();
+
+        code
+    }
+
+    /// Layout `name`'s `layer` as a Rust array of `KeyCode`s, column by column.
+    fn layer_mapping(
+        &self,
+        name: &str,
+        layer: &str,
+        rows: usize,
+        cols: usize,
+        key_codes: &[&str],
+    ) -> String {
+        let key = format!("layouts.{name}.{layer}");
+        let columns = self.layer(name, layer, rows, cols);
+        for (col, column) in columns.iter().enumerate() {
+            for (row, key_name) in column.iter().enumerate() {
+                if !key_codes.contains(&key_name.as_str()) {
+                    panic!(
+                        "board.toml: `{key}` has `{key_name}` at column {col}, row {row}, which \
+                         isn't a `KeyCode`"
+                    );
+                }
+                // A key there could never be pressed.
+                if self.layouts[name].unpopulated.contains(&(col, row)) && key_name != "Empty" {
+                    panic!(
+                        "board.toml: `{key}` has `{key_name}` at column {col}, row {row}, which \
+                         is unpopulated, so it should be `Empty`"
+                    );
+                }
+            }
+        }
+
+        let columns: Vec<String> = columns
+            .iter()
+            .map(|column| {
+                let keys: Vec<String> =
+                    column.iter().map(|key_name| format!("KeyCode::{key_name}")).collect();
+                format!("\n    [{}],", keys.join(", "))
+            })
+            .collect();
+        format!("[{}\n]", columns.concat())
+    }
+
+    /// The key names of layout `name`'s `layer`, column by column.
+    fn layer(&self, name: &str, layer: &str, rows: usize, cols: usize) -> Vec<Vec<String>> {
+        let key = format!("layouts.{name}.{layer}");
+        let columns: Vec<Vec<String>> = match self.layouts[name].layers.get(layer) {
+            None => panic!("board.toml: `{key}` is missing"),
+            Some(Layer::Grid(grid)) => {
+                let lines: Vec<Vec<&str>> = grid
+                    .lines()
+                    .map(|line| line.split_whitespace().collect::<Vec<_>>())
+                    .filter(|line| !line.is_empty())
+                    .collect();
+                if lines.len() != rows {
+                    panic!("board.toml: `{key}` should have {rows} rows");
+                }
+                for (row, line) in lines.iter().enumerate() {
+                    if line.len() != cols {
+                        panic!("board.toml: row {row} of `{key}` should have {cols} keys");
+                    }
+                }
+                (0..cols)
+                    .map(|col| lines.iter().map(|line| line[col].to_string()).collect())
+                    .collect()
+            },
+            Some(Layer::Columns(columns)) => columns.clone(),
+        };
+
+        if columns.len() != cols {
+            panic!("board.toml: `{key}` should have {cols} columns");
+        }
+        if columns.iter().any(|column| column.len() != rows) {
+            panic!("board.toml: every column of `{key}` should have {rows} keys");
+        }
+        columns
+    }
+}
+
+/// The names of every `KeyCode`, from the same list in `src/key_code_list.rs` the enum is
+/// built from, so a key misspelled in `board.toml` is caught here rather than as an error
+/// in generated code.
+fn key_code_names() -> Vec<&'static str> {
+    macro_rules! key_codes {
+        ($($(#[$attr:meta])* $name:ident = $value:literal,)*) => {
+            vec![$(stringify!($name)),*]
+        };
+    }
+    include!("src/key_code_list.rs")
 }
@@ -0,0 +1,158 @@
+//! The smallest board binary built on the `key_ripper` library: scan the matrix, debounce
+//! it, and send the reports over USB, all from a polling loop without interrupts.
+//!
+//! A new RP2040 board starts from a copy of this. Its matrix, pins, USB IDs and keymaps go
+//! in a board file of its own, in the format of the crate's `board.toml`, which the build
+//! reads in place of that one when `KEY_RIPPER_BOARD` is set to its path. The firmware in
+//! `src/main.rs` is the same thing with everything else layered on top: interrupt-driven
+//! USB, settings, expansion modules, and the wireless links.
+//!
+//! ```
+//! cargo run --release --example minimal_board
+//! KEY_RIPPER_BOARD=/path/to/my_board.toml cargo run --release --example minimal_board
+//! ```
+
+#![no_std]
+#![no_main]
+
+use core::convert::Infallible;
+
+use defmt::info;
+use defmt_rtt as _;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use key_ripper::{
+    debounce::Debounce,
+    hid_descriptor,
+    key_scan::{KeyScan, MatrixWiring},
+    keyboard::Keyboard,
+    profile::Profile,
+    NUM_COLS, NUM_ROWS, SCAN_PERIOD_MS,
+};
+use panic_probe as _;
+use rp2040_hal::{pac, usb::UsbBus, Clock, Timer, Watchdog};
+use usb_device::{bus::UsbBusAllocator, device::UsbDeviceBuilder, prelude::*};
+use usbd_hid::hid_class::{
+    HIDClass, HidClassSettings, HidCountryCode, HidProtocol, HidSubClass, ProtocolModeConfig,
+};
+
+const EXTERNAL_CRYSTAL_FREQUENCY_HZ: u32 = 12_000_000;
+
+/// How often the matrix is scanned, which is also the debounce tick.
+const SCAN_PERIOD_US: u64 = SCAN_PERIOD_MS as u64 * 1000;
+
+/// The number of scans a released key is still reported as held for.
+const DEBOUNCE_TICKS: u8 = 5;
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let mut pac = pac::Peripherals::take().unwrap();
+    let core = pac::CorePeripherals::take().unwrap();
+
+    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+    let clocks = rp2040_hal::clocks::init_clocks_and_plls(
+        EXTERNAL_CRYSTAL_FREQUENCY_HZ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+
+    let sio = rp2040_hal::Sio::new(pac.SIO);
+    let pins =
+        rp2040_hal::gpio::Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+
+    // The board's matrix: driven columns, and rows with pull-downs behind the diodes, which
+    // is how `MatrixWiring::default()` scans it.
+    let rows: &[&dyn InputPin<Error = Infallible>] = &[
+        &pins.gpio26.into_pull_down_input(),
+        &pins.gpio25.into_pull_down_input(),
+        &pins.gpio27.into_pull_down_input(),
+        &pins.gpio28.into_pull_down_input(),
+        &pins.gpio15.into_pull_down_input(),
+        &pins.gpio24.into_pull_down_input(),
+    ];
+    let cols: &mut [&mut dyn OutputPin<Error = Infallible>] = &mut [
+        &mut pins.gpio29.into_push_pull_output(),
+        &mut pins.gpio16.into_push_pull_output(),
+        &mut pins.gpio17.into_push_pull_output(),
+        &mut pins.gpio18.into_push_pull_output(),
+        &mut pins.gpio9.into_push_pull_output(),
+        &mut pins.gpio10.into_push_pull_output(),
+        &mut pins.gpio19.into_push_pull_output(),
+        &mut pins.gpio11.into_push_pull_output(),
+        &mut pins.gpio12.into_push_pull_output(),
+        &mut pins.gpio13.into_push_pull_output(),
+        &mut pins.gpio14.into_push_pull_output(),
+        &mut pins.gpio20.into_push_pull_output(),
+        &mut pins.gpio22.into_push_pull_output(),
+        &mut pins.gpio23.into_push_pull_output(),
+    ];
+    let matrix_mask = [[true; NUM_ROWS]; NUM_COLS];
+
+    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+
+    let bus_allocator = UsbBusAllocator::new(UsbBus::new(
+        pac.USBCTRL_REGS,
+        pac.USBCTRL_DPRAM,
+        clocks.usb_clock,
+        true,
+        &mut pac.RESETS,
+    ));
+    let mut hid = HIDClass::new_with_settings(
+        &bus_allocator,
+        hid_descriptor::KEYBOARD_REPORT_DESCRIPTOR,
+        SCAN_PERIOD_MS as u8,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Keyboard,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::US,
+        },
+    );
+    // https://github.com/obdev/v-usb/blob/7a28fdc685952412dad2b8842429127bc1cf9fa7/usbdrv/USB-IDs-for-free.txt#L128
+    let mut usb_device = UsbDeviceBuilder::new(&bus_allocator, UsbVidPid(0x16c0, 0x27db))
+        .manufacturer("bschwind")
+        .product("key ripper (minimal)")
+        .build();
+
+    // Modifiers aren't exempt from debouncing here, to keep things short.
+    let mut debounce: Debounce<NUM_ROWS, NUM_COLS> =
+        Debounce::new(DEBOUNCE_TICKS, [[false; NUM_ROWS]; NUM_COLS]);
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    info!("Scanning");
+    let mut next_scan_at = timer.get_counter();
+    loop {
+        usb_device.poll(&mut [&mut hid]);
+
+        if timer.get_counter() < next_scan_at {
+            continue;
+        }
+        next_scan_at += SCAN_PERIOD_US;
+
+        let scan = KeyScan::scan(
+            rows,
+            cols,
+            MatrixWiring::default(),
+            &mut delay,
+            &matrix_mask,
+            &mut debounce,
+        );
+        let report = keyboard.report(&scan);
+        if usb_device.state() == UsbDeviceState::Configured {
+            // A report the host hasn't taken yet is replaced by the next scan's.
+            hid.push_input(&report).ok();
+        }
+        hid.pull_raw_output(&mut [0; 64]).ok();
+    }
+}
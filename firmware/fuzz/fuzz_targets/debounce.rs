@@ -0,0 +1,79 @@
+//! Feeds arbitrary sequences of matrix transitions through `Debounce`, checking it against
+//! a simple model of the algorithm.
+//!
+//! The first byte picks the expiration ticks. Every following byte toggles one key and
+//! then runs a scan, or with the high bit set, toggles the key without scanning so several
+//! keys can change in the same scan. Bytes which don't name a key just run a scan.
+
+#![no_main]
+
+use key_ripper::{
+    debounce::{Debounce, Debouncer},
+    key_mapping, NUM_COLS, NUM_ROWS,
+};
+use libfuzzer_sys::fuzz_target;
+
+const NUM_KEYS: usize = NUM_COLS * NUM_ROWS;
+
+/// Stands in for "never pressed" in the released scan counts.
+const NEVER_PRESSED: u32 = u32::MAX;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&ticks, events)) = data.split_first() else { return };
+    let expiration_ticks = ticks % 32 + 1;
+
+    let mut modifier_mask = [[false; NUM_ROWS]; NUM_COLS];
+    for (col, mapping_col) in modifier_mask.iter_mut().zip(key_mapping::NORMAL_LAYER_MAPPING) {
+        for (key, mapping_key) in col.iter_mut().zip(mapping_col) {
+            *key = mapping_key.is_modifier();
+        }
+    }
+
+    let mut debounce = Debounce::new(expiration_ticks, modifier_mask);
+    let mut matrix = [[false; NUM_ROWS]; NUM_COLS];
+
+    // The number of scans each key has been released for.
+    let mut released_scans = [[NEVER_PRESSED; NUM_ROWS]; NUM_COLS];
+
+    let mut scan = |matrix: &[[bool; NUM_ROWS]; NUM_COLS]| {
+        let debounced = debounce.report_and_tick(matrix);
+
+        for col in 0..NUM_COLS {
+            for row in 0..NUM_ROWS {
+                let released = &mut released_scans[col][row];
+                *released = if matrix[col][row] { 0 } else { released.saturating_add(1) };
+
+                let expected = if modifier_mask[col][row] {
+                    matrix[col][row]
+                } else {
+                    *released < expiration_ticks as u32
+                };
+
+                assert_eq!(
+                    debounced[col][row], expected,
+                    "key ({col}, {row}) released for {} scans",
+                    *released
+                );
+            }
+        }
+    };
+
+    for &event in events {
+        let key = (event & 0x7F) as usize;
+        if key < NUM_KEYS {
+            let (col, row) = (key / NUM_ROWS, key % NUM_ROWS);
+            matrix[col][row] = !matrix[col][row];
+        }
+
+        if event & 0x80 == 0 {
+            scan(&matrix);
+        }
+    }
+
+    // No key may stay stuck once everything has been released for long enough.
+    let released = [[false; NUM_ROWS]; NUM_COLS];
+    for _ in 0..expiration_ticks {
+        scan(&released);
+    }
+    assert!(debounce.report_and_tick(&released).iter().flatten().all(|pressed| !pressed));
+});
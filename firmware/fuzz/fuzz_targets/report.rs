@@ -0,0 +1,103 @@
+//! Feeds arbitrary sequences of matrix transitions through `Keyboard::report`, checking the
+//! invariants every report must hold.
+//!
+//! The first byte picks the profile. Every following byte toggles one key and then builds
+//! a report, or with the high bit set, toggles the key without building a report so
+//! several keys can change at once. Bytes which don't name a key just build a report.
+
+#![no_main]
+
+use key_ripper::{
+    key_codes::KeyCode, key_mapping, key_scan::KeyScan, keyboard::Keyboard, profile::Profile,
+    NUM_COLS, NUM_ROWS,
+};
+use libfuzzer_sys::fuzz_target;
+
+const NUM_KEYS: usize = NUM_COLS * NUM_ROWS;
+
+const LAYERS: [[[KeyCode; NUM_ROWS]; NUM_COLS]; 3] = [
+    key_mapping::NORMAL_LAYER_MAPPING,
+    key_mapping::FN_LAYER_MAPPING,
+    key_mapping::NUM_LAYER_MAPPING,
+];
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&profile, events)) = data.split_first() else { return };
+    let profile = if profile & 1 == 0 { Profile::Typing } else { Profile::Gaming };
+
+    let mut keyboard = Keyboard::new(profile);
+    let mut matrix = [[false; NUM_ROWS]; NUM_COLS];
+
+    for &event in events {
+        let key = (event & 0x7F) as usize;
+        if key < NUM_KEYS {
+            let (col, row) = (key / NUM_ROWS, key % NUM_ROWS);
+            matrix[col][row] = !matrix[col][row] && key_mapping::MATRIX_MASK[col][row];
+        }
+
+        if event & 0x80 == 0 {
+            check_report(&mut keyboard, &matrix);
+        }
+    }
+
+    // Releasing everything has to clear the report, whatever state the keyboard is in.
+    let report = keyboard.report(&KeyScan::from([[false; NUM_ROWS]; NUM_COLS]));
+    assert_eq!(report.modifier, 0);
+    assert_eq!(report.keycodes, [0; 6]);
+});
+
+fn check_report(keyboard: &mut Keyboard, matrix: &Matrix) {
+    let num_word_was_active = keyboard.num_word_active();
+    let profile = keyboard.profile();
+    let report = keyboard.report(&KeyScan::from(*matrix));
+
+    let pressed_keys = |layer: &[[KeyCode; NUM_ROWS]; NUM_COLS]| {
+        layer
+            .iter()
+            .flatten()
+            .zip(matrix.iter().flatten())
+            .filter(|(_, pressed)| **pressed)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>()
+    };
+
+    // Keycodes are packed at the start of the report, with no gaps.
+    let used = report.keycodes.iter().take_while(|code| **code != 0).count();
+    assert!(report.keycodes[used..].iter().all(|code| *code == 0), "{:?}", report.keycodes);
+
+    // Every keycode is a real HID usage of a key which is pressed on some layer.
+    for code in &report.keycodes[..used] {
+        let key = LAYERS
+            .iter()
+            .flat_map(pressed_keys)
+            .find(|key| *key as u8 == *code)
+            .unwrap_or_else(|| panic!("{code:#04x} doesn't belong to any pressed key"));
+
+        assert!(!key.is_firmware_key() && !key.is_modifier(), "{key:?} was reported");
+    }
+
+    // Without Fn or Num Word, the report is exactly the first six pressed keys on the normal
+    // layer, along with the pressed modifiers.
+    let normal_keys = pressed_keys(&key_mapping::NORMAL_LAYER_MAPPING);
+    if num_word_was_active || normal_keys.contains(&KeyCode::Fn) {
+        return;
+    }
+
+    let expected_keycodes: Vec<u8> = normal_keys
+        .iter()
+        .filter(|key| !key.is_modifier() && !key.is_firmware_key() && **key != KeyCode::Empty)
+        .take(6)
+        .map(|key| *key as u8)
+        .collect();
+    assert_eq!(&report.keycodes[..used], &expected_keycodes[..]);
+
+    let gui_locked = profile.settings().gui_locked;
+    let expected_modifier = normal_keys
+        .iter()
+        .filter(|key| !(gui_locked && matches!(key, KeyCode::LeftCmd | KeyCode::RightCmd)))
+        .filter_map(|key| key.modifier_bitmask())
+        .fold(0, |modifier, bitmask| modifier | bitmask);
+    assert_eq!(report.modifier, expected_modifier);
+}
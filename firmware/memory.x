@@ -1,9 +1,41 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 64K
+
+    /* Persistent data partitions, at the end of flash. Each one has to start on a 4K
+       sector boundary, and flashing new firmware leaves them untouched. */
+    SCAN_TRACE  : ORIGIN = 0x101F0000, LENGTH = 32K
+    KEYMAP      : ORIGIN = 0x101F8000, LENGTH = 12K
+    MACROS      : ORIGIN = 0x101FB000, LENGTH = 8K
+    CRASH_LOG   : ORIGIN = 0x101FD000, LENGTH = 4K
+    CALIBRATION : ORIGIN = 0x101FE000, LENGTH = 4K
+    SETTINGS    : ORIGIN = 0x101FF000, LENGTH = 4K
+
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
+/* The partition boundaries, used by the `flash` module to find each partition. */
+__scan_trace_start = ORIGIN(SCAN_TRACE);
+__scan_trace_end = ORIGIN(SCAN_TRACE) + LENGTH(SCAN_TRACE);
+__keymap_start = ORIGIN(KEYMAP);
+__keymap_end = ORIGIN(KEYMAP) + LENGTH(KEYMAP);
+__macros_start = ORIGIN(MACROS);
+__macros_end = ORIGIN(MACROS) + LENGTH(MACROS);
+__crash_log_start = ORIGIN(CRASH_LOG);
+__crash_log_end = ORIGIN(CRASH_LOG) + LENGTH(CRASH_LOG);
+__calibration_start = ORIGIN(CALIBRATION);
+__calibration_end = ORIGIN(CALIBRATION) + LENGTH(CALIBRATION);
+__settings_start = ORIGIN(SETTINGS);
+__settings_end = ORIGIN(SETTINGS) + LENGTH(SETTINGS);
+
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= ORIGIN(SCAN_TRACE), "Firmware overlaps the scan trace partition");
+ASSERT(ORIGIN(SCAN_TRACE) % 4K == 0, "SCAN_TRACE partition must be sector aligned");
+ASSERT(ORIGIN(KEYMAP) % 4K == 0, "KEYMAP partition must be sector aligned");
+ASSERT(ORIGIN(MACROS) % 4K == 0, "MACROS partition must be sector aligned");
+ASSERT(ORIGIN(CRASH_LOG) % 4K == 0, "CRASH_LOG partition must be sector aligned");
+ASSERT(ORIGIN(CALIBRATION) % 4K == 0, "CALIBRATION partition must be sector aligned");
+ASSERT(ORIGIN(SETTINGS) % 4K == 0, "SETTINGS partition must be sector aligned");
+
 EXTERN(BOOT2_FIRMWARE)
 
 SECTIONS {
@@ -0,0 +1,155 @@
+//! Auto Shift, which sends the shifted version of a letter, number or symbol when its key is
+//! held a little longer than a tap, so Shift rarely has to be reached for.
+//!
+//! When a key which auto shifts is pressed, it's held back until it's either released,
+//! which sends it as it is for one scan, or held for `AUTO_SHIFT_TICKS`, after which it's
+//! pressed with Shift until it's released. Pressing another key first sends it unshifted
+//! straight away, and that key is held back for a scan so the host sees it after. Pressing
+//! another key while one is shifted releases the shifted one, so Shift doesn't spill onto
+//! the new key. Keys pressed along with a modifier aren't held back, as they're already
+//! what was asked for. The report is held back while a key is.
+
+use crate::{key_codes::KeyCode, ms_to_ticks, NUM_COLS, NUM_ROWS};
+
+/// How long a key has to be held to be shifted, 175 ms.
+pub const AUTO_SHIFT_TICKS: u32 = ms_to_ticks(175);
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+
+    /// A key is held back, pressed for `ticks` scans counting the one which saw it.
+    Pending {
+        position: (usize, usize),
+        ticks: u32,
+    },
+
+    /// A key released while it was held back, sent for one scan.
+    Tapped((usize, usize)),
+
+    /// A key held long enough to be shifted, pressed with Shift until it's released.
+    Shifted((usize, usize)),
+
+    /// A shifted key which another key was pressed after, which does nothing until it's
+    /// released.
+    Released((usize, usize)),
+}
+
+pub struct AutoShift {
+    enabled: bool,
+    state: State,
+
+    /// The matrix from the previous update, used to find newly pressed keys.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl Default for AutoShift {
+    fn default() -> Self {
+        Self { enabled: false, state: State::Idle, previous_matrix: [[false; NUM_ROWS]; NUM_COLS] }
+    }
+}
+
+impl AutoShift {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Advance Auto Shift by `elapsed_ticks` scan ticks, zero for another report in the same
+    /// scan, with `mapping` the keys resolved through the layers. Returns the matrix to build
+    /// the report from, or `None` while a key is held back and the report should be too.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        mapping: &[[KeyCode; NUM_ROWS]; NUM_COLS],
+        elapsed_ticks: u32,
+    ) -> Option<[[bool; NUM_ROWS]; NUM_COLS]> {
+        let mut new_presses = [[false; NUM_ROWS]; NUM_COLS];
+        for (col, column) in new_presses.iter_mut().enumerate() {
+            for (row, new_press) in column.iter_mut().enumerate() {
+                *new_press = matrix[col][row] && !self.previous_matrix[col][row];
+            }
+        }
+        let previous_matrix = core::mem::replace(&mut self.previous_matrix, *matrix);
+        let pressed = |(col, row): (usize, usize)| matrix[col][row];
+
+        // Pressing another key decides a key held back, or releases a shifted one, and the
+        // other key waits a scan to follow it.
+        let other_key_pressed = |position| {
+            positions().any(|(col, row)| new_presses[col][row] && (col, row) != position)
+        };
+        let interrupted = match self.state {
+            State::Pending { position, .. } | State::Shifted(position) => {
+                other_key_pressed(position)
+            },
+            _ => false,
+        };
+
+        self.state = match self.state {
+            State::Pending { position, .. } if !pressed(position) => State::Tapped(position),
+            State::Pending { .. } if interrupted => State::Idle,
+            State::Pending { position, ticks } if ticks + elapsed_ticks >= AUTO_SHIFT_TICKS => {
+                State::Shifted(position)
+            },
+            State::Pending { position, ticks } => {
+                State::Pending { position, ticks: ticks + elapsed_ticks }
+            },
+            State::Shifted(position) if !pressed(position) => State::Idle,
+            State::Shifted(position) if interrupted => State::Released(position),
+            State::Released(position) if !pressed(position) => State::Idle,
+            State::Tapped(_) => State::Idle,
+            state => state,
+        };
+
+        if interrupted {
+            // Still newly pressed next scan.
+            for (col, row) in positions() {
+                self.previous_matrix[col][row] &= !new_presses[col][row];
+            }
+        } else if self.state == State::Idle && self.enabled {
+            let modifier_held =
+                positions().any(|(col, row)| matrix[col][row] && mapping[col][row].is_modifier());
+            let first_press = positions().find(|(col, row)| new_presses[*col][*row]);
+
+            if let Some((col, row)) = first_press {
+                if mapping[col][row].auto_shifts() && !modifier_held {
+                    self.state = State::Pending { position: (col, row), ticks: 1 };
+
+                    // Anything pressed in the same scan follows it.
+                    self.previous_matrix = previous_matrix;
+                    self.previous_matrix[col][row] = true;
+                }
+            }
+        }
+
+        let mut matrix = *matrix;
+        for (pressed, was_pressed) in
+            matrix.iter_mut().flatten().zip(self.previous_matrix.iter().flatten())
+        {
+            // Keys waiting for a later scan.
+            *pressed &= *was_pressed;
+        }
+        match self.state {
+            State::Pending { .. } => return None,
+            State::Tapped((col, row)) => matrix[col][row] = true,
+            State::Released((col, row)) => matrix[col][row] = false,
+            State::Idle | State::Shifted(_) => {},
+        }
+        Some(matrix)
+    }
+
+    /// The modifiers to add to the report, Shift while a key is shifted.
+    pub fn modifiers(&self) -> u8 {
+        match self.state {
+            State::Shifted(_) => KeyCode::LeftShift.modifier_bitmask().unwrap_or(0),
+            _ => 0,
+        }
+    }
+}
+
+fn positions() -> impl Iterator<Item = (usize, usize)> {
+    (0..NUM_COLS).flat_map(|col| (0..NUM_ROWS).map(move |row| (col, row)))
+}
@@ -0,0 +1,226 @@
+//! A bridge to an external Bluetooth LE HID module over UART, such as an nRF52 running
+//! bridge firmware. The module advertises itself as a Bluetooth keyboard and mouse, and
+//! sends the hosts it's connected to whatever reports it gets from the keyboard.
+//!
+//! # Protocol
+//! Frames in both directions are the start byte `0x7E`, the frame type, the length of the
+//! payload, the payload, and a CRC-8 (polynomial `0x07`) over the type, length and payload.
+//! The UART runs at 115200 baud, 8N1.
+//!
+//! | Type   | Direction   | Payload                                              |
+//! |--------|-------------|------------------------------------------------------|
+//! | `0x01` | To module   | Keyboard report: modifier, reserved, six keycodes    |
+//! | `0x02` | To module   | Mouse report: buttons, X, Y, wheel, pan              |
+//! | `0x03` | To module   | None, asks the module to send its status             |
+//! | `0x81` | From module | Status: 0 idle, 1 advertising, 2 connected           |
+//!
+//! The module sends its status whenever its connection changes, as well as when asked.
+//! Frames of unknown types are ignored, so either end can add more later.
+
+#[cfg(feature = "ble")]
+use core::convert::Infallible;
+
+use defmt::Format;
+#[cfg(feature = "ble")]
+use embedded_hal::serial::{Read, Write};
+use usbd_hid::descriptor::{KeyboardReport, MouseReport};
+
+const START: u8 = 0x7E;
+
+const TYPE_KEYBOARD_REPORT: u8 = 0x01;
+const TYPE_MOUSE_REPORT: u8 = 0x02;
+const TYPE_STATUS_REQUEST: u8 = 0x03;
+const TYPE_STATUS: u8 = 0x81;
+
+/// The longest payload either end sends.
+const MAX_PAYLOAD_LEN: usize = 8;
+
+/// The size of a frame with the longest payload.
+pub const MAX_FRAME_SIZE: usize = MAX_PAYLOAD_LEN + 4;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum BleStatus {
+    /// Not connected, and not looking for a host.
+    Idle,
+
+    /// Waiting for a host to connect.
+    Advertising,
+
+    /// Connected to a host, which gets the reports.
+    Connected,
+}
+
+/// What the keyboard sends the module.
+#[derive(Copy, Clone)]
+pub enum Message {
+    Keyboard(KeyboardReport),
+    Mouse(MouseReport),
+    StatusRequest,
+}
+
+impl Message {
+    /// Encode the message into `buf`, returning the frame.
+    pub fn encode<'a>(&self, buf: &'a mut [u8; MAX_FRAME_SIZE]) -> &'a [u8] {
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        let (frame_type, payload_len) = match self {
+            Message::Keyboard(report) => {
+                payload[0] = report.modifier;
+                payload[1] = report.reserved;
+                payload[2..8].copy_from_slice(&report.keycodes);
+                (TYPE_KEYBOARD_REPORT, 8)
+            },
+            Message::Mouse(report) => {
+                payload[..5].copy_from_slice(&[
+                    report.buttons,
+                    report.x as u8,
+                    report.y as u8,
+                    report.wheel as u8,
+                    report.pan as u8,
+                ]);
+                (TYPE_MOUSE_REPORT, 5)
+            },
+            Message::StatusRequest => (TYPE_STATUS_REQUEST, 0),
+        };
+
+        buf[0] = START;
+        buf[1] = frame_type;
+        buf[2] = payload_len as u8;
+        buf[3..3 + payload_len].copy_from_slice(&payload[..payload_len]);
+        buf[3 + payload_len] = crc8(&buf[1..3 + payload_len]);
+
+        &buf[..4 + payload_len]
+    }
+}
+
+/// CRC-8 with the polynomial 0x07 and no reflection, starting from zero.
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { crc << 1 ^ 0x07 } else { crc << 1 })
+    })
+}
+
+/// Picks the module's frames out of the bytes coming from the UART.
+#[derive(Default)]
+pub struct FrameParser {
+    buf: [u8; MAX_FRAME_SIZE],
+    len: usize,
+}
+
+impl FrameParser {
+    /// Add a received byte, returning the module's status if it completes a status frame.
+    pub fn push(&mut self, byte: u8) -> Option<BleStatus> {
+        if self.len == 0 && byte != START {
+            return None;
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len < 3 {
+            return None;
+        }
+
+        let payload_len = self.buf[2] as usize;
+        if payload_len > MAX_PAYLOAD_LEN {
+            // Not a real frame, so look for the next start byte.
+            self.len = 0;
+            return None;
+        }
+
+        if self.len < 4 + payload_len {
+            return None;
+        }
+
+        self.len = 0;
+        let frame = &self.buf[..4 + payload_len];
+        if crc8(&frame[1..3 + payload_len]) != frame[3 + payload_len] {
+            return None;
+        }
+
+        match (frame[1], &frame[3..3 + payload_len]) {
+            (TYPE_STATUS, [0]) => Some(BleStatus::Idle),
+            (TYPE_STATUS, [1]) => Some(BleStatus::Advertising),
+            (TYPE_STATUS, [2]) => Some(BleStatus::Connected),
+            _ => None,
+        }
+    }
+}
+
+/// Talks to the module over a UART, keeping track of its connection.
+#[cfg(feature = "ble")]
+pub struct BleLink<UART> {
+    uart: UART,
+    parser: FrameParser,
+    status: BleStatus,
+
+    /// The last keyboard report sent to the module.
+    sent_keyboard_report: Option<(u8, [u8; 6])>,
+}
+
+#[cfg(feature = "ble")]
+impl<UART> BleLink<UART>
+where
+    UART: Read<u8> + Write<u8, Error = Infallible>,
+{
+    /// Start talking to the module, asking it for its status.
+    pub fn new(uart: UART) -> Self {
+        let mut link = Self {
+            uart,
+            parser: FrameParser::default(),
+            status: BleStatus::Idle,
+            sent_keyboard_report: None,
+        };
+        link.send(Message::StatusRequest);
+        link
+    }
+
+    pub fn status(&self) -> BleStatus {
+        self.status
+    }
+
+    /// Handle everything the module has sent, returning its new status if it changed.
+    pub fn poll(&mut self) -> Option<BleStatus> {
+        let previous = self.status;
+
+        loop {
+            match self.uart.read() {
+                Ok(byte) => {
+                    if let Some(status) = self.parser.push(byte) {
+                        self.status = status;
+                    }
+                },
+                // Framing and overrun errors just lose bytes, which the CRC catches.
+                Err(nb::Error::Other(_)) => {},
+                Err(nb::Error::WouldBlock) => break,
+            }
+        }
+
+        if self.status != previous {
+            // Make sure a newly connected host gets the current state of the keys.
+            self.sent_keyboard_report = None;
+            Some(self.status)
+        } else {
+            None
+        }
+    }
+
+    /// Send the keyboard report if the module doesn't have it yet.
+    pub fn send_keyboard(&mut self, report: &KeyboardReport) {
+        let contents = (report.modifier, report.keycodes);
+        if self.sent_keyboard_report != Some(contents) {
+            self.send(Message::Keyboard(*report));
+            self.sent_keyboard_report = Some(contents);
+        }
+    }
+
+    pub fn send_mouse(&mut self, report: &MouseReport) {
+        self.send(Message::Mouse(*report));
+    }
+
+    fn send(&mut self, message: Message) {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        for byte in message.encode(&mut buf) {
+            nb::block!(self.uart.write(*byte)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Getting into the USB bootloader without holding Escape while plugging the keyboard in,
+//! which is awkward once the board is in a case: with `KeyCode::Bootloader`, or by pressing
+//! the reset button twice in quick succession.
+//!
+//! A double reset is spotted with a marker in RAM, set at boot and cleared once
+//! `DOUBLE_RESET_WINDOW_MS` have passed, so a reset in between finds it still set. RAM keeps
+//! its contents through the reset button, unlike the watchdog's scratch registers, which are
+//! cleared along with the rest of the chip. Only resets from the button count, so a crash
+//! early in boot doesn't end up in the bootloader.
+
+use core::{mem::MaybeUninit, ptr::addr_of_mut};
+
+use rp2040_hal::pac;
+
+use crate::crash;
+
+/// How soon after a reset the button has to be pressed again.
+pub const DOUBLE_RESET_WINDOW_MS: u64 = 500;
+
+/// Marks a reset as within `DOUBLE_RESET_WINDOW_MS` of the last one.
+const DOUBLE_RESET_MAGIC: u32 = 0x4B52_4452;
+
+/// Left alone by the startup code, so it survives resets.
+#[link_section = ".uninit.double_reset"]
+static mut DOUBLE_RESET_MARKER: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Whether the reset button was pressed twice, starting the window for the next press if
+/// not. Call this once, early in boot, then `end_double_reset_window` once
+/// `DOUBLE_RESET_WINDOW_MS` have passed.
+pub fn double_reset_detected() -> bool {
+    // Note (safety): The reset reason is only read
+    let chip_reset = unsafe { &*pac::VREG_AND_CHIP_RESET::ptr() }.chip_reset.read();
+    let reset_button = chip_reset.had_run().bit_is_set();
+
+    // Note (safety): The marker is only used on the main thread, and a `u32` is valid for any
+    // contents RAM powers up with
+    let marker = addr_of_mut!(DOUBLE_RESET_MARKER) as *mut u32;
+    let detected = reset_button && unsafe { marker.read_volatile() } == DOUBLE_RESET_MAGIC;
+    unsafe { marker.write_volatile(if detected { 0 } else { DOUBLE_RESET_MAGIC }) };
+    detected
+}
+
+/// Close the window for a double reset.
+pub fn end_double_reset_window() {
+    // Note (safety): The marker is only used on the main thread
+    let marker = addr_of_mut!(DOUBLE_RESET_MARKER) as *mut u32;
+    unsafe { marker.write_volatile(0) };
+}
+
+/// Restart into the USB bootloader.
+pub fn reboot_to_bootloader() {
+    let gpio_activity_pin_mask = 0;
+    let disable_interface_mask = 0;
+    end_double_reset_window();
+    crash::expect_reset();
+    rp2040_hal::rom_data::reset_to_usb_boot(gpio_activity_pin_mask, disable_interface_mask);
+}
@@ -0,0 +1,152 @@
+//! What the firmware was built from, so host tools can check a keymap was made for the
+//! keyboard's matrix before writing it, and builds can be told apart in bug reports.
+//!
+//! `BUILD_INFO` sits in flash behind `BUILD_INFO_MAGIC`, so it can also be found in a
+//! firmware image or a flash dump. The keyboard reports it over raw HID with
+//! `raw_hid::Command::Identify`, and as the string describing the configuration interface
+//! (see `webusb`), which any USB descriptor viewer shows.
+
+use core::fmt::{self, Write};
+
+use crate::{
+    config_block::Crc32,
+    key_mapping::{self, NUM_LAYERS},
+    NUM_COLS, NUM_ROWS,
+};
+
+/// Marks the start of `BUILD_INFO` in flash.
+pub const BUILD_INFO_MAGIC: [u8; 8] = *b"KRBUILD\0";
+
+/// The longest git hash kept, as `build.rs` abbreviates them.
+pub const GIT_HASH_LEN: usize = 12;
+
+/// The room for `Description`, which is more than its text ever needs.
+const DESCRIPTION_LEN: usize = 64;
+
+#[repr(C)]
+pub struct BuildInfo {
+    pub magic: [u8; 8],
+
+    /// The git commit built, or `unknown` outside a git checkout, padded with NULs.
+    pub git_hash: [u8; GIT_HASH_LEN],
+
+    /// When the firmware was built, in seconds since the Unix epoch.
+    pub build_time: u32,
+
+    pub rows: u8,
+    pub cols: u8,
+    pub layers: u8,
+
+    /// The CRC-32 of the compiled-in keymap, laid out as in a saved keymap: every layer,
+    /// column by column, with each key as two bytes, little endian.
+    pub keymap_crc: u32,
+}
+
+#[used]
+pub static BUILD_INFO: BuildInfo = BuildInfo {
+    magic: BUILD_INFO_MAGIC,
+    git_hash: padded(env!("KEY_RIPPER_GIT_HASH")),
+    build_time: parse_u32(env!("KEY_RIPPER_BUILD_TIME")),
+    rows: NUM_ROWS as u8,
+    cols: NUM_COLS as u8,
+    layers: NUM_LAYERS as u8,
+    keymap_crc: keymap_crc(),
+};
+
+impl BuildInfo {
+    pub fn git_hash(&self) -> &str {
+        let len = self.git_hash.iter().position(|byte| *byte == 0).unwrap_or(GIT_HASH_LEN);
+        core::str::from_utf8(&self.git_hash[..len]).unwrap_or("unknown")
+    }
+
+    /// The `raw_hid::Command::Identify` response: the matrix rows, columns and layers, then
+    /// the keymap CRC and build time, each four bytes little endian, then the git hash.
+    pub fn to_bytes(&self) -> [u8; 11 + GIT_HASH_LEN] {
+        let mut bytes = [0u8; 11 + GIT_HASH_LEN];
+        bytes[0..3].copy_from_slice(&[self.rows, self.cols, self.layers]);
+        bytes[3..7].copy_from_slice(&self.keymap_crc.to_le_bytes());
+        bytes[7..11].copy_from_slice(&self.build_time.to_le_bytes());
+        bytes[11..].copy_from_slice(&self.git_hash);
+        bytes
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key ripper {} {} {}x{}x{} keymap {:08x}",
+            env!("CARGO_PKG_VERSION"),
+            self.git_hash(),
+            self.cols,
+            self.rows,
+            self.layers,
+            self.keymap_crc
+        )
+    }
+}
+
+/// `BUILD_INFO` as text, for a USB string descriptor.
+pub struct Description {
+    text: [u8; DESCRIPTION_LEN],
+    len: usize,
+}
+
+impl Description {
+    pub fn new(info: &BuildInfo) -> Self {
+        let mut description = Self { text: [0; DESCRIPTION_LEN], len: 0 };
+        write!(description, "{info}").ok();
+        description
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for Description {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let text = self.text.get_mut(self.len..self.len + s.len()).ok_or(fmt::Error)?;
+        text.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+const fn padded(text: &str) -> [u8; GIT_HASH_LEN] {
+    let mut bytes = [0u8; GIT_HASH_LEN];
+    let mut i = 0;
+    while i < text.len() && i < GIT_HASH_LEN {
+        bytes[i] = text.as_bytes()[i];
+        i += 1;
+    }
+    bytes
+}
+
+const fn parse_u32(digits: &str) -> u32 {
+    let mut value = 0u32;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits.as_bytes()[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+const fn keymap_crc() -> u32 {
+    let mut crc = Crc32::new();
+    let mut layer = 0;
+    while layer < NUM_LAYERS {
+        let mut col = 0;
+        while col < NUM_COLS {
+            let mut row = 0;
+            while row < NUM_ROWS {
+                crc.update(&(key_mapping::LAYERS[layer][col][row] as u16).to_le_bytes());
+                row += 1;
+            }
+            col += 1;
+        }
+        layer += 1;
+    }
+    crc.finish()
+}
@@ -0,0 +1,194 @@
+//! Per-key calibration for analog (Hall-effect) switches, persisted to flash.
+//!
+//! Every analog sensor reads a slightly different value at rest and when fully pressed,
+//! depending on magnet strength and mounting height, and some read higher as the key is
+//! pressed while others read lower. Calibration records both ends of each key's travel so
+//! raw readings can be turned into a consistent travel distance.
+
+use crate::{config_block::ConfigBlock, flash::Partition, NUM_COLS, NUM_ROWS};
+
+/// The size of a serialized `KeyCalibration`.
+const KEY_CALIBRATION_SIZE: usize = 4;
+
+/// The number of scans to average the resting value of each key over.
+const REST_SAMPLES: u32 = 256;
+
+/// A key needs to travel at least this far (in raw ADC counts) during calibration for its
+/// new calibration to be kept, so untouched keys keep their previous values.
+const MIN_CALIBRATED_TRAVEL: u16 = 100;
+
+/// The raw sensor readings of a key at rest and when fully pressed.
+#[derive(Copy, Clone, PartialEq)]
+pub struct KeyCalibration {
+    pub rest: u16,
+    pub pressed: u16,
+}
+
+impl Default for KeyCalibration {
+    /// A guess for an uncalibrated key: the middle of the 12-bit ADC range at rest, rising
+    /// by a quarter of the range when pressed.
+    fn default() -> Self {
+        Self { rest: 2048, pressed: 3072 }
+    }
+}
+
+impl KeyCalibration {
+    /// How far a key has traveled given a raw reading, from 0 (at rest) to 255 (fully
+    /// pressed).
+    pub fn travel(&self, raw: u16) -> u8 {
+        let (low, high, raw) = if self.pressed >= self.rest {
+            (self.rest, self.pressed, raw)
+        } else {
+            // The sensor reading drops as the key is pressed, flip it around.
+            (u16::MAX - self.rest, u16::MAX - self.pressed, u16::MAX - raw)
+        };
+
+        let range = high.saturating_sub(low).max(1) as u32;
+        let offset = raw.clamp(low, high).saturating_sub(low) as u32;
+        (offset * 255 / range) as u8
+    }
+}
+
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct CalibrationTable {
+    keys: [[KeyCalibration; NUM_ROWS]; NUM_COLS],
+}
+
+impl CalibrationTable {
+    pub fn key(&self, col: usize, row: usize) -> &KeyCalibration {
+        &self.keys[col][row]
+    }
+}
+
+impl ConfigBlock for CalibrationTable {
+    const MAGIC: [u8; 4] = *b"KRCL";
+    const PARTITION: Partition = Partition::Calibration;
+    const VERSION: u16 = 1;
+
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        for (chunk, key) in
+            buffer.chunks_exact_mut(KEY_CALIBRATION_SIZE).zip(self.keys.iter().flatten())
+        {
+            chunk[..2].copy_from_slice(&key.rest.to_le_bytes());
+            chunk[2..].copy_from_slice(&key.pressed.to_le_bytes());
+        }
+
+        NUM_COLS * NUM_ROWS * KEY_CALIBRATION_SIZE
+    }
+
+    fn deserialize(version: u16, payload: &[u8]) -> Option<Self> {
+        if version != 1 || payload.len() != NUM_COLS * NUM_ROWS * KEY_CALIBRATION_SIZE {
+            return None;
+        }
+
+        let mut table = Self::default();
+        for (key, chunk) in
+            table.keys.iter_mut().flatten().zip(payload.chunks_exact(KEY_CALIBRATION_SIZE))
+        {
+            key.rest = u16::from_le_bytes([chunk[0], chunk[1]]);
+            key.pressed = u16::from_le_bytes([chunk[2], chunk[3]]);
+        }
+
+        Some(table)
+    }
+}
+
+enum CalibrationState {
+    Idle,
+
+    /// Averaging the resting value of every key. All keys must be released.
+    Rest {
+        samples: u32,
+    },
+
+    /// Recording the furthest point each key travels to. Every key should be pressed all
+    /// the way down at least once.
+    Travel,
+}
+
+/// The calibration routine. It is started with `KeyCode::CalibrateAnalog`, first measuring
+/// every key at rest, then recording the full travel of each key as the user presses them
+/// all one by one. Pressing `KeyCode::CalibrateAnalog` again finishes calibration.
+pub struct Calibrator {
+    state: CalibrationState,
+    rest_sums: [[u32; NUM_ROWS]; NUM_COLS],
+    extremes: [[u16; NUM_ROWS]; NUM_COLS],
+}
+
+impl Default for Calibrator {
+    fn default() -> Self {
+        Self {
+            state: CalibrationState::Idle,
+            rest_sums: [[0; NUM_ROWS]; NUM_COLS],
+            extremes: [[0; NUM_ROWS]; NUM_COLS],
+        }
+    }
+}
+
+impl Calibrator {
+    pub fn start(&mut self) {
+        *self = Self { state: CalibrationState::Rest { samples: 0 }, ..Self::default() };
+    }
+
+    pub fn is_running(&self) -> bool {
+        !matches!(self.state, CalibrationState::Idle)
+    }
+
+    /// Feed one scan's worth of raw sensor readings into the calibration routine.
+    pub fn sample(&mut self, readings: &[[u16; NUM_ROWS]; NUM_COLS]) {
+        match self.state {
+            CalibrationState::Idle => {},
+            CalibrationState::Rest { samples } => {
+                for (sums, readings) in self.rest_sums.iter_mut().zip(readings) {
+                    for (sum, reading) in sums.iter_mut().zip(readings) {
+                        *sum += *reading as u32;
+                    }
+                }
+
+                self.state = if samples + 1 >= REST_SAMPLES {
+                    for (extremes, sums) in self.extremes.iter_mut().zip(&self.rest_sums) {
+                        for (extreme, sum) in extremes.iter_mut().zip(sums) {
+                            *extreme = (*sum / REST_SAMPLES) as u16;
+                        }
+                    }
+                    CalibrationState::Travel
+                } else {
+                    CalibrationState::Rest { samples: samples + 1 }
+                };
+            },
+            CalibrationState::Travel => {
+                let columns = self.extremes.iter_mut().zip(&self.rest_sums).zip(readings);
+                for ((extremes, sums), readings) in columns {
+                    for ((extreme, sum), reading) in extremes.iter_mut().zip(sums).zip(readings) {
+                        let rest = (*sum / REST_SAMPLES) as u16;
+                        if reading.abs_diff(rest) > extreme.abs_diff(rest) {
+                            *extreme = *reading;
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    /// Stop calibrating, returning `previous` updated with every key that was pressed far
+    /// enough during calibration. If calibration is stopped before the resting values were
+    /// measured, `previous` is returned unchanged.
+    pub fn finish(&mut self, previous: &CalibrationTable) -> CalibrationTable {
+        let mut table = *previous;
+
+        if let CalibrationState::Travel = self.state {
+            for col in 0..NUM_COLS {
+                for row in 0..NUM_ROWS {
+                    let rest = (self.rest_sums[col][row] / REST_SAMPLES) as u16;
+                    let pressed = self.extremes[col][row];
+                    if pressed.abs_diff(rest) >= MIN_CALIBRATED_TRAVEL {
+                        table.keys[col][row] = KeyCalibration { rest, pressed };
+                    }
+                }
+            }
+        }
+
+        self.state = CalibrationState::Idle;
+        table
+    }
+}
@@ -0,0 +1,124 @@
+//! Scanning for electrostatic capacitive (Topre-style) switches.
+//!
+//! Capacitive switches have no contacts. Pressing a key pushes a conical spring down onto
+//! a pair of pads on the PCB, increasing the capacitance between a column (drive) line and
+//! a row (sense) line. To read a key, its row is selected through an analog multiplexer,
+//! the sense line is released from ground, and the column is driven high. The charge
+//! coupled through the key is held by the sense circuit and sampled with the ADC, then the
+//! sense line is discharged again before the next key.
+//!
+//! Readings are turned into travel with the analog calibration, and each key has its own
+//! press and release thresholds, with a gap between them so a key resting right at its
+//! actuation point doesn't chatter.
+
+use core::convert::Infallible;
+
+use cortex_m::delay::Delay;
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::{calibration::CalibrationTable, NUM_COLS, NUM_ROWS};
+
+/// How long the sense line is held at ground before sampling the next key.
+const DISCHARGE_US: u32 = 10;
+
+/// How long to wait after driving a column before sampling, for the sense circuit to
+/// settle on the coupled charge.
+const SETTLE_US: u32 = 5;
+
+/// The points in a key's travel, from 0 (at rest) to 255 (fully pressed), where it counts
+/// as pressed and released.
+#[derive(Copy, Clone)]
+pub struct Thresholds {
+    pub press: u8,
+    pub release: u8,
+}
+
+impl Default for Thresholds {
+    /// Actuate halfway down, and release a little above that.
+    fn default() -> Self {
+        Self { press: 128, release: 96 }
+    }
+}
+
+pub struct CapacitiveMatrix {
+    thresholds: [[Thresholds; NUM_ROWS]; NUM_COLS],
+    readings: [[u16; NUM_ROWS]; NUM_COLS],
+    pressed: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl Default for CapacitiveMatrix {
+    fn default() -> Self {
+        Self {
+            thresholds: [[Thresholds::default(); NUM_ROWS]; NUM_COLS],
+            readings: [[0; NUM_ROWS]; NUM_COLS],
+            pressed: [[false; NUM_ROWS]; NUM_COLS],
+        }
+    }
+}
+
+impl CapacitiveMatrix {
+    pub fn set_thresholds(&mut self, col: usize, row: usize, thresholds: Thresholds) {
+        self.thresholds[col][row] = thresholds;
+    }
+
+    /// The raw ADC readings from the last scan, for calibration.
+    pub fn readings(&self) -> &[[u16; NUM_ROWS]; NUM_COLS] {
+        &self.readings
+    }
+
+    /// Sample every key and return which ones are pressed, without any debouncing.
+    ///
+    /// `row_select` are the multiplexer's select lines, least significant bit first, and
+    /// `discharge` grounds the sense line while it is high. `sample` reads the sense line
+    /// with the ADC. Positions which are false in `matrix_mask` always read as released.
+    #[allow(clippy::too_many_arguments)]
+    pub fn read_raw(
+        &mut self,
+        columns: &mut [&mut dyn OutputPin<Error = Infallible>],
+        row_select: &mut [&mut dyn OutputPin<Error = Infallible>],
+        discharge: &mut dyn OutputPin<Error = Infallible>,
+        delay: &mut Delay,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+        calibration: &CalibrationTable,
+        mut sample: impl FnMut() -> u16,
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        for (col, gpio_col) in columns.iter_mut().enumerate() {
+            for row in 0..NUM_ROWS {
+                for (bit, select) in row_select.iter_mut().enumerate() {
+                    if row & (1 << bit) != 0 {
+                        select.set_high().unwrap();
+                    } else {
+                        select.set_low().unwrap();
+                    }
+                }
+
+                discharge.set_low().unwrap();
+                gpio_col.set_high().unwrap();
+                delay.delay_us(SETTLE_US);
+
+                self.readings[col][row] = sample();
+
+                gpio_col.set_low().unwrap();
+                discharge.set_high().unwrap();
+                delay.delay_us(DISCHARGE_US);
+            }
+        }
+
+        for (col, mask_col) in matrix_mask.iter().enumerate() {
+            for (row, populated) in mask_col.iter().enumerate() {
+                let travel = calibration.key(col, row).travel(self.readings[col][row]);
+                let thresholds = self.thresholds[col][row];
+                let pressed = &mut self.pressed[col][row];
+
+                *pressed = *populated
+                    && if *pressed {
+                        travel >= thresholds.release
+                    } else {
+                        travel >= thresholds.press
+                    };
+            }
+        }
+
+        self.pressed
+    }
+}
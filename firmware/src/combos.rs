@@ -0,0 +1,222 @@
+//! Combos, which send another key when a set of keys is pressed together, such as Escape
+//! for J and K.
+//!
+//! A key which could start a combo is held back when it's pressed, until either every key
+//! of a combo has been pressed within `COMBO_WINDOW_TICKS` of it, or the combo can't happen
+//! any more: the window passes, one of the held back keys is released, or another key is
+//! pressed. Then the held back keys go out as they were pressed, with the other key a scan
+//! after them so it still arrives in the order it was typed. The report is held back while
+//! keys are, so nothing else gets ahead of them either.
+//!
+//! A combo stays pressed until one of its keys is released. The rest of its keys do nothing
+//! until they're released too.
+
+use crate::{key_codes::KeyCode, ms_to_ticks, NUM_COLS, NUM_ROWS};
+
+/// How soon after the first key of a combo the rest have to be pressed, 50 ms.
+pub const COMBO_WINDOW_TICKS: u32 = ms_to_ticks(50);
+
+/// The most combos a layout can have.
+pub const MAX_COMBOS: usize = 8;
+
+/// Keys which send `key` when pressed together. For example,
+/// `Combo { positions: &[(7, 3), (8, 3)], key: KeyCode::Escape }` makes J and K together an
+/// Escape key. The combo is sent as though the first position was pressed.
+pub struct Combo {
+    pub positions: &'static [(usize, usize)],
+    pub key: KeyCode,
+}
+
+impl Combo {
+    fn contains(&self, col: usize, row: usize) -> bool {
+        self.positions.contains(&(col, row))
+    }
+}
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+pub struct Combos {
+    combos: &'static [Combo],
+
+    /// Which combos are pressed.
+    active: [bool; MAX_COMBOS],
+
+    /// Keys held back in case they're part of a combo, and how long since the first one.
+    held_back: Matrix,
+    held_back_ticks: u32,
+
+    /// Keys released while they were held back, which are sent for one scan.
+    tapped: Matrix,
+
+    /// Keys of an active combo, or left over from one, which do nothing until released.
+    suppressed: Matrix,
+
+    /// The matrix from the previous update, used to find newly pressed keys.
+    previous_matrix: Matrix,
+}
+
+impl Combos {
+    /// Combos past `MAX_COMBOS` are ignored.
+    pub fn new(combos: &'static [Combo]) -> Self {
+        Self {
+            combos: &combos[..combos.len().min(MAX_COMBOS)],
+            active: [false; MAX_COMBOS],
+            held_back: [[false; NUM_ROWS]; NUM_COLS],
+            held_back_ticks: 0,
+            tapped: [[false; NUM_ROWS]; NUM_COLS],
+            suppressed: [[false; NUM_ROWS]; NUM_COLS],
+            previous_matrix: [[false; NUM_ROWS]; NUM_COLS],
+        }
+    }
+
+    /// Advance the combos by `elapsed_ticks` scan ticks, zero for another report in the same
+    /// scan. Returns the matrix to build the report from, with the first key of each active
+    /// combo pressed in place of the rest, or `None` while keys are held back and the report
+    /// should be too.
+    pub fn update(&mut self, matrix: &Matrix, elapsed_ticks: u32) -> Option<Matrix> {
+        // A combo ends as soon as one of its keys is released.
+        for (combo, active) in self.combos.iter().zip(&mut self.active) {
+            *active &= combo.positions.iter().all(|(col, row)| matrix[*col][*row]);
+        }
+        for (suppressed, pressed) in
+            self.suppressed.iter_mut().flatten().zip(matrix.iter().flatten())
+        {
+            *suppressed &= *pressed;
+        }
+        self.tapped = [[false; NUM_ROWS]; NUM_COLS];
+
+        let mut other_keys_pressed = [[false; NUM_ROWS]; NUM_COLS];
+        let mut held_back_released = false;
+        for (col, row) in positions() {
+            let (pressed, was_pressed) = (matrix[col][row], self.previous_matrix[col][row]);
+            if self.held_back[col][row] && !pressed {
+                held_back_released = true;
+            } else if pressed && !was_pressed {
+                if self.could_start_combo(matrix, col, row) {
+                    if !self.held_back.iter().flatten().any(|held_back| *held_back) {
+                        self.held_back_ticks = 0;
+                    }
+                    self.held_back[col][row] = true;
+                } else {
+                    other_keys_pressed[col][row] = true;
+                }
+            }
+        }
+        self.previous_matrix = *matrix;
+
+        let interrupted = other_keys_pressed.iter().flatten().any(|pressed| *pressed);
+        let mut delayed = [[false; NUM_ROWS]; NUM_COLS];
+        if self.held_back.iter().flatten().any(|held_back| *held_back) {
+            self.held_back_ticks += elapsed_ticks;
+            let window_passed = self.held_back_ticks >= COMBO_WINDOW_TICKS;
+            let complete = self.complete_combo();
+
+            // Wait for a bigger combo while one could still happen.
+            let waiting = !window_passed
+                && !interrupted
+                && !held_back_released
+                && (complete.is_none() || self.could_grow());
+            if waiting {
+                return None;
+            }
+
+            if let Some(index) = complete {
+                self.active[index] = true;
+                for (col, row) in self.combos[index].positions {
+                    self.held_back[*col][*row] = false;
+                    self.suppressed[*col][*row] = true;
+                }
+            }
+
+            // Whatever is left goes out as it was typed.
+            for (col, row) in positions() {
+                self.tapped[col][row] = self.held_back[col][row] && !matrix[col][row];
+            }
+            self.held_back = [[false; NUM_ROWS]; NUM_COLS];
+
+            if interrupted {
+                // The other keys follow a scan later, so they're still newly pressed then.
+                delayed = other_keys_pressed;
+                for (col, row) in positions() {
+                    self.previous_matrix[col][row] &= !delayed[col][row];
+                }
+            }
+        }
+
+        let mut matrix = *matrix;
+        for (col, row) in positions() {
+            matrix[col][row] =
+                (matrix[col][row] && !self.suppressed[col][row] && !delayed[col][row])
+                    || self.tapped[col][row];
+        }
+        for (combo, active) in self.combos.iter().zip(self.active) {
+            if active {
+                let (col, row) = combo.positions[0];
+                matrix[col][row] = true;
+            }
+        }
+        Some(matrix)
+    }
+
+    /// The first position of each combo, and the combo's key while it's pressed.
+    pub fn keys(&self) -> impl Iterator<Item = ((usize, usize), Option<KeyCode>)> + '_ {
+        self.combos.iter().enumerate().filter_map(|(index, combo)| {
+            let position = combo.positions[0];
+
+            // Combos starting at the same position would otherwise undo each other.
+            let shared_with_active = self.combos.iter().zip(self.active).enumerate().any(
+                |(other_index, (other, active))| {
+                    active && other_index != index && other.positions[0] == position
+                },
+            );
+            if shared_with_active && !self.active[index] {
+                return None;
+            }
+            Some((position, self.active[index].then_some(combo.key)))
+        })
+    }
+
+    /// Whether a key just pressed could be part of a combo, with the combo's other keys
+    /// either held back or still to be pressed.
+    fn could_start_combo(&self, matrix: &Matrix, col: usize, row: usize) -> bool {
+        self.combos.iter().zip(self.active).any(|(combo, active)| {
+            !active
+                && combo.contains(col, row)
+                && combo.positions.iter().all(|(other_col, other_row)| {
+                    (*other_col, *other_row) == (col, row)
+                        || self.held_back[*other_col][*other_row]
+                        || !matrix[*other_col][*other_row]
+                })
+                && self.held_back_fit(combo)
+        })
+    }
+
+    /// Whether every key held back is part of `combo`.
+    fn held_back_fit(&self, combo: &Combo) -> bool {
+        positions().all(|(col, row)| !self.held_back[col][row] || combo.contains(col, row))
+    }
+
+    /// The biggest combo with every key held back, if there is one.
+    fn complete_combo(&self) -> Option<usize> {
+        self.combos
+            .iter()
+            .enumerate()
+            .filter(|(_, combo)| {
+                combo.positions.iter().all(|(col, row)| self.held_back[*col][*row])
+            })
+            .max_by_key(|(_, combo)| combo.positions.len())
+            .map(|(index, _)| index)
+    }
+
+    /// Whether a combo bigger than the keys held back could still be completed.
+    fn could_grow(&self) -> bool {
+        let held_back = self.held_back.iter().flatten().filter(|held_back| **held_back).count();
+        self.combos
+            .iter()
+            .any(|combo| combo.positions.len() > held_back && self.held_back_fit(combo))
+    }
+}
+
+fn positions() -> impl Iterator<Item = (usize, usize)> {
+    (0..NUM_COLS).flat_map(|col| (0..NUM_ROWS).map(move |row| (col, row)))
+}
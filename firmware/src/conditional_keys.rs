@@ -0,0 +1,69 @@
+//! Conditional keys, which send one key or another depending on the modifiers held with
+//! them, like `KeyCode::GraveEscape`: Escape on its own, but `` ` `` with Shift or GUI held,
+//! so `~` and GUI + `` ` `` still work on a board without a row for Escape.
+//!
+//! A conditional key is resolved when it's pressed, from the modifiers in the report being
+//! built, and keeps that key until it's released, so letting go of Shift before the key
+//! doesn't turn a held `~` into Escape.
+
+use crate::{key_codes::KeyCode, NUM_COLS, NUM_ROWS};
+
+/// What a conditional key sends, see `KeyCode::condition`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Condition {
+    /// The modifiers, as a report bitmask, any of which picks `held`.
+    pub modifiers: u8,
+
+    /// The key sent while any of `modifiers` is held.
+    pub held: KeyCode,
+
+    /// The key sent otherwise.
+    pub otherwise: KeyCode,
+}
+
+impl Condition {
+    pub fn resolve(&self, modifiers: u8) -> KeyCode {
+        if modifiers & self.modifiers != 0 {
+            self.held
+        } else {
+            self.otherwise
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ConditionalKeys {
+    /// The key each held conditional key was resolved to when it was pressed.
+    resolved: [[Option<KeyCode>; NUM_ROWS]; NUM_COLS],
+}
+
+impl ConditionalKeys {
+    /// Update from a debounced scan, with `mapping` the keys resolved through the layers and
+    /// `modifiers` those in the report. Calls `press` with the key each conditional key held
+    /// sends, to add to the report.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        mapping: &[[KeyCode; NUM_ROWS]; NUM_COLS],
+        modifiers: u8,
+        mut press: impl FnMut(KeyCode),
+    ) {
+        for (col, column) in matrix.iter().enumerate() {
+            for (row, pressed) in column.iter().enumerate() {
+                let resolved = &mut self.resolved[col][row];
+                if !pressed {
+                    *resolved = None;
+                    continue;
+                }
+
+                if resolved.is_none() {
+                    *resolved =
+                        mapping[col][row].condition().map(|condition| condition.resolve(modifiers));
+                }
+                if let Some(key) = *resolved {
+                    press(key);
+                }
+            }
+        }
+    }
+}
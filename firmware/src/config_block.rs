@@ -0,0 +1,128 @@
+//! A common container format for everything persisted to flash.
+//!
+//! Each block starts with a header holding a magic number identifying what kind of data it
+//! is, the version of that data's format, the payload length, and a CRC over all of it.
+//! Erased or corrupted flash fails the magic or CRC check and is ignored, and payloads
+//! written by older firmware are handed to `ConfigBlock::deserialize` along with their
+//! version, so each format can migrate its old versions forward.
+
+use crate::flash::{self, Partition, WriteError};
+
+/// The size of the block header: magic, version, length, and CRC.
+const HEADER_SIZE: usize = 12;
+
+/// The largest block that can be saved, including its header.
+pub const MAX_BLOCK_SIZE: usize = flash::SECTOR_SIZE;
+
+pub trait ConfigBlock: Sized {
+    /// The flash partition the block is stored at the start of.
+    const PARTITION: Partition;
+
+    /// Identifies the kind of data stored in the block.
+    const MAGIC: [u8; 4];
+
+    /// The current version of the payload format. Bump this whenever the format changes,
+    /// and teach `deserialize` how to read the previous version.
+    const VERSION: u16;
+
+    /// Write the current version of the payload into `buffer`, returning the number of
+    /// bytes used.
+    fn serialize(&self, buffer: &mut [u8]) -> usize;
+
+    /// Read a payload written with format `version`, which may be older than `VERSION`.
+    /// Returns `None` if the payload is invalid or its version isn't supported.
+    fn deserialize(version: u16, payload: &[u8]) -> Option<Self>;
+
+    /// Load the block from flash, if a valid one has been saved.
+    fn load() -> Option<Self> {
+        let header = Self::PARTITION.read(0, HEADER_SIZE);
+        if header[0..4] != Self::MAGIC {
+            return None;
+        }
+
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        let len = u16::from_le_bytes([header[6], header[7]]) as usize;
+        let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if HEADER_SIZE + len > MAX_BLOCK_SIZE {
+            return None;
+        }
+
+        let payload = Self::PARTITION.read(HEADER_SIZE, len);
+        if crc32(&[&header[4..8], payload]) != crc {
+            return None;
+        }
+
+        Self::deserialize(version, payload)
+    }
+
+    /// Write the block to flash. This blocks with interrupts disabled for as long as it
+    /// takes to erase and program a sector (tens of milliseconds), so it should only
+    /// happen in response to the user changing something.
+    fn save(&self) -> Result<(), WriteError> {
+        let mut buffer = [0xFFu8; MAX_BLOCK_SIZE];
+        let written = self.encode(&mut buffer);
+        Self::PARTITION.write(0, &buffer[..written])
+    }
+
+    /// Write the block, header and all, into `buffer` as it's laid out at the start of the
+    /// partition, returning the number of bytes to program: the block rounded up to whole
+    /// pages. The rest of `buffer` is left as it was, which should be `0xFF` like erased
+    /// flash.
+    fn encode(&self, buffer: &mut [u8; MAX_BLOCK_SIZE]) -> usize {
+        let len = self.serialize(&mut buffer[HEADER_SIZE..]);
+
+        buffer[0..4].copy_from_slice(&Self::MAGIC);
+        buffer[4..6].copy_from_slice(&Self::VERSION.to_le_bytes());
+        buffer[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        let crc = crc32(&[&buffer[4..8], &buffer[HEADER_SIZE..HEADER_SIZE + len]]);
+        buffer[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        (HEADER_SIZE + len).div_ceil(flash::PAGE_SIZE) * flash::PAGE_SIZE
+    }
+}
+
+/// The standard CRC-32 (as used by zlib and Ethernet) of a sequence of byte slices.
+pub fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = Crc32::new();
+    for chunk in chunks {
+        crc.update(chunk);
+    }
+    crc.finish()
+}
+
+/// A CRC-32 calculated piece by piece, for data which isn't all in memory at once.
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { crc: 0xFFFF_FFFF }
+    }
+
+    /// Add `bytes` to the CRC. It's a `const fn`, so `build_info` can take the CRC of the
+    /// compiled-in keymap at compile time.
+    pub const fn update(&mut self, bytes: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            self.crc ^= bytes[i] as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                let mask = (self.crc & 1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (0xEDB8_8320 & mask);
+                bit += 1;
+            }
+            i += 1;
+        }
+    }
+
+    pub const fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
@@ -0,0 +1,204 @@
+//! Finding out why the keyboard last restarted, from the watchdog's scratch registers, which
+//! keep their contents through every reset except a power cycle.
+//!
+//! The panic handler records the location of a panic with `record_panic` before resetting.
+//! A reset by the watchdog's timer after `watchdog_started` means the main loop got stuck,
+//! unless `expect_reset` said it was on purpose, as the bootrom also uses the watchdog to
+//! reboot into and out of the USB bootloader. Either way, `take_last_crash` picks it up on
+//! the next boot, so it can be logged and read over raw HID.
+//!
+//! Only scratch registers 0 to 3 are used. The bootrom uses 4 to 7 when the watchdog is
+//! used to reboot into the USB bootloader.
+//!
+//! The scratch registers don't survive unplugging the keyboard, which a halted keyboard, or
+//! one left in the bootloader, usually gets next. So the panic handler also saves a
+//! `PanicLog` of the panic's location and message to flash, before doing whatever the
+//! saved `PanicAction` says, and it's kept until the next panic replaces it.
+
+use core::fmt::{self, Write};
+
+use defmt::Format;
+use rp2040_hal::pac;
+
+use crate::{config_block::ConfigBlock, flash::Partition};
+
+/// Marks the scratch registers as holding a panic location.
+const PANIC_MAGIC: u32 = 0x4B52_5043;
+
+/// Marks the watchdog as running, so a reset by its timer is a crash.
+const WATCHDOG_MAGIC: u32 = 0x4B52_5744;
+
+/// Where flash is mapped, which the panicking file name has to be in to be trusted.
+const XIP_START: u32 = 0x1000_0000;
+const XIP_END: u32 = 0x1100_0000;
+
+/// The longest file name taken from the scratch registers.
+const MAX_FILE_LEN: u32 = 256;
+
+/// The most text a `PanicLog` keeps, with the rest of a long message cut off.
+pub const PANIC_LOG_LEN: usize = 255;
+
+/// What the keyboard does after a panic.
+#[derive(Copy, Clone, Debug, Default, Format, PartialEq)]
+pub enum PanicAction {
+    /// Restart the firmware straight away.
+    #[default]
+    Reset,
+
+    /// Restart into the USB bootloader, ready for fixed firmware.
+    Bootloader,
+
+    /// Stop, blinking `Fault::Panic` on the indicator LED until the keyboard is reset.
+    Halt,
+}
+
+impl PanicAction {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            PanicAction::Reset => 0,
+            PanicAction::Bootloader => 1,
+            PanicAction::Halt => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PanicAction::Reset),
+            1 => Some(PanicAction::Bootloader),
+            2 => Some(PanicAction::Halt),
+            _ => None,
+        }
+    }
+}
+
+/// The last panic, as `file:line: message` text, kept in flash.
+#[derive(Copy, Clone, PartialEq)]
+pub struct PanicLog {
+    text: [u8; PANIC_LOG_LEN],
+    len: usize,
+}
+
+impl PanicLog {
+    /// A log of a panic in `file` at `line`, cut short to `PANIC_LOG_LEN` bytes.
+    pub fn new(file: &str, line: u32, message: impl fmt::Display) -> Self {
+        let mut log = Self { text: [0; PANIC_LOG_LEN], len: 0 };
+        // Running out of room only cuts the text short.
+        write!(log, "{file}:{line}: {message}").ok();
+        log
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.text[..self.len]
+    }
+}
+
+impl Write for PanicLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(PANIC_LOG_LEN - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.text[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        if len < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl ConfigBlock for PanicLog {
+    const MAGIC: [u8; 4] = *b"KRPL";
+    const PARTITION: Partition = Partition::CrashLog;
+    const VERSION: u16 = 1;
+
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        buffer[..self.len].copy_from_slice(self.text());
+        self.len
+    }
+
+    fn deserialize(version: u16, payload: &[u8]) -> Option<Self> {
+        if version != 1 || payload.len() > PANIC_LOG_LEN {
+            return None;
+        }
+
+        let mut log = Self { text: [0; PANIC_LOG_LEN], len: payload.len() };
+        log.text[..payload.len()].copy_from_slice(payload);
+        Some(log)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Crash {
+    /// The firmware panicked in `file` at `line`.
+    Panic { file: &'static str, line: u32 },
+
+    /// The watchdog reset the keyboard, because the main loop stopped feeding it.
+    Watchdog,
+}
+
+/// Note that the watchdog has been started. Call this straight after starting it.
+pub fn watchdog_started() {
+    write_marker(WATCHDOG_MAGIC);
+}
+
+/// Note that the keyboard is about to reset on purpose, like into the USB bootloader, so
+/// the reset isn't taken for a crash.
+pub fn expect_reset() {
+    write_marker(0);
+}
+
+/// Record the location of a panic for the next boot to report. Only the panic handler
+/// should call this.
+pub fn record_panic(file: &str, line: u32) {
+    // Note (safety): Scratch registers 0 to 3 are only used by this module
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    watchdog.scratch1.write(|w| unsafe { w.bits(line) });
+    watchdog.scratch2.write(|w| unsafe { w.bits(file.as_ptr() as u32) });
+    watchdog.scratch3.write(|w| unsafe { w.bits(file.len() as u32) });
+    write_marker(PANIC_MAGIC);
+}
+
+fn write_marker(marker: u32) {
+    // Note (safety): Scratch registers 0 to 3 are only used by this module
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    watchdog.scratch0.write(|w| unsafe { w.bits(marker) });
+}
+
+/// Why the keyboard last restarted, if it was a crash, clearing the record so it's only
+/// reported once. Call this once, early in boot.
+pub fn take_last_crash() -> Option<Crash> {
+    // Note (safety): Scratch registers 0 to 3 are only used by this module, and the reset
+    // reason is only read
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    let marker = watchdog.scratch0.read().bits();
+    write_marker(0);
+
+    if marker == PANIC_MAGIC {
+        let line = watchdog.scratch1.read().bits();
+        let ptr = watchdog.scratch2.read().bits();
+        let len = watchdog.scratch3.read().bits();
+
+        // The file name is a string constant in flash, which is still there after the reset
+        // as long as the firmware hasn't been replaced in between. Anything which doesn't
+        // look like one is left out.
+        let in_flash = (XIP_START..XIP_END).contains(&ptr)
+            && len <= MAX_FILE_LEN
+            && ptr.saturating_add(len) <= XIP_END;
+        let file = in_flash
+            .then(|| {
+                // Note (safety): The range was checked to be within flash, which is always
+                // mapped and never written through this pointer
+                let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+                core::str::from_utf8(bytes).ok()
+            })
+            .flatten()
+            .unwrap_or("");
+
+        return Some(Crash::Panic { file, line });
+    }
+
+    let watchdog_fired = watchdog.reason.read().timer().bit_is_set();
+    (marker == WATCHDOG_MAGIC && watchdog_fired).then_some(Crash::Watchdog)
+}
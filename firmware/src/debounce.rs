@@ -1,5 +1,39 @@
 //! A simple-as-possible key debouncer module to reduce undesired duplicate keypress
 //! reports.
+//!
+//! Switches bounce in different ways, so there's more than one strategy, all behind the
+//! `Debouncer` trait:
+//!
+//! - `Debounce` reports presses immediately and defers releases, which suits most
+//!   mechanical switches and adds no latency to a press.
+//! - `Integrator` counts up while a key reads pressed and down while it reads released,
+//!   only changing state at either end, which rides out noisy switches that read wrong for
+//!   a scan here and there.
+//! - `DeferredDebounce` only reports a change once the key has read the same for the whole
+//!   debounce time, which also filters out spurious presses from electrical noise.
+//!
+//! The firmware uses `Debounce` unless the `debounce-integrator` or `debounce-deferred`
+//! feature picks another.
+//!
+//! Whichever it is, `BounceStats` counts the bounces it filters out of each key's presses,
+//! and flags keys which bounce so much their switch is likely failing, before the bounces
+//! outlast the debounce time and start typing double letters.
+
+use defmt::warn;
+
+/// Turns raw matrix scans into debounced ones, one scan per tick.
+pub trait Debouncer<const NUM_ROWS: usize, const NUM_COLS: usize> {
+    /// Report a new raw key scan matrix, expected to be called at a periodic "tick rate"
+    /// corresponding to the debounce time in ticks, returning the debounced matrix.
+    fn report_and_tick(
+        &mut self,
+        report_matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS];
+
+    /// Change the debounce time, in ticks. Keys part way through debouncing finish with
+    /// the time they started with.
+    fn set_expiration_ticks(&mut self, expiration_ticks: u8);
+}
 
 /// `Debounce` is a tick-based allocation-free "eager" (reports keypresses immediately)
 /// debouncer.
@@ -32,11 +66,18 @@ impl<const NUM_ROWS: usize, const NUM_COLS: usize> Debounce<NUM_ROWS, NUM_COLS>
     pub fn new(expiration_ticks: u8, passthrough_mask: [[bool; NUM_ROWS]; NUM_COLS]) -> Self {
         Self { countdown_matrix: [[0; NUM_ROWS]; NUM_COLS], passthrough_mask, expiration_ticks }
     }
+}
 
-    /// Report a new raw key scan matrix, expected to be called at a periodic "tick rate"
-    /// corresponding to the same debouncing expiration tick amount specified in the
-    /// constructor.
-    pub fn report_and_tick(
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> Debouncer<NUM_ROWS, NUM_COLS>
+    for Debounce<NUM_ROWS, NUM_COLS>
+{
+    /// Change the number of ticks a repeat keypress is suppressed for. Keys which are
+    /// already counting down keep their current countdown.
+    fn set_expiration_ticks(&mut self, expiration_ticks: u8) {
+        self.expiration_ticks = expiration_ticks;
+    }
+
+    fn report_and_tick(
         &mut self,
         report_matrix: &[[bool; NUM_ROWS]; NUM_COLS],
     ) -> [[bool; NUM_ROWS]; NUM_COLS] {
@@ -61,3 +102,224 @@ impl<const NUM_ROWS: usize, const NUM_COLS: usize> Debounce<NUM_ROWS, NUM_COLS>
         debounced_matrix
     }
 }
+
+/// `Integrator` is a per-key integrating debouncer, for switches which read the wrong way
+/// for a scan here and there rather than just bouncing at each edge.
+///
+/// # Algorithm
+/// Each key has a counter, which goes up every tick the key reads pressed and down every
+/// tick it reads released, between zero and the debounce time. The key is reported pressed
+/// once its counter reaches the top, and released once it gets back to zero, so a press
+/// takes `expiration_ticks` ticks to be reported, and a single wrong reading only delays
+/// the next change by a tick.
+pub struct Integrator<const NUM_ROWS: usize, const NUM_COLS: usize> {
+    counters: [[u8; NUM_ROWS]; NUM_COLS],
+    debounced_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+
+    /// The keys that are not to be debounced, typically the set of modifier keys.
+    passthrough_mask: [[bool; NUM_ROWS]; NUM_COLS],
+
+    expiration_ticks: u8,
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> Integrator<NUM_ROWS, NUM_COLS> {
+    pub fn new(expiration_ticks: u8, passthrough_mask: [[bool; NUM_ROWS]; NUM_COLS]) -> Self {
+        Self {
+            counters: [[0; NUM_ROWS]; NUM_COLS],
+            debounced_matrix: [[false; NUM_ROWS]; NUM_COLS],
+            passthrough_mask,
+            expiration_ticks,
+        }
+    }
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> Debouncer<NUM_ROWS, NUM_COLS>
+    for Integrator<NUM_ROWS, NUM_COLS>
+{
+    fn set_expiration_ticks(&mut self, expiration_ticks: u8) {
+        self.expiration_ticks = expiration_ticks;
+    }
+
+    fn report_and_tick(
+        &mut self,
+        report_matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        let keys =
+            self.debounced_matrix.iter_mut().flatten().zip(self.counters.iter_mut().flatten());
+        let inputs = report_matrix.iter().flatten().zip(self.passthrough_mask.iter().flatten());
+        for ((debounced, counter), (pressed, passthrough)) in keys.zip(inputs) {
+            if *passthrough || self.expiration_ticks == 0 {
+                *debounced = *pressed;
+                continue;
+            }
+
+            *counter = if *pressed {
+                counter.saturating_add(1).min(self.expiration_ticks)
+            } else {
+                counter.saturating_sub(1).min(self.expiration_ticks)
+            };
+
+            if *counter == 0 {
+                *debounced = false;
+            } else if *counter == self.expiration_ticks {
+                *debounced = true;
+            }
+        }
+
+        self.debounced_matrix
+    }
+}
+
+/// `DeferredDebounce` is a symmetric deferring debouncer, which only reports a key changing
+/// state once it has read the same way for the whole debounce time.
+///
+/// # Algorithm
+/// Each key counts how many ticks in a row it has read differently from its reported
+/// state, starting over whenever it reads the same again. Once the count reaches
+/// `expiration_ticks`, the change is reported. Presses and releases are both delayed by the
+/// debounce time, but a glitch shorter than that is never reported at all.
+pub struct DeferredDebounce<const NUM_ROWS: usize, const NUM_COLS: usize> {
+    pending_ticks: [[u8; NUM_ROWS]; NUM_COLS],
+    debounced_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+
+    /// The keys that are not to be debounced, typically the set of modifier keys.
+    passthrough_mask: [[bool; NUM_ROWS]; NUM_COLS],
+
+    expiration_ticks: u8,
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> DeferredDebounce<NUM_ROWS, NUM_COLS> {
+    pub fn new(expiration_ticks: u8, passthrough_mask: [[bool; NUM_ROWS]; NUM_COLS]) -> Self {
+        Self {
+            pending_ticks: [[0; NUM_ROWS]; NUM_COLS],
+            debounced_matrix: [[false; NUM_ROWS]; NUM_COLS],
+            passthrough_mask,
+            expiration_ticks,
+        }
+    }
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> Debouncer<NUM_ROWS, NUM_COLS>
+    for DeferredDebounce<NUM_ROWS, NUM_COLS>
+{
+    fn set_expiration_ticks(&mut self, expiration_ticks: u8) {
+        self.expiration_ticks = expiration_ticks;
+    }
+
+    fn report_and_tick(
+        &mut self,
+        report_matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        let keys =
+            self.debounced_matrix.iter_mut().flatten().zip(self.pending_ticks.iter_mut().flatten());
+        let inputs = report_matrix.iter().flatten().zip(self.passthrough_mask.iter().flatten());
+        for ((debounced, pending), (pressed, passthrough)) in keys.zip(inputs) {
+            if *passthrough || *pressed == *debounced {
+                *debounced = *pressed;
+                *pending = 0;
+                continue;
+            }
+
+            *pending = pending.saturating_add(1);
+            if *pending >= self.expiration_ticks {
+                *debounced = *pressed;
+                *pending = 0;
+            }
+        }
+
+        self.debounced_matrix
+    }
+}
+
+/// The buckets of a key's histogram in `BounceStats`: presses which didn't bounce, bounced
+/// once, twice, three or four times, and five times or more.
+pub const BOUNCE_BUCKETS: usize = 5;
+
+/// The first bucket of presses which bounced enough to count towards chattering.
+const CHATTER_BUCKET: usize = 3;
+
+/// How many presses bouncing three times or more flag a key as chattering.
+pub const CHATTER_PRESSES: u16 = 8;
+
+/// Counts the bounces the debouncer filters out of each key's presses, by comparing the raw
+/// and debounced matrices. The raw key changing while its debounced state doesn't is a
+/// change the debouncer held back, and the debounced state changing later on its own takes
+/// one of those back, as it was only deferred. What's left are the changes filtered out,
+/// two to a bounce: the contacts parting and closing again. A press runs from one debounced
+/// release to the next, taking in the bounces on its way down and on its way back up.
+#[derive(Clone)]
+pub struct BounceStats<const NUM_ROWS: usize, const NUM_COLS: usize> {
+    /// How many presses of each key bounced how many times, see `BOUNCE_BUCKETS`.
+    histograms: [[[u16; BOUNCE_BUCKETS]; NUM_ROWS]; NUM_COLS],
+
+    /// The raw changes filtered out of each key's current press.
+    filtered: [[u8; NUM_ROWS]; NUM_COLS],
+
+    previous_raw: [[bool; NUM_ROWS]; NUM_COLS],
+    previous_debounced: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> Default for BounceStats<NUM_ROWS, NUM_COLS> {
+    fn default() -> Self {
+        Self {
+            histograms: [[[0; BOUNCE_BUCKETS]; NUM_ROWS]; NUM_COLS],
+            filtered: [[0; NUM_ROWS]; NUM_COLS],
+            previous_raw: [[false; NUM_ROWS]; NUM_COLS],
+            previous_debounced: [[false; NUM_ROWS]; NUM_COLS],
+        }
+    }
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> BounceStats<NUM_ROWS, NUM_COLS> {
+    /// Count the bounces in a scan, given the raw matrix and what the debouncer made of it.
+    pub fn record(
+        &mut self,
+        raw_matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        debounced_matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) {
+        for col in 0..NUM_COLS {
+            for row in 0..NUM_ROWS {
+                let raw_changed = raw_matrix[col][row] != self.previous_raw[col][row];
+                let debounced = debounced_matrix[col][row];
+                let was_debounced = self.previous_debounced[col][row];
+                let debounced_changed = debounced != was_debounced;
+                let filtered = &mut self.filtered[col][row];
+                if raw_changed && !debounced_changed {
+                    *filtered = filtered.saturating_add(1);
+                } else if debounced_changed && !raw_changed {
+                    *filtered = filtered.saturating_sub(1);
+                }
+
+                if was_debounced && !debounced {
+                    let bucket = match *filtered / 2 {
+                        bounces @ 0..=2 => bounces as usize,
+                        3 | 4 => 3,
+                        _ => 4,
+                    };
+                    let was_chattering = self.is_chattering(col, row);
+                    let count = &mut self.histograms[col][row][bucket];
+                    *count = count.saturating_add(1);
+                    self.filtered[col][row] = 0;
+                    if !was_chattering && self.is_chattering(col, row) {
+                        warn!("Key ({}, {}) is chattering, its switch may be failing", col, row);
+                    }
+                }
+            }
+        }
+
+        self.previous_raw = *raw_matrix;
+        self.previous_debounced = *debounced_matrix;
+    }
+
+    /// How many presses of a key bounced how many times, see `BOUNCE_BUCKETS`.
+    pub fn histogram(&self, col: usize, row: usize) -> [u16; BOUNCE_BUCKETS] {
+        self.histograms[col][row]
+    }
+
+    /// Whether `CHATTER_PRESSES` of a key's presses have bounced three times or more.
+    pub fn is_chattering(&self, col: usize, row: usize) -> bool {
+        let histogram = &self.histograms[col][row];
+        histogram[CHATTER_BUCKET..].iter().fold(0u16, |sum, count| sum.saturating_add(*count))
+            >= CHATTER_PRESSES
+    }
+}
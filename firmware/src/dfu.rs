@@ -0,0 +1,125 @@
+//! A DFU runtime interface, so tools like `dfu-util -e` can put the keyboard into its
+//! bootloader over USB, without holding Escape while plugging it in.
+//!
+//! The RP2040's bootloader is the boot ROM's UF2 mass storage device rather than DFU, so
+//! only the runtime half of DFU is implemented: the host sends DFU_DETACH, and the keyboard
+//! detaches by itself and restarts into the boot ROM, where it can be flashed as usual.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use usb_device::{
+    bus::{InterfaceNumber, UsbBusAllocator},
+    class::{ControlIn, ControlOut, UsbClass},
+    class_prelude::UsbBus,
+    control::{Recipient, RequestType},
+    descriptor::DescriptorWriter,
+};
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_RUNTIME: u8 = 0x01;
+
+const DESCRIPTOR_DFU_FUNCTIONAL: u8 = 0x21;
+
+/// The keyboard detaches by itself after DFU_DETACH, instead of waiting for a bus reset.
+const ATTRIBUTE_WILL_DETACH: u8 = 1 << 3;
+
+/// How long the host should wait for the keyboard to detach, in milliseconds.
+const DETACH_TIMEOUT_MS: u16 = 1000;
+
+/// The largest transfer the DFU mode would take. The keyboard never gets there, but the
+/// descriptor needs a value.
+const TRANSFER_SIZE: u16 = 64;
+
+const DFU_VERSION: u16 = 0x0110;
+
+const DFU_DETACH: u8 = 0;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_GETSTATE: u8 = 5;
+
+const STATUS_OK: u8 = 0x00;
+const STATE_APP_IDLE: u8 = 0;
+
+pub struct DfuRuntimeClass {
+    interface: InterfaceNumber,
+
+    /// Set once the host asks the keyboard to detach, for the main loop to act on.
+    detach_requested: &'static AtomicBool,
+
+    /// While set, detaching is refused, so the firmware can't be replaced from the host.
+    config_locked: &'static AtomicBool,
+}
+
+impl DfuRuntimeClass {
+    pub fn new<B: UsbBus>(
+        alloc: &UsbBusAllocator<B>,
+        detach_requested: &'static AtomicBool,
+        config_locked: &'static AtomicBool,
+    ) -> Self {
+        Self { interface: alloc.interface(), detach_requested, config_locked }
+    }
+
+    fn is_dfu_request(&self, request: &usb_device::control::Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntimeClass {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(
+            self.interface,
+            CLASS_APPLICATION_SPECIFIC,
+            SUBCLASS_DFU,
+            PROTOCOL_RUNTIME,
+        )?;
+
+        let [timeout_low, timeout_high] = DETACH_TIMEOUT_MS.to_le_bytes();
+        let [transfer_low, transfer_high] = TRANSFER_SIZE.to_le_bytes();
+        let [version_low, version_high] = DFU_VERSION.to_le_bytes();
+        writer.write(
+            DESCRIPTOR_DFU_FUNCTIONAL,
+            &[
+                ATTRIBUTE_WILL_DETACH,
+                timeout_low,
+                timeout_high,
+                transfer_low,
+                transfer_high,
+                version_low,
+                version_high,
+            ],
+        )
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if !self.is_dfu_request(request) {
+            return;
+        }
+
+        match request.request {
+            // No poll timeout and no status string.
+            DFU_GETSTATUS => xfer.accept_with(&[STATUS_OK, 0, 0, 0, STATE_APP_IDLE, 0]).ok(),
+            DFU_GETSTATE => xfer.accept_with(&[STATE_APP_IDLE]).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if !self.is_dfu_request(request) {
+            return;
+        }
+
+        if request.request == DFU_DETACH && !self.config_locked.load(Ordering::Relaxed) {
+            self.detach_requested.store(true, Ordering::Relaxed);
+            xfer.accept().ok();
+        } else {
+            xfer.reject().ok();
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Boards without a matrix, like small macropads, where every switch has a GPIO of its own.
+//!
+//! Each pin is given the matrix position its switch reports as, so keymaps, debouncing and
+//! everything after work the same as for a matrix. Pins can be pulled either way, set up
+//! with `into_pull_up_input` or `into_pull_down_input`, with their `Sense` saying which.
+//! There's nothing to drive or wait for, so a scan only reads each pin once.
+
+use core::convert::Infallible;
+
+use embedded_hal::digital::v2::InputPin;
+
+use crate::key_scan::{Sense, SwitchScanner};
+
+/// A switch on a pin of its own.
+pub struct DirectPin<'a> {
+    pub pin: &'a dyn InputPin<Error = Infallible>,
+
+    /// The matrix position the switch reports as, `(column, row)`.
+    pub position: (usize, usize),
+
+    /// How the pin is pulled, so which level reads as pressed.
+    pub sense: Sense,
+}
+
+impl DirectPin<'_> {
+    fn is_pressed(&self) -> bool {
+        self.pin.is_high().unwrap() != (self.sense == Sense::PullUp)
+    }
+}
+
+/// Reads switches on pins of their own, through `SwitchScanner`.
+pub struct DirectPins<'a> {
+    pins: &'a [DirectPin<'a>],
+}
+
+impl<'a> DirectPins<'a> {
+    pub fn new(pins: &'a [DirectPin<'a>]) -> Self {
+        Self { pins }
+    }
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> SwitchScanner<NUM_ROWS, NUM_COLS>
+    for DirectPins<'_>
+{
+    /// Read every pin. Pins with a position outside the matrix are ignored.
+    fn read_raw(
+        &mut self,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        let mut raw_matrix = [[false; NUM_ROWS]; NUM_COLS];
+        for pin in self.pins {
+            let (col, row) = pin.position;
+            if col < NUM_COLS && row < NUM_ROWS {
+                raw_matrix[col][row] = matrix_mask[col][row] && pin.is_pressed();
+            }
+        }
+        raw_matrix
+    }
+}
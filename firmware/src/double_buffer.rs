@@ -0,0 +1,48 @@
+//! A lock-free handoff of a value from the main loop to an interrupt handler.
+//!
+//! The writer fills whichever of the two slots isn't published, then publishes it by
+//! switching an atomic index, so the reader always sees a complete value without either
+//! side disabling interrupts.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub struct DoubleBuffer<T> {
+    slots: [UnsafeCell<T>; 2],
+    published: AtomicUsize,
+}
+
+// Note (safety): Access to the slots is coordinated through `published`, see the safety
+// requirements of `publish` and `read`.
+unsafe impl<T: Send> Sync for DoubleBuffer<T> {}
+
+impl<T: Copy> DoubleBuffer<T> {
+    pub const fn new(initial: T) -> Self {
+        Self {
+            slots: [UnsafeCell::new(initial), UnsafeCell::new(initial)],
+            published: AtomicUsize::new(0),
+        }
+    }
+
+    /// Replace the published value.
+    ///
+    /// # Safety
+    /// Only one context may publish, and it must not be able to interrupt a `read` in
+    /// progress. Publishing from the main loop and reading from an interrupt handler on the
+    /// same core meets both. A read can interrupt a publish.
+    pub unsafe fn publish(&self, value: T) {
+        let unpublished = 1 - self.published.load(Ordering::Relaxed);
+        *self.slots[unpublished].get() = value;
+        self.published.store(unpublished, Ordering::Release);
+    }
+
+    /// The most recently published value.
+    ///
+    /// # Safety
+    /// A `publish` must not be able to interrupt the read, see `publish`.
+    pub unsafe fn read(&self) -> T {
+        *self.slots[self.published.load(Ordering::Acquire)].get()
+    }
+}
@@ -0,0 +1,261 @@
+//! Running the backlight on the RP2040's second core, with the `dual-core` feature, so
+//! rendering its effects never holds up the scans and reports on the first.
+//!
+//! Core 0 keeps scanning the matrix, running the keymap and talking USB. After each scan it
+//! leaves the keys in `BACKLIGHT_KEYS`, which is too big for the SIO FIFO between the
+//! cores, and sends core 1 a `Message` asking for a frame. Each message is a single FIFO
+//! word, with its tag in the top byte. A frame asked for while core 1 is still busy with the
+//! last one is skipped, rather than making core 0 wait.
+//!
+//! Flash can't be read while it's written, so core 1 mustn't run from it then. Before
+//! writing, `flash` calls `pause_core1`, which has core 1 wait in RAM until `resume_core1`.
+
+use core::{
+    cell::RefCell,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use critical_section::Mutex;
+use defmt::Format;
+use rp2040_hal::{
+    multicore::{self, Core},
+    pio::{PIOExt, StateMachineIndex},
+};
+
+use crate::{
+    flash::{read_register, write_register},
+    key_codes::KeyCode,
+    rgb::{Animation, RgbBacklight, RgbSettings, Ws2812},
+    NUM_COLS, NUM_ROWS,
+};
+
+/// The SIO registers of the FIFO, which each core sees its own end of.
+const SIO_FIFO_ST: *mut u32 = 0xD000_0050 as *mut u32;
+const SIO_FIFO_WR: *mut u32 = 0xD000_0054 as *mut u32;
+const SIO_FIFO_RD: *mut u32 = 0xD000_0058 as *mut u32;
+
+/// `SIO_FIFO_ST` bits: a word is waiting to be read, and there's room to write one.
+const FIFO_VALID: u32 = 1 << 0;
+const FIFO_READY: u32 = 1 << 1;
+
+const TAG_FRAME: u32 = 0x01;
+const TAG_SETTINGS: u32 = 0x02;
+const TAG_LOCKED_MODIFIERS: u32 = 0x03;
+const TAG_PAUSE: u32 = 0x04;
+const TAG_PAUSED: u32 = 0x05;
+const TAG_RESUME: u32 = 0x06;
+
+/// Whether core 1 is running the backlight, so flash writes have to pause it.
+static CORE1_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The keys the next frame shows, left by core 0 for core 1.
+static BACKLIGHT_KEYS: Mutex<RefCell<BacklightKeys>> = Mutex::new(RefCell::new(BacklightKeys {
+    now_ms: 0,
+    matrix: [[false; NUM_ROWS]; NUM_COLS],
+    mapping: [[KeyCode::Empty; NUM_ROWS]; NUM_COLS],
+}));
+
+#[derive(Copy, Clone)]
+struct BacklightKeys {
+    now_ms: u64,
+    matrix: [[bool; NUM_ROWS]; NUM_COLS],
+    mapping: [[KeyCode; NUM_ROWS]; NUM_COLS],
+}
+
+/// A message between the cores, sent as one FIFO word.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Message {
+    /// Update the backlight from the keys in `BACKLIGHT_KEYS`, sent after every scan.
+    Frame,
+
+    Settings(RgbSettings),
+
+    /// The locked modifiers, see `RgbBacklight::set_locked_modifiers`.
+    LockedModifiers(u8),
+
+    /// Stop running from flash until `Resume`. Core 1 answers with `Paused` once it's
+    /// waiting in RAM.
+    Pause,
+    Paused,
+    Resume,
+}
+
+impl Message {
+    pub fn to_word(self) -> u32 {
+        match self {
+            Message::Frame => TAG_FRAME << 24,
+            Message::Settings(settings) => {
+                (TAG_SETTINGS << 24)
+                    | ((settings.enabled as u32) << 16)
+                    | ((settings.brightness as u32) << 8)
+                    | settings.animation.to_u8() as u32
+            },
+            Message::LockedModifiers(modifiers) => (TAG_LOCKED_MODIFIERS << 24) | modifiers as u32,
+            Message::Pause => TAG_PAUSE << 24,
+            Message::Paused => TAG_PAUSED << 24,
+            Message::Resume => TAG_RESUME << 24,
+        }
+    }
+
+    pub fn from_word(word: u32) -> Option<Self> {
+        let [tag, high, middle, low] = word.to_be_bytes();
+        match tag as u32 {
+            TAG_FRAME => Some(Message::Frame),
+            TAG_SETTINGS => Some(Message::Settings(RgbSettings {
+                enabled: high != 0,
+                brightness: middle,
+                animation: Animation::from_u8(low)?,
+            })),
+            TAG_LOCKED_MODIFIERS => Some(Message::LockedModifiers(low)),
+            TAG_PAUSE => Some(Message::Pause),
+            TAG_PAUSED => Some(Message::Paused),
+            TAG_RESUME => Some(Message::Resume),
+            _ => None,
+        }
+    }
+}
+
+/// Send a message to the other core, unless its end of the FIFO is full. Returns whether
+/// it was sent.
+fn try_send(message: Message) -> bool {
+    // Note (safety): The FIFO registers are only used from the core's own thread mode
+    unsafe {
+        if read_volatile(SIO_FIFO_ST) & FIFO_READY == 0 {
+            return false;
+        }
+        write_volatile(SIO_FIFO_WR, message.to_word());
+    }
+    cortex_m::asm::sev();
+    true
+}
+
+fn send_blocking(message: Message) {
+    while !try_send(message) {}
+}
+
+/// Take a word from the other core, if one is waiting, skipping any word which isn't a
+/// message.
+fn try_receive() -> Option<Message> {
+    // Note (safety): The FIFO registers are only used from the core's own thread mode
+    unsafe {
+        if read_volatile(SIO_FIFO_ST) & FIFO_VALID == 0 {
+            return None;
+        }
+        Message::from_word(read_volatile(SIO_FIFO_RD))
+    }
+}
+
+/// Wait for a message from the other core, sleeping until each `try_send`.
+fn receive_blocking() -> Message {
+    loop {
+        if let Some(message) = try_receive() {
+            return message;
+        }
+        cortex_m::asm::wfe();
+    }
+}
+
+/// Core 0's end of the backlight, passing on what core 1 needs to render it.
+#[derive(Default)]
+pub struct BacklightLink {
+    sent_settings: Option<RgbSettings>,
+    sent_locked_modifiers: Option<u8>,
+}
+
+impl BacklightLink {
+    /// Hand core 1 a debounced scan, with the keys resolved through the active layers, and
+    /// any change to the settings or locked modifiers. A change which doesn't fit in the
+    /// FIFO is sent again after the next scan.
+    pub fn update(
+        &mut self,
+        now_ms: u64,
+        settings: RgbSettings,
+        locked_modifiers: u8,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        mapping: &[[KeyCode; NUM_ROWS]; NUM_COLS],
+    ) {
+        if self.sent_settings != Some(settings) && try_send(Message::Settings(settings)) {
+            self.sent_settings = Some(settings);
+        }
+        if self.sent_locked_modifiers != Some(locked_modifiers)
+            && try_send(Message::LockedModifiers(locked_modifiers))
+        {
+            self.sent_locked_modifiers = Some(locked_modifiers);
+        }
+
+        critical_section::with(|cs| {
+            BACKLIGHT_KEYS.replace(cs, BacklightKeys { now_ms, matrix: *matrix, mapping: *mapping })
+        });
+        try_send(Message::Frame);
+    }
+}
+
+/// Start core 1 running the backlight, on `stack`, rendering frames as `BacklightLink`
+/// asks for them.
+pub fn spawn_backlight<P: PIOExt + Send + 'static, SM: StateMachineIndex + Send + 'static>(
+    core1: &mut Core,
+    stack: &'static mut [usize],
+    backlight: RgbBacklight,
+    ws2812: Ws2812<P, SM>,
+) -> Result<(), multicore::Error> {
+    // Set first, so a flash write straight away still waits for core 1.
+    CORE1_RUNNING.store(true, Ordering::Release);
+    core1.spawn(stack, move || run_backlight(backlight, ws2812))
+}
+
+fn run_backlight<P: PIOExt, SM: StateMachineIndex>(
+    mut backlight: RgbBacklight,
+    mut ws2812: Ws2812<P, SM>,
+) -> ! {
+    loop {
+        match receive_blocking() {
+            Message::Frame => {
+                let keys = critical_section::with(|cs| *BACKLIGHT_KEYS.borrow_ref(cs));
+                if let Some(frame) = backlight.update(keys.now_ms, &keys.matrix, &keys.mapping) {
+                    ws2812.write(frame);
+                }
+            },
+            Message::Settings(settings) => backlight.set_settings(settings),
+            Message::LockedModifiers(modifiers) => backlight.set_locked_modifiers(modifiers),
+            // Note (safety): Core 0 waits for `Paused` before touching the flash
+            Message::Pause => unsafe { wait_in_ram() },
+            Message::Paused | Message::Resume => {},
+        }
+    }
+}
+
+/// Have core 1 wait in RAM, if it's running, until `resume_core1`. Called by core 0 before
+/// writing to flash.
+pub fn pause_core1() {
+    if CORE1_RUNNING.load(Ordering::Acquire) {
+        send_blocking(Message::Pause);
+        // Core 1 can't wake core 0 from RAM, so this spins rather than sleeping.
+        while try_receive() != Some(Message::Paused) {}
+    }
+}
+
+/// Let core 1 carry on after `pause_core1`, once the flash is readable again.
+pub fn resume_core1() {
+    if CORE1_RUNNING.load(Ordering::Acquire) {
+        send_blocking(Message::Resume);
+    }
+}
+
+/// Answer `Message::Pause` and wait for `Message::Resume`, without touching flash. Only the
+/// FIFO registers are used, through `flash::read_register` and `flash::write_register`.
+/// Not `read_volatile` and `write_volatile`, which are calls into flash in unoptimized
+/// builds.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn wait_in_ram() {
+    while read_register(SIO_FIFO_ST) & FIFO_READY == 0 {}
+    write_register(SIO_FIFO_WR, TAG_PAUSED << 24);
+
+    loop {
+        while read_register(SIO_FIFO_ST) & FIFO_VALID == 0 {}
+        if read_register(SIO_FIFO_RD) == TAG_RESUME << 24 {
+            break;
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Detection of add-on modules plugged into the expansion connector.
+//!
+//! The connector carries the row and column lines of the key matrix, so a module's switches
+//! sit at matrix positions which every layout leaves unpopulated. Each module also has an
+//! ID resistor from column 0 (GPIO29, which doubles as ADC input 3) to 3.3V, forming a
+//! voltage divider with a 10k pull-down on the main board. The firmware reads the divider
+//! once at power-on, before column 0 becomes a matrix output, and enables the module's
+//! key positions and keymap.
+//!
+//! Nothing plugged in leaves only the pull-down, reading close to zero.
+
+use defmt::Format;
+
+use crate::{key_codes::KeyCode, key_mapping, NUM_COLS, NUM_ROWS};
+
+/// How far (in 12-bit ADC counts) a reading can be from a module's nominal value and still
+/// identify it, allowing for 5% resistors and supply variation.
+const ID_TOLERANCE: u16 = 150;
+
+/// Readings below this mean nothing is plugged in.
+const NO_MODULE_THRESHOLD: u16 = 200;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Module {
+    /// Six keys for entering numbers, with a 10k ID resistor.
+    Numpad,
+
+    /// A rotary encoder's push switch alongside two keys, with a 22k ID resistor. Turning
+    /// the encoder isn't supported yet.
+    EncoderCluster,
+
+    /// A trackball with its buttons, with a 4.7k ID resistor. Pointer movement and the
+    /// buttons aren't supported yet, so it is only detected.
+    Trackball,
+
+    /// A TrackPoint with its three buttons, with a 47k ID resistor. It talks PS/2 over two
+    /// extra pins on the connector, see the `ps2` module.
+    TrackPoint,
+
+    /// A pad of up to six keys scanned by the module itself, with a 100k ID resistor. It
+    /// talks I2C over the same pins as a TrackPoint, see the `macropad` module.
+    MacroPad,
+}
+
+/// The result of reading the ID resistor, for a reading which doesn't match any module.
+#[derive(Copy, Clone, Debug, Format)]
+pub struct UnknownModule {
+    pub reading: u16,
+}
+
+impl Module {
+    const ALL: [Module; 5] = [
+        Module::Numpad,
+        Module::EncoderCluster,
+        Module::Trackball,
+        Module::TrackPoint,
+        Module::MacroPad,
+    ];
+
+    /// Identify the attached module from a 12-bit ADC reading of the ID line.
+    pub fn from_id_reading(reading: u16) -> Result<Option<Self>, UnknownModule> {
+        if reading < NO_MODULE_THRESHOLD {
+            return Ok(None);
+        }
+
+        Self::ALL
+            .into_iter()
+            .find(|module| reading.abs_diff(module.nominal_reading()) <= ID_TOLERANCE)
+            .map(Some)
+            .ok_or(UnknownModule { reading })
+    }
+
+    /// The expected ADC reading, given the module's ID resistor and the 10k pull-down.
+    fn nominal_reading(self) -> u16 {
+        let id_resistor_ohms: u32 = match self {
+            Module::Numpad => 10_000,
+            Module::EncoderCluster => 22_000,
+            Module::Trackball => 4_700,
+            Module::TrackPoint => 47_000,
+            Module::MacroPad => 100_000,
+        };
+
+        (4095 * 10_000 / (10_000 + id_resistor_ohms)) as u16
+    }
+
+    /// The module's keys, as the matrix position they're wired to and what they send.
+    pub fn keys(self) -> &'static [((usize, usize), KeyCode)] {
+        match self {
+            Module::Numpad => &[
+                ((6, 0), KeyCode::Num1),
+                ((4, 5), KeyCode::Num2),
+                ((5, 5), KeyCode::Num3),
+                ((7, 5), KeyCode::Num0),
+                ((8, 5), KeyCode::Period),
+                ((9, 5), KeyCode::Enter),
+            ],
+            Module::EncoderCluster => &[
+                ((6, 0), KeyCode::VolumeMute),
+                ((4, 5), KeyCode::PageUp),
+                ((5, 5), KeyCode::PageDown),
+            ],
+            // The pad's keys are read over I2C rather than wired into the matrix, but are
+            // given matrix positions so the keymap can treat them like any other key.
+            Module::MacroPad => &[
+                ((6, 0), KeyCode::F13),
+                ((4, 5), KeyCode::F14),
+                ((5, 5), KeyCode::F15),
+                ((7, 5), KeyCode::F16),
+                ((8, 5), KeyCode::F17),
+                ((9, 5), KeyCode::F18),
+            ],
+            Module::Trackball | Module::TrackPoint => &[],
+        }
+    }
+}
+
+/// The layout's matrix mask, with the positions used by `module` added to it.
+pub fn matrix_mask(module: Option<Module>) -> [[bool; NUM_ROWS]; NUM_COLS] {
+    let mut mask = key_mapping::MATRIX_MASK;
+
+    for ((col, row), _) in module.map_or(&[][..], Module::keys) {
+        mask[*col][*row] = true;
+    }
+
+    mask
+}
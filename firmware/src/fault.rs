@@ -0,0 +1,112 @@
+//! Reports runtime errors by blinking an indicator LED, so they can be told apart without
+//! a debug probe attached.
+//!
+//! Each kind of fault blinks the LED a different number of times, followed by a pause, over
+//! and over until the keyboard is reset. When more than one kind has happened, the most
+//! serious one is shown.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use defmt::Format;
+
+use crate::SCAN_PERIOD_MS;
+
+/// How long the LED stays on, and then off, for each blink.
+pub const BLINK_MS: u32 = 200;
+
+/// The pause after a fault's blinks, before they repeat.
+pub const PAUSE_MS: u32 = 1000;
+
+/// Kinds of faults, from least to most serious.
+#[derive(Copy, Clone, Debug, Format, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Fault {
+    /// USB ran out of buffer or endpoint space, so something the host asked for was lost.
+    UsbOverflow = 1,
+
+    /// Flash didn't read back what was written to it, so settings or a scan trace weren't
+    /// saved.
+    FlashWrite = 2,
+
+    /// Some hardware failed its power-on self-test, such as a TrackPoint module or radio.
+    SelfTest = 3,
+
+    /// The firmware panicked, and `crash::PanicAction::Halt` stopped it. Only the panic
+    /// handler blinks this one.
+    Panic = 4,
+}
+
+impl Fault {
+    /// The number of blinks identifying the fault.
+    pub fn blinks(self) -> u32 {
+        self as u32 + 1
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Fault::UsbOverflow),
+            2 => Some(Fault::FlashWrite),
+            3 => Some(Fault::SelfTest),
+            4 => Some(Fault::Panic),
+            _ => None,
+        }
+    }
+}
+
+/// The most serious fault so far, which can be raised from interrupts as well as the main
+/// loop.
+pub struct FaultLatch(AtomicU8);
+
+impl FaultLatch {
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(0))
+    }
+
+    /// Record that `fault` happened, unless something more serious already has.
+    pub fn raise(&self, fault: Fault) {
+        // The RP2040 has no atomic read-modify-write, so keep anything else from raising
+        // a fault in between.
+        critical_section::with(|_| {
+            if fault as u8 > self.0.load(Ordering::Relaxed) {
+                self.0.store(fault as u8, Ordering::Relaxed);
+            }
+        });
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        Fault::from_u8(self.0.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for FaultLatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Works out when the indicator LED should be lit to blink a fault's pattern.
+#[derive(Default)]
+pub struct FaultBlinker {
+    fault: Option<Fault>,
+    elapsed_ms: u32,
+}
+
+impl FaultBlinker {
+    /// Advance by a scan, with the fault to show. Returns whether the LED should be
+    /// lit. A new fault starts its pattern from the beginning.
+    pub fn tick(&mut self, fault: Option<Fault>) -> bool {
+        if fault != self.fault {
+            self.fault = fault;
+            self.elapsed_ms = 0;
+        }
+
+        let Some(fault) = fault else {
+            return false;
+        };
+
+        let blinking_ms = fault.blinks() * BLINK_MS * 2;
+        let position = self.elapsed_ms;
+        self.elapsed_ms = (self.elapsed_ms + SCAN_PERIOD_MS) % (blinking_ms + PAUSE_MS);
+
+        position < blinking_ms && (position / BLINK_MS).is_multiple_of(2)
+    }
+}
@@ -0,0 +1,403 @@
+//! Low-level access to the QSPI flash chip the firmware runs from.
+//!
+//! Reading goes through the XIP (execute in place) window like any other memory access.
+//! Writing has to take the flash out of XIP mode, so the code doing it runs from RAM with
+//! interrupts disabled, and restores XIP afterwards by re-running the boot2 stage.
+//!
+//! Persistent data is only ever written to one of the `Partition`s defined in `memory.x`,
+//! so different features can't clobber each other (or the firmware itself).
+
+use core::{
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use defmt::Format;
+use rp2040_hal::rom_data;
+
+use crate::dual_core;
+
+/// Set while `erase_and_program` or `unique_id` has core 1 paused, so a panic part way
+/// through knows not to write to the flash itself. See `busy`.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Where the flash is mapped into the address space.
+const XIP_BASE: u32 = 0x1000_0000;
+
+/// The SRAM, including the two 4 KiB scratch banks, which data to write has to be in.
+const SRAM: core::ops::Range<usize> = 0x2000_0000..0x2004_2000;
+
+/// The amount of flash in use, matching `memory.x`. The W25Q128JV on the board is larger,
+/// but keeping to 2 MiB means the firmware works with any common RP2040 flash chip.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// The smallest erasable unit of flash.
+pub const SECTOR_SIZE: usize = 4096;
+
+/// The smallest programmable unit of flash.
+pub const PAGE_SIZE: usize = 256;
+
+/// The standard 4 KiB sector erase command.
+const SECTOR_ERASE_COMMAND: u8 = 0x20;
+
+/// The command reading the flash chip's 64-bit unique ID, after four dummy bytes.
+const READ_UNIQUE_ID_COMMAND: u8 = 0x4B;
+const UNIQUE_ID_DUMMY_BYTES: usize = 4;
+
+/// The length of the unique ID, see `unique_id`.
+pub const UNIQUE_ID_LEN: usize = 8;
+
+/// The QSPI chip select's control register, whose output override drives it by hand.
+const QSPI_SS_CTRL: *mut u32 = 0x4001_800C as *mut u32;
+const QSPI_SS_OUTOVER_MASK: u32 = 0b11 << 8;
+const QSPI_SS_OUTOVER_LOW: u32 = 0b10 << 8;
+const QSPI_SS_OUTOVER_HIGH: u32 = 0b11 << 8;
+
+/// The SSI's status and data registers, which talk to the flash once XIP is off.
+const SSI_SR: *const u32 = 0x1800_0028 as *const u32;
+const SSI_DR0: *mut u32 = 0x1800_0060 as *mut u32;
+const SSI_SR_TFNF: u32 = 1 << 1;
+const SSI_SR_RFNE: u32 = 1 << 3;
+
+/// The SSI's FIFOs hold 16 entries, and a couple are left spare so the receive FIFO never
+/// overflows.
+const SSI_MAX_IN_FLIGHT: usize = 14;
+
+/// The boot ROM functions needed to erase and program the flash, looked up ahead of time
+/// because the lookup itself can't run while XIP is disabled.
+struct FlashFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+extern "C" {
+    static __scan_trace_start: u8;
+    static __scan_trace_end: u8;
+    static __keymap_start: u8;
+    static __keymap_end: u8;
+    static __macros_start: u8;
+    static __macros_end: u8;
+    static __crash_log_start: u8;
+    static __crash_log_end: u8;
+    static __calibration_start: u8;
+    static __calibration_end: u8;
+    static __settings_start: u8;
+    static __settings_end: u8;
+}
+
+/// A region of flash reserved for a particular kind of persistent data. The size and
+/// location of each one is defined in `memory.x`.
+#[allow(unused)]
+#[derive(Copy, Clone, Format, PartialEq)]
+pub enum Partition {
+    /// A recording of raw key scans, for reproducing intermittent bugs.
+    ScanTrace,
+
+    /// Keymaps edited at runtime, replacing the compiled-in ones.
+    Keymap,
+
+    /// Recorded or uploaded macro sequences.
+    Macros,
+
+    /// Information about the last crash, kept around to be reported after a reboot.
+    CrashLog,
+
+    /// Analog key calibration.
+    Calibration,
+
+    /// User settings.
+    Settings,
+}
+
+/// The flash didn't read back what was written to it, which usually means it's worn out.
+#[derive(Copy, Clone, Format)]
+pub struct WriteError {
+    pub partition: Partition,
+
+    /// The offset into the partition of the failed write.
+    pub offset: usize,
+}
+
+impl Partition {
+    /// The partition's offset from the start of flash, and its size in bytes.
+    fn bounds(self) -> (u32, usize) {
+        // Only the addresses of the linker symbols are meaningful, never their values.
+        let (start, end) = match self {
+            Partition::ScanTrace => (addr_of!(__scan_trace_start), addr_of!(__scan_trace_end)),
+            Partition::Keymap => (addr_of!(__keymap_start), addr_of!(__keymap_end)),
+            Partition::Macros => (addr_of!(__macros_start), addr_of!(__macros_end)),
+            Partition::CrashLog => (addr_of!(__crash_log_start), addr_of!(__crash_log_end)),
+            Partition::Calibration => (addr_of!(__calibration_start), addr_of!(__calibration_end)),
+            Partition::Settings => (addr_of!(__settings_start), addr_of!(__settings_end)),
+        };
+
+        (start as u32 - XIP_BASE, end as usize - start as usize)
+    }
+
+    /// The size of the partition in bytes.
+    pub fn size(self) -> usize {
+        self.bounds().1
+    }
+
+    /// Read `len` bytes, starting `offset` bytes into the partition.
+    pub fn read(self, offset: usize, len: usize) -> &'static [u8] {
+        let (start, size) = self.bounds();
+        assert!(offset + len <= size);
+
+        read(start + offset as u32, len)
+    }
+
+    /// Erase the sectors needed to hold `data`, starting `offset` bytes into the partition,
+    /// and then program `data` into them. See `erase_and_program` for the requirements
+    /// on `offset` and `data`. The flash is read back afterwards, to check it took.
+    pub fn write(self, offset: usize, data: &[u8]) -> Result<(), WriteError> {
+        let (start, size) = self.bounds();
+        assert!(offset + data.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE <= size);
+
+        erase_and_program(start + offset as u32, data, true);
+
+        if read(start + offset as u32, data.len()) == data {
+            Ok(())
+        } else {
+            Err(WriteError { partition: self, offset })
+        }
+    }
+
+    /// Program `data` into flash which has already been erased, starting `offset` bytes
+    /// into the partition, without erasing the rest of its sector. This lets several
+    /// writes share a sector between erases. `offset` and `data` must be page aligned.
+    pub fn program(self, offset: usize, data: &[u8]) -> Result<(), WriteError> {
+        let (start, size) = self.bounds();
+        assert!(offset + data.len() <= size);
+        assert!(offset.is_multiple_of(PAGE_SIZE));
+
+        erase_and_program(start + offset as u32, data, false);
+
+        if read(start + offset as u32, data.len()) == data {
+            Ok(())
+        } else {
+            Err(WriteError { partition: self, offset })
+        }
+    }
+}
+
+impl FlashFunctions {
+    fn lookup() -> Self {
+        Self {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        }
+    }
+}
+
+/// Copy boot2 into RAM, so it can be used to put the flash back into its fast XIP mode.
+fn copy_boot2() -> [u32; 64] {
+    let mut boot2 = [0u32; 64];
+    for (i, word) in boot2.iter_mut().enumerate() {
+        // Safety: boot2 occupies the first 256 bytes of flash.
+        *word = unsafe { core::ptr::read_volatile((XIP_BASE as *const u32).add(i)) };
+    }
+    boot2
+}
+
+/// Whether a write, or a read of the unique ID, was interrupted by a panic. Core 1 is
+/// already waiting in RAM then and won't answer another pause, so the panic handler mustn't
+/// write to the flash.
+pub fn busy() -> bool {
+    BUSY.load(Ordering::Acquire)
+}
+
+/// Read a slice of flash, `offset` bytes from the start of the chip.
+fn read(offset: u32, len: usize) -> &'static [u8] {
+    assert!(offset as usize + len <= FLASH_SIZE);
+
+    // Safety: The range is within the XIP window, which is always mapped and readable.
+    unsafe { core::slice::from_raw_parts((XIP_BASE + offset) as *const u8, len) }
+}
+
+/// Erase the sectors starting at `offset` (from the start of the chip) which are needed
+/// to hold `data`, if `erase` is set, and then program `data` into them.
+///
+/// `offset` must be sector aligned when erasing (page aligned otherwise), and `data` must
+/// be a multiple of the page size. `data` also has to live in RAM, as the flash is
+/// unreadable while it is being written.
+fn erase_and_program(offset: u32, data: &[u8], erase: bool) {
+    let alignment = if erase { SECTOR_SIZE } else { PAGE_SIZE };
+    assert!((offset as usize).is_multiple_of(alignment));
+    assert!(data.len().is_multiple_of(PAGE_SIZE));
+    assert!(offset as usize + data.len() <= FLASH_SIZE);
+    debug_assert!(
+        SRAM.contains(&(data.as_ptr() as usize)) && data.as_ptr() as usize + data.len() <= SRAM.end,
+        "data to write to flash has to be in RAM"
+    );
+
+    let erase_len = if erase { data.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE } else { 0 };
+
+    let functions = FlashFunctions::lookup();
+
+    let boot2 = copy_boot2();
+
+    BUSY.store(true, Ordering::Release);
+    // Core 1 runs from flash too, when it's running the backlight.
+    dual_core::pause_core1();
+    critical_section::with(|_| unsafe {
+        // Safety: Interrupts are disabled and core 1 is paused, so nothing else can execute
+        // from flash while XIP is disabled.
+        erase_and_program_from_ram(
+            offset,
+            data.as_ptr(),
+            data.len(),
+            erase_len,
+            &functions,
+            boot2.as_ptr(),
+        );
+    });
+    dual_core::resume_core1();
+    BUSY.store(false, Ordering::Release);
+}
+
+/// The flash chip's unique ID, which tells apart boards built from the same firmware, such
+/// as for a USB serial number. Like writing, it's read with XIP disabled, so it takes
+/// about the same precautions.
+pub fn unique_id() -> [u8; UNIQUE_ID_LEN] {
+    let functions = FlashFunctions::lookup();
+
+    let boot2 = copy_boot2();
+
+    let mut command = [0u8; 1 + UNIQUE_ID_DUMMY_BYTES + UNIQUE_ID_LEN];
+    command[0] = READ_UNIQUE_ID_COMMAND;
+    let mut response = [0u8; 1 + UNIQUE_ID_DUMMY_BYTES + UNIQUE_ID_LEN];
+
+    BUSY.store(true, Ordering::Release);
+    dual_core::pause_core1();
+    critical_section::with(|_| unsafe {
+        // Safety: As for `erase_and_program`, nothing else can execute from flash.
+        command_from_ram(
+            command.as_ptr(),
+            command.len(),
+            response.as_mut_ptr(),
+            response.len(),
+            &functions,
+            boot2.as_ptr(),
+        );
+    });
+    dual_core::resume_core1();
+    BUSY.store(false, Ordering::Release);
+
+    let mut id = [0u8; UNIQUE_ID_LEN];
+    id.copy_from_slice(&response[1 + UNIQUE_ID_DUMMY_BYTES..]);
+    id
+}
+
+/// Send the flash chip the `command_len` bytes at `command` with the chip select held low,
+/// reading a byte back into `response` for every byte sent, like the SDK's `flash_do_cmd`.
+/// It runs from RAM, like `erase_and_program_from_ram`.
+///
+/// Nothing here may call into flash while XIP is off, even unoptimized, so there's no slice
+/// indexing with its bounds-check panics, no arithmetic which can overflow, and registers
+/// go through `read_register` and `write_register` rather than `read_volatile`.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn command_from_ram(
+    command: *const u8,
+    command_len: usize,
+    response: *mut u8,
+    response_len: usize,
+    functions: &FlashFunctions,
+    boot2: *const u32,
+) {
+    (functions.connect_internal_flash)();
+    (functions.flash_exit_xip)();
+
+    let ss_ctrl = read_register(QSPI_SS_CTRL) & !QSPI_SS_OUTOVER_MASK;
+    write_register(QSPI_SS_CTRL, ss_ctrl | QSPI_SS_OUTOVER_LOW);
+
+    let (mut sent, mut received) = (0usize, 0usize);
+    while received < response_len {
+        let status = read_register(SSI_SR);
+        if status & SSI_SR_TFNF != 0
+            && sent < command_len
+            && sent.wrapping_sub(received) < SSI_MAX_IN_FLIGHT
+        {
+            write_register(SSI_DR0, *command.wrapping_add(sent) as u32);
+            sent = sent.wrapping_add(1);
+        }
+        if status & SSI_SR_RFNE != 0 {
+            *response.wrapping_add(received) = read_register(SSI_DR0) as u8;
+            received = received.wrapping_add(1);
+        }
+    }
+
+    write_register(QSPI_SS_CTRL, ss_ctrl | QSPI_SS_OUTOVER_HIGH);
+    (functions.flash_flush_cache)();
+
+    // Thumb function pointers have their lowest bit set.
+    let boot2: unsafe extern "C" fn() = core::mem::transmute(boot2 as usize | 1);
+    boot2();
+}
+
+/// Read a register with a single load, for code running while XIP is off. It's always
+/// inlined, and is in RAM as well in case it ever isn't.
+#[inline(always)]
+#[link_section = ".data.ram_func"]
+pub(crate) unsafe fn read_register(register: *const u32) -> u32 {
+    #[cfg(target_arch = "arm")]
+    {
+        let value;
+        core::arch::asm!(
+            "ldr {value}, [{register}]",
+            register = in(reg) register,
+            value = out(reg) value,
+            options(nostack, preserves_flags, readonly),
+        );
+        value
+    }
+    // The library is built for host tools too, which never talk to the flash.
+    #[cfg(not(target_arch = "arm"))]
+    register.read_volatile()
+}
+
+/// Write a register with a single store, like `read_register`.
+#[inline(always)]
+#[link_section = ".data.ram_func"]
+pub(crate) unsafe fn write_register(register: *mut u32, value: u32) {
+    #[cfg(target_arch = "arm")]
+    core::arch::asm!(
+        "str {value}, [{register}]",
+        register = in(reg) register,
+        value = in(reg) value,
+        options(nostack, preserves_flags),
+    );
+    #[cfg(not(target_arch = "arm"))]
+    register.write_volatile(value)
+}
+
+/// The part of a flash write which must not touch flash at all, placed in RAM by the
+/// `.data` section.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn erase_and_program_from_ram(
+    offset: u32,
+    data: *const u8,
+    len: usize,
+    erase_len: usize,
+    functions: &FlashFunctions,
+    boot2: *const u32,
+) {
+    (functions.connect_internal_flash)();
+    (functions.flash_exit_xip)();
+    if erase_len > 0 {
+        (functions.flash_range_erase)(offset, erase_len, SECTOR_SIZE as u32, SECTOR_ERASE_COMMAND);
+    }
+    (functions.flash_range_program)(offset, data, len);
+    (functions.flash_flush_cache)();
+
+    // Thumb function pointers have their lowest bit set.
+    let boot2: unsafe extern "C" fn() = core::mem::transmute(boot2 as usize | 1);
+    boot2();
+}
@@ -0,0 +1,194 @@
+//! Scanning for analog (Hall-effect) switches.
+//!
+//! Each key has a Hall-effect sensor whose output changes as the magnet in the switch comes
+//! closer. Every column has its own analog multiplexer, enabled by driving the column high,
+//! and the multiplexers' select lines pick the row. Their outputs are joined on one line,
+//! sampled with the ADC, so only one column's multiplexer can be enabled at a time.
+//!
+//! Readings are turned into travel with the analog calibration. A key counts as pressed
+//! past the actuation point in `AnalogSettings`, and released a little above it again so a
+//! key resting right at that point doesn't chatter. With rapid trigger on, a pressed key is
+//! released as soon as it moves back up by the rapid trigger distance, wherever that is,
+//! and pressed again as soon as it moves down by as much, so it can be tapped without
+//! coming all the way back up past the actuation point.
+
+use core::convert::Infallible;
+
+use cortex_m::delay::Delay;
+use defmt::Format;
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::{calibration::CalibrationTable, NUM_COLS, NUM_ROWS};
+
+/// How long to wait after enabling a multiplexer and selecting a row, before sampling.
+const SETTLE_US: u32 = 5;
+
+/// How far above the actuation point a key has to come back up to be released, in travel
+/// from 0 (at rest) to 255 (fully pressed).
+const RELEASE_HYSTERESIS: u8 = 16;
+
+/// How much each press of `KeyCode::ActuationDeeper` or `ActuationShallower` moves the
+/// actuation point.
+pub const ACTUATION_STEP: u8 = 16;
+
+/// The shallowest and deepest actuation points allowed, keeping clear of the noise at
+/// either end of the travel.
+const MIN_ACTUATION: u8 = 32;
+const MAX_ACTUATION: u8 = 224;
+
+/// The rapid trigger distance `KeyCode::ToggleRapidTrigger` turns on.
+pub const RAPID_TRIGGER_DISTANCE: u8 = 16;
+
+/// How analog keys actuate, saved with the other settings.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub struct AnalogSettings {
+    /// How far down a key is pressed, from 0 (at rest) to 255 (fully pressed).
+    pub actuation: u8,
+
+    /// How far a key has to move back up to be released, or down again to be pressed, with
+    /// rapid trigger. Zero turns rapid trigger off.
+    pub rapid_trigger: u8,
+}
+
+impl Default for AnalogSettings {
+    /// Actuate halfway down, without rapid trigger.
+    fn default() -> Self {
+        Self { actuation: 128, rapid_trigger: 0 }
+    }
+}
+
+impl AnalogSettings {
+    pub fn deeper(self) -> Self {
+        let actuation = self.actuation.saturating_add(ACTUATION_STEP).min(MAX_ACTUATION);
+        Self { actuation, ..self }
+    }
+
+    pub fn shallower(self) -> Self {
+        let actuation = self.actuation.saturating_sub(ACTUATION_STEP).max(MIN_ACTUATION);
+        Self { actuation, ..self }
+    }
+
+    pub fn toggle_rapid_trigger(self) -> Self {
+        let rapid_trigger = if self.rapid_trigger == 0 { RAPID_TRIGGER_DISTANCE } else { 0 };
+        Self { rapid_trigger, ..self }
+    }
+}
+
+/// Whether a key is pressed, and how far it has gone since it last changed.
+#[derive(Copy, Clone, Default)]
+struct KeyState {
+    pressed: bool,
+
+    /// The deepest travel while pressed, or the shallowest while released.
+    extreme: u8,
+}
+
+impl KeyState {
+    /// Move the key to `travel`, returning whether it's now pressed.
+    fn update(&mut self, settings: AnalogSettings, travel: u8) -> bool {
+        let was_pressed = self.pressed;
+        let distance = settings.rapid_trigger;
+
+        if self.pressed {
+            self.extreme = self.extreme.max(travel);
+            let released_above = settings.actuation.saturating_sub(RELEASE_HYSTERESIS);
+            let rapid_release = distance != 0 && travel.saturating_add(distance) <= self.extreme;
+            self.pressed = travel >= released_above && !rapid_release;
+        } else {
+            // Pressed on the way down past the actuation point, or after a rapid release, as
+            // soon as it's gone back down far enough.
+            self.extreme = self.extreme.min(travel);
+            let rapid_press = distance != 0 && travel >= self.extreme.saturating_add(distance);
+            self.pressed =
+                travel >= settings.actuation && (self.extreme < settings.actuation || rapid_press);
+        }
+
+        // The next change is measured from where this one happened.
+        if self.pressed != was_pressed {
+            self.extreme = travel;
+        }
+        self.pressed
+    }
+}
+
+/// Scans analog switches, see the module docs.
+pub struct HallEffectMatrix {
+    settings: AnalogSettings,
+    keys: [[KeyState; NUM_ROWS]; NUM_COLS],
+    readings: [[u16; NUM_ROWS]; NUM_COLS],
+}
+
+impl Default for HallEffectMatrix {
+    fn default() -> Self {
+        Self {
+            settings: AnalogSettings::default(),
+            keys: [[KeyState::default(); NUM_ROWS]; NUM_COLS],
+            readings: [[0; NUM_ROWS]; NUM_COLS],
+        }
+    }
+}
+
+impl HallEffectMatrix {
+    pub fn set_settings(&mut self, settings: AnalogSettings) {
+        self.settings = settings;
+    }
+
+    /// The raw ADC readings from the last scan, for calibration.
+    pub fn readings(&self) -> &[[u16; NUM_ROWS]; NUM_COLS] {
+        &self.readings
+    }
+
+    /// Sample every key and return which ones are pressed, without any debouncing.
+    ///
+    /// `columns` enable each column's multiplexer while high, and `row_select` are the
+    /// multiplexers' select lines, least significant bit first. `sample` reads their joined
+    /// output with the ADC. Positions which are false in `matrix_mask` always read as
+    /// released.
+    pub fn read_raw(
+        &mut self,
+        columns: &mut [&mut dyn OutputPin<Error = Infallible>],
+        row_select: &mut [&mut dyn OutputPin<Error = Infallible>],
+        delay: &mut Delay,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+        calibration: &CalibrationTable,
+        mut sample: impl FnMut() -> u16,
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        let mut readings = [[0; NUM_ROWS]; NUM_COLS];
+        for (gpio_col, readings_col) in columns.iter_mut().zip(readings.iter_mut()) {
+            gpio_col.set_high().unwrap();
+            for (row, reading) in readings_col.iter_mut().enumerate() {
+                for (bit, select) in row_select.iter_mut().enumerate() {
+                    if row & (1 << bit) != 0 {
+                        select.set_high().unwrap();
+                    } else {
+                        select.set_low().unwrap();
+                    }
+                }
+
+                delay.delay_us(SETTLE_US);
+                *reading = sample();
+            }
+            gpio_col.set_low().unwrap();
+        }
+
+        self.update(&readings, matrix_mask, calibration)
+    }
+
+    /// Move every key to its reading in `readings`, returning which ones are pressed.
+    pub fn update(
+        &mut self,
+        readings: &[[u16; NUM_ROWS]; NUM_COLS],
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+        calibration: &CalibrationTable,
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        self.readings = *readings;
+        let mut pressed = [[false; NUM_ROWS]; NUM_COLS];
+        for (col, mask_col) in matrix_mask.iter().enumerate() {
+            for (row, populated) in mask_col.iter().enumerate() {
+                let travel = calibration.key(col, row).travel(self.readings[col][row]);
+                pressed[col][row] = *populated && self.keys[col][row].update(self.settings, travel);
+            }
+        }
+        pressed
+    }
+}
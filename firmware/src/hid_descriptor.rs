@@ -44,3 +44,206 @@ pub const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
 
     0xC0,              // End Collection
 ];
+
+/// An N-key rollover keyboard, with the modifiers in the first byte followed by a bitmap
+/// of usages `0x00` to `0xDF`, the same layout as `nkro::NkroReport`. It has no LED output,
+/// the host sends the lock states to the boot keyboard.
+#[rustfmt::skip]
+pub const NKRO_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x06,        // Usage (Keyboard)
+    0xA1, 0x01,        // Collection (Application)
+
+    // Modifier Keys
+    0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
+    0x19, 0xE0,        //   Usage Minimum (0xE0)
+    0x29, 0xE7,        //   Usage Maximum (0xE7)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x95, 0x08,        //   Report Count (8)
+    0x75, 0x01,        //   Report Size (1)
+    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+
+    // Key Bitmap
+    0x19, 0x00,        //   Usage Minimum (0x00)
+    0x29, 0xDF,        //   Usage Maximum (0xDF)
+    0x96, 0xE0, 0x00,  //   Report Count (224)
+    0x75, 0x01,        //   Report Size (1)
+    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+
+    0xC0,              // End Collection
+];
+
+/// Media keys, as one usage from the Consumer page at a time, or zero when none is pressed.
+/// The input report has the same layout as `usbd_hid::descriptor::MediaKeyboardReport`.
+#[rustfmt::skip]
+pub const CONSUMER_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C,        // Usage Page (Consumer)
+    0x09, 0x01,        // Usage (Consumer Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x19, 0x00,        //   Usage Minimum (Unassigned)
+    0x2A, 0xFF, 0x03,  //   Usage Maximum (0x3FF)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x03,  //   Logical Maximum (1023)
+    0x95, 0x01,        //   Report Count (1)
+    0x75, 0x10,        //   Report Size (16)
+    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              // End Collection
+];
+
+/// System keys, as one usage from the Generic Desktop page's System Control collection at a
+/// time, such as `0x82` for System Sleep, or zero when none is pressed.
+#[rustfmt::skip]
+pub const SYSTEM_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x80,        // Usage (Sys Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x19, 0x01,        //   Usage Minimum (Pointer)
+    0x29, 0xB7,        //   Usage Maximum (Sys Display LCD Autoscale)
+    0x15, 0x01,        //   Logical Minimum (1)
+    0x26, 0xB7, 0x00,  //   Logical Maximum (183)
+    0x95, 0x01,        //   Report Count (1)
+    0x75, 0x08,        //   Report Size (8)
+    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              // End Collection
+];
+
+/// The usage page and usage of the raw HID interface's collection, which configuration tools
+/// find it by, like a WebHID filter in a browser.
+pub const RAW_HID_USAGE_PAGE: u16 = 0xFF00;
+pub const RAW_HID_USAGE: u8 = 0x01;
+
+/// A vendor-defined interface for configuration tools, with 32 byte input and output reports
+/// and no report IDs, see `raw_hid`. It's on a different usage page to VIA's, so VIA doesn't
+/// mistake it for its own, and browsers don't block it from WebHID like they do keyboards.
+#[rustfmt::skip]
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, RAW_HID_USAGE_PAGE as u8, (RAW_HID_USAGE_PAGE >> 8) as u8,  // Usage Page (Vendor Defined)
+    0x09, RAW_HID_USAGE,                                              // Usage
+    0xA1, 0x01,        // Collection (Application)
+
+    // Responses
+    0x09, 0x62,        //   Usage (0x62)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
+    0x75, 0x08,        //   Report Size (8)
+    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+
+    // Requests
+    0x09, 0x63,        //   Usage (0x63)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
+    0x75, 0x08,        //   Report Size (8)
+    0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+
+    0xC0,              // End Collection
+];
+
+/// The raw HID interface VIA looks for, by its usage page and usage, with 32 byte input and
+/// output reports and no report IDs, see `via`.
+#[rustfmt::skip]
+pub const VIA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF,  // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,        // Usage (0x61)
+    0xA1, 0x01,        // Collection (Application)
+
+    // Responses
+    0x09, 0x62,        //   Usage (0x62)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
+    0x75, 0x08,        //   Report Size (8)
+    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+
+    // Requests
+    0x09, 0x63,        //   Usage (0x63)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
+    0x75, 0x08,        //   Report Size (8)
+    0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+
+    0xC0,              // End Collection
+];
+
+/// A mouse with eight buttons, X and Y movement, a wheel and horizontal panning. The input
+/// report has the same layout as `usbd_hid::descriptor::MouseReport`.
+///
+/// The wheel and pan each have a Resolution Multiplier, set by the host in a one byte feature
+/// report (wheel in bits 0-1, pan in bits 2-3). When it's 1, every wheel step is an eighth of
+/// a detent, see `pointer::RESOLUTION_MULTIPLIER`.
+#[rustfmt::skip]
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x02,        // Usage (Mouse)
+    0xA1, 0x01,        // Collection (Application)
+    0x09, 0x01,        //   Usage (Pointer)
+    0xA1, 0x00,        //   Collection (Physical)
+
+    // Buttons
+    0x05, 0x09,        //     Usage Page (Button)
+    0x19, 0x01,        //     Usage Minimum (0x01)
+    0x29, 0x08,        //     Usage Maximum (0x08)
+    0x15, 0x00,        //     Logical Minimum (0)
+    0x25, 0x01,        //     Logical Maximum (1)
+    0x95, 0x08,        //     Report Count (8)
+    0x75, 0x01,        //     Report Size (1)
+    0x81, 0x02,        //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+
+    // Movement
+    0x05, 0x01,        //     Usage Page (Generic Desktop Ctrls)
+    0x09, 0x30,        //     Usage (X)
+    0x09, 0x31,        //     Usage (Y)
+    0x15, 0x81,        //     Logical Minimum (-127)
+    0x25, 0x7F,        //     Logical Maximum (127)
+    0x95, 0x02,        //     Report Count (2)
+    0x75, 0x08,        //     Report Size (8)
+    0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+
+    // Wheel
+    0xA1, 0x02,        //     Collection (Logical)
+    0x09, 0x48,        //       Usage (Resolution Multiplier)
+    0x15, 0x00,        //       Logical Minimum (0)
+    0x25, 0x01,        //       Logical Maximum (1)
+    0x35, 0x01,        //       Physical Minimum (1)
+    0x45, 0x08,        //       Physical Maximum (8)
+    0x95, 0x01,        //       Report Count (1)
+    0x75, 0x02,        //       Report Size (2)
+    0xB1, 0x02,        //       Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x09, 0x38,        //       Usage (Wheel)
+    0x15, 0x81,        //       Logical Minimum (-127)
+    0x25, 0x7F,        //       Logical Maximum (127)
+    0x35, 0x00,        //       Physical Minimum (0)
+    0x45, 0x00,        //       Physical Maximum (0)
+    0x75, 0x08,        //       Report Size (8)
+    0x81, 0x06,        //       Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              //     End Collection
+
+    // Pan
+    0xA1, 0x02,        //     Collection (Logical)
+    0x09, 0x48,        //       Usage (Resolution Multiplier)
+    0x15, 0x00,        //       Logical Minimum (0)
+    0x25, 0x01,        //       Logical Maximum (1)
+    0x35, 0x01,        //       Physical Minimum (1)
+    0x45, 0x08,        //       Physical Maximum (8)
+    0x75, 0x02,        //       Report Size (2)
+    0xB1, 0x02,        //       Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x05, 0x0C,        //       Usage Page (Consumer)
+    0x0A, 0x38, 0x02,  //       Usage (AC Pan)
+    0x15, 0x81,        //       Logical Minimum (-127)
+    0x25, 0x7F,        //       Logical Maximum (127)
+    0x35, 0x00,        //       Physical Minimum (0)
+    0x45, 0x00,        //       Physical Maximum (0)
+    0x75, 0x08,        //       Report Size (8)
+    0x81, 0x06,        //       Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,              //     End Collection
+
+    // Feature Padding
+    0x75, 0x04,        //     Report Size (4)
+    0xB1, 0x03,        //     Feature (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+
+    0xC0,              //   End Collection
+    0xC0,              // End Collection
+];
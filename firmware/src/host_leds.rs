@@ -0,0 +1,63 @@
+//! The state of the host's lock LEDs, which it sends in the keyboard's output report, and
+//! the keyboard's own indicator LEDs which show it.
+
+use core::convert::Infallible;
+
+use defmt::Format;
+use embedded_hal::digital::v2::{OutputPin, PinState};
+
+/// One of the LEDs in the keyboard's output report, with its bit in the report.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum HostLed {
+    NumLock = 0b0000_0001,
+    CapsLock = 0b0000_0010,
+    ScrollLock = 0b0000_0100,
+    Compose = 0b0000_1000,
+    Kana = 0b0001_0000,
+}
+
+/// Which of the host's LEDs are lit. Hosts only send these to keyboards they've configured,
+/// so they're all off until then.
+#[derive(Copy, Clone, Debug, Default, Format, PartialEq)]
+pub struct HostLeds {
+    report: u8,
+}
+
+impl HostLeds {
+    pub fn from_report(report: u8) -> Self {
+        Self { report }
+    }
+
+    pub fn is_lit(&self, led: HostLed) -> bool {
+        self.report & led as u8 != 0
+    }
+}
+
+/// Indicator LEDs on the keyboard which mirror the host's lock LEDs, each on a GPIO of its
+/// own. Boards pick which LEDs they have, and which pins they're on:
+///
+/// ```ignore
+/// let mut caps_lock = pins.gpio4.into_push_pull_output();
+/// let mut leds: [(HostLed, &mut dyn OutputPin<Error = Infallible>); 1] =
+///     [(HostLed::CapsLock, &mut caps_lock)];
+/// let mut lock_leds = LockLeds::new(&mut leds);
+/// ```
+pub struct LockLeds<'a, 'p> {
+    leds: &'a mut [(HostLed, &'p mut dyn OutputPin<Error = Infallible>)],
+}
+
+impl<'a, 'p> LockLeds<'a, 'p> {
+    /// The LEDs start off, like the host's until it configures the keyboard.
+    pub fn new(leds: &'a mut [(HostLed, &'p mut dyn OutputPin<Error = Infallible>)]) -> Self {
+        let mut lock_leds = Self { leds };
+        lock_leds.show(HostLeds::default());
+        lock_leds
+    }
+
+    /// Light the LEDs which are lit on the host, and switch off the rest.
+    pub fn show(&mut self, host_leds: HostLeds) {
+        for (led, pin) in self.leds.iter_mut() {
+            pin.set_state(PinState::from(host_leds.is_lit(*led))).unwrap();
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Noticing when the keyboard has been left alone, to save power and tell the host.
+//!
+//! Once no key has been pressed for the idle timeout the keyboard is idle: the backlight
+//! dims, the matrix is scanned less often, and the away macro, if one is picked, is played
+//! once, such as to set a chat status to away or lock the screen. After another timeout the
+//! backlight turns off. The next key press makes the keyboard active again. The timeout and
+//! away macro are saved with the other settings, and set over the raw HID interface.
+
+use defmt::Format;
+
+/// How far the backlight is dimmed while idle, as a fraction of its brightness.
+const IDLE_BRIGHTNESS_DIVISOR: u8 = 4;
+
+/// The idle timeout and away macro.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub struct IdleSettings {
+    /// How long the keys have to be left alone for the keyboard to go idle, in minutes.
+    /// Zero never goes idle.
+    pub timeout_min: u8,
+
+    /// The macro played when the keyboard goes idle, an index into the `MacroBuffer`.
+    pub away_macro: Option<u8>,
+}
+
+impl Default for IdleSettings {
+    fn default() -> Self {
+        Self { timeout_min: 10, away_macro: None }
+    }
+}
+
+impl IdleSettings {
+    /// The settings as two bytes, with no away macro stored as `0xFF`.
+    pub fn to_bytes(self) -> [u8; 2] {
+        [self.timeout_min, self.away_macro.unwrap_or(0xFF)]
+    }
+
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        let away_macro = Some(bytes[1]).filter(|index| *index != 0xFF);
+        Self { timeout_min: bytes[0], away_macro }
+    }
+}
+
+/// How long it's been since a key was pressed.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum IdleState {
+    Active,
+
+    /// Left alone for the timeout, with the backlight dimmed.
+    Idle,
+
+    /// Left alone for twice the timeout, with the backlight off.
+    Asleep,
+}
+
+pub struct IdleTimer {
+    settings: IdleSettings,
+    last_key_ms: u64,
+    state: IdleState,
+    away_macro_requested: bool,
+}
+
+impl IdleTimer {
+    pub fn new(settings: IdleSettings) -> Self {
+        Self { settings, last_key_ms: 0, state: IdleState::Active, away_macro_requested: false }
+    }
+
+    pub fn settings(&self) -> IdleSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: IdleSettings) {
+        self.settings = settings;
+    }
+
+    /// Keep track of the keys, with the time in milliseconds and whether any key is pressed.
+    pub fn update(&mut self, now_ms: u64, key_pressed: bool) {
+        if key_pressed {
+            self.last_key_ms = now_ms;
+        }
+
+        let timeout_ms = self.settings.timeout_min as u64 * 60 * 1000;
+        let idle_ms = now_ms - self.last_key_ms;
+        let state = match timeout_ms {
+            0 => IdleState::Active,
+            _ if idle_ms >= 2 * timeout_ms => IdleState::Asleep,
+            _ if idle_ms >= timeout_ms => IdleState::Idle,
+            _ => IdleState::Active,
+        };
+
+        if self.state == IdleState::Active && state != IdleState::Active {
+            self.away_macro_requested = self.settings.away_macro.is_some();
+        }
+        self.state = state;
+    }
+
+    pub fn state(&self) -> IdleState {
+        self.state
+    }
+
+    /// The backlight brightness to show for `brightness` as set, or `None` for off.
+    pub fn backlight_brightness(&self, brightness: u8) -> Option<u8> {
+        match self.state {
+            IdleState::Active => Some(brightness),
+            IdleState::Idle => Some((brightness / IDLE_BRIGHTNESS_DIVISOR).max(1)),
+            IdleState::Asleep => None,
+        }
+    }
+
+    /// The away macro to play, if the keyboard went idle since this was last called.
+    pub fn take_away_macro(&mut self) -> Option<u8> {
+        core::mem::take(&mut self.away_macro_requested)
+            .then_some(self.settings.away_macro)
+            .flatten()
+    }
+}
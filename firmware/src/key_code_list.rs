@@ -0,0 +1,316 @@
+// Every `KeyCode`, with its value. `key_codes` builds the enum and `KeyCode::from_u16` from
+// this list, and `build.rs` reads the names from it to check the keys in `board.toml`, so a
+// key added here can be used in a keymap, and saved to flash, straight away.
+key_codes! {
+    Empty = 0x0,
+    /// Falls through to the next active layer down, see `layers::Layers`.
+    Transparent = 0x01,
+    A = 0x04,
+    B = 0x05,
+    C = 0x06,
+    D = 0x07,
+    E = 0x08,
+    F = 0x09,
+    G = 0x0A,
+    H = 0x0B,
+    I = 0x0C,
+    J = 0x0D,
+    K = 0x0E,
+    L = 0x0F,
+    M = 0x10,
+    N = 0x11,
+    O = 0x12,
+    P = 0x13,
+    Q = 0x14,
+    R = 0x15,
+    S = 0x16,
+    T = 0x17,
+    U = 0x18,
+    V = 0x19,
+    W = 0x1A,
+    X = 0x1B,
+    Y = 0x1C,
+    Z = 0x1D,
+    Num1 = 0x1E,
+    Num2 = 0x1F,
+    Num3 = 0x20,
+    Num4 = 0x21,
+    Num5 = 0x22,
+    Num6 = 0x23,
+    Num7 = 0x24,
+    Num8 = 0x25,
+    Num9 = 0x26,
+    Num0 = 0x27,
+    Enter = 0x28,
+    Escape = 0x29,
+    Backspace = 0x2A,
+    Tab = 0x2B,
+    Space = 0x2C,
+    Minus = 0x2D,
+    Equals = 0x2E,
+    LeftSquareBracket = 0x2F,
+    RightSquareBracket = 0x30,
+    BackSlash = 0x31,
+    NonUsHash = 0x32,
+    Semicolon = 0x33,
+    SingleQuote = 0x34,
+    Tilde = 0x35,
+    Comma = 0x36,
+    Period = 0x37,
+    ForwardSlash = 0x38,
+    CapsLock = 0x39,
+    F1 = 0x3A,
+    F2 = 0x3B,
+    F3 = 0x3C,
+    F4 = 0x3D,
+    F5 = 0x3E,
+    F6 = 0x3F,
+    F7 = 0x40,
+    F8 = 0x41,
+    F9 = 0x42,
+    F10 = 0x43,
+    F11 = 0x44,
+    F12 = 0x45,
+    PrintScreen = 0x46,
+    ScrollLock = 0x47,
+    Pause = 0x48,
+    Insert = 0x49,
+
+    Right = 0x4F,
+    Left = 0x50,
+    Down = 0x51,
+    Up = 0x52,
+
+    NonUsBackslash = 0x64,
+    Application = 0x65,
+
+    F13 = 0x68,
+    F14 = 0x69,
+    F15 = 0x6A,
+    F16 = 0x6B,
+    F17 = 0x6C,
+    F18 = 0x6D,
+    F19 = 0x6E,
+    F20 = 0x6F,
+    F21 = 0x70,
+    F22 = 0x71,
+    F23 = 0x72,
+    F24 = 0x73,
+
+    // Editing keys, which few hosts still act on
+    Execute = 0x74,
+    Help = 0x75,
+    Menu = 0x76,
+    Select = 0x77,
+    Stop = 0x78,
+    Again = 0x79,
+    Undo = 0x7A,
+    Cut = 0x7B,
+    Copy = 0x7C,
+    Paste = 0x7D,
+    Find = 0x7E,
+
+    // International keys for JIS, Brazilian and other keyboards: `International1` is Ro (the
+    // JIS `\`), `International3` Yen and `International4` and `5` Henkan and Muhenkan
+    International1 = 0x87,
+    International2 = 0x88,
+    International3 = 0x89,
+    International4 = 0x8A,
+    International5 = 0x8B,
+    International6 = 0x8C,
+    International7 = 0x8D,
+    International8 = 0x8E,
+    International9 = 0x8F,
+
+    // Language keys: `Lang1` is Hangul/English (or Kana on a Mac), and `Lang2` Hanja (or
+    // Eisu on a Mac)
+    Lang1 = 0x90,
+    Lang2 = 0x91,
+    Lang3 = 0x92,
+    Lang4 = 0x93,
+    Lang5 = 0x94,
+    Lang6 = 0x95,
+    Lang7 = 0x96,
+    Lang8 = 0x97,
+    Lang9 = 0x98,
+
+    Home = 0x4A,
+    PageUp = 0x4B,
+    Delete = 0x4C,
+    End = 0x4D,
+    PageDown = 0x4E,
+
+    // Media keys, sent as Consumer Control usages (see `consumer_usage`) rather than in the
+    // keyboard report, so their values here only have to be unique.
+    VolumeMute = 0x7F,
+    VolumeUp = 0x80,
+    VolumeDown = 0x81,
+    PlayPause = 0xA0,
+    NextTrack = 0xA1,
+    PreviousTrack = 0xA2,
+    BrightnessUp = 0xA3,
+    BrightnessDown = 0xA4,
+
+    // Keypad keys
+    NumLock = 0x53,
+    KeypadSlash = 0x54,
+    KeypadAsterisk = 0x55,
+    KeypadMinus = 0x56,
+    KeypadPlus = 0x57,
+    KeypadEnter = 0x58,
+    Keypad1 = 0x59,
+    Keypad2 = 0x5A,
+    Keypad3 = 0x5B,
+    Keypad4 = 0x5C,
+    Keypad5 = 0x5D,
+    Keypad6 = 0x5E,
+    Keypad7 = 0x5F,
+    Keypad8 = 0x60,
+    Keypad9 = 0x61,
+    Keypad0 = 0x62,
+    KeypadPeriod = 0x63,
+    KeypadEquals = 0x67,
+    KeypadComma = 0x85,
+    LeftParen = 0xB6,
+    RightParen = 0xB7,
+
+    // Firmware keys, handled on the keyboard and never sent to the host
+    CycleSocd = 0xA5,
+    ToggleNkro = 0xA6,
+
+    // Layer keys, see `layer_action`
+    Layer1 = 0xA7,
+    Layer2 = 0xA8,
+    Layer3 = 0xA9,
+    ToggleLayer1 = 0xAA,
+    ToggleLayer2 = 0xAB,
+    ToggleLayer3 = 0xAC,
+    OneShotLayer1 = 0xAD,
+    OneShotLayer2 = 0xAE,
+    OneShotLayer3 = 0xAF,
+
+    // Macro keys, see `macro_index`
+    Macro0 = 0x100,
+    Macro1 = 0x101,
+    Macro2 = 0x102,
+    Macro3 = 0x103,
+    Macro4 = 0x104,
+    Macro5 = 0x105,
+    Macro6 = 0x106,
+    Macro7 = 0x107,
+    Macro8 = 0x108,
+    Macro9 = 0x109,
+    Macro10 = 0x10A,
+    Macro11 = 0x10B,
+    Macro12 = 0x10C,
+    Macro13 = 0x10D,
+    Macro14 = 0x10E,
+    Macro15 = 0x10F,
+
+    // Dynamic macro keys, see `macros::MacroRecorder`
+    RecordMacro = 0x110,
+    StopMacroRecording = 0x111,
+    PlayRecordedMacro = 0x112,
+
+    // Backlight keys, see `rgb`
+    RgbToggle = 0x113,
+    RgbBrightnessUp = 0x114,
+    RgbBrightnessDown = 0x115,
+    RgbNextAnimation = 0x116,
+
+    // Restarts into the USB bootloader, see `bootloader`
+    Bootloader = 0x117,
+
+    // Mouse keys, see `mouse_keys`
+    MouseUp = 0x118,
+    MouseDown = 0x119,
+    MouseLeft = 0x11A,
+    MouseRight = 0x11B,
+    MouseWheelUp = 0x11C,
+    MouseWheelDown = 0x11D,
+    MouseWheelLeft = 0x11E,
+    MouseWheelRight = 0x11F,
+    MouseButton1 = 0x120,
+    MouseButton2 = 0x121,
+    MouseButton3 = 0x122,
+    MouseButton4 = 0x123,
+    MouseButton5 = 0x124,
+
+    // System keys, sent as System Control usages (see `system_usage`) rather than in the
+    // keyboard report.
+    SystemPower = 0x125,
+    SystemSleep = 0x126,
+    SystemWake = 0x127,
+
+    // Analog switch keys, see `hall_effect`
+    ActuationDeeper = 0x128,
+    ActuationShallower = 0x129,
+    ToggleRapidTrigger = 0x12A,
+
+    // One-shot modifiers, see `one_shot`
+    OneShotShift = 0x12B,
+    OneShotCtrl = 0x12C,
+    OneShotAlt = 0x12D,
+    OneShotCmd = 0x12E,
+
+    // Turns Auto Shift on and off, see `auto_shift`
+    ToggleAutoShift = 0x12F,
+
+    // Unicode keys, typing the layout's `UNICODE_CHARS`, see `unicode`
+    Unicode0 = 0x130,
+    Unicode1 = 0x131,
+    Unicode2 = 0x132,
+    Unicode3 = 0x133,
+    Unicode4 = 0x134,
+    Unicode5 = 0x135,
+    Unicode6 = 0x136,
+    Unicode7 = 0x137,
+    CycleUnicodeMode = 0x138,
+
+    /// Types a rolling chord every scan, to check no report is lost, see `usb_stress`.
+    UsbStressTest = 0x139,
+
+    /// Changes settings with the keys rather than typing them, see `settings_mode`.
+    SettingsMode = 0x13A,
+
+    // Default layer keys, which pick the layer at the bottom of the stack, see `layer_action`
+    DefaultLayer0 = 0x13B,
+    DefaultLayer1 = 0x13C,
+    DefaultLayer2 = 0x13D,
+    DefaultLayer3 = 0x13E,
+    DefaultLayer4 = 0x13F,
+
+    /// Escape, or `` ` `` with Shift or GUI held, see `conditional_keys`.
+    GraveEscape = 0x140,
+
+    // Modifier options, see `mod_swaps`
+    ToggleAltGuiSwap = 0x141,
+    ToggleCapsAsCtrl = 0x142,
+    ToggleGuiDisabled = 0x143,
+
+    /// Turns game mode on or off, see `Keyboard::game_mode`.
+    ToggleGameMode = 0x144,
+
+    /// Sends the last key sent again, see `repeat_key`.
+    Repeat = 0x145,
+
+    NumWord = 0xE8,
+    ToggleProfile = 0xE9,
+    CalibrateAnalog = 0xEA,
+    SaveScanTrace = 0xEB,
+    ReplayScanTrace = 0xEC,
+    ToggleConfigLock = 0xED,
+    SwitchOutput = 0xEE,
+    SnoozeBreak = 0xEF,
+
+    // Modifier keys
+    Fn = 0xF0,
+    LeftShift = 0xF1,
+    LeftCtrl = 0xF2,
+    LeftAlt = 0xF3,
+    LeftCmd = 0xF4,
+    RightCmd = 0xF5,
+    RightAlt = 0xF6,
+    RightCtrl = 0xF7,
+    RightShift = 0xF8,
+}
@@ -1,108 +1,50 @@
 use defmt::Format;
 
-#[allow(unused)]
-#[repr(u8)]
-#[derive(Copy, Clone, Format, PartialEq)]
-pub enum KeyCode {
-    Empty = 0x0,
-    A = 0x04,
-    B = 0x05,
-    C = 0x06,
-    D = 0x07,
-    E = 0x08,
-    F = 0x09,
-    G = 0x0A,
-    H = 0x0B,
-    I = 0x0C,
-    J = 0x0D,
-    K = 0x0E,
-    L = 0x0F,
-    M = 0x10,
-    N = 0x11,
-    O = 0x12,
-    P = 0x13,
-    Q = 0x14,
-    R = 0x15,
-    S = 0x16,
-    T = 0x17,
-    U = 0x18,
-    V = 0x19,
-    W = 0x1A,
-    X = 0x1B,
-    Y = 0x1C,
-    Z = 0x1D,
-    Num1 = 0x1E,
-    Num2 = 0x1F,
-    Num3 = 0x20,
-    Num4 = 0x21,
-    Num5 = 0x22,
-    Num6 = 0x23,
-    Num7 = 0x24,
-    Num8 = 0x25,
-    Num9 = 0x26,
-    Num0 = 0x27,
-    Enter = 0x28,
-    Escape = 0x29,
-    Backspace = 0x2A,
-    Tab = 0x2B,
-    Space = 0x2C,
-    Minus = 0x2D,
-    Equals = 0x2E,
-    LeftSquareBracket = 0x2F,
-    RightSquareBracket = 0x30,
-    BackSlash = 0x31,
-    Semicolon = 0x33,
-    SingleQuote = 0x34,
-    Tilde = 0x35,
-    Comma = 0x36,
-    Period = 0x37,
-    ForwardSlash = 0x38,
-    CapsLock = 0x39,
-    F1 = 0x3A,
-    F2 = 0x3B,
-    F3 = 0x3C,
-    F4 = 0x3D,
-    F5 = 0x3E,
-    F6 = 0x3F,
-    F7 = 0x40,
-    F8 = 0x41,
-    F9 = 0x42,
-    F10 = 0x43,
-    F11 = 0x44,
-    F12 = 0x45,
-
-    Right = 0x4F,
-    Left = 0x50,
-    Down = 0x51,
-    Up = 0x52,
-
-    Home = 0x4A,
-    PageUp = 0x4B,
-    Delete = 0x4C,
-    End = 0x4D,
-    PageDown = 0x4E,
-
-    // Media Keys
-    VolumeMute = 0x7F,
-    VolumeUp = 0x80,
-    VolumeDown = 0x81,
-
-    // Keypad keys
-    LeftParen = 0xB6,
-    RightParen = 0xB7,
-
-    // Modifier keys
-    Fn = 0xF0,
-    LeftShift = 0xF1,
-    LeftCtrl = 0xF2,
-    LeftAlt = 0xF3,
-    LeftCmd = 0xF4,
-    RightCmd = 0xF5,
-    RightAlt = 0xF6,
-    RightCtrl = 0xF7,
-    RightShift = 0xF8,
+use crate::{
+    conditional_keys::Condition, key_mapping::FN_LAYER, layers::LayerAction, macros::MACRO_COUNT,
+    mouse_keys::MOUSE_BUTTONS, unicode::UNICODE_KEYS,
+};
+
+/// Defines `KeyCode` from the list in `key_code_list.rs`, along with reading one back from
+/// its value.
+macro_rules! key_codes {
+    ($($(#[$attr:meta])* $name:ident = $value:literal,)*) => {
+        #[allow(unused)]
+        #[repr(u16)]
+        #[derive(Copy, Clone, Debug, Format, PartialEq)]
+        pub enum KeyCode {
+            $($(#[$attr])* $name = $value,)*
+        }
+
+        impl KeyCode {
+            /// The highest value of any key, for going through every key with `from_u16`.
+            pub const MAX_VALUE: u16 = {
+                let values = [$($value),*];
+                let mut max = 0;
+                let mut i = 0;
+                while i < values.len() {
+                    if values[i] > max {
+                        max = values[i];
+                    }
+                    i += 1;
+                }
+                max
+            };
+
+            /// The key with the given value, such as one read back from a keymap saved to
+            /// flash.
+            pub fn from_u16(value: u16) -> Option<Self> {
+                match value {
+                    $($value => Some(KeyCode::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
 }
 
+include!("key_code_list.rs");
+
 impl KeyCode {
     pub fn modifier_bitmask(&self) -> Option<u8> {
         match *self {
@@ -118,7 +60,190 @@ impl KeyCode {
         }
     }
 
+    /// The usage on the Consumer page which media keys are sent as.
+    pub fn consumer_usage(&self) -> Option<u16> {
+        match *self {
+            KeyCode::BrightnessUp => Some(0x6F),
+            KeyCode::BrightnessDown => Some(0x70),
+            KeyCode::NextTrack => Some(0xB5),
+            KeyCode::PreviousTrack => Some(0xB6),
+            KeyCode::PlayPause => Some(0xCD),
+            KeyCode::VolumeMute => Some(0xE2),
+            KeyCode::VolumeUp => Some(0xE9),
+            KeyCode::VolumeDown => Some(0xEA),
+            _ => None,
+        }
+    }
+
+    /// The usage on the Generic Desktop page which system keys are sent as.
+    pub fn system_usage(&self) -> Option<u8> {
+        match *self {
+            KeyCode::SystemPower => Some(0x81),
+            KeyCode::SystemSleep => Some(0x82),
+            KeyCode::SystemWake => Some(0x83),
+            _ => None,
+        }
+    }
+
+    /// What a conditional key sends, depending on the modifiers held.
+    pub fn condition(&self) -> Option<Condition> {
+        match *self {
+            KeyCode::GraveEscape => Some(Condition {
+                modifiers: KeyCode::LeftShift.modifier_bitmask().unwrap_or(0)
+                    | KeyCode::RightShift.modifier_bitmask().unwrap_or(0)
+                    | KeyCode::LeftCmd.modifier_bitmask().unwrap_or(0)
+                    | KeyCode::RightCmd.modifier_bitmask().unwrap_or(0),
+                held: KeyCode::Tilde,
+                otherwise: KeyCode::Escape,
+            }),
+            _ => None,
+        }
+    }
+
+    /// What a layer key does to its layer. `Fn` holds the Fn layer.
+    pub fn layer_action(&self) -> Option<LayerAction> {
+        match *self {
+            KeyCode::Fn => Some(LayerAction::Momentary(FN_LAYER)),
+            KeyCode::Layer1 => Some(LayerAction::Momentary(1)),
+            KeyCode::Layer2 => Some(LayerAction::Momentary(2)),
+            KeyCode::Layer3 => Some(LayerAction::Momentary(3)),
+            KeyCode::ToggleLayer1 => Some(LayerAction::Toggle(1)),
+            KeyCode::ToggleLayer2 => Some(LayerAction::Toggle(2)),
+            KeyCode::ToggleLayer3 => Some(LayerAction::Toggle(3)),
+            KeyCode::OneShotLayer1 => Some(LayerAction::OneShot(1)),
+            KeyCode::OneShotLayer2 => Some(LayerAction::OneShot(2)),
+            KeyCode::OneShotLayer3 => Some(LayerAction::OneShot(3)),
+            KeyCode::DefaultLayer0 => Some(LayerAction::Default(0)),
+            KeyCode::DefaultLayer1 => Some(LayerAction::Default(1)),
+            KeyCode::DefaultLayer2 => Some(LayerAction::Default(2)),
+            KeyCode::DefaultLayer3 => Some(LayerAction::Default(3)),
+            KeyCode::DefaultLayer4 => Some(LayerAction::Default(4)),
+            _ => None,
+        }
+    }
+
+    /// The modifier a one-shot modifier key applies to the next key, as a report bitmask.
+    pub fn one_shot_modifier(&self) -> Option<u8> {
+        match *self {
+            KeyCode::OneShotShift => KeyCode::LeftShift.modifier_bitmask(),
+            KeyCode::OneShotCtrl => KeyCode::LeftCtrl.modifier_bitmask(),
+            KeyCode::OneShotAlt => KeyCode::LeftAlt.modifier_bitmask(),
+            KeyCode::OneShotCmd => KeyCode::LeftCmd.modifier_bitmask(),
+            _ => None,
+        }
+    }
+
+    /// Modifiers, one-shot ones included, and the keys which hold a layer.
     pub fn is_modifier(&self) -> bool {
-        *self == KeyCode::Fn || self.modifier_bitmask().is_some()
+        matches!(self.layer_action(), Some(LayerAction::Momentary(_)))
+            || self.modifier_bitmask().is_some()
+            || self.one_shot_modifier().is_some()
+    }
+
+    /// Num Lock and the keys of a numeric keypad.
+    pub fn is_keypad(&self) -> bool {
+        (KeyCode::NumLock as u16..=KeyCode::KeypadPeriod as u16).contains(&(*self as u16))
+            || matches!(
+                *self,
+                KeyCode::KeypadEquals
+                    | KeyCode::KeypadComma
+                    | KeyCode::LeftParen
+                    | KeyCode::RightParen
+            )
+    }
+
+    /// Keys only found on keyboards for some languages, like the ISO and JIS layouts' extra
+    /// keys and the Korean and Japanese input method keys.
+    pub fn is_international(&self) -> bool {
+        (KeyCode::International1 as u16..=KeyCode::Lang9 as u16).contains(&(*self as u16))
+            || matches!(*self, KeyCode::NonUsHash | KeyCode::NonUsBackslash)
+    }
+
+    /// Letters, numbers and symbols, which have a shifted version for Auto Shift to send.
+    pub fn auto_shifts(&self) -> bool {
+        (KeyCode::A as u16..=KeyCode::Num0 as u16).contains(&(*self as u16))
+            || (KeyCode::Minus as u16..=KeyCode::ForwardSlash as u16).contains(&(*self as u16))
+            || *self == KeyCode::NonUsBackslash
+    }
+
+    /// Which macro a macro key plays, see `macros::MacroPlayer`.
+    pub fn macro_index(&self) -> Option<usize> {
+        let index = (*self as u16).checked_sub(KeyCode::Macro0 as u16)? as usize;
+        (index < MACRO_COUNT).then_some(index)
+    }
+
+    /// Which of the layout's `UNICODE_CHARS` a Unicode key types, see `unicode`.
+    pub fn unicode_index(&self) -> Option<usize> {
+        let index = (*self as u16).checked_sub(KeyCode::Unicode0 as u16)? as usize;
+        (index < UNICODE_KEYS).then_some(index)
+    }
+
+    /// Which mouse button a mouse key clicks, counting from zero for the left button.
+    pub fn mouse_button(&self) -> Option<usize> {
+        let button = (*self as u16).checked_sub(KeyCode::MouseButton1 as u16)? as usize;
+        (button < MOUSE_BUTTONS).then_some(button)
+    }
+
+    /// Keys which move the pointer, scroll or click, see `mouse_keys::MouseKeys`.
+    pub fn is_mouse_key(&self) -> bool {
+        self.mouse_button().is_some()
+            || matches!(
+                *self,
+                KeyCode::MouseUp
+                    | KeyCode::MouseDown
+                    | KeyCode::MouseLeft
+                    | KeyCode::MouseRight
+                    | KeyCode::MouseWheelUp
+                    | KeyCode::MouseWheelDown
+                    | KeyCode::MouseWheelLeft
+                    | KeyCode::MouseWheelRight
+            )
+    }
+
+    /// Keys which only change the keyboard's own behavior, and have no HID usage.
+    pub fn is_firmware_key(&self) -> bool {
+        self.layer_action().is_some()
+            || self.macro_index().is_some()
+            || self.unicode_index().is_some()
+            || self.is_mouse_key()
+            || self.condition().is_some()
+            || matches!(
+                *self,
+                KeyCode::Transparent
+                    | KeyCode::NumWord
+                    | KeyCode::ToggleProfile
+                    | KeyCode::CalibrateAnalog
+                    | KeyCode::SaveScanTrace
+                    | KeyCode::ReplayScanTrace
+                    | KeyCode::ToggleConfigLock
+                    | KeyCode::SwitchOutput
+                    | KeyCode::SnoozeBreak
+                    | KeyCode::CycleSocd
+                    | KeyCode::ToggleNkro
+                    | KeyCode::RecordMacro
+                    | KeyCode::StopMacroRecording
+                    | KeyCode::PlayRecordedMacro
+                    | KeyCode::RgbToggle
+                    | KeyCode::RgbBrightnessUp
+                    | KeyCode::RgbBrightnessDown
+                    | KeyCode::RgbNextAnimation
+                    | KeyCode::Bootloader
+                    | KeyCode::ActuationDeeper
+                    | KeyCode::ActuationShallower
+                    | KeyCode::ToggleRapidTrigger
+                    | KeyCode::OneShotShift
+                    | KeyCode::OneShotCtrl
+                    | KeyCode::OneShotAlt
+                    | KeyCode::OneShotCmd
+                    | KeyCode::ToggleAutoShift
+                    | KeyCode::CycleUnicodeMode
+                    | KeyCode::UsbStressTest
+                    | KeyCode::SettingsMode
+                    | KeyCode::ToggleAltGuiSwap
+                    | KeyCode::ToggleCapsAsCtrl
+                    | KeyCode::ToggleGuiDisabled
+                    | KeyCode::ToggleGameMode
+                    | KeyCode::Repeat
+            )
     }
 }
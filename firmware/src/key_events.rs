@@ -0,0 +1,112 @@
+//! A queue of key presses and releases between debouncing and building reports, so every
+//! change reaches the host, in order, however long the endpoint keeps reports waiting.
+//!
+//! Each scan's debounced matrix is compared with the last one recorded, and every key which
+//! changed becomes a `KeyEvent`. Reports are built from `KeyEvents::matrix`, which `advance`
+//! takes the waiting events into, as many at a time as one report can show: it stops before
+//! an event for a key already changed, so a chord goes out in one report but a press and
+//! its release never share one. The main loop only advances once the report built from the
+//! last events is queued for the host, and builds another report in the same scan while the
+//! report queue has room, so a key pressed and released while the queue is full still sends
+//! both, where building reports straight from each scan would only send where the key ended
+//! up. A change which doesn't fit in a full event queue isn't recorded, so it's picked up
+//! again on the next scan.
+
+use defmt::Format;
+
+use crate::{NUM_COLS, NUM_ROWS};
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+/// How many events can wait for a report, enough for a fast roll across the keyboard while
+/// the host isn't reading.
+pub const KEY_EVENT_QUEUE_LEN: usize = 32;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub struct KeyEvent {
+    /// The key's position in the matrix, as `(column, row)`.
+    pub position: (usize, usize),
+    pub pressed: bool,
+
+    /// When the change was scanned, in milliseconds since the keyboard started.
+    pub time_ms: u64,
+}
+
+pub struct KeyEvents<const N: usize> {
+    /// A ring buffer of the events waiting, the oldest at `head`.
+    events: [KeyEvent; N],
+    head: usize,
+    len: usize,
+
+    /// The matrix with every event recorded so far applied.
+    recorded: Matrix,
+
+    /// The matrix with every event taken in by `advance` applied.
+    matrix: Matrix,
+}
+
+impl<const N: usize> Default for KeyEvents<N> {
+    fn default() -> Self {
+        let event = KeyEvent { position: (0, 0), pressed: false, time_ms: 0 };
+        Self {
+            events: [event; N],
+            head: 0,
+            len: 0,
+            recorded: [[false; NUM_ROWS]; NUM_COLS],
+            matrix: [[false; NUM_ROWS]; NUM_COLS],
+        }
+    }
+}
+
+impl<const N: usize> KeyEvents<N> {
+    /// Queue an event for every key which changed in `scan`, made at `now_ms`. Returns false
+    /// if the queue filled up, leaving the changes which didn't fit for a later scan.
+    pub fn record(&mut self, now_ms: u64, scan: &Matrix) -> bool {
+        for (col, (column, recorded_column)) in scan.iter().zip(&mut self.recorded).enumerate() {
+            for (row, (pressed, recorded)) in column.iter().zip(recorded_column).enumerate() {
+                if pressed == recorded {
+                    continue;
+                }
+                if self.len == N {
+                    return false;
+                }
+
+                let event = KeyEvent { position: (col, row), pressed: *pressed, time_ms: now_ms };
+                self.events[(self.head + self.len) % N] = event;
+                self.len += 1;
+                *recorded = *pressed;
+            }
+        }
+        true
+    }
+
+    /// Apply the waiting events to `matrix`, oldest first, up to the first for a key one of
+    /// them already changed. Returns the oldest event applied, or `None` if none were waiting.
+    pub fn advance(&mut self) -> Option<KeyEvent> {
+        let oldest = self.is_waiting().then(|| self.events[self.head])?;
+        let mut changed = [[false; NUM_ROWS]; NUM_COLS];
+        while self.is_waiting() {
+            let event = self.events[self.head];
+            let (col, row) = event.position;
+            if changed[col][row] {
+                break;
+            }
+
+            changed[col][row] = true;
+            self.matrix[col][row] = event.pressed;
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+        }
+        Some(oldest)
+    }
+
+    /// Whether any events are waiting to be taken in by `advance`.
+    pub fn is_waiting(&self) -> bool {
+        self.len > 0
+    }
+
+    /// The keys to build the next report from, as of the last events taken in by `advance`.
+    pub fn matrix(&self) -> &Matrix {
+        &self.matrix
+    }
+}
@@ -1,37 +1,118 @@
-use crate::{key_codes::KeyCode, NUM_COLS, NUM_ROWS};
-
-#[rustfmt::skip]
-pub const NORMAL_LAYER_MAPPING: [[KeyCode; NUM_ROWS]; NUM_COLS] = [
-    [KeyCode::Escape, KeyCode::Tilde, KeyCode::Tab, KeyCode::CapsLock, KeyCode::LeftShift, KeyCode::Fn],
-    [KeyCode::F1, KeyCode::Num1, KeyCode::Q, KeyCode::A, KeyCode::Empty, KeyCode::LeftCtrl],
-    [KeyCode::F2, KeyCode::Num2, KeyCode::W, KeyCode::S, KeyCode::Z, KeyCode::LeftAlt],
-    [KeyCode::F3, KeyCode::Num3, KeyCode::E, KeyCode::D, KeyCode::X, KeyCode::LeftCmd],
-    [KeyCode::F4, KeyCode::Num4, KeyCode::R, KeyCode::F, KeyCode::C, KeyCode::Empty],
-    [KeyCode::F5, KeyCode::Num5, KeyCode::T, KeyCode::G, KeyCode::V, KeyCode::Empty],
-    [KeyCode::Empty, KeyCode::Num6, KeyCode::Y, KeyCode::H, KeyCode::B, KeyCode::Space],
-    [KeyCode::F6, KeyCode::Num7, KeyCode::U, KeyCode::J, KeyCode::N, KeyCode::Empty],
-    [KeyCode::F7, KeyCode::Num8, KeyCode::I, KeyCode::K, KeyCode::M, KeyCode::Empty],
-    [KeyCode::F8, KeyCode::Num9, KeyCode::O, KeyCode::L, KeyCode::Comma, KeyCode::Empty],
-    [KeyCode::F9, KeyCode::Num0, KeyCode::P, KeyCode::Semicolon, KeyCode::Period, KeyCode::RightCmd],
-    [KeyCode::F10, KeyCode::Minus, KeyCode::LeftSquareBracket, KeyCode::SingleQuote, KeyCode::ForwardSlash, KeyCode::Left],
-    [KeyCode::F11, KeyCode::Equals, KeyCode::RightSquareBracket, KeyCode::Enter, KeyCode::Up, KeyCode::Down],
-    [KeyCode::F12, KeyCode::Backspace, KeyCode::BackSlash, KeyCode::Empty, KeyCode::Empty, KeyCode::Right],
-];
-
-#[rustfmt::skip]
-pub const FN_LAYER_MAPPING: [[KeyCode; NUM_ROWS]; NUM_COLS] = [
-    [KeyCode::Escape, KeyCode::Tilde, KeyCode::Tab, KeyCode::CapsLock, KeyCode::LeftShift, KeyCode::Empty],
-    [KeyCode::F1, KeyCode::Num1, KeyCode::Q, KeyCode::A, KeyCode::Empty, KeyCode::LeftCtrl],
-    [KeyCode::F2, KeyCode::Num2, KeyCode::W, KeyCode::S, KeyCode::Z, KeyCode::LeftAlt],
-    [KeyCode::F3, KeyCode::Num3, KeyCode::E, KeyCode::D, KeyCode::X, KeyCode::LeftCmd],
-    [KeyCode::F4, KeyCode::Num4, KeyCode::R, KeyCode::F, KeyCode::C, KeyCode::Empty],
-    [KeyCode::F5, KeyCode::Num5, KeyCode::T, KeyCode::G, KeyCode::V, KeyCode::Empty],
-    [KeyCode::Empty, KeyCode::Num6, KeyCode::Y, KeyCode::H, KeyCode::B, KeyCode::Space],
-    [KeyCode::F6, KeyCode::Num7, KeyCode::U, KeyCode::J, KeyCode::N, KeyCode::Empty],
-    [KeyCode::F7, KeyCode::Num8, KeyCode::I, KeyCode::K, KeyCode::M, KeyCode::Empty],
-    [KeyCode::F8, KeyCode::Num9, KeyCode::O, KeyCode::L, KeyCode::Comma, KeyCode::Empty],
-    [KeyCode::F9, KeyCode::Num0, KeyCode::P, KeyCode::Semicolon, KeyCode::Period, KeyCode::RightCmd],
-    [KeyCode::VolumeMute, KeyCode::Minus, KeyCode::LeftSquareBracket, KeyCode::SingleQuote, KeyCode::ForwardSlash, KeyCode::Left],
-    [KeyCode::VolumeDown, KeyCode::Equals, KeyCode::RightSquareBracket, KeyCode::Enter, KeyCode::Up, KeyCode::Down],
-    [KeyCode::VolumeUp, KeyCode::Backspace, KeyCode::BackSlash, KeyCode::Empty, KeyCode::Empty, KeyCode::Right],
-];
+//! Default keymaps for the physical layout variants the PCB can be built as.
+//!
+//! Exactly one `layout-*` Cargo feature selects the variant. Each variant's keymap comes
+//! from its `[layouts.<name>]` table in `board.toml`: the normal, num and Fn layers, any
+//! extra base layers, and the matrix positions without a switch, which `build.rs` turns into
+//! constants. The variant's module adds its lock LED bindings, tap-hold and tap-dance keys,
+//! combos and Unicode characters.
+
+use crate::{
+    host_leds::{HostLed, HostLeds},
+    key_codes::KeyCode,
+    NUM_COLS, NUM_ROWS,
+};
+
+#[cfg(feature = "layout-ansi")]
+mod ansi;
+#[cfg(feature = "layout-hhkb")]
+mod hhkb;
+#[cfg(feature = "layout-iso")]
+mod iso;
+
+#[cfg(feature = "layout-ansi")]
+pub use ansi::*;
+#[cfg(feature = "layout-hhkb")]
+pub use hhkb::*;
+#[cfg(feature = "layout-iso")]
+pub use iso::*;
+
+#[cfg(not(any(feature = "layout-ansi", feature = "layout-iso", feature = "layout-hhkb")))]
+compile_error!("Select a keyboard layout with one of the `layout-*` features.");
+
+#[cfg(any(
+    all(feature = "layout-ansi", feature = "layout-iso"),
+    all(feature = "layout-ansi", feature = "layout-hhkb"),
+    all(feature = "layout-iso", feature = "layout-hhkb"),
+))]
+compile_error!(
+    "Only one `layout-*` feature can be enabled at a time, use `--no-default-features` when \
+     selecting a layout other than `layout-ansi`."
+);
+
+/// The number of keymap layers, see `LAYERS`.
+pub const NUM_LAYERS: usize = FIRST_EXTRA_BASE_LAYER + EXTRA_BASE_LAYERS.len();
+
+/// The layer Num Word activates, see `num_word::NumWord`.
+pub const NUM_LAYER: usize = 1;
+
+/// The layer `KeyCode::Fn` holds. It sits above the num layer, so Fn still works while Num
+/// Word is active.
+pub const FN_LAYER: usize = 2;
+
+/// Where the layout's `EXTRA_BASE_LAYERS` start, above the others so that adding them
+/// doesn't move the num and Fn layers.
+pub const FIRST_EXTRA_BASE_LAYER: usize = 3;
+
+/// The keymap layers, from the base layer up. The layer keys (see `KeyCode::layer_action`)
+/// for layers past these do nothing.
+pub const LAYERS: [[[KeyCode; NUM_ROWS]; NUM_COLS]; NUM_LAYERS] = {
+    let mut layers = [NORMAL_LAYER_MAPPING; NUM_LAYERS];
+    layers[NUM_LAYER] = NUM_LAYER_MAPPING;
+    layers[FN_LAYER] = FN_LAYER_MAPPING;
+    let mut i = 0;
+    while i < EXTRA_BASE_LAYERS.len() {
+        layers[FIRST_EXTRA_BASE_LAYER + i] = EXTRA_BASE_LAYERS[i];
+        i += 1;
+    }
+    layers
+};
+
+/// The base layer after `layer` (the normal layer, then the layout's `EXTRA_BASE_LAYERS`),
+/// for cycling through them.
+pub fn next_base_layer(layer: usize) -> usize {
+    let next = if layer == 0 { FIRST_EXTRA_BASE_LAYER } else { layer + 1 };
+    if next < NUM_LAYERS {
+        next
+    } else {
+        0
+    }
+}
+
+/// The positions in the key matrix which have a switch installed for the selected layout.
+/// Unpopulated positions are never reported as pressed, regardless of what the scan reads.
+pub const MATRIX_MASK: [[bool; NUM_ROWS]; NUM_COLS] = matrix_mask(UNPOPULATED_KEYS);
+
+/// Build a matrix mask from a list of `(column, row)` positions without a switch.
+const fn matrix_mask(unpopulated: &[(usize, usize)]) -> [[bool; NUM_ROWS]; NUM_COLS] {
+    let mut mask = [[true; NUM_ROWS]; NUM_COLS];
+
+    let mut i = 0;
+    while i < unpopulated.len() {
+        let (col, row) = unpopulated[i];
+        mask[col][row] = false;
+        i += 1;
+    }
+
+    mask
+}
+
+/// A key which does something else on the normal layer while one of the host's LEDs is lit.
+/// For example, `LedBinding { led: HostLed::CapsLock, position: (0, 0), key: KeyCode::Tilde }`
+/// would make Escape type `~` while caps lock is on.
+pub struct LedBinding {
+    pub led: HostLed,
+    pub position: (usize, usize),
+    pub key: KeyCode,
+}
+
+/// Replace the keys in `layer` which have a binding for one of the lit LEDs.
+pub fn apply_led_bindings(
+    layer: &mut [[KeyCode; NUM_ROWS]; NUM_COLS],
+    leds: HostLeds,
+    bindings: &[LedBinding],
+) {
+    for binding in bindings.iter().filter(|binding| leds.is_lit(binding.led)) {
+        let (col, row) = binding.position;
+        layer[col][row] = binding.key;
+    }
+}
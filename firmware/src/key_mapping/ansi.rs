@@ -0,0 +1,27 @@
+//! The stock layout of the board: a 6.25u spacebar, a full-width backspace, and an ANSI
+//! enter key, with the arrow keys tucked under the enter key in place of a right shift.
+//!
+//! Its keymaps, and the matrix positions without a switch, are in `board.toml` under
+//! `[layouts.ansi]`.
+
+use super::LedBinding;
+use crate::{
+    combos::Combo, key_codes::KeyCode, tap_dance::TapDance, tap_hold::TapHold, NUM_COLS, NUM_ROWS,
+};
+
+/// Keys which change while one of the host's LEDs is lit, see `LedBinding`.
+pub const LED_BINDINGS: &[LedBinding] = &[];
+
+/// Keys which do something else when held, see `TapHold`.
+pub const TAP_HOLD_KEYS: &[TapHold] = &[];
+
+/// Keys which do something else when tapped more than once, see `TapDance`.
+pub const TAP_DANCE_KEYS: &[TapDance] = &[];
+
+/// Keys which do something else when pressed together, see `Combo`.
+pub const COMBOS: &[Combo] = &[];
+
+/// The characters `KeyCode::Unicode0` onward type, see `unicode`.
+pub const UNICODE_CHARS: &[char] = &[];
+
+include!(concat!(env!("OUT_DIR"), "/layout_ansi.rs"));
@@ -0,0 +1,29 @@
+//! The stock layout with a split backspace and an HHKB-style bottom row. Control moves to
+//! the caps lock position, the two outermost bottom-left keys are left empty, and Fn sits
+//! to the right of the up arrow. The right half of the split backspace is wired to the
+//! otherwise unused matrix position at column 13, row 3.
+//!
+//! Its keymaps, and the matrix positions without a switch, are in `board.toml` under
+//! `[layouts.hhkb]`.
+
+use super::LedBinding;
+use crate::{
+    combos::Combo, key_codes::KeyCode, tap_dance::TapDance, tap_hold::TapHold, NUM_COLS, NUM_ROWS,
+};
+
+/// Keys which change while one of the host's LEDs is lit, see `LedBinding`.
+pub const LED_BINDINGS: &[LedBinding] = &[];
+
+/// Keys which do something else when held, see `TapHold`.
+pub const TAP_HOLD_KEYS: &[TapHold] = &[];
+
+/// Keys which do something else when tapped more than once, see `TapDance`.
+pub const TAP_DANCE_KEYS: &[TapDance] = &[];
+
+/// Keys which do something else when pressed together, see `Combo`.
+pub const COMBOS: &[Combo] = &[];
+
+/// The characters `KeyCode::Unicode0` onward type, see `unicode`.
+pub const UNICODE_CHARS: &[char] = &[];
+
+include!(concat!(env!("OUT_DIR"), "/layout_hhkb.rs"));
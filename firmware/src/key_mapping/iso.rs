@@ -0,0 +1,27 @@
+//! The stock layout with an ISO enter key. The key to the left of the enter key sends the
+//! non-US `#` usage, and the short left shift frees up a position for the non-US `\` key.
+//!
+//! Its keymaps, and the matrix positions without a switch, are in `board.toml` under
+//! `[layouts.iso]`.
+
+use super::LedBinding;
+use crate::{
+    combos::Combo, key_codes::KeyCode, tap_dance::TapDance, tap_hold::TapHold, NUM_COLS, NUM_ROWS,
+};
+
+/// Keys which change while one of the host's LEDs is lit, see `LedBinding`.
+pub const LED_BINDINGS: &[LedBinding] = &[];
+
+/// Keys which do something else when held, see `TapHold`.
+pub const TAP_HOLD_KEYS: &[TapHold] = &[];
+
+/// Keys which do something else when tapped more than once, see `TapDance`.
+pub const TAP_DANCE_KEYS: &[TapDance] = &[];
+
+/// Keys which do something else when pressed together, see `Combo`.
+pub const COMBOS: &[Combo] = &[];
+
+/// The characters `KeyCode::Unicode0` onward type, see `unicode`.
+pub const UNICODE_CHARS: &[char] = &[];
+
+include!(concat!(env!("OUT_DIR"), "/layout_iso.rs"));
@@ -1,10 +1,61 @@
 use core::{convert::Infallible, ops::Deref};
 
 use cortex_m::delay::Delay;
-use embedded_hal::digital::v2::InputPin;
-use usbd_hid::descriptor::KeyboardReport;
+use defmt::Format;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
 
-use crate::{debounce::Debounce, key_codes::KeyCode, key_mapping};
+use crate::debounce::Debouncer;
+
+/// Which way current flows through a switch's diode.
+#[derive(Clone, Copy, Debug, Default, Format, PartialEq)]
+pub enum DiodeDirection {
+    /// From the column to the row, like on the key ripper.
+    #[default]
+    ColToRow,
+
+    /// From the row to the column.
+    RowToCol,
+}
+
+/// How the lines which read the switches are pulled while nothing drives them.
+#[derive(Clone, Copy, Debug, Default, Format, PartialEq)]
+pub enum Sense {
+    /// Pulled down, so they read high through a pressed switch on a line driven high.
+    #[default]
+    PullDown,
+
+    /// Pulled up, so they read low through a pressed switch on a line driven low.
+    PullUp,
+}
+
+/// How a board's matrix is wired, from `matrix.diode_direction` and `matrix.sense` in
+/// `board.toml`. Together they decide which lines are driven: current has to flow from the
+/// driven line to a pulled down one, or from a pulled up one to the driven line, so the
+/// other side of the diodes is driven in either case.
+#[derive(Clone, Copy, Debug, Default, Format, PartialEq)]
+pub struct MatrixWiring {
+    pub diode_direction: DiodeDirection,
+    pub sense: Sense,
+}
+
+impl MatrixWiring {
+    /// Whether the columns are driven and the rows read, rather than the other way around.
+    pub const fn drives_columns(&self) -> bool {
+        matches!(
+            (self.diode_direction, self.sense),
+            (DiodeDirection::ColToRow, Sense::PullDown) | (DiodeDirection::RowToCol, Sense::PullUp)
+        )
+    }
+
+    /// The number of lines driven in turn, for a matrix of this size.
+    pub const fn drive_lines(&self, num_rows: usize, num_cols: usize) -> usize {
+        if self.drives_columns() {
+            num_cols
+        } else {
+            num_rows
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct KeyScan<const NUM_ROWS: usize, const NUM_COLS: usize> {
@@ -19,70 +70,208 @@ impl<const NUM_ROWS: usize, const NUM_COLS: usize> Deref for KeyScan<NUM_ROWS, N
     }
 }
 
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> From<[[bool; NUM_ROWS]; NUM_COLS]>
+    for KeyScan<NUM_ROWS, NUM_COLS>
+{
+    /// Wrap an already debounced matrix, as if it had just been scanned.
+    fn from(matrix: [[bool; NUM_ROWS]; NUM_COLS]) -> Self {
+        Self { matrix }
+    }
+}
+
 impl<const NUM_ROWS: usize, const NUM_COLS: usize> KeyScan<NUM_ROWS, NUM_COLS> {
+    /// Read and debounce the matrix. `sense` are the lines which read the switches and
+    /// `drive` the ones driven in turn, the rows and columns in whichever order `wiring`
+    /// says.
     pub fn scan(
-        rows: &[&dyn InputPin<Error = Infallible>],
-        columns: &mut [&mut dyn embedded_hal::digital::v2::OutputPin<Error = Infallible>],
+        sense: &[&dyn InputPin<Error = Infallible>],
+        drive: &mut [&mut dyn OutputPin<Error = Infallible>],
+        wiring: MatrixWiring,
         delay: &mut Delay,
-        debounce: &mut Debounce<NUM_ROWS, NUM_COLS>,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+        debounce: &mut impl Debouncer<NUM_ROWS, NUM_COLS>,
     ) -> Self {
-        let mut raw_matrix = [[false; NUM_ROWS]; NUM_COLS];
-
-        for (gpio_col, matrix_col) in columns.iter_mut().zip(raw_matrix.iter_mut()) {
-            gpio_col.set_high().unwrap();
-            delay.delay_us(10);
+        let raw_matrix = Self::read_raw(sense, drive, wiring, delay, matrix_mask);
+        Self::from_raw(raw_matrix, debounce)
+    }
 
-            for (gpio_row, matrix_row) in rows.iter().zip(matrix_col.iter_mut()) {
-                *matrix_row = gpio_row.is_high().unwrap();
+    /// Read the state of every switch, without any debouncing. Positions which are false in
+    /// `matrix_mask` have no switch installed, and always read as released.
+    ///
+    /// This busy-waits for the lines to settle, see `MatrixScanner` to do something else in
+    /// the meantime.
+    pub fn read_raw(
+        sense: &[&dyn InputPin<Error = Infallible>],
+        drive: &mut [&mut dyn OutputPin<Error = Infallible>],
+        wiring: MatrixWiring,
+        delay: &mut Delay,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        let mut scanner = MatrixScanner::new(COLUMN_SETTLE_US, wiring);
+        loop {
+            match scanner.advance(sense, drive, matrix_mask) {
+                ScanStep::Wait(settle_us) => delay.delay_us(settle_us),
+                ScanStep::Done(raw_matrix) => return raw_matrix,
             }
-
-            gpio_col.set_low().unwrap();
-            delay.delay_us(10);
         }
+    }
 
+    /// Finish a scan from a raw matrix, either just read from the switches or replayed from
+    /// a recording.
+    pub fn from_raw(
+        raw_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+        debounce: &mut impl Debouncer<NUM_ROWS, NUM_COLS>,
+    ) -> Self {
         let matrix = debounce.report_and_tick(&raw_matrix);
         Self { matrix }
     }
 }
 
-impl<const NUM_ROWS: usize, const NUM_COLS: usize> From<KeyScan<NUM_ROWS, NUM_COLS>>
-    for KeyboardReport
+/// Reads the raw state of a board's switches, whether they're in a matrix or each on a pin
+/// of its own (see `direct_pins::DirectPins`), so either can be debounced and turned into
+/// reports the same way.
+pub trait SwitchScanner<const NUM_ROWS: usize, const NUM_COLS: usize> {
+    /// Read the state of every switch, without any debouncing. Positions which are false in
+    /// `matrix_mask` always read as released.
+    fn read_raw(
+        &mut self,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS];
+
+    /// Read and debounce every switch.
+    fn scan(
+        &mut self,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+        debounce: &mut impl Debouncer<NUM_ROWS, NUM_COLS>,
+    ) -> KeyScan<NUM_ROWS, NUM_COLS> {
+        let raw_matrix = self.read_raw(matrix_mask);
+        KeyScan::from_raw(raw_matrix, debounce)
+    }
+}
+
+/// A matrix's pins, for scanning it through `SwitchScanner`. See `KeyScan::scan` for what
+/// they are, and `delay` waits for them to settle.
+pub struct MatrixPins<'a> {
+    pub sense: &'a [&'a dyn InputPin<Error = Infallible>],
+    pub drive: &'a mut [&'a mut dyn OutputPin<Error = Infallible>],
+    pub wiring: MatrixWiring,
+    pub delay: &'a mut Delay,
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> SwitchScanner<NUM_ROWS, NUM_COLS>
+    for MatrixPins<'_>
 {
-    fn from(scan: KeyScan<NUM_ROWS, NUM_COLS>) -> Self {
-        let mut keycodes = [0u8; 6];
-        let mut keycode_index = 0;
-        let mut modifier = 0;
-
-        let mut push_keycode = |key| {
-            if keycode_index < keycodes.len() {
-                keycodes[keycode_index] = key;
-                keycode_index += 1;
-            }
-        };
+    fn read_raw(
+        &mut self,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        KeyScan::read_raw(self.sense, self.drive, self.wiring, self.delay, matrix_mask)
+    }
+}
 
-        // First scan for any function keys being pressed
-        let mut layer_mapping = key_mapping::NORMAL_LAYER_MAPPING;
-        for (matrix_column, mapping_column) in scan.matrix.iter().zip(layer_mapping) {
-            for (key_pressed, mapping_row) in matrix_column.iter().zip(mapping_column) {
-                if mapping_row == KeyCode::Fn && *key_pressed {
-                    layer_mapping = key_mapping::FN_LAYER_MAPPING;
-                }
-            }
+/// How long a driven line takes to settle after it's driven or released, before the others
+/// can be read or the next one driven. This is a safe default, see `settle_calibration` for
+/// measuring it on a particular board.
+pub const COLUMN_SETTLE_US: u32 = 10;
+
+/// What to do after advancing a `MatrixScanner`.
+pub enum ScanStep<const NUM_ROWS: usize, const NUM_COLS: usize> {
+    /// Wait this many microseconds for a line to settle, then advance again.
+    Wait(u32),
+
+    /// The scan is complete, with the raw state of every switch.
+    Done([[bool; NUM_ROWS]; NUM_COLS]),
+}
+
+#[derive(Clone, Copy)]
+enum ScanState {
+    Idle,
+
+    /// The line is driven, and the others will be read once it settles.
+    Driving(usize),
+
+    /// The line was just released, and the next one can be driven once it settles.
+    Releasing(usize),
+}
+
+/// Reads the key matrix one line at a time without blocking, so the settling time between
+/// lines is free for other work. Each call to `advance` does the next step of the scan, and
+/// says how long to wait before the following one.
+pub struct MatrixScanner<const NUM_ROWS: usize, const NUM_COLS: usize> {
+    state: ScanState,
+    raw_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+    settle_us: u32,
+    wiring: MatrixWiring,
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> Default for MatrixScanner<NUM_ROWS, NUM_COLS> {
+    fn default() -> Self {
+        Self::new(COLUMN_SETTLE_US, MatrixWiring::default())
+    }
+}
+
+impl<const NUM_ROWS: usize, const NUM_COLS: usize> MatrixScanner<NUM_ROWS, NUM_COLS> {
+    /// A scanner for a matrix wired like `wiring`, which waits `settle_us` microseconds for
+    /// each line to settle.
+    pub fn new(settle_us: u32, wiring: MatrixWiring) -> Self {
+        Self {
+            state: ScanState::Idle,
+            raw_matrix: [[false; NUM_ROWS]; NUM_COLS],
+            settle_us,
+            wiring,
         }
+    }
 
-        // Second scan to generate the correct keycodes given the activated key map
-        for (matrix_column, mapping_column) in scan.matrix.iter().zip(layer_mapping) {
-            for (key_pressed, mapping_row) in matrix_column.iter().zip(mapping_column) {
-                if *key_pressed {
-                    if let Some(bitmask) = mapping_row.modifier_bitmask() {
-                        modifier |= bitmask;
-                    } else {
-                        push_keycode(mapping_row as u8);
+    /// Do the next step of the scan, starting a new one if none is in progress. `sense` are
+    /// the lines which read the switches and `drive` the ones driven in turn, see
+    /// `MatrixWiring`. Positions which are false in `matrix_mask` always read as released.
+    pub fn advance(
+        &mut self,
+        sense: &[&dyn InputPin<Error = Infallible>],
+        drive: &mut [&mut dyn OutputPin<Error = Infallible>],
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> ScanStep<NUM_ROWS, NUM_COLS> {
+        let pulled_up = self.wiring.sense == Sense::PullUp;
+        let next_line = match self.state {
+            ScanState::Idle => {
+                // Pins start out driven low, which holds pulled up lines down until released.
+                for pin in drive.iter_mut() {
+                    pin.set_state(pulled_up.into()).unwrap();
+                }
+                0
+            },
+            ScanState::Driving(line) => {
+                for (index, pin) in sense.iter().enumerate() {
+                    let (col, row) =
+                        if self.wiring.drives_columns() { (line, index) } else { (index, line) };
+                    if col < NUM_COLS && row < NUM_ROWS {
+                        self.raw_matrix[col][row] = pin.is_high().unwrap() != pulled_up;
                     }
                 }
+
+                drive[line].set_state(pulled_up.into()).unwrap();
+                self.state = ScanState::Releasing(line);
+                return ScanStep::Wait(self.settle_us);
+            },
+            ScanState::Releasing(line) => line + 1,
+        };
+
+        if next_line < self.wiring.drive_lines(NUM_ROWS, NUM_COLS).min(drive.len()) {
+            drive[next_line].set_state((!pulled_up).into()).unwrap();
+            self.state = ScanState::Driving(next_line);
+            return ScanStep::Wait(self.settle_us);
+        }
+
+        self.state = ScanState::Idle;
+
+        // Ignore any positions without a switch installed.
+        let mut raw_matrix = self.raw_matrix;
+        for (matrix_col, mask_col) in raw_matrix.iter_mut().zip(matrix_mask) {
+            for (matrix_row, populated) in matrix_col.iter_mut().zip(mask_col) {
+                *matrix_row &= populated;
             }
         }
 
-        KeyboardReport { modifier, reserved: 0, leds: 0, keycodes }
+        ScanStep::Done(raw_matrix)
     }
 }
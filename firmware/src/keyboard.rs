@@ -0,0 +1,756 @@
+//! Turns debounced key scans into HID reports, keeping track of the layer and firmware key
+//! state which has to persist from one scan to the next.
+
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::{
+    auto_shift::AutoShift,
+    combos::Combos,
+    conditional_keys::ConditionalKeys,
+    expansion::Module,
+    hall_effect::AnalogSettings,
+    host_leds::HostLeds,
+    key_codes::KeyCode,
+    key_mapping::{self, NUM_LAYERS},
+    key_scan::KeyScan,
+    keymap::Keymap,
+    layers::Layers,
+    locking_mods::LockingMods,
+    macros::{MacroBuffer, MacroPlayer, MacroRecorder, RECORDED_MACRO_INDEX},
+    mod_swaps::ModSwaps,
+    mouse_keys::{MouseKeys, MouseMotion},
+    nkro::{NkroReport, NKRO_KEYS},
+    num_word::NumWord,
+    one_shot::OneShotMods,
+    profile::Profile,
+    repeat_key::RepeatKey,
+    rgb::RgbSettings,
+    settings_mode::{Action, ModeSettings, SettingsMode},
+    socd::{SocdCleaner, SocdMode},
+    tap_dance::TapDanceKeys,
+    tap_hold::TapHoldKeys,
+    unicode::{UnicodeMode, UnicodePlayer},
+    NUM_COLS, NUM_ROWS,
+};
+
+pub struct Keyboard {
+    layers: Layers<NUM_LAYERS>,
+    combos: Combos,
+    tap_hold: TapHoldKeys,
+    tap_dance: TapDanceKeys,
+    num_word: NumWord,
+    one_shot_mods: OneShotMods,
+    auto_shift: AutoShift,
+    locking_mods: LockingMods,
+    conditional_keys: ConditionalKeys,
+    mod_swaps: ModSwaps,
+    repeat_key: RepeatKey,
+    profile: Profile,
+    socd: SocdCleaner,
+    expansion_module: Option<Module>,
+    host_leds: HostLeds,
+    macros: MacroBuffer,
+    macro_player: MacroPlayer,
+    unicode_player: UnicodePlayer,
+    unicode_mode: UnicodeMode,
+    macro_recorder: MacroRecorder,
+    macro_recorded: bool,
+    calibration_requested: bool,
+    trace_save_requested: bool,
+    trace_replay_requested: bool,
+    stress_test_requested: bool,
+    config_locked: bool,
+    game_mode: bool,
+    output_switch_requested: bool,
+    break_snooze_requested: bool,
+    nkro_toggle_requested: bool,
+    bootloader_requested: bool,
+    rgb_settings: RgbSettings,
+    settings_mode: SettingsMode,
+    analog_settings: AnalogSettings,
+
+    /// The last report, with every pressed key rather than the first six.
+    nkro_report: NkroReport,
+
+    /// The Consumer Control usage of the media key in the last report, or zero.
+    consumer_usage: u16,
+
+    /// The System Control usage of the system key in the last report, or zero.
+    system_usage: u8,
+
+    mouse_keys: MouseKeys,
+
+    /// What the mouse keys have done since the motion was last taken.
+    mouse_motion: MouseMotion,
+
+    /// The matrix from the previous scan, used to act on firmware keys once per press.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+
+    /// The keys held in the last report, remapped, or `KeyCode::Empty`. Mouse keys go on
+    /// with these while keys are held back.
+    held_keys: [[KeyCode; NUM_ROWS]; NUM_COLS],
+
+    /// The keys typed in the last report, before a macro or Unicode character playing joined
+    /// in, which are sent again while a tap-hold key is undecided.
+    typed: TypedKeys,
+
+    /// The last report.
+    last_report: KeyboardReport,
+}
+
+/// The keys typed in a report, before the keys held by a macro or Unicode character playing
+/// join in.
+#[derive(Copy, Clone, Default)]
+struct TypedKeys {
+    keycodes: [u8; 6],
+    len: usize,
+    report: NkroReport,
+}
+
+impl Keyboard {
+    pub fn new(profile: Profile) -> Self {
+        Self {
+            layers: Layers::new(key_mapping::LAYERS),
+            combos: Combos::new(key_mapping::COMBOS),
+            tap_hold: TapHoldKeys::new(key_mapping::TAP_HOLD_KEYS),
+            tap_dance: TapDanceKeys::new(key_mapping::TAP_DANCE_KEYS),
+            num_word: NumWord::default(),
+            one_shot_mods: OneShotMods::default(),
+            auto_shift: AutoShift::default(),
+            locking_mods: LockingMods::default(),
+            conditional_keys: ConditionalKeys::default(),
+            mod_swaps: ModSwaps::default(),
+            repeat_key: RepeatKey::default(),
+            profile,
+            socd: SocdCleaner::new(SocdMode::Off),
+            expansion_module: None,
+            host_leds: HostLeds::default(),
+            macros: MacroBuffer::default(),
+            macro_player: MacroPlayer::default(),
+            unicode_player: UnicodePlayer::default(),
+            unicode_mode: UnicodeMode::Linux,
+            macro_recorder: MacroRecorder::default(),
+            macro_recorded: false,
+            calibration_requested: false,
+            trace_save_requested: false,
+            trace_replay_requested: false,
+            stress_test_requested: false,
+            config_locked: false,
+            game_mode: false,
+            output_switch_requested: false,
+            break_snooze_requested: false,
+            nkro_toggle_requested: false,
+            bootloader_requested: false,
+            rgb_settings: RgbSettings::default(),
+            settings_mode: SettingsMode::default(),
+            analog_settings: AnalogSettings::default(),
+            nkro_report: NkroReport::default(),
+            consumer_usage: 0,
+            system_usage: 0,
+            mouse_keys: MouseKeys::default(),
+            mouse_motion: MouseMotion::default(),
+            previous_matrix: [[false; NUM_ROWS]; NUM_COLS],
+            held_keys: [[KeyCode::Empty; NUM_ROWS]; NUM_COLS],
+            typed: TypedKeys::default(),
+            last_report: KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes: [0; 6] },
+        }
+    }
+
+    /// The currently selected profile, which can be changed with `KeyCode::ToggleProfile`.
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Add the keys of an expansion module to every layer, where the layer doesn't already
+    /// map something to their position.
+    pub fn set_expansion_module(&mut self, module: Option<Module>) {
+        self.expansion_module = module;
+    }
+
+    /// Update the host's LED state, from the last output report it sent.
+    pub fn set_host_leds(&mut self, leds: HostLeds) {
+        self.host_leds = leds;
+    }
+
+    pub fn host_leds(&self) -> HostLeds {
+        self.host_leds
+    }
+
+    /// Replace the compiled-in keymap, such as with one saved to flash.
+    pub fn set_keymap(&mut self, keymap: &Keymap) {
+        self.layers.set_mappings(*keymap.layers());
+    }
+
+    /// Replace the macros played by the macro keys, such as with the ones saved to flash.
+    pub fn set_macros(&mut self, macros: &MacroBuffer) {
+        self.macros = *macros;
+    }
+
+    /// Start playing a macro, as if its key was pressed, such as the away macro once the
+    /// keyboard goes idle.
+    pub fn play_macro(&mut self, index: usize) {
+        self.macro_player.start(&self.macros, index);
+    }
+
+    pub fn macros(&self) -> &MacroBuffer {
+        &self.macros
+    }
+
+    /// Whether a macro was recorded into `macros` since the last call.
+    pub fn take_macro_recorded(&mut self) -> bool {
+        core::mem::take(&mut self.macro_recorded)
+    }
+
+    /// The highest active layer, as of the last report.
+    pub fn active_layer(&self) -> usize {
+        self.layers.active_layer()
+    }
+
+    /// The layer used as the base layer, changed with the `KeyCode::DefaultLayer` keys.
+    pub fn default_layer(&self) -> usize {
+        self.layers.default_layer()
+    }
+
+    /// Restore the default layer, such as from the saved settings.
+    pub fn set_default_layer(&mut self, layer: usize) {
+        self.layers.set_default_layer(layer);
+    }
+
+    /// The keys resolved through the active layers, as of the last report.
+    pub fn layer_mapping(&self) -> [[KeyCode; NUM_ROWS]; NUM_COLS] {
+        self.layers.mapping()
+    }
+
+    /// Whether the num layer is currently active through `KeyCode::NumWord`.
+    pub fn num_word_active(&self) -> bool {
+        self.num_word.is_active()
+    }
+
+    /// Whether the host is kept from changing the keyboard's configuration, toggled with
+    /// `KeyCode::ToggleConfigLock`.
+    pub fn config_locked(&self) -> bool {
+        self.config_locked
+    }
+
+    /// Restore the configuration lock, such as from the saved settings.
+    pub fn set_config_locked(&mut self, locked: bool) {
+        self.config_locked = locked;
+    }
+
+    /// How opposing direction keys are cleaned, cycled with `KeyCode::CycleSocd`.
+    pub fn socd_mode(&self) -> SocdMode {
+        self.socd.mode()
+    }
+
+    /// Restore the SOCD mode, such as from the saved settings.
+    pub fn set_socd_mode(&mut self, mode: SocdMode) {
+        self.socd.set_mode(mode);
+    }
+
+    /// The backlight settings, changed with the backlight keys.
+    pub fn rgb_settings(&self) -> RgbSettings {
+        self.rgb_settings
+    }
+
+    /// Restore the backlight settings, such as from the saved settings.
+    pub fn set_rgb_settings(&mut self, settings: RgbSettings) {
+        self.rgb_settings = settings;
+    }
+
+    /// How analog keys actuate, changed with the actuation and rapid trigger keys.
+    pub fn analog_settings(&self) -> AnalogSettings {
+        self.analog_settings
+    }
+
+    /// Restore how analog keys actuate, such as from the saved settings.
+    pub fn set_analog_settings(&mut self, settings: AnalogSettings) {
+        self.analog_settings = settings;
+    }
+
+    /// Whether Auto Shift is on, toggled with `KeyCode::ToggleAutoShift`.
+    pub fn auto_shift(&self) -> bool {
+        self.auto_shift.is_enabled()
+    }
+
+    /// Restore whether Auto Shift is on, such as from the saved settings.
+    pub fn set_auto_shift(&mut self, enabled: bool) {
+        self.auto_shift.set_enabled(enabled);
+    }
+
+    /// How the host takes Unicode input, cycled with `KeyCode::CycleUnicodeMode`.
+    pub fn unicode_mode(&self) -> UnicodeMode {
+        self.unicode_mode
+    }
+
+    /// Restore the Unicode input mode, such as from the saved settings.
+    pub fn set_unicode_mode(&mut self, mode: UnicodeMode) {
+        self.unicode_mode = mode;
+    }
+
+    /// Whether game mode is on, toggled with `KeyCode::ToggleGameMode`. It masks the GUI keys
+    /// like the gaming profile does, whichever profile is in use, and the main loop sends
+    /// the N-key rollover report, keeps scanning at full speed while idle, and lights the
+    /// indicator LED while it's on.
+    pub fn game_mode(&self) -> bool {
+        self.game_mode
+    }
+
+    /// Restore game mode, such as from the saved settings.
+    pub fn set_game_mode(&mut self, enabled: bool) {
+        self.game_mode = enabled;
+    }
+
+    /// The modifier swaps, toggled with keys like `KeyCode::ToggleAltGuiSwap`.
+    pub fn mod_swaps(&self) -> ModSwaps {
+        self.mod_swaps
+    }
+
+    /// Restore the modifier swaps, such as from the saved settings.
+    pub fn set_mod_swaps(&mut self, mod_swaps: ModSwaps) {
+        self.mod_swaps = mod_swaps;
+    }
+
+    /// Whether the keys are changing settings rather than being typed, see `settings_mode`.
+    /// Changes made meanwhile aren't final until it's left, so aren't worth saving yet.
+    pub fn in_settings_mode(&self) -> bool {
+        self.settings_mode.is_active()
+    }
+
+    /// Whether the indicator LED should be lit for settings mode, or `None` outside it.
+    pub fn settings_mode_led_lit(&self) -> Option<bool> {
+        self.settings_mode.led_lit()
+    }
+
+    /// The modifiers locked by double tapping their keys, as a report bitmask, to show on an
+    /// indicator.
+    pub fn locked_modifiers(&self) -> u8 {
+        self.locking_mods.locked()
+    }
+
+    /// Switch to another profile, such as the one saved for a different output.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+    }
+
+    /// Whether `KeyCode::SwitchOutput` was pressed since the last call.
+    pub fn take_output_switch_request(&mut self) -> bool {
+        core::mem::take(&mut self.output_switch_requested)
+    }
+
+    /// Whether `KeyCode::SnoozeBreak` was pressed since the last call.
+    pub fn take_break_snooze_request(&mut self) -> bool {
+        core::mem::take(&mut self.break_snooze_requested)
+    }
+
+    /// Whether `KeyCode::ToggleNkro` was pressed since the last call.
+    pub fn take_nkro_toggle_request(&mut self) -> bool {
+        core::mem::take(&mut self.nkro_toggle_requested)
+    }
+
+    /// Whether `KeyCode::Bootloader` was pressed since the last call.
+    pub fn take_bootloader_request(&mut self) -> bool {
+        core::mem::take(&mut self.bootloader_requested)
+    }
+
+    /// The N-key rollover version of the last report, which has every key that was pressed
+    /// even when there were more than six.
+    pub fn nkro_report(&self) -> NkroReport {
+        self.nkro_report
+    }
+
+    /// The Consumer Control usage of the media key held in the last report, or zero when
+    /// there isn't one. When several are held, the first in the matrix wins.
+    pub fn consumer_usage(&self) -> u16 {
+        self.consumer_usage
+    }
+
+    /// The System Control usage of the system key held in the last report, or zero when
+    /// there isn't one, picked like `consumer_usage`.
+    pub fn system_usage(&self) -> u8 {
+        self.system_usage
+    }
+
+    /// What the mouse keys have done since the last call, and the mouse buttons they hold.
+    pub fn take_mouse_motion(&mut self) -> MouseMotion {
+        let motion = self.mouse_motion;
+        self.mouse_motion = MouseMotion { buttons: motion.buttons, ..MouseMotion::default() };
+        motion
+    }
+
+    /// Whether `KeyCode::CalibrateAnalog` was pressed since the last call.
+    pub fn take_calibration_request(&mut self) -> bool {
+        core::mem::take(&mut self.calibration_requested)
+    }
+
+    /// Whether `KeyCode::SaveScanTrace` was pressed since the last call.
+    pub fn take_trace_save_request(&mut self) -> bool {
+        core::mem::take(&mut self.trace_save_requested)
+    }
+
+    /// Whether `KeyCode::ReplayScanTrace` was pressed since the last call.
+    pub fn take_trace_replay_request(&mut self) -> bool {
+        core::mem::take(&mut self.trace_replay_requested)
+    }
+
+    /// Whether `KeyCode::UsbStressTest` was pressed since the last call.
+    pub fn take_stress_test_request(&mut self) -> bool {
+        core::mem::take(&mut self.stress_test_requested)
+    }
+
+    /// Convert a scan into a keyboard report, updating any stateful key behaviors and
+    /// moving their timers on by one scan tick.
+    pub fn report(&mut self, scan: &KeyScan<NUM_ROWS, NUM_COLS>) -> KeyboardReport {
+        self.report_after(scan, 1)
+    }
+
+    /// Convert another matrix into a report in the same scan tick as the last, such as one
+    /// from a backlog of key events. The timers, such as the tapping term, stay where they
+    /// are, so however many reports a scan builds they run for as long.
+    pub fn report_within_tick(&mut self, scan: &KeyScan<NUM_ROWS, NUM_COLS>) -> KeyboardReport {
+        self.report_after(scan, 0)
+    }
+
+    fn report_after(
+        &mut self,
+        scan: &KeyScan<NUM_ROWS, NUM_COLS>,
+        elapsed_ticks: u32,
+    ) -> KeyboardReport {
+        if self.settings_mode.is_active() {
+            return self.settings_mode_report(scan, elapsed_ticks);
+        }
+
+        // Playback, a one-shot layer's timeout and mouse keys don't wait for keys held back
+        // below, which only hold back what's typed.
+        self.layers.tick(elapsed_ticks);
+        for _ in 0..elapsed_ticks {
+            self.macro_player.tick(&self.macros);
+            self.unicode_player.tick();
+            for key in self.held_keys.iter().flatten() {
+                self.mouse_keys.hold(*key);
+            }
+            self.mouse_motion.add(self.mouse_keys.tick());
+        }
+
+        let mut keycodes = [0u8; 6];
+        let mut keycode_index = 0;
+        let mut modifier = 0;
+        let mut nkro_report = NkroReport::default();
+        let mut consumer_usage = 0;
+        let mut system_usage = 0;
+
+        let mut push_keycode = |key| {
+            if keycode_index < keycodes.len() {
+                keycodes[keycode_index] = key;
+                keycode_index += 1;
+            }
+        };
+
+        let Some(scan) = self.combos.update(scan, elapsed_ticks) else {
+            // Keys which could be part of a combo wait to see if the rest are pressed.
+            return self.join_played(self.typed);
+        };
+        let Some(scan) = self.tap_hold.update(&scan, elapsed_ticks) else {
+            // A tap-hold key is undecided, so the keys pressed since wait for it.
+            return self.join_played(self.typed);
+        };
+        let Some(scan) = self.tap_dance.update(&scan, elapsed_ticks) else {
+            // Likewise while a tap-dance key is still counting taps.
+            return self.join_played(self.typed);
+        };
+        for ((col, row), key) in
+            self.combos.keys().chain(self.tap_hold.keys()).chain(self.tap_dance.keys())
+        {
+            self.layers.set_override(col, row, key);
+        }
+
+        // Num Word's terminating key is resolved below the num layer, as it switches off.
+        self.layers.update(&scan);
+        self.num_word.update(&scan, &self.layers.mapping());
+        self.layers.set_locked(key_mapping::NUM_LAYER, self.num_word.is_active());
+
+        let mut layer_mapping = self.layers.mapping();
+        if self.layers.active_layer() == 0 {
+            key_mapping::apply_led_bindings(
+                &mut layer_mapping,
+                self.host_leds,
+                key_mapping::LED_BINDINGS,
+            );
+        }
+        for ((col, row), key) in self.expansion_module.map_or(&[][..], Module::keys) {
+            if layer_mapping[*col][*row] == KeyCode::Empty {
+                layer_mapping[*col][*row] = *key;
+            }
+        }
+
+        let Some(scan) = self.auto_shift.update(&scan, &layer_mapping, elapsed_ticks) else {
+            // Likewise while a key waits to see whether it's held long enough to shift.
+            return self.join_played(self.typed);
+        };
+
+        // Firmware keys take effect once, at the moment they are pressed.
+        for col in 0..NUM_COLS {
+            for row in 0..NUM_ROWS {
+                if !scan[col][row] || self.previous_matrix[col][row] {
+                    continue;
+                }
+
+                match layer_mapping[col][row] {
+                    KeyCode::ToggleProfile => self.profile = self.profile.toggled(),
+                    KeyCode::CalibrateAnalog => self.calibration_requested = true,
+                    KeyCode::SaveScanTrace => self.trace_save_requested = true,
+                    KeyCode::ReplayScanTrace => self.trace_replay_requested = true,
+                    KeyCode::UsbStressTest => self.stress_test_requested = true,
+                    KeyCode::ToggleConfigLock => self.config_locked = !self.config_locked,
+                    KeyCode::ToggleGameMode => self.game_mode = !self.game_mode,
+                    KeyCode::SwitchOutput => self.output_switch_requested = true,
+                    KeyCode::SnoozeBreak => self.break_snooze_requested = true,
+                    KeyCode::ToggleNkro => self.nkro_toggle_requested = true,
+                    KeyCode::Bootloader => self.bootloader_requested = true,
+                    KeyCode::CycleSocd => self.socd.set_mode(self.socd.mode().next()),
+                    KeyCode::RgbToggle => self.rgb_settings.enabled = !self.rgb_settings.enabled,
+                    KeyCode::RgbBrightnessUp => self.rgb_settings = self.rgb_settings.brighter(),
+                    KeyCode::RgbBrightnessDown => self.rgb_settings = self.rgb_settings.dimmer(),
+                    KeyCode::RgbNextAnimation => {
+                        self.rgb_settings.animation = self.rgb_settings.animation.next()
+                    },
+                    KeyCode::ActuationDeeper => {
+                        self.analog_settings = self.analog_settings.deeper()
+                    },
+                    KeyCode::ActuationShallower => {
+                        self.analog_settings = self.analog_settings.shallower()
+                    },
+                    KeyCode::ToggleRapidTrigger => {
+                        self.analog_settings = self.analog_settings.toggle_rapid_trigger()
+                    },
+                    KeyCode::ToggleAutoShift => {
+                        self.auto_shift.set_enabled(!self.auto_shift.is_enabled())
+                    },
+                    KeyCode::CycleUnicodeMode => self.unicode_mode = self.unicode_mode.next(),
+                    KeyCode::ToggleAltGuiSwap => {
+                        self.mod_swaps.alt_gui_swapped = !self.mod_swaps.alt_gui_swapped
+                    },
+                    KeyCode::ToggleCapsAsCtrl => {
+                        self.mod_swaps.caps_as_ctrl = !self.mod_swaps.caps_as_ctrl
+                    },
+                    KeyCode::ToggleGuiDisabled => {
+                        self.mod_swaps.gui_disabled = !self.mod_swaps.gui_disabled
+                    },
+                    KeyCode::SettingsMode => self.settings_mode.enter(self.mode_settings(), &scan),
+                    KeyCode::RecordMacro if self.macro_recorder.is_recording() => {
+                        self.stop_macro_recording()
+                    },
+                    KeyCode::RecordMacro => self.macro_recorder.start(&self.nkro_report),
+                    KeyCode::StopMacroRecording => self.stop_macro_recording(),
+                    // Playing the macro into its own recording would only repeat it.
+                    KeyCode::PlayRecordedMacro if !self.macro_recorder.is_recording() => {
+                        self.macro_player.start(&self.macros, RECORDED_MACRO_INDEX)
+                    },
+                    key => {
+                        if let Some(index) = key.macro_index() {
+                            self.macro_player.start(&self.macros, index);
+                        }
+                        let character = key
+                            .unicode_index()
+                            .and_then(|index| key_mapping::UNICODE_CHARS.get(index));
+                        if let Some(character) = character {
+                            self.unicode_player.start(*character, self.unicode_mode);
+                        }
+                    },
+                }
+            }
+        }
+        self.previous_matrix = scan;
+
+        let gui_locked = self.profile.settings().gui_locked || self.game_mode;
+
+        self.socd.update(|key| {
+            scan.iter().zip(layer_mapping).any(|(matrix_column, mapping_column)| {
+                matrix_column
+                    .iter()
+                    .zip(mapping_column)
+                    .any(|(pressed, mapped)| *pressed && mapped == key)
+            })
+        });
+
+        // Second scan to generate the correct keycodes given the activated key map
+        let mut held_keys = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+        for ((matrix_column, mapping_column), held_column) in
+            scan.iter().zip(layer_mapping).zip(&mut held_keys)
+        {
+            for ((key_pressed, mapping_row), held) in
+                matrix_column.iter().zip(mapping_column).zip(held_column)
+            {
+                if *key_pressed {
+                    let mapping_row = self.mod_swaps.remap(mapping_row);
+                    *held = mapping_row;
+
+                    if gui_locked && matches!(mapping_row, KeyCode::LeftCmd | KeyCode::RightCmd) {
+                        continue;
+                    }
+
+                    if self.socd.suppresses(mapping_row) {
+                        continue;
+                    }
+
+                    if let Some(bitmask) = mapping_row.modifier_bitmask() {
+                        modifier |= bitmask;
+                    } else if let Some(usage) = mapping_row.consumer_usage() {
+                        if consumer_usage == 0 {
+                            consumer_usage = usage;
+                        }
+                    } else if let Some(usage) = mapping_row.system_usage() {
+                        if system_usage == 0 {
+                            system_usage = usage;
+                        }
+                    } else if !mapping_row.is_firmware_key() && mapping_row != KeyCode::Empty {
+                        // Keys with nothing mapped on this layer shouldn't take up one of
+                        // the six keycode slots.
+                        push_keycode(mapping_row as u8);
+                        nkro_report.press(mapping_row as u8);
+                    }
+                }
+            }
+        }
+
+        // One-shot modifiers join the key they were tapped before.
+        let mut one_shot_modifiers =
+            self.one_shot_mods.update(&scan, &layer_mapping, elapsed_ticks);
+        if gui_locked {
+            let gui = KeyCode::LeftCmd.modifier_bitmask().unwrap_or(0);
+            one_shot_modifiers &= !gui;
+        }
+        modifier |= one_shot_modifiers | self.auto_shift.modifiers();
+
+        // Locked modifiers stay held until their key is tapped again.
+        if self.profile.settings().locking_mods {
+            modifier |= self.locking_mods.update(&scan, &layer_mapping, elapsed_ticks);
+        } else {
+            self.locking_mods = LockingMods::default();
+        }
+
+        modifier = self.mod_swaps.apply(modifier);
+
+        // Conditional keys go by the modifiers held with them, so come once those are known.
+        self.conditional_keys.update(&scan, &layer_mapping, modifier, |key| {
+            push_keycode(key as u8);
+            nkro_report.press(key as u8);
+        });
+
+        let repeat_held = scan
+            .iter()
+            .zip(layer_mapping)
+            .flat_map(|(matrix_column, mapping_column)| matrix_column.iter().zip(mapping_column))
+            .any(|(pressed, mapped)| *pressed && mapped == KeyCode::Repeat);
+        if let Some(usage) = self.repeat_key.update(repeat_held) {
+            if !nkro_report.is_pressed(usage) {
+                push_keycode(usage);
+                nkro_report.press(usage);
+            }
+        }
+
+        // Recorded before the macro's keys join in, so only what was typed is recorded.
+        nkro_report.modifier = modifier;
+        self.macro_recorder.record(&nkro_report);
+
+        self.held_keys = held_keys;
+        self.consumer_usage = consumer_usage;
+        self.system_usage = system_usage;
+        self.typed = TypedKeys { keycodes, len: keycode_index, report: nkro_report };
+        self.join_played(self.typed)
+    }
+
+    /// Finish a report from the keys typed, joining in the keys held by a macro or Unicode
+    /// character playing. A macro plays alongside the keys held, so holding Shift still
+    /// shifts what it types.
+    fn join_played(&mut self, typed: TypedKeys) -> KeyboardReport {
+        let TypedKeys { mut keycodes, mut len, mut report } = typed;
+
+        // A Unicode character's sequence would be changed by the modifiers held, so they're
+        // left out while it's typed.
+        if self.unicode_player.is_playing() {
+            report.modifier = 0;
+        }
+
+        for played_keys in [self.macro_player.held(), self.unicode_player.held()] {
+            report.modifier |= played_keys.modifier;
+            for usage in (0..NKRO_KEYS as u8).filter(|usage| played_keys.is_pressed(*usage)) {
+                if !report.is_pressed(usage) {
+                    if len < keycodes.len() {
+                        keycodes[len] = usage;
+                        len += 1;
+                    }
+                    report.press(usage);
+                }
+            }
+        }
+
+        self.repeat_key.record(&self.nkro_report, &report);
+        self.nkro_report = report;
+        self.last_report =
+            KeyboardReport { modifier: report.modifier, reserved: 0, leds: 0, keycodes };
+        self.last_report
+    }
+
+    /// Change settings with the keys pressed, while in settings mode, sending nothing.
+    fn settings_mode_report(
+        &mut self,
+        scan: &KeyScan<NUM_ROWS, NUM_COLS>,
+        elapsed_ticks: u32,
+    ) -> KeyboardReport {
+        let layers = &self.layers;
+        let action =
+            self.settings_mode.update(scan, |col, row| layers.base_key(col, row), elapsed_ticks);
+        match action {
+            Some(Action::Adjust(adjustment)) => {
+                let mut settings = self.mode_settings();
+                settings.adjust(adjustment);
+                self.set_mode_settings(settings);
+            },
+            Some(Action::Cancel) => {
+                if let Some(settings) = self.settings_mode.entered_with() {
+                    self.set_mode_settings(settings);
+                }
+            },
+            Some(Action::Save) | None => {},
+        }
+
+        self.previous_matrix = **scan;
+        self.held_keys = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+        self.typed = TypedKeys::default();
+        self.nkro_report = NkroReport::default();
+        self.consumer_usage = 0;
+        self.system_usage = 0;
+        self.mouse_motion = MouseMotion::default();
+        self.last_report = KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes: [0; 6] };
+        self.last_report
+    }
+
+    fn mode_settings(&self) -> ModeSettings {
+        ModeSettings {
+            profile: self.profile,
+            rgb: self.rgb_settings,
+            socd_mode: self.socd.mode(),
+            auto_shift: self.auto_shift.is_enabled(),
+            unicode_mode: self.unicode_mode,
+            default_layer: self.layers.default_layer(),
+        }
+    }
+
+    fn set_mode_settings(&mut self, settings: ModeSettings) {
+        self.profile = settings.profile;
+        self.rgb_settings = settings.rgb;
+        self.socd.set_mode(settings.socd_mode);
+        self.auto_shift.set_enabled(settings.auto_shift);
+        self.unicode_mode = settings.unicode_mode;
+        self.layers.set_default_layer(settings.default_layer);
+    }
+
+    fn stop_macro_recording(&mut self) {
+        if !self.macro_recorder.is_recording() {
+            return;
+        }
+
+        let steps = self.macro_recorder.stop();
+        self.macro_recorded = self.macros.set_macro(RECORDED_MACRO_INDEX, steps);
+    }
+}
@@ -0,0 +1,173 @@
+//! Keymaps edited at runtime, persisted to the keymap flash partition in place of the
+//! compiled-in `key_mapping::LAYERS`.
+//!
+//! A keymap is saved every time a key is remapped, far more often than the other settings,
+//! so rather than erasing the same sector for every save, each one goes into the next free
+//! slot of the partition along with a sequence number. Loading picks the valid slot with
+//! the highest sequence number, and a sector is only erased once the slots before it are
+//! used up, spreading the wear over the whole partition. A slot which fails its CRC check,
+//! like one cut short by unplugging the keyboard, is skipped in favor of the one saved
+//! before it, or the compiled-in keymap if there is none.
+
+use crate::{
+    config_block::crc32,
+    flash::{self, Partition, WriteError},
+    key_codes::KeyCode,
+    key_mapping::{self, NUM_LAYERS},
+    NUM_COLS, NUM_ROWS,
+};
+
+/// Identifies a slot holding a keymap.
+const MAGIC: [u8; 4] = *b"KRKM";
+
+/// The current version of the slot format. Version 1 stored keys in a byte each, which
+/// stopped being enough once `KeyCode` went past `0xFF`, so those slots are ignored.
+const VERSION: u16 = 2;
+
+/// The size of the slot header: magic, version, length, sequence number, and CRC.
+const HEADER_SIZE: usize = 16;
+
+/// The size of a serialized key.
+const KEY_SIZE: usize = 2;
+
+/// The size of a serialized keymap, every key on each layer.
+const KEYMAP_SIZE: usize = NUM_LAYERS * NUM_COLS * NUM_ROWS * KEY_SIZE;
+
+/// The space each saved keymap takes up in the partition, a whole number of pages which
+/// divides evenly into sectors.
+pub const SLOT_SIZE: usize = {
+    let size = (HEADER_SIZE + KEYMAP_SIZE).next_power_of_two();
+    if size < flash::PAGE_SIZE {
+        flash::PAGE_SIZE
+    } else {
+        size
+    }
+};
+
+/// How many keymaps can be saved before a sector has to be erased.
+pub const SLOTS_PER_SECTOR: usize = flash::SECTOR_SIZE / SLOT_SIZE;
+
+const _: () = assert!(flash::SECTOR_SIZE.is_multiple_of(SLOT_SIZE));
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Keymap {
+    layers: [[[KeyCode; NUM_ROWS]; NUM_COLS]; NUM_LAYERS],
+}
+
+impl Default for Keymap {
+    /// The compiled-in keymap of the selected layout.
+    fn default() -> Self {
+        Self { layers: key_mapping::LAYERS }
+    }
+}
+
+impl Keymap {
+    pub fn layers(&self) -> &[[[KeyCode; NUM_ROWS]; NUM_COLS]; NUM_LAYERS] {
+        &self.layers
+    }
+
+    pub fn key(&self, layer: usize, col: usize, row: usize) -> KeyCode {
+        self.layers[layer][col][row]
+    }
+
+    pub fn set_key(&mut self, layer: usize, col: usize, row: usize, key: KeyCode) {
+        self.layers[layer][col][row] = key;
+    }
+
+    /// The keymap as a slot, saved with `sequence`. A partition erased apart from this in
+    /// its first slot loads it, which is how keymaps are flashed without the firmware, see
+    /// the CLI's `settings-uf2`.
+    pub fn encode(&self, sequence: u32) -> [u8; SLOT_SIZE] {
+        let mut buffer = [0xFFu8; SLOT_SIZE];
+        buffer[0..4].copy_from_slice(&MAGIC);
+        buffer[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        buffer[6..8].copy_from_slice(&(KEYMAP_SIZE as u16).to_le_bytes());
+        buffer[8..12].copy_from_slice(&sequence.to_le_bytes());
+        for (chunk, key) in buffer[HEADER_SIZE..]
+            .chunks_exact_mut(KEY_SIZE)
+            .zip(self.layers.iter().flatten().flatten())
+        {
+            chunk.copy_from_slice(&(*key as u16).to_le_bytes());
+        }
+        let crc = crc32(&[&buffer[4..12], &buffer[HEADER_SIZE..HEADER_SIZE + KEYMAP_SIZE]]);
+        buffer[12..16].copy_from_slice(&crc.to_le_bytes());
+        buffer
+    }
+
+    /// Load the most recently saved keymap, if a valid one has been saved.
+    pub fn load() -> Option<Self> {
+        latest_slot().map(|(_, _, keymap)| keymap)
+    }
+
+    /// Save the keymap to the next free slot. Like `ConfigBlock::save`, this blocks with
+    /// interrupts disabled while the flash is written, which takes tens of milliseconds
+    /// whenever a sector has to be erased.
+    pub fn save(&self) -> Result<(), WriteError> {
+        let num_slots = Partition::Keymap.size() / SLOT_SIZE;
+        let (mut slot, sequence) = match latest_slot() {
+            Some((slot, sequence, _)) => ((slot + 1) % num_slots, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+
+        // Flash can only be programmed once between erases, so a slot which isn't erased
+        // (which only happens if something else wrote to the partition) means starting
+        // over in the next sector.
+        let erased = Partition::Keymap.read(slot * SLOT_SIZE, SLOT_SIZE).iter().all(|b| *b == 0xFF);
+        if !erased {
+            slot = slot.next_multiple_of(SLOTS_PER_SECTOR) % num_slots;
+        }
+
+        let buffer = self.encode(sequence);
+        let offset = slot * SLOT_SIZE;
+        if slot.is_multiple_of(SLOTS_PER_SECTOR) || !erased {
+            Partition::Keymap.write(offset, &buffer)
+        } else {
+            Partition::Keymap.program(offset, &buffer)
+        }
+    }
+
+    /// Erase every saved keymap, going back to the compiled-in one from the next boot.
+    pub fn reset() -> Result<(), WriteError> {
+        // A local, so it's in RAM rather than promoted to a constant in flash.
+        let erased = [0xFFu8; flash::PAGE_SIZE];
+        for offset in (0..Partition::Keymap.size()).step_by(flash::SECTOR_SIZE) {
+            Partition::Keymap.write(offset, &erased)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The valid slot with the highest sequence number, with its sequence number and keymap.
+fn latest_slot() -> Option<(usize, u32, Keymap)> {
+    (0..Partition::Keymap.size() / SLOT_SIZE)
+        .filter_map(|slot| read_slot(slot).map(|(sequence, keymap)| (slot, sequence, keymap)))
+        .max_by_key(|(_, sequence, _)| *sequence)
+}
+
+fn read_slot(slot: usize) -> Option<(u32, Keymap)> {
+    let data = Partition::Keymap.read(slot * SLOT_SIZE, SLOT_SIZE);
+    let (header, payload) = data.split_at(HEADER_SIZE);
+
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    let len = u16::from_le_bytes([header[6], header[7]]) as usize;
+    if header[0..4] != MAGIC || version != VERSION || len != KEYMAP_SIZE {
+        return None;
+    }
+
+    let sequence = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    let payload = &payload[..len];
+    if crc32(&[&header[4..12], payload]) != crc {
+        return None;
+    }
+
+    let mut keymap = Keymap::default();
+    for (key, chunk) in
+        keymap.layers.iter_mut().flatten().flatten().zip(payload.chunks_exact(KEY_SIZE))
+    {
+        *key = KeyCode::from_u16(u16::from_le_bytes([chunk[0], chunk[1]]))?;
+    }
+
+    Some((sequence, keymap))
+}
@@ -0,0 +1,103 @@
+//! Switching the keyboard between two computers through a KVM switch or a USB mux, with
+//! `KeyCode::SwitchOutput`.
+//!
+//! Switching types the KVM's hotkey for the new output, and with the `kvm-mux` feature also
+//! drives a GPIO to select the output of an external USB mux. Each output remembers its own
+//! profile, so a gaming machine can keep the gaming profile while a work machine uses the
+//! typing one.
+
+use defmt::Format;
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::key_codes::KeyCode;
+
+/// The number of outputs the keyboard switches between.
+pub const NUM_OUTPUTS: usize = 2;
+
+/// How many scans each key of the hotkey is held, and then released, for. KVMs tend to
+/// miss keys which are tapped any faster.
+pub const HOTKEY_HOLD_TICKS: u8 = 20;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Output {
+    Primary,
+    Secondary,
+}
+
+impl Output {
+    pub fn toggled(self) -> Self {
+        match self {
+            Output::Primary => Output::Secondary,
+            Output::Secondary => Output::Primary,
+        }
+    }
+
+    /// The index of the output, for looking up its settings.
+    pub fn index(self) -> usize {
+        match self {
+            Output::Primary => 0,
+            Output::Secondary => 1,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self.index() as u8
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Output::Primary),
+            1 => Some(Output::Secondary),
+            _ => None,
+        }
+    }
+
+    /// The keys a KVM switch needs to see to switch to this output. This is the common
+    /// "Scroll Lock, Scroll Lock, number" hotkey, change it to match the KVM.
+    pub fn hotkey(self) -> [KeyCode; 3] {
+        match self {
+            Output::Primary => [KeyCode::ScrollLock, KeyCode::ScrollLock, KeyCode::Num1],
+            Output::Secondary => [KeyCode::ScrollLock, KeyCode::ScrollLock, KeyCode::Num2],
+        }
+    }
+}
+
+/// Types a KVM hotkey one key at a time, replacing the keyboard's own reports while it
+/// does.
+#[derive(Default)]
+pub struct HotkeyPlayer {
+    /// The output whose hotkey is being typed, and the number of scans since it started.
+    playing: Option<(Output, usize)>,
+}
+
+impl HotkeyPlayer {
+    /// Start typing the hotkey for `output`, abandoning any hotkey still being typed.
+    pub fn start(&mut self, output: Output) {
+        self.playing = Some((output, 0));
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    /// The report to send for this scan in place of the keyboard's own, or `None` once the
+    /// hotkey has been typed.
+    pub fn next_report(&mut self) -> Option<KeyboardReport> {
+        let (output, tick) = self.playing?;
+        let hold_ticks = HOTKEY_HOLD_TICKS as usize;
+
+        // Each key is pressed for `HOTKEY_HOLD_TICKS`, then released for as long.
+        let Some(key) = output.hotkey().get(tick / (hold_ticks * 2)).copied() else {
+            self.playing = None;
+            return None;
+        };
+        self.playing = Some((output, tick + 1));
+
+        let mut keycodes = [0u8; 6];
+        if tick % (hold_ticks * 2) < hold_ticks {
+            keycodes[0] = key as u8;
+        }
+
+        Some(KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes })
+    }
+}
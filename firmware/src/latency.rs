@@ -0,0 +1,112 @@
+//! Measuring how long a key press takes to reach the host, from the scan which first saw the
+//! switch close to the USB interrupt handing the report carrying it to the endpoint, with
+//! the RP2040's microsecond timer.
+//!
+//! `PressTimer` notes when the raw matrix first shows a new press, before debouncing, and
+//! hands the time on once the keys in the report gain one, so the debounce time, tap-hold
+//! decisions and anything else in between all count. The main loop queues it with the report
+//! as an `InFlightPress`, and the USB interrupt records it in `LatencyStats` once that report
+//! has been sent. Only one press is timed at a time, so a chord counts as its first key.
+//! A press which never changes the report, like `Fn`, is forgotten once every key is let go.
+//!
+//! Reports sent over Bluetooth or the radio aren't measured, and the time the host takes to
+//! poll the endpoint afterwards is out of the keyboard's sight.
+
+use defmt::Format;
+
+use crate::{nkro::NkroReport, NUM_COLS, NUM_ROWS};
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+/// The latencies recorded since the keyboard started, or the stats were last reset.
+#[derive(Copy, Clone, Debug, Default, Format, PartialEq)]
+pub struct LatencyStats {
+    pub samples: u32,
+    pub min_us: u32,
+    pub max_us: u32,
+    pub total_us: u64,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency_us: u32) {
+        self.min_us = if self.samples == 0 { latency_us } else { self.min_us.min(latency_us) };
+        self.max_us = self.max_us.max(latency_us);
+        self.total_us += u64::from(latency_us);
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    pub fn average_us(&self) -> u32 {
+        self.total_us.checked_div(u64::from(self.samples)).unwrap_or(0) as u32
+    }
+
+    /// The number of samples, then the minimum, average and maximum in microseconds, each
+    /// four bytes little endian.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&self.samples.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.min_us.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.average_us().to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.max_us.to_le_bytes());
+        bytes
+    }
+}
+
+/// A press on its way to the host, in the report queued with `report_index`.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub struct InFlightPress {
+    /// The timer's count, in microseconds, when the press was scanned. It wraps every 71
+    /// minutes, which is fine for anything shorter.
+    pub pressed_at_us: u32,
+
+    /// The number of reports pushed to the queue before this one.
+    pub report_index: usize,
+}
+
+impl InFlightPress {
+    /// Whether the report carrying the press is out of the queue, given the number of
+    /// reports ever popped from it.
+    pub fn is_sent(&self, popped: usize) -> bool {
+        (popped.wrapping_sub(self.report_index) as isize) > 0
+    }
+}
+
+#[derive(Default)]
+pub struct PressTimer {
+    /// When the press being timed was scanned, until it reaches a report.
+    pressed_at_us: Option<u32>,
+    previous_matrix: Matrix,
+    previous_keys: NkroReport,
+}
+
+impl PressTimer {
+    /// Take a raw scan made at `now_us`, starting the timer on a new press if it isn't
+    /// already running.
+    pub fn scan(&mut self, now_us: u32, raw_matrix: &Matrix) {
+        let previous_matrix = core::mem::replace(&mut self.previous_matrix, *raw_matrix);
+        let newly_pressed = raw_matrix
+            .iter()
+            .flatten()
+            .zip(previous_matrix.iter().flatten())
+            .any(|(pressed, was_pressed)| *pressed && !was_pressed);
+        if newly_pressed && self.pressed_at_us.is_none() {
+            self.pressed_at_us = Some(now_us);
+        }
+    }
+
+    /// Take the keys the keyboard is reporting, returning when the press being timed was
+    /// scanned if they've gained one since the last call.
+    pub fn report(&mut self, keys: &NkroReport) -> Option<u32> {
+        let previous_keys = core::mem::replace(&mut self.previous_keys, *keys);
+        let gained_modifier = keys.modifier & !previous_keys.modifier != 0;
+        let gained_key =
+            keys.keys.iter().zip(previous_keys.keys).any(|(byte, previous)| byte & !previous != 0);
+        if gained_modifier || gained_key {
+            return self.pressed_at_us.take();
+        }
+
+        if !self.previous_matrix.iter().flatten().any(|pressed| *pressed) {
+            self.pressed_at_us = None;
+        }
+        None
+    }
+}
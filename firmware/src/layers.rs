@@ -0,0 +1,284 @@
+//! A stack of keymap layers, each activated by layer keys held, toggled, or tapped for the
+//! next key only.
+//!
+//! Layer 0 is the base layer and is always active. A key resolves on the highest active
+//! layer which doesn't map it to `KeyCode::Transparent`, so a layer only needs to define the
+//! keys it changes.
+//!
+//! The default layer is the one used as the base layer, layer 0 unless it's changed, such as
+//! to an alternative like Colemak. It takes layer 0's place at the bottom of the stack, so
+//! the layers above it still apply on top of it.
+//!
+//! A key keeps what it resolved to when it was pressed until it's released, so releasing a
+//! layer key before the keys pressed on its layer still releases those keys, rather than
+//! whatever they'd resolve to on the layers below.
+
+use defmt::Format;
+
+use crate::{key_codes::KeyCode, one_shot::ONE_SHOT_TIMEOUT_TICKS, NUM_COLS, NUM_ROWS};
+
+/// What a layer key does to its layer, see `KeyCode::layer_action`.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum LayerAction {
+    /// The layer is active while the key is held.
+    Momentary(usize),
+
+    /// Each press switches the layer on or off.
+    Toggle(usize),
+
+    /// The layer is active for the next key pressed, until that key is released. Pressing
+    /// the key again before then, or waiting `ONE_SHOT_TIMEOUT_TICKS`, cancels it.
+    OneShot(usize),
+
+    /// The layer becomes the default layer, in place of layer 0.
+    Default(usize),
+}
+
+pub struct Layers<const N: usize> {
+    mappings: [[[KeyCode; NUM_ROWS]; NUM_COLS]; N],
+
+    /// The layer used as the base layer.
+    default: usize,
+
+    /// The layers switched on by a toggle key.
+    toggled: [bool; N],
+
+    /// The layers switched on from outside the stack, like the num layer by Num Word.
+    locked: [bool; N],
+
+    /// Keys which take the place of every layer's, like a tap-hold key which is held.
+    overrides: [[Option<KeyCode>; NUM_ROWS]; NUM_COLS],
+
+    /// The one-shot layer, and the position of the key which used it once one has.
+    one_shot: Option<(usize, Option<(usize, usize)>)>,
+
+    /// How long the one-shot layer has waited for a key.
+    one_shot_ticks: u32,
+
+    /// What each held key resolved to when it was pressed.
+    held: [[Option<KeyCode>; NUM_ROWS]; NUM_COLS],
+
+    /// The keys pressed in the last `update`, which are resolved again if the layers change
+    /// before the report is built.
+    newly_pressed: [[bool; NUM_ROWS]; NUM_COLS],
+
+    /// The layers active as of the last `update`.
+    active: [bool; N],
+
+    /// The matrix from the previous update, used to act on layer keys once per press.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl<const N: usize> Layers<N> {
+    pub fn new(mappings: [[[KeyCode; NUM_ROWS]; NUM_COLS]; N]) -> Self {
+        let mut active = [false; N];
+        active[0] = true;
+
+        Self {
+            mappings,
+            default: 0,
+            toggled: [false; N],
+            locked: [false; N],
+            overrides: [[None; NUM_ROWS]; NUM_COLS],
+            one_shot: None,
+            one_shot_ticks: 0,
+            held: [[None; NUM_ROWS]; NUM_COLS],
+            newly_pressed: [[false; NUM_ROWS]; NUM_COLS],
+            active,
+            previous_matrix: [[false; NUM_ROWS]; NUM_COLS],
+        }
+    }
+
+    /// Replace the keys on every layer, keeping the layers which are active.
+    pub fn set_mappings(&mut self, mappings: [[[KeyCode; NUM_ROWS]; NUM_COLS]; N]) {
+        self.mappings = mappings;
+    }
+
+    /// Keep a layer active (or not) regardless of its layer keys, until it's set again.
+    /// Unlocking a layer resolves the keys pressed in the last `update` again without it, so
+    /// the key which makes Num Word switch off the num layer isn't sent from that layer.
+    pub fn set_locked(&mut self, layer: usize, locked: bool) {
+        let unlocked = self.locked[layer] && !locked;
+        self.locked[layer] = locked;
+        let matrix = self.previous_matrix;
+        self.resolve_active(&matrix);
+
+        if unlocked {
+            for col in 0..NUM_COLS {
+                for row in 0..NUM_ROWS {
+                    let layer_key = self.held[col][row].is_some_and(is_momentary);
+                    if self.newly_pressed[col][row] && !layer_key {
+                        let key =
+                            self.overrides[col][row].unwrap_or_else(|| self.active_key(col, row));
+                        self.held[col][row] = Some(key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolve a position to `key` on every layer, or go back to the layers' own keys with
+    /// `None`. Takes effect from the next `update`.
+    pub fn set_override(&mut self, col: usize, row: usize, key: Option<KeyCode>) {
+        self.overrides[col][row] = key;
+    }
+
+    /// The layer used as the base layer, changed by `LayerAction::Default` keys.
+    pub fn default_layer(&self) -> usize {
+        self.default
+    }
+
+    /// Use `layer` as the base layer, such as the one saved in the settings. A layer which
+    /// doesn't exist leaves it as it was.
+    pub fn set_default_layer(&mut self, layer: usize) {
+        if layer < N {
+            self.default = layer;
+        }
+    }
+
+    /// The highest active layer.
+    pub fn active_layer(&self) -> usize {
+        self.active.iter().rposition(|active| *active).unwrap_or(0)
+    }
+
+    /// Move a one-shot layer's timeout on by `elapsed_ticks` scan ticks. This runs even
+    /// while the keys wait on an undecided key, so it's separate from `update`.
+    pub fn tick(&mut self, elapsed_ticks: u32) {
+        if let Some((_, None)) = self.one_shot {
+            self.one_shot_ticks += elapsed_ticks;
+            if self.one_shot_ticks >= ONE_SHOT_TIMEOUT_TICKS {
+                self.one_shot = None;
+            }
+        }
+    }
+
+    /// Update the active layers from a debounced scan.
+    pub fn update(&mut self, matrix: &[[bool; NUM_ROWS]; NUM_COLS]) {
+        for (held_column, column) in self.held.iter_mut().zip(matrix) {
+            for (held, pressed) in held_column.iter_mut().zip(column) {
+                if !pressed {
+                    *held = None;
+                }
+            }
+        }
+
+        // A one-shot layer lasts until the key pressed on it is released, or until it times
+        // out waiting for one, see `tick`.
+        if let Some((_, Some((col, row)))) = self.one_shot {
+            if !matrix[col][row] {
+                self.one_shot = None;
+            }
+        }
+
+        // A momentary key is held as what it was on the layers active before it, not as
+        // whatever the layer it turns on has in its place.
+        for (col, (column, previous_column)) in matrix.iter().zip(self.previous_matrix).enumerate()
+        {
+            for (row, (pressed, was_pressed)) in column.iter().zip(previous_column).enumerate() {
+                let key = self.key(col, row);
+                if *pressed && !was_pressed && is_momentary(key) {
+                    self.held[col][row] = Some(key);
+                }
+            }
+        }
+
+        self.resolve_active(matrix);
+
+        for (col, (column, previous_column)) in matrix.iter().zip(self.previous_matrix).enumerate()
+        {
+            for (row, (pressed, was_pressed)) in column.iter().zip(previous_column).enumerate() {
+                self.newly_pressed[col][row] = *pressed && !was_pressed;
+                if !pressed || was_pressed {
+                    continue;
+                }
+
+                let key = self.key(col, row);
+                self.held[col][row] = Some(key);
+                match key.layer_action() {
+                    Some(LayerAction::Toggle(layer)) if layer < N => {
+                        self.toggled[layer] = !self.toggled[layer];
+                    },
+                    Some(LayerAction::Default(layer)) => self.set_default_layer(layer),
+                    Some(LayerAction::OneShot(layer)) if layer < N => {
+                        self.one_shot = if self.one_shot == Some((layer, None)) {
+                            None
+                        } else {
+                            Some((layer, None))
+                        };
+                        self.one_shot_ticks = 0;
+                    },
+                    // Modifiers are held alongside the next key, rather than being it.
+                    None if !key.is_modifier() => {
+                        if let Some((layer, None)) = self.one_shot {
+                            self.one_shot = Some((layer, Some((col, row))));
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        self.previous_matrix = *matrix;
+    }
+
+    /// The key at a position, resolved through the active layers, or what it resolved to
+    /// when it was pressed if it's held.
+    pub fn key(&self, col: usize, row: usize) -> KeyCode {
+        self.overrides[col][row]
+            .or(self.held[col][row])
+            .unwrap_or_else(|| self.active_key(col, row))
+    }
+
+    /// The key at a position on the layers active now, leaving out overrides and what it's
+    /// held as.
+    fn active_key(&self, col: usize, row: usize) -> KeyCode {
+        (0..N)
+            .rev()
+            .filter(|layer| self.active[*layer])
+            .map(|layer| self.mappings[if layer == 0 { self.default } else { layer }][col][row])
+            .find(|key| *key != KeyCode::Transparent)
+            .unwrap_or(KeyCode::Empty)
+    }
+
+    /// The key at a position on the default layer, whichever layers are active.
+    pub fn base_key(&self, col: usize, row: usize) -> KeyCode {
+        self.mappings[self.default][col][row]
+    }
+
+    /// Every key resolved through the active layers.
+    pub fn mapping(&self) -> [[KeyCode; NUM_ROWS]; NUM_COLS] {
+        core::array::from_fn(|col| core::array::from_fn(|row| self.key(col, row)))
+    }
+
+    fn resolve_active(&mut self, matrix: &[[bool; NUM_ROWS]; NUM_COLS]) {
+        for layer in 0..N {
+            self.active[layer] = layer == 0
+                || self.toggled[layer]
+                || self.locked[layer]
+                || self.one_shot.is_some_and(|(one_shot, _)| one_shot == layer);
+        }
+
+        // Momentary keys are looked up on the layers they activate as well, so a layer key
+        // on one layer can reach the next.
+        while let Some(layer) = self.held_momentary_layer(matrix) {
+            self.active[layer] = true;
+        }
+    }
+
+    /// An inactive layer with one of its momentary keys held, if there is one.
+    fn held_momentary_layer(&self, matrix: &[[bool; NUM_ROWS]; NUM_COLS]) -> Option<usize> {
+        (0..NUM_COLS)
+            .flat_map(|col| (0..NUM_ROWS).map(move |row| (col, row)))
+            .filter(|(col, row)| matrix[*col][*row])
+            .find_map(|(col, row)| match self.key(col, row).layer_action() {
+                Some(LayerAction::Momentary(layer)) if layer < N && !self.active[layer] => {
+                    Some(layer)
+                },
+                _ => None,
+            })
+    }
+}
+
+fn is_momentary(key: KeyCode) -> bool {
+    matches!(key.layer_action(), Some(LayerAction::Momentary(_)))
+}
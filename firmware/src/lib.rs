@@ -0,0 +1,143 @@
+//! The keyboard logic of the key ripper firmware: matrix scanning, debouncing, keymaps,
+//! and turning key presses into USB HID reports. The firmware binary in `main.rs` wires
+//! it up to the hardware, and the on-target tests exercise it on a real board.
+//!
+//! Other RP2040 boards can use the library with a binary of their own, rather than forking
+//! the firmware. The pieces fit together like this:
+//!
+//! - [`key_scan`] reads the switch matrix, either blocking or one line at a time, and
+//!   [`direct_pins`] the switches of boards with a pin for each, both through
+//!   [`key_scan::SwitchScanner`].
+//! - [`debounce`] filters the raw matrix, once per scan tick.
+//! - [`keyboard`] turns the debounced matrix into HID reports, through the keymap layers in
+//!   [`key_mapping`] and firmware keys like Num Word and profile switching.
+//! - [`hid_descriptor`] has the report descriptors to hand the USB HID class.
+//!
+//! `examples/minimal_board.rs` is the smallest binary that does all of that, a good place to
+//! start a new board from. Everything else (flash storage, expansion modules, the wireless
+//! links and so on) is optional, and is used the same way as in `main.rs`.
+//!
+//! The matrix size, its pins and the keymaps come from `board.toml`, with the keymaps
+//! chosen by the `layout-*` features, and the boot2 stage by the `boot2-*` features.
+
+#![no_std]
+
+pub mod auto_shift;
+pub mod ble;
+pub mod bootloader;
+pub mod build_info;
+pub mod calibration;
+#[cfg(feature = "capacitive")]
+pub mod capacitive;
+pub mod combos;
+pub mod conditional_keys;
+pub mod config_block;
+pub mod crash;
+pub mod debounce;
+pub mod dfu;
+pub mod direct_pins;
+pub mod double_buffer;
+pub mod dual_core;
+pub mod expansion;
+pub mod fault;
+pub mod flash;
+pub mod hall_effect;
+pub mod hid_descriptor;
+pub mod host_leds;
+pub mod idle;
+pub mod key_codes;
+pub mod key_events;
+pub mod key_mapping;
+pub mod key_scan;
+pub mod keyboard;
+pub mod keymap;
+pub mod kvm;
+pub mod latency;
+pub mod layers;
+pub mod locking_mods;
+pub mod macropad;
+pub mod macros;
+pub mod matrix_check;
+pub mod mod_swaps;
+pub mod mouse_keys;
+pub mod nkro;
+#[cfg(feature = "wireless")]
+pub mod nrf24;
+pub mod num_word;
+pub mod one_shot;
+pub mod pio_scan;
+pub mod pointer;
+pub mod profile;
+#[cfg(feature = "trackpoint")]
+pub mod ps2;
+pub mod raw_hid;
+pub mod repeat_key;
+pub mod report_queue;
+pub mod resolution_multiplier;
+pub mod rgb;
+pub mod scan_trace;
+pub mod settings;
+pub mod settings_mode;
+pub mod settle_calibration;
+pub mod socd;
+pub mod split;
+pub mod tap_dance;
+pub mod tap_hold;
+pub mod typing_break;
+pub mod unicode;
+pub mod usb_log;
+pub mod usb_stall;
+pub mod usb_stress;
+pub mod via;
+pub mod webusb;
+pub mod wireless;
+
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
+
+/// The number of scans it takes for at least `ms` milliseconds to pass, for the timings
+/// which are counted in scans.
+pub const fn ms_to_ticks(ms: u32) -> u32 {
+    ms.div_ceil(SCAN_PERIOD_MS)
+}
+
+/// The linker will place this boot block at the start of our program image. We
+/// need this to help the ROM bootloader get our code up and running. Which one is
+/// needed depends on the QSPI flash chip on the board, selected with a `boot2-*` feature.
+///
+/// `memory.x` refers to it by name, so it's linked into every binary using this crate. It's
+/// only built for the board, since a host build (like the simulator's) has no `.boot2`
+/// section to put it in.
+#[cfg(target_arch = "arm")]
+#[link_section = ".boot2"]
+#[no_mangle]
+#[used]
+pub static BOOT2_FIRMWARE: [u8; 256] = BOOT2;
+
+#[cfg(all(target_arch = "arm", feature = "boot2-w25q080"))]
+const BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+#[cfg(all(target_arch = "arm", feature = "boot2-at25sf128a"))]
+const BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_AT25SF128A;
+#[cfg(all(target_arch = "arm", feature = "boot2-gd25q64cs"))]
+const BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GD25Q64CS;
+
+#[cfg(all(
+    target_arch = "arm",
+    not(any(
+        feature = "boot2-w25q080",
+        feature = "boot2-at25sf128a",
+        feature = "boot2-gd25q64cs"
+    ))
+))]
+compile_error!(
+    "Select a boot2 stage for the board's flash chip with one of the `boot2-*` features."
+);
+
+#[cfg(all(
+    target_arch = "arm",
+    any(
+        all(feature = "boot2-w25q080", feature = "boot2-at25sf128a"),
+        all(feature = "boot2-w25q080", feature = "boot2-gd25q64cs"),
+        all(feature = "boot2-at25sf128a", feature = "boot2-gd25q64cs"),
+    )
+))]
+compile_error!("Only one `boot2-*` feature can be enabled at a time.");
@@ -0,0 +1,101 @@
+//! Locking modifiers, which stay held after being double tapped, so a run of capitals or a
+//! stretch of Ctrl shortcuts doesn't need the modifier held down.
+//!
+//! Tapping a modifier key twice within `DOUBLE_TAP_TICKS` locks its modifier, which is then
+//! added to every report until the key is tapped again. A tap only counts if no other key
+//! was pressed while the modifier was down, so Shift held for a capital never locks. Which
+//! modifiers are locked is exposed for an indicator, like the backlight, to show.
+
+use crate::{key_codes::KeyCode, ms_to_ticks, NUM_COLS, NUM_ROWS};
+
+/// How soon after a modifier is tapped the second tap has to come to lock it, 200 ms.
+pub const DOUBLE_TAP_TICKS: u32 = ms_to_ticks(200);
+
+#[derive(Default)]
+pub struct LockingMods {
+    /// The modifiers of the modifier keys held down.
+    held: u8,
+
+    /// Whether another key was pressed while modifier keys were held, so releasing them
+    /// isn't a tap.
+    interrupted: bool,
+
+    /// The modifiers of keys pressed to lock or unlock them, whose release isn't a tap.
+    ignored: u8,
+
+    /// The modifiers tapped once, waiting for the second tap, and how long they've waited.
+    tapped: u8,
+    tapped_ticks: u32,
+
+    locked: u8,
+
+    /// The matrix from the previous update, used to find newly pressed keys.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl LockingMods {
+    /// Update from a debounced scan, with `mapping` the keys resolved through the layers,
+    /// `elapsed_ticks` after the last update. Returns the locked modifiers, to add to the
+    /// report.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        mapping: &[[KeyCode; NUM_ROWS]; NUM_COLS],
+        elapsed_ticks: u32,
+    ) -> u8 {
+        let mut held = 0;
+        let mut other_key_pressed = false;
+        for (col, (column, previous_column)) in matrix.iter().zip(self.previous_matrix).enumerate()
+        {
+            for (row, (pressed, was_pressed)) in column.iter().zip(previous_column).enumerate() {
+                let key = mapping[col][row];
+                if !pressed {
+                    continue;
+                }
+
+                if let Some(modifier) = key.modifier_bitmask() {
+                    held |= modifier;
+                } else if !was_pressed && !key.is_modifier() && key != KeyCode::Empty {
+                    other_key_pressed = true;
+                }
+            }
+        }
+        self.previous_matrix = *matrix;
+
+        // A locked modifier's key unlocks it, and a second tap locks it.
+        let newly_held = held & !self.held;
+        let unlocking = newly_held & self.locked;
+        let locking = newly_held & self.tapped & !unlocking;
+        self.locked = (self.locked & !unlocking) | locking;
+        self.ignored |= unlocking | locking;
+        self.tapped &= !locking;
+
+        if other_key_pressed {
+            self.interrupted |= held != 0;
+            self.tapped = 0;
+        }
+
+        let tapped = self.held & !held & !self.ignored;
+        if !self.interrupted && tapped != 0 {
+            self.tapped = tapped;
+            self.tapped_ticks = 0;
+        } else if self.tapped != 0 {
+            self.tapped_ticks += elapsed_ticks;
+            if self.tapped_ticks >= DOUBLE_TAP_TICKS {
+                self.tapped = 0;
+            }
+        }
+
+        self.ignored &= held;
+        if held == 0 {
+            self.interrupted = false;
+        }
+        self.held = held;
+        self.locked
+    }
+
+    /// The locked modifiers, as a report bitmask.
+    pub fn locked(&self) -> u8 {
+        self.locked
+    }
+}
@@ -0,0 +1,94 @@
+//! The I2C protocol of macropad expansion modules, which scan their own keys (with a small
+//! microcontroller, or an I/O expander running the same protocol) rather than wiring them
+//! into the key matrix.
+//!
+//! The pad is identified by its ID resistor like any other module, and talks I2C over the
+//! two extra connector pins otherwise used for a TrackPoint: SDA on GPIO2 and SCL on GPIO3,
+//! with pull-ups on the module. The keyboard is the controller, and reads the pad's
+//! registers by writing the register number and then reading its contents:
+//!
+//! | Register | Contents                                                              |
+//! |----------|-----------------------------------------------------------------------|
+//! | `0x00`   | Identity: `b"KP"`, the protocol version (1), and the number of keys   |
+//! | `0x01`   | Key state: one bit per key, key 0 in the lowest bit, set when pressed |
+//!
+//! The pad's keys then appear at the matrix positions listed for `Module::MacroPad`, in
+//! order, so the keymap treats them like any other key.
+
+use defmt::Format;
+use embedded_hal::blocking::i2c::WriteRead;
+
+use crate::{expansion::Module, NUM_COLS, NUM_ROWS};
+
+/// The pad's 7-bit I2C address.
+pub const ADDRESS: u8 = 0x3A;
+
+/// The version of the protocol described above.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const REGISTER_IDENTITY: u8 = 0x00;
+const REGISTER_KEYS: u8 = 0x01;
+
+const IDENTITY_MAGIC: [u8; 2] = *b"KP";
+
+/// The most keys a pad can have, one for each bit of the key state register.
+pub const MAX_KEYS: usize = 8;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum MacroPadError {
+    /// Nothing acknowledged the pad's address, or a transfer failed part way through.
+    Bus,
+
+    /// Something answered, but not with a macropad's identity.
+    NotRecognized,
+
+    /// The pad speaks a version of the protocol this firmware doesn't know.
+    UnsupportedVersion(u8),
+}
+
+pub struct MacroPad<I2C> {
+    i2c: I2C,
+    key_count: usize,
+}
+
+impl<I2C: WriteRead> MacroPad<I2C> {
+    /// Check that a macropad is answering on the bus, and how many keys it has.
+    pub fn init(mut i2c: I2C) -> Result<Self, MacroPadError> {
+        let mut identity = [0u8; 4];
+        i2c.write_read(ADDRESS, &[REGISTER_IDENTITY], &mut identity)
+            .map_err(|_| MacroPadError::Bus)?;
+
+        let [magic @ .., version, key_count] = identity;
+        if magic[..] != IDENTITY_MAGIC {
+            return Err(MacroPadError::NotRecognized);
+        }
+        if version != PROTOCOL_VERSION {
+            return Err(MacroPadError::UnsupportedVersion(version));
+        }
+
+        let key_count = (key_count as usize).min(MAX_KEYS).min(Module::MacroPad.keys().len());
+        Ok(Self { i2c, key_count })
+    }
+
+    /// The number of the pad's keys which have a position in the matrix.
+    pub fn key_count(&self) -> usize {
+        self.key_count
+    }
+
+    /// Read which of the pad's keys are pressed, one bit per key.
+    pub fn read_keys(&mut self) -> Result<u8, MacroPadError> {
+        let mut keys = [0u8];
+        self.i2c
+            .write_read(ADDRESS, &[REGISTER_KEYS], &mut keys)
+            .map_err(|_| MacroPadError::Bus)?;
+
+        Ok(keys[0] & ((1u16 << self.key_count) - 1) as u8)
+    }
+}
+
+/// Add the pad's pressed keys, as read from its key state register, to a raw matrix.
+pub fn apply_keys(keys: u8, raw_matrix: &mut [[bool; NUM_ROWS]; NUM_COLS]) {
+    for (bit, ((col, row), _)) in Module::MacroPad.keys().iter().enumerate().take(MAX_KEYS) {
+        raw_matrix[*col][*row] |= keys & (1 << bit) != 0;
+    }
+}
@@ -0,0 +1,423 @@
+//! Macros uploaded by configuration tools, persisted to the macros flash partition.
+//!
+//! The macros are kept the way VIA edits them: one buffer of NUL-terminated sequences, the
+//! first being macro 0. Tools read and write the buffer in chunks, and count the macros by
+//! their terminators, so the buffer starts out as `MACRO_COUNT` empty sequences.
+//!
+//! Each sequence is played back by `MacroPlayer` when its `KeyCode::Macro0`..`Macro15` key
+//! is pressed. Within a sequence:
+//!
+//! - A printable ASCII character (or tab, newline, backspace or escape) is typed, with Shift
+//!   for the characters which need it on a US layout.
+//! - `0x01 0x01 usage` taps the keyboard usage, `0x01 0x02 usage` presses it, and
+//!   `0x01 0x03 usage` releases it.
+//! - `0x01 0x04` followed by a number of milliseconds in ASCII digits and a `|` waits before
+//!   the next step.
+//!
+//! Anything else ends the sequence, and whatever it left pressed is released.
+//!
+//! The last macro doubles as the dynamic macro: `KeyCode::RecordMacro` records the keys
+//! typed from then on into it, as the press and release steps above, until
+//! `KeyCode::StopMacroRecording` (or `RecordMacro` again), and `KeyCode::PlayRecordedMacro`
+//! plays it back. With the `save-recorded-macro` feature, each recording is saved to flash
+//! along with the other macros.
+
+use crate::{
+    config_block::ConfigBlock,
+    flash::Partition,
+    ms_to_ticks,
+    nkro::{NkroReport, NKRO_KEYS},
+};
+
+/// The number of macros tools can edit.
+pub const MACRO_COUNT: usize = 16;
+
+/// The size of the buffer holding every macro.
+pub const MACRO_BUFFER_SIZE: usize = 1024;
+
+/// The macro keys typed into by `KeyCode::RecordMacro` are recorded into.
+pub const RECORDED_MACRO_INDEX: usize = MACRO_COUNT - 1;
+
+/// The most steps a recording holds. Keys pressed after it fills up are left out.
+pub const MAX_RECORDED_STEPS: usize = 128;
+
+/// How many scans each key typed by a macro is held, and then released, for. Some hosts
+/// miss keys pressed and released within the same millisecond.
+pub const MACRO_TAP_TICKS: u16 = 5;
+
+pub(crate) const MACRO_PREFIX: u8 = 0x01;
+pub(crate) const MACRO_TAP: u8 = 0x01;
+pub(crate) const MACRO_DOWN: u8 = 0x02;
+pub(crate) const MACRO_UP: u8 = 0x03;
+const MACRO_DELAY: u8 = 0x04;
+const MACRO_DELAY_END: u8 = b'|';
+
+/// The Left Shift bit of the report's modifier byte.
+const LEFT_SHIFT: u8 = 0x02;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct MacroBuffer {
+    bytes: [u8; MACRO_BUFFER_SIZE],
+}
+
+impl Default for MacroBuffer {
+    /// Every macro empty.
+    fn default() -> Self {
+        Self { bytes: [0; MACRO_BUFFER_SIZE] }
+    }
+}
+
+impl MacroBuffer {
+    pub fn bytes(&self) -> &[u8; MACRO_BUFFER_SIZE] {
+        &self.bytes
+    }
+
+    /// Read `buffer.len()` bytes starting at `offset`. Returns `false` if they don't all
+    /// fit within the buffer.
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) -> bool {
+        let Some(bytes) = self.bytes.get(offset..offset + buffer.len()) else {
+            return false;
+        };
+        buffer.copy_from_slice(bytes);
+        true
+    }
+
+    /// Write `data` starting at `offset`. Returns `false`, writing nothing, if it doesn't
+    /// all fit within the buffer.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> bool {
+        let Some(bytes) = self.bytes.get_mut(offset..offset + data.len()) else {
+            return false;
+        };
+        bytes.copy_from_slice(data);
+        true
+    }
+
+    /// Replace macro `index` with `sequence`, which mustn't contain NULs. The macros after
+    /// it move along to make room. Returns `false`, changing nothing, if they don't fit.
+    pub fn set_macro(&mut self, index: usize, sequence: &[u8]) -> bool {
+        if index >= MACRO_COUNT {
+            return false;
+        }
+
+        let (start, end) = self.macro_range(index);
+        let used = (self.macro_range(MACRO_COUNT - 1).1 + 1).min(MACRO_BUFFER_SIZE);
+        let new_end = start + sequence.len();
+        let new_used = used - (end - start) + sequence.len();
+        if new_used > MACRO_BUFFER_SIZE {
+            return false;
+        }
+
+        let mut bytes = [0u8; MACRO_BUFFER_SIZE];
+        bytes[..start].copy_from_slice(&self.bytes[..start]);
+        bytes[start..new_end].copy_from_slice(sequence);
+        bytes[new_end..new_used].copy_from_slice(&self.bytes[end..used]);
+        self.bytes = bytes;
+        true
+    }
+
+    /// Where macro `index` starts and ends in the buffer, not counting its terminator.
+    fn macro_range(&self, index: usize) -> (usize, usize) {
+        let mut start = 0;
+        for _ in 0..index {
+            start = self.bytes[start..]
+                .iter()
+                .position(|byte| *byte == 0)
+                .map_or(MACRO_BUFFER_SIZE, |len| start + len + 1);
+        }
+
+        let len = self.bytes[start..].iter().position(|byte| *byte == 0);
+        (start, len.map_or(MACRO_BUFFER_SIZE, |len| start + len))
+    }
+}
+
+impl ConfigBlock for MacroBuffer {
+    const MAGIC: [u8; 4] = *b"KRMC";
+    const PARTITION: Partition = Partition::Macros;
+    const VERSION: u16 = 1;
+
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        buffer[..MACRO_BUFFER_SIZE].copy_from_slice(&self.bytes);
+        MACRO_BUFFER_SIZE
+    }
+
+    fn deserialize(version: u16, payload: &[u8]) -> Option<Self> {
+        if version != 1 || payload.len() != MACRO_BUFFER_SIZE {
+            return None;
+        }
+
+        let mut macros = Self::default();
+        macros.bytes.copy_from_slice(payload);
+        Some(macros)
+    }
+}
+
+/// One step of a macro sequence, and the length it takes up in the buffer.
+#[derive(Copy, Clone, PartialEq)]
+enum Step {
+    /// Tap a usage, with these modifier bits held alongside it.
+    Tap(u8, u8),
+    Down(u8),
+    Up(u8),
+    Delay(u16),
+    End,
+}
+
+impl Step {
+    fn parse(bytes: &[u8]) -> (Self, usize) {
+        match bytes {
+            [MACRO_PREFIX, MACRO_TAP, usage, ..] => (Step::Tap(*usage, 0), 3),
+            [MACRO_PREFIX, MACRO_DOWN, usage, ..] => (Step::Down(*usage), 3),
+            [MACRO_PREFIX, MACRO_UP, usage, ..] => (Step::Up(*usage), 3),
+            [MACRO_PREFIX, MACRO_DELAY, rest @ ..] => {
+                let Some(len) = rest.iter().position(|byte| *byte == MACRO_DELAY_END) else {
+                    return (Step::End, 0);
+                };
+                let ms = rest[..len]
+                    .iter()
+                    .filter(|byte| byte.is_ascii_digit())
+                    .fold(0u16, |ms, digit| {
+                        ms.saturating_mul(10).saturating_add((digit - b'0') as u16)
+                    });
+                (Step::Delay(ms), 2 + len + 1)
+            },
+            [character, ..] => match ascii_usage(*character) {
+                Some((usage, shifted)) => {
+                    (Step::Tap(usage, if shifted { LEFT_SHIFT } else { 0 }), 1)
+                },
+                None => (Step::End, 0),
+            },
+            [] => (Step::End, 0),
+        }
+    }
+}
+
+/// The keyboard usage which types an ASCII character on a US layout, and whether it needs
+/// Shift.
+fn ascii_usage(character: u8) -> Option<(u8, bool)> {
+    let usage = match character {
+        b'a'..=b'z' => (0x04 + character - b'a', false),
+        b'A'..=b'Z' => (0x04 + character - b'A', true),
+        b'1'..=b'9' => (0x1E + character - b'1', false),
+        b'0' => (0x27, false),
+        b'!' => (0x1E, true),
+        b'@' => (0x1F, true),
+        b'#' => (0x20, true),
+        b'$' => (0x21, true),
+        b'%' => (0x22, true),
+        b'^' => (0x23, true),
+        b'&' => (0x24, true),
+        b'*' => (0x25, true),
+        b'(' => (0x26, true),
+        b')' => (0x27, true),
+        b'\n' => (0x28, false),
+        0x1B => (0x29, false),
+        0x08 => (0x2A, false),
+        b'\t' => (0x2B, false),
+        b' ' => (0x2C, false),
+        b'-' => (0x2D, false),
+        b'_' => (0x2D, true),
+        b'=' => (0x2E, false),
+        b'+' => (0x2E, true),
+        b'[' => (0x2F, false),
+        b'{' => (0x2F, true),
+        b']' => (0x30, false),
+        b'}' => (0x30, true),
+        b'\\' => (0x31, false),
+        b'|' => (0x31, true),
+        b';' => (0x33, false),
+        b':' => (0x33, true),
+        b'\'' => (0x34, false),
+        b'"' => (0x34, true),
+        b'`' => (0x35, false),
+        b'~' => (0x35, true),
+        b',' => (0x36, false),
+        b'<' => (0x36, true),
+        b'.' => (0x37, false),
+        b'>' => (0x37, true),
+        b'/' => (0x38, false),
+        b'?' => (0x38, true),
+        _ => return None,
+    };
+    Some(usage)
+}
+
+/// Press a keyboard usage in `report`, setting its bit in the modifier byte for the
+/// modifiers.
+fn press(report: &mut NkroReport, usage: u8) {
+    match usage {
+        0xE0..=0xE7 => report.modifier |= 1 << (usage - 0xE0),
+        _ => report.press(usage),
+    }
+}
+
+fn release(report: &mut NkroReport, usage: u8) {
+    match usage {
+        0xE0..=0xE7 => report.modifier &= !(1 << (usage - 0xE0)),
+        _ => report.release(usage),
+    }
+}
+
+fn is_pressed(report: &NkroReport, usage: u8) -> bool {
+    match usage {
+        0xE0..=0xE7 => report.modifier & (1 << (usage - 0xE0)) != 0,
+        _ => report.is_pressed(usage),
+    }
+}
+
+/// Plays back a macro one step per scan, alongside the keys held on the keyboard.
+#[derive(Default)]
+pub struct MacroPlayer {
+    /// Where the next step starts in the buffer, while a macro is playing.
+    position: Option<usize>,
+
+    /// Scans left before the next step.
+    wait_ticks: u16,
+
+    /// A tapped usage and its modifier bits, released once it's been held for
+    /// `MACRO_TAP_TICKS`.
+    tapped: Option<(u8, u8)>,
+
+    /// The keys the macro holds down.
+    held: NkroReport,
+}
+
+impl MacroPlayer {
+    /// Start playing macro `index`, abandoning (and releasing) any macro still playing.
+    pub fn start(&mut self, macros: &MacroBuffer, index: usize) {
+        let (start, _) = macros.macro_range(index);
+
+        *self = Self::default();
+        self.position = (start < MACRO_BUFFER_SIZE).then_some(start);
+    }
+
+    /// Start playing a sequence from its beginning, to be played with `tick_sequence`.
+    pub(crate) fn start_sequence(&mut self) {
+        *self = Self { position: Some(0), ..Self::default() };
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.position.is_some()
+    }
+
+    /// The keys the macro holds down as of the last `tick`.
+    pub fn held(&self) -> &NkroReport {
+        &self.held
+    }
+
+    /// Advance the macro playing by one scan tick.
+    pub fn tick(&mut self, macros: &MacroBuffer) {
+        self.tick_sequence(&macros.bytes);
+    }
+
+    /// Advance the sequence playing by one scan tick, with `sequence` holding the steps in
+    /// the macro format, such as the whole macro buffer.
+    pub(crate) fn tick_sequence(&mut self, sequence: &[u8]) {
+        let Some(position) = self.position else {
+            return;
+        };
+
+        if self.wait_ticks > 0 {
+            self.wait_ticks -= 1;
+            return;
+        }
+
+        if let Some((usage, modifier)) = self.tapped.take() {
+            release(&mut self.held, usage);
+            self.held.modifier &= !modifier;
+            self.wait_ticks = MACRO_TAP_TICKS - 1;
+            return;
+        }
+
+        let (step, len) = Step::parse(sequence.get(position..).unwrap_or(&[]));
+        self.position = Some(position + len);
+        match step {
+            Step::Tap(usage, modifier) => {
+                press(&mut self.held, usage);
+                self.held.modifier |= modifier;
+                self.tapped = Some((usage, modifier));
+                self.wait_ticks = MACRO_TAP_TICKS - 1;
+            },
+            Step::Down(usage) => press(&mut self.held, usage),
+            Step::Up(usage) => release(&mut self.held, usage),
+            Step::Delay(ms) => self.wait_ticks = ms_to_ticks(ms.into()) as u16,
+            Step::End => *self = Self::default(),
+        }
+    }
+}
+
+/// Records the keys typed on the keyboard as a macro, from the reports they're sent in.
+pub struct MacroRecorder {
+    recording: bool,
+    steps: [u8; MAX_RECORDED_STEPS * 3],
+    len: usize,
+
+    /// The keys recorded as pressed, so only releases of those are recorded.
+    pressed: NkroReport,
+
+    /// The report from the previous `record`.
+    previous: NkroReport,
+}
+
+impl Default for MacroRecorder {
+    fn default() -> Self {
+        Self {
+            recording: false,
+            steps: [0; MAX_RECORDED_STEPS * 3],
+            len: 0,
+            pressed: NkroReport::EMPTY,
+            previous: NkroReport::EMPTY,
+        }
+    }
+}
+
+impl MacroRecorder {
+    /// Start a new recording, discarding any unfinished one. Keys already held when it
+    /// starts are left out.
+    pub fn start(&mut self, report: &NkroReport) {
+        *self = Self { recording: true, previous: *report, ..Self::default() };
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Record the keys pressed and released since the previous report.
+    pub fn record(&mut self, report: &NkroReport) {
+        if !self.recording {
+            return;
+        }
+
+        let modifiers = (0..8u8).map(|bit| (0xE0 + bit, report.modifier & (1 << bit) != 0));
+        let keys = (0..NKRO_KEYS as u8).map(|usage| (usage, report.is_pressed(usage)));
+        for (usage, pressed) in keys.chain(modifiers) {
+            let was_pressed = is_pressed(&self.previous, usage);
+            if pressed && !was_pressed {
+                self.push([MACRO_PREFIX, MACRO_DOWN, usage]);
+            } else if !pressed && was_pressed && is_pressed(&self.pressed, usage) {
+                self.push([MACRO_PREFIX, MACRO_UP, usage]);
+            }
+        }
+
+        self.previous = *report;
+    }
+
+    /// Finish the recording, returning its steps in the macro format. Keys still held are
+    /// released when it's played back, as at the end of every macro.
+    pub fn stop(&mut self) -> &[u8] {
+        self.recording = false;
+        &self.steps[..self.len]
+    }
+
+    fn push(&mut self, step: [u8; 3]) {
+        let Some(space) = self.steps.get_mut(self.len..self.len + step.len()) else {
+            return;
+        };
+        space.copy_from_slice(&step);
+        self.len += step.len();
+
+        match step[1] {
+            MACRO_DOWN => press(&mut self.pressed, step[2]),
+            _ => release(&mut self.pressed, step[2]),
+        }
+    }
+}
@@ -5,79 +5,571 @@
 #![no_std]
 
 use usb_device::class::UsbClass;
-mod debounce;
-mod hid_descriptor;
-mod key_codes;
-mod key_mapping;
-mod key_scan;
 
-use core::{cell::RefCell, convert::Infallible};
+#[cfg(feature = "dual-core")]
+use core::ptr::addr_of_mut;
+use core::{
+    cell::{Cell, RefCell},
+    convert::Infallible,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
 use critical_section::Mutex;
+#[cfg(feature = "usb-log")]
+use critical_section::{CriticalSection, RestoreState};
 use defmt::{error, info, warn};
+#[cfg(not(any(feature = "production", feature = "usb-log")))]
 use defmt_rtt as _;
-use embedded_hal::digital::v2::{InputPin, OutputPin};
-use panic_probe as _;
+#[cfg(not(feature = "analog"))]
+use embedded_hal::digital::v2::InputPin;
+use embedded_hal::{
+    adc::OneShot,
+    digital::v2::{OutputPin, PinState},
+    watchdog::{Watchdog as _, WatchdogEnable},
+};
+use fugit::{MicrosDurationU32, RateExtU32};
+#[cfg(feature = "dual-core")]
+use key_ripper::dual_core::{self, BacklightLink};
+#[cfg(feature = "rgb")]
+use key_ripper::rgb::{RgbBacklight, Ws2812, MAX_LEDS};
+#[cfg(any(feature = "trackpoint", feature = "rgb"))]
+use rp2040_hal::gpio::Pin;
+#[cfg(feature = "rgb")]
+use rp2040_hal::gpio::{bank0::Gpio7, FunctionPio1};
+#[cfg(feature = "trackpoint")]
+use rp2040_hal::gpio::{
+    bank0::{Gpio2, Gpio3},
+    FunctionPio0,
+};
+#[cfg(feature = "dual-core")]
+use rp2040_hal::multicore::{Multicore, Stack};
+#[cfg(any(feature = "trackpoint", feature = "rgb", feature = "pio-scan"))]
+use rp2040_hal::pio::PIOExt;
 use rp2040_hal::{
+    adc::Adc,
+    gpio::FunctionI2C,
     pac::{self, interrupt},
+    timer::{Alarm, Alarm0},
     usb::{self, UsbBus},
-    Clock, Watchdog,
+    Clock, Timer, Watchdog, I2C,
+};
+#[cfg(feature = "wireless")]
+use rp2040_hal::{gpio::FunctionSpi, Spi};
+#[cfg(any(feature = "ble", feature = "split"))]
+use rp2040_hal::{
+    gpio::FunctionUart,
+    uart::{UartConfig, UartPeripheral},
 };
 use usb_device::{bus::UsbBusAllocator, device::UsbDeviceBuilder, prelude::*};
+#[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+use usbd_hid::descriptor::MouseReport;
 use usbd_hid::{
     descriptor::KeyboardReport,
     hid_class::{
-        HIDClass, HidClassSettings, HidCountryCode, HidProtocol, HidSubClass, ProtocolModeConfig,
+        HIDClass, HidClassSettings, HidCountryCode, HidProtocol, HidProtocolMode, HidSubClass,
+        ProtocolModeConfig,
     },
 };
 
-use debounce::Debounce;
-use key_scan::KeyScan;
+#[cfg(feature = "ble")]
+use key_ripper::ble::{BleLink, BleStatus};
+#[cfg(feature = "analog")]
+use key_ripper::calibration::{CalibrationTable, Calibrator};
+#[cfg(feature = "capacitive")]
+use key_ripper::capacitive::CapacitiveMatrix;
+#[cfg(not(any(feature = "debounce-integrator", feature = "debounce-deferred")))]
+use key_ripper::debounce::Debounce;
+#[cfg(feature = "debounce-deferred")]
+use key_ripper::debounce::DeferredDebounce;
+#[cfg(feature = "debounce-integrator")]
+use key_ripper::debounce::Integrator;
+#[cfg(all(feature = "analog", not(feature = "capacitive")))]
+use key_ripper::hall_effect::HallEffectMatrix;
+#[cfg(feature = "lock-leds")]
+use key_ripper::host_leds::{HostLed, LockLeds};
+#[cfg(not(any(feature = "analog", feature = "pio-scan")))]
+use key_ripper::key_scan::{MatrixScanner, ScanStep};
+#[cfg(feature = "kvm-mux")]
+use key_ripper::kvm::Output;
+#[cfg(feature = "nkro")]
+use key_ripper::nkro::NkroReport;
+#[cfg(feature = "trackpoint")]
+use key_ripper::ps2::{Ps2Host, TrackPoint};
+#[cfg(feature = "split")]
+use key_ripper::split::{self, SplitLink};
+#[cfg(feature = "usb-log")]
+use key_ripper::usb_log::{CdcAcmClass, LogBuffer};
+use key_ripper::{
+    bootloader,
+    config_block::ConfigBlock,
+    crash::{self, PanicAction, PanicLog},
+    debounce::{BounceStats, Debouncer},
+    dfu::DfuRuntimeClass,
+    double_buffer::DoubleBuffer,
+    expansion::{self, Module},
+    fault::{Fault, FaultBlinker, FaultLatch},
+    flash::{self, WriteError, UNIQUE_ID_LEN},
+    hid_descriptor,
+    host_leds::HostLeds,
+    idle::{IdleState, IdleTimer},
+    key_events::{KeyEvents, KEY_EVENT_QUEUE_LEN},
+    key_scan::KeyScan,
+    keyboard::Keyboard,
+    keymap::Keymap,
+    kvm::HotkeyPlayer,
+    latency::{InFlightPress, LatencyStats, PressTimer},
+    macropad::{self, MacroPad},
+    macros::MacroBuffer,
+    matrix_check::MatrixCheck,
+    ms_to_ticks,
+    profile::Profile,
+    raw_hid::{self, RawHid, ReportSlot},
+    report_queue::ReportQueue,
+    scan_trace,
+    settings::Settings,
+    typing_break::BreakReminder,
+    usb_stall::StallDetector,
+    usb_stress::StressTest,
+    via::{Via, VIA_REPORT_LEN},
+    webusb::WebUsbClass,
+    MATRIX_WIRING, NUM_COLS, NUM_ROWS, SCAN_PERIOD_MS, USB_MANUFACTURER, USB_MAX_POWER_MA,
+    USB_PRODUCT, USB_PRODUCT_ID, USB_SELF_POWERED, USB_VENDOR_ID,
+};
+#[cfg(feature = "pio-scan")]
+use key_ripper::{
+    key_scan::SwitchScanner,
+    pio_scan::{PioScanner, ScanBuffers},
+};
+#[cfg(not(feature = "analog"))]
+use key_ripper::{key_scan::COLUMN_SETTLE_US, settle_calibration, COL_PINS, ROW_PINS};
+#[cfg(feature = "wireless")]
+use key_ripper::{
+    nrf24::{Nrf24, Role},
+    wireless::RadioLink,
+};
+#[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+use key_ripper::{
+    pointer::{Pointer, ResolutionMultipliers},
+    resolution_multiplier::ResolutionMultiplierClass,
+};
+
+#[cfg(all(feature = "kvm-mux", feature = "ble"))]
+compile_error!("The `kvm-mux` and `ble` features can't be enabled together, both use GPIO0.");
+
+#[cfg(all(feature = "debounce-integrator", feature = "debounce-deferred"))]
+compile_error!(
+    "Only one of the `debounce-integrator` and `debounce-deferred` features can be enabled."
+);
+
+#[cfg(all(feature = "split", any(feature = "ble", feature = "kvm-mux")))]
+compile_error!(
+    "The `split` feature can't be combined with `ble` or `kvm-mux`, they all use GPIO0 and GPIO1."
+);
+
+#[cfg(all(feature = "pio-scan", feature = "analog"))]
+compile_error!("The `pio-scan` feature can't be combined with `analog` or `capacitive`.");
+
+#[cfg(all(feature = "rgb", feature = "wireless"))]
+compile_error!("The `rgb` and `wireless` features can't be enabled together, both use GPIO7.");
+
+#[cfg(all(feature = "lock-leds", feature = "wireless"))]
+compile_error!(
+    "The `lock-leds` and `wireless` features can't be enabled together, both use GPIO4 to GPIO6."
+);
+
+/// The rate of USB interrupt polling the device will ask of the host, once a scan so every
+/// scan's report goes out as soon as it's ready.
+const USB_POLL_RATE_MS: u8 = SCAN_PERIOD_MS as u8;
+
+/// The time from the start of one scan to the start of the next.
+const SCAN_PERIOD_US: u64 = SCAN_PERIOD_MS as u64 * 1000;
+
+/// The debouncing strategy, picked by the `debounce-*` features.
+#[cfg(not(any(feature = "debounce-integrator", feature = "debounce-deferred")))]
+type MatrixDebounce = Debounce<NUM_ROWS, NUM_COLS>;
+#[cfg(feature = "debounce-integrator")]
+type MatrixDebounce = Integrator<NUM_ROWS, NUM_COLS>;
+#[cfg(feature = "debounce-deferred")]
+type MatrixDebounce = DeferredDebounce<NUM_ROWS, NUM_COLS>;
+
+/// The time between scans while the host has suspended the bus, slow enough to save power
+/// but still quick to notice a key pressed to wake the host.
+const SUSPENDED_SCAN_PERIOD_US: u64 = 10_000;
+
+/// The time between scans once the keyboard has gone idle, see `idle`. The first key
+/// pressed is reported at most this late.
+const IDLE_SCAN_PERIOD_US: u64 = 5_000;
+
+/// How long the main loop can go without coming round before the watchdog resets the
+/// keyboard, long enough for the slowest flash writes, like saving a scan trace.
+const WATCHDOG_TIMEOUT_MS: u32 = 4000;
+
+/// The shortest time a TIMER alarm can be scheduled for.
+const MIN_ALARM_US: u64 = 10;
+
+/// How long to type without a break before the indicator LED reminds you to take one, in
+/// minutes. Zero turns the reminders off.
+const TYPING_BREAK_INTERVAL_MIN: u64 = 50;
+
+/// The number of keyboard report changes which can wait for the host. When it's full, key
+/// presses and releases wait as key events until there's room.
+const REPORT_QUEUE_LEN: usize = 8;
+
+/// Key events held back by a full report queue for longer than this, in milliseconds, are
+/// logged once they've all been sent.
+const KEY_EVENT_DELAY_LOG_MS: u64 = 10;
+
+/// How many presses have their latency timed between each time the stats are logged.
+const LATENCY_LOG_SAMPLES: u32 = 100;
 
-/// The rate of polling of the keyboard itself in firmware.
-const SCAN_LOOP_RATE_MS: u32 = 1;
-/// The rate of USB interrupt polling the device will ask of the host.
-const USB_POLL_RATE_MS: u8 = SCAN_LOOP_RATE_MS as u8;
-/// The number of milliseconds to wait until a "key-off-then-key-on" in quick succession is allowed.
-const DEBOUNCE_MS: u8 = 6;
+/// How many bytes of log messages are kept for the USB serial port while no terminal has it
+/// open, or while it's sending them.
+#[cfg(feature = "usb-log")]
+const USB_LOG_LEN: usize = 2048;
 
-const DEBOUNCE_TICKS: u8 = DEBOUNCE_MS / (SCAN_LOOP_RATE_MS as u8);
+/// How long to stay disconnected when reconnecting to get out of a stuck USB connection,
+/// long enough for the host to notice.
+const USB_DISCONNECT_MS: u32 = 100;
 
-/// The linker will place this boot block at the start of our program image. We
-/// need this to help the ROM bootloader get our code up and running.
-#[link_section = ".boot2"]
-#[used]
-pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+/// How long to wait after a DFU detach request before restarting into the bootloader, long
+/// enough for the host to finish the request.
+const DFU_DETACH_DELAY_MS: u32 = 10;
 
-const NUM_COLS: usize = 14;
-const NUM_ROWS: usize = 6;
+/// The most current the keyboard asks the host for, `usb.max_power_ma` in `board.toml`, or
+/// no more than a single unit load (100 mA) with the `low-power` feature.
+const MAX_POWER_MA: usize =
+    if cfg!(feature = "low-power") && USB_MAX_POWER_MA > 100 { 100 } else { USB_MAX_POWER_MA };
+
+/// The number of raw matrix changes kept in RAM when recording a scan trace.
+#[cfg(feature = "scan-trace")]
+const SCAN_TRACE_LEN: usize = 1024;
+
+// Analog boards sense the rows through their multiplexers, so they have to drive the
+// columns.
+#[cfg(feature = "analog")]
+const _: () = assert!(
+    MATRIX_WIRING.drives_columns(),
+    "Analog boards need a matrix wired to drive its columns, check `board.toml`."
+);
+
+// The setup of the matrix and indicator LED pins, generated from `board.toml`.
+include!(concat!(env!("OUT_DIR"), "/board_pins.rs"));
+
+/// The I2C clock of a macropad module.
+const MACROPAD_I2C_FREQUENCY_KHZ: u32 = 400;
+
+/// The PS/2 data pin of a TrackPoint module. Its clock is on the next pin.
+#[cfg(feature = "trackpoint")]
+const PS2_DATA_PIN: u8 = 2;
+
+/// The data pin of the backlight's LED chain.
+#[cfg(feature = "rgb")]
+const RGB_DATA_PIN: u8 = 7;
+
+/// The number of the mouse's USB interface. Interfaces are numbered in the order their
+/// classes are created, and the keyboard comes first.
+#[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+const MOUSE_INTERFACE: u16 = 1;
 
 const EXTERNAL_CRYSTAL_FREQUENCY_HZ: u32 = 12_000_000;
 
-/// The USB Device Driver (shared with the interrupt).
-static mut USB_DEVICE: Option<UsbDevice<usb::UsbBus>> = None;
+/// The size of core 1's stack, in words. Rendering a frame only needs a few hundred bytes.
+#[cfg(feature = "dual-core")]
+const CORE1_STACK_WORDS: usize = 1024;
+
+/// The stack of core 1, which runs the backlight with `dual-core`.
+#[cfg(feature = "dual-core")]
+static mut CORE1_STACK: Stack<CORE1_STACK_WORDS> = Stack::new();
+
+/// The USB device and its classes, once they're set up. Only the USB interrupt uses them
+/// from then on, borrowing them within a critical section.
+static USB: Mutex<RefCell<Option<Usb>>> = Mutex::new(RefCell::new(None));
+
+struct Usb {
+    device: UsbDevice<'static, usb::UsbBus>,
+
+    /// The keyboard interface, in boot or report protocol.
+    hid: HIDClass<'static, usb::UsbBus>,
+
+    /// The vendor configuration interface and its WebUSB and Microsoft OS descriptors.
+    webusb: WebUsbClass,
+
+    /// The DFU runtime interface, for detaching into the bootloader.
+    dfu: DfuRuntimeClass,
+
+    /// The N-key rollover keyboard interface.
+    #[cfg(feature = "nkro")]
+    nkro_hid: HIDClass<'static, usb::UsbBus>,
+
+    /// The Consumer Control interface, for media keys.
+    consumer_hid: HIDClass<'static, usb::UsbBus>,
+
+    /// The System Control interface, for system keys.
+    system_hid: HIDClass<'static, usb::UsbBus>,
+
+    /// The raw HID interface, for configuration tools.
+    raw_hid: HIDClass<'static, usb::UsbBus>,
+
+    /// The raw HID interface for VIA.
+    via_hid: HIDClass<'static, usb::UsbBus>,
+
+    /// The USB mouse interface, for a TrackPoint module and mouse keys.
+    #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+    mouse_hid: HIDClass<'static, usb::UsbBus>,
+
+    /// Handles the mouse's Resolution Multiplier feature report.
+    #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+    resolution_multiplier: ResolutionMultiplierClass,
+
+    /// The serial port carrying the log, see `usb_log`.
+    #[cfg(feature = "usb-log")]
+    serial: CdcAcmClass<'static, usb::UsbBus>,
+}
+
+/// The scrolling resolution the host last asked for.
+#[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+static RESOLUTION_MULTIPLIERS: Mutex<RefCell<ResolutionMultipliers>> =
+    Mutex::new(RefCell::new(ResolutionMultipliers { wheel: false, pan: false }));
+
+/// The next mouse report to send, which is taken once the host has received it.
+#[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+static MOUSE_REPORT: Mutex<RefCell<Option<MouseReport>>> = Mutex::new(RefCell::new(None));
+
+/// Whether the host has configured the keyboard over USB, and hasn't suspended it. Until it
+/// has, reports go over Bluetooth or the radio instead.
+static USB_CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the host has suspended the bus, such as while it's asleep.
+static USB_SUSPENDED: AtomicBool = AtomicBool::new(false);
 
-/// The USB Bus Driver (shared with the interrupt).
-static mut USB_BUS: Option<UsbBusAllocator<usb::UsbBus>> = None;
+/// Set by the main loop when a key is pressed while the bus is suspended, for the USB
+/// interrupt to wake the host.
+static USB_WAKEUP_REQUESTED: AtomicBool = AtomicBool::new(false);
 
-/// The USB Human Interface Device Driver (shared with the interrupt).
-static mut USB_HID: Option<HIDClass<usb::UsbBus>> = None;
+/// Whether the host has switched the keyboard interface to boot protocol, as BIOS and UEFI
+/// firmware do, so it only reads the boot keyboard report.
+static USB_BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+/// Every change to the keyboard report while USB is configured, waiting for the host to
+/// take it. Reports stay queued while the endpoint is busy, rather than being dropped.
+static KEYBOARD_REPORT_QUEUE: ReportQueue<KeyboardReport, REPORT_QUEUE_LEN> =
+    ReportQueue::new(KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes: [0u8; 6] });
+
+/// The press on its way to the host in a queued keyboard report, see `latency`.
+static KEYBOARD_PRESS: Mutex<Cell<Option<InFlightPress>>> = Mutex::new(Cell::new(None));
+
+/// The latencies of presses sent to the host, recorded by the USB interrupt.
+static LATENCY_STATS: Mutex<Cell<LatencyStats>> =
+    Mutex::new(Cell::new(LatencyStats { samples: 0, min_us: 0, max_us: 0, total_us: 0 }));
+
+/// Whether the last keyboard report pushed to the host got `WouldBlock`.
+static USB_REPORT_BLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// The alarm which wakes the main loop from `sleep` (shared with the interrupt).
+static SCAN_ALARM: Mutex<RefCell<Option<Alarm0>>> = Mutex::new(RefCell::new(None));
+
+/// The most serious fault so far, blinked on the indicator LED.
+static FAULT: FaultLatch = FaultLatch::new();
+
+/// What the panic handler does, as `PanicAction::to_u8`.
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(0);
+
+/// Set by the panic handler, so a panic inside it goes straight to the `PanicAction`.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the host is kept from changing the keyboard's configuration. Everything which
+/// takes configuration from the host checks this first.
+static CONFIG_LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the host has asked over DFU for the keyboard to restart into the bootloader.
+static DFU_DETACH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The LED output report the host last sent, with its lock states.
+static HOST_LEDS: AtomicU8 = AtomicU8::new(0);
 
 /// The latest keyboard report for responding to USB interrupts.
-static KEYBOARD_REPORT: Mutex<RefCell<KeyboardReport>> = Mutex::new(RefCell::new(KeyboardReport {
-    modifier: 0,
-    reserved: 0,
-    leds: 0,
-    keycodes: [0u8; 6],
-}));
+static KEYBOARD_REPORT: DoubleBuffer<KeyboardReport> =
+    DoubleBuffer::new(KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes: [0u8; 6] });
+
+/// Every change to the media key being held, like `KEYBOARD_REPORT_QUEUE`.
+static CONSUMER_REPORT_QUEUE: ReportQueue<u16, REPORT_QUEUE_LEN> = ReportQueue::new(0);
+
+/// The Consumer Control usage of the media key being held, or zero, for responding to USB
+/// interrupts.
+static CONSUMER_REPORT: DoubleBuffer<u16> = DoubleBuffer::new(0);
+
+/// Every change to the system key being held, like `KEYBOARD_REPORT_QUEUE`.
+static SYSTEM_REPORT_QUEUE: ReportQueue<u8, REPORT_QUEUE_LEN> = ReportQueue::new(0);
+
+/// The System Control usage of the system key being held, or zero, for responding to USB
+/// interrupts.
+static SYSTEM_REPORT: DoubleBuffer<u8> = DoubleBuffer::new(0);
+
+/// Every change to the N-key rollover report, like `KEYBOARD_REPORT_QUEUE`.
+#[cfg(feature = "nkro")]
+static NKRO_REPORT_QUEUE: ReportQueue<NkroReport, REPORT_QUEUE_LEN> =
+    ReportQueue::new(NkroReport::EMPTY);
+
+/// The latest N-key rollover report for responding to USB interrupts.
+#[cfg(feature = "nkro")]
+static NKRO_REPORT: DoubleBuffer<NkroReport> = DoubleBuffer::new(NkroReport::EMPTY);
+
+/// The press on its way to the host in a queued N-key rollover report.
+#[cfg(feature = "nkro")]
+static NKRO_PRESS: Mutex<Cell<Option<InFlightPress>>> = Mutex::new(Cell::new(None));
+
+/// The last raw HID request from the host, until the main loop handles it.
+static RAW_HID_REQUEST: ReportSlot = Mutex::new(RefCell::new(None));
+
+/// The response to the last raw HID request, which is taken once the host has received it.
+static RAW_HID_RESPONSE: ReportSlot = Mutex::new(RefCell::new(None));
+
+/// The last `raw_hid` request made over the vendor interface, see `webusb`.
+static WEBUSB_REQUEST: ReportSlot = Mutex::new(RefCell::new(None));
+
+/// The response to the last request made over the vendor interface.
+static WEBUSB_RESPONSE: ReportSlot = Mutex::new(RefCell::new(None));
+
+/// The last VIA request, like `RAW_HID_REQUEST`.
+static VIA_REQUEST: Mutex<RefCell<Option<[u8; VIA_REPORT_LEN]>>> = Mutex::new(RefCell::new(None));
+
+/// The response to the last VIA request, like `RAW_HID_RESPONSE`.
+static VIA_RESPONSE: Mutex<RefCell<Option<[u8; VIA_REPORT_LEN]>>> = Mutex::new(RefCell::new(None));
 
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
 }
 
+/// Record where the panic happened for the next boot to report, then do what the saved
+/// `PanicAction` says. A panic while doing so, like in saving the log or in the logger,
+/// skips to the action, keeping the first panic's record.
+#[panic_handler]
+fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    // There's no atomic swap on the M0+, but interrupts are off and only this sets the flag.
+    let first = !PANICKING.load(Ordering::Relaxed);
+    PANICKING.store(true, Ordering::Relaxed);
+    if first {
+        if let Some(location) = info.location() {
+            crash::record_panic(location.file(), location.line());
+            // Core 1 can't write to flash while core 0 runs from it, and a panic during a
+            // write would wait forever for core 1 to pause again. The same panic over and
+            // over, like one at boot which resets, isn't written again, to spare the flash.
+            let log = PanicLog::new(location.file(), location.line(), info.message());
+            if rp2040_hal::Sio::core() == 0 && !flash::busy() && PanicLog::load() != Some(log) {
+                log.save().ok();
+            }
+        }
+        error!("{}", defmt::Display2Format(info));
+    }
+
+    match PanicAction::from_u8(PANIC_ACTION.load(Ordering::Relaxed)).unwrap_or_default() {
+        PanicAction::Reset => {},
+        // Not `bootloader::reboot_to_bootloader`, which would clear the panic's record.
+        PanicAction::Bootloader => rp2040_hal::rom_data::reset_to_usb_boot(0, 0),
+        PanicAction::Halt => halt_blinking(),
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Stop after a panic, blinking `Fault::Panic` on the indicator LED until the keyboard is
+/// reset. The watchdog is stopped, so it doesn't reset the keyboard first.
+fn halt_blinking() -> ! {
+    use key_ripper::fault::{BLINK_MS, PAUSE_MS};
+
+    // The system clock's speed once it's set up. A panic before then blinks slower.
+    const CYCLES_PER_MS: u32 = 125_000;
+
+    // Note (safety): Nothing else runs after a panic, and the LED pin is only taken over
+    // here. Interrupts were turned off at the start of the panic handler and the main loop
+    // which owns the pin never resumes, so no owner of the pin or the watchdog can run again
+    // to race these writes. Core 1, if it's running, only ever drives the backlight.
+    unsafe {
+        (*pac::WATCHDOG::ptr()).ctrl.modify(|_, w| w.enable().clear_bit());
+        (*pac::IO_BANK0::ptr()).gpio[INDICATOR_LED_GPIO].gpio_ctrl.write(|w| w.funcsel().sio());
+    }
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let led = 1 << INDICATOR_LED_GPIO;
+    sio.gpio_oe_set.write(|w| unsafe { w.bits(led) });
+
+    loop {
+        for _ in 0..Fault::Panic.blinks() {
+            sio.gpio_out_set.write(|w| unsafe { w.bits(led) });
+            cortex_m::asm::delay(BLINK_MS * CYCLES_PER_MS);
+            sio.gpio_out_clr.write(|w| unsafe { w.bits(led) });
+            cortex_m::asm::delay(BLINK_MS * CYCLES_PER_MS);
+        }
+        cortex_m::asm::delay(PAUSE_MS * CYCLES_PER_MS);
+    }
+}
+
+/// Production builds have no debug probe to log to, so log messages go nowhere, unless
+/// they're sent over USB.
+#[cfg(all(feature = "production", not(feature = "usb-log")))]
+#[defmt::global_logger]
+struct DiscardLogger;
+
+#[cfg(all(feature = "production", not(feature = "usb-log")))]
+unsafe impl defmt::Logger for DiscardLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+/// The log messages waiting to go out over the serial port, in place of RTT.
+#[cfg(feature = "usb-log")]
+static USB_LOG: Mutex<RefCell<LogBuffer<USB_LOG_LEN>>> = Mutex::new(RefCell::new(LogBuffer::new()));
+
+/// How to leave the critical section a log message is written in.
+#[cfg(feature = "usb-log")]
+static USB_LOG_RESTORE: Mutex<Cell<RestoreState>> = Mutex::new(Cell::new(RestoreState::invalid()));
+
+/// Sends log messages over the USB serial port, see `usb_log`.
+#[cfg(feature = "usb-log")]
+#[defmt::global_logger]
+struct UsbLogger;
+
+#[cfg(feature = "usb-log")]
+unsafe impl defmt::Logger for UsbLogger {
+    fn acquire() {
+        // Note (safety): Left again in `release`, which defmt always calls next
+        let restore = unsafe { critical_section::acquire() };
+        // Note (safety): The critical section was entered just above
+        let cs = unsafe { CriticalSection::new() };
+        USB_LOG_RESTORE.borrow(cs).set(restore);
+        USB_LOG.borrow_ref_mut(cs).start_frame();
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let cs = CriticalSection::new();
+        USB_LOG.borrow_ref_mut(cs).end_frame();
+        critical_section::release(USB_LOG_RESTORE.borrow(cs).get());
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        USB_LOG.borrow_ref_mut(CriticalSection::new()).write(bytes);
+    }
+}
+
 #[cortex_m_rt::entry]
 fn main() -> ! {
     info!("Start of main()");
+    let last_crash = crash::take_last_crash();
+    if let Some(crash) = last_crash {
+        warn!("Restarted after a crash: {}", crash);
+    }
+
+    if bootloader::double_reset_detected() {
+        info!("Reset pressed twice, going into bootloader mode.");
+        bootloader::reboot_to_bootloader();
+    }
+
     let mut pac = pac::Peripherals::take().unwrap();
     let core = pac::CorePeripherals::take().unwrap();
 
@@ -101,58 +593,228 @@ fn main() -> ! {
     let pins =
         rp2040_hal::gpio::Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
 
-    // Set up keyboard matrix pins.
-    let rows: &[&dyn InputPin<Error = Infallible>] = &[
-        &pins.gpio26.into_pull_down_input(),
-        &pins.gpio25.into_pull_down_input(),
-        &pins.gpio27.into_pull_down_input(),
-        &pins.gpio28.into_pull_down_input(),
-        &pins.gpio15.into_pull_down_input(),
-        &pins.gpio24.into_pull_down_input(),
+    // Identify any expansion module before its ID line becomes column 0 of the matrix.
+    let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
+    let mut module_id_pin = module_id_pin!(pins);
+    let module_id_reading: u16 = adc.read(&mut module_id_pin).unwrap();
+    let expansion_module = Module::from_id_reading(module_id_reading).unwrap_or_else(|unknown| {
+        warn!("Unrecognized expansion module, ID reading {}", unknown.reading);
+        None
+    });
+    info!("Expansion module: {}", expansion_module);
+    let matrix_mask = expansion::matrix_mask(expansion_module);
+    // A split half only scans its own columns, which are wired to the first column pins.
+    #[cfg(feature = "split")]
+    let matrix_mask = split::SIDE.scan_mask(&matrix_mask);
+
+    // Set up keyboard matrix pins. The PIO scanner reads the sense lines straight from the
+    // GPIOs, so it only needs them pulled.
+    #[cfg(not(any(feature = "analog", feature = "pio-scan")))]
+    let sense_lines: &[&dyn InputPin<Error = Infallible>] = sense_pins!(pins, module_id_pin);
+    #[cfg(feature = "pio-scan")]
+    let _sense_lines: &[&dyn InputPin<Error = Infallible>] = sense_pins!(pins, module_id_pin);
+
+    // Analog boards sense every row through multiplexers on the first row's pin, and use
+    // the other row pins to control them.
+    #[cfg(feature = "analog")]
+    let mut sense_pin = pins.gpio26.into_floating_input();
+    #[cfg(feature = "analog")]
+    let row_select: &mut [&mut dyn OutputPin<Error = Infallible>] = &mut [
+        &mut pins.gpio25.into_push_pull_output(),
+        &mut pins.gpio27.into_push_pull_output(),
+        &mut pins.gpio28.into_push_pull_output(),
     ];
+    #[cfg(feature = "capacitive")]
+    let discharge = &mut pins.gpio15.into_push_pull_output();
+    #[cfg(feature = "capacitive")]
+    let mut capacitive_matrix = CapacitiveMatrix::default();
+    #[cfg(all(feature = "analog", not(feature = "capacitive")))]
+    let mut hall_effect_matrix = HallEffectMatrix::default();
+
+    #[cfg(not(feature = "pio-scan"))]
+    let drive_lines: &mut [&mut dyn OutputPin<Error = Infallible>] =
+        drive_pins!(pins, module_id_pin);
+    #[cfg(feature = "pio-scan")]
+    pio_drive_pins!(pins, module_id_pin);
 
-    let cols: &mut [&mut dyn OutputPin<Error = Infallible>] = &mut [
-        &mut pins.gpio29.into_push_pull_output(),
-        &mut pins.gpio16.into_push_pull_output(),
-        &mut pins.gpio17.into_push_pull_output(),
-        &mut pins.gpio18.into_push_pull_output(),
-        &mut pins.gpio9.into_push_pull_output(),
-        &mut pins.gpio10.into_push_pull_output(),
-        &mut pins.gpio19.into_push_pull_output(),
-        &mut pins.gpio11.into_push_pull_output(),
-        &mut pins.gpio12.into_push_pull_output(),
-        &mut pins.gpio13.into_push_pull_output(),
-        &mut pins.gpio14.into_push_pull_output(),
-        &mut pins.gpio20.into_push_pull_output(),
-        &mut pins.gpio22.into_push_pull_output(),
-        &mut pins.gpio23.into_push_pull_output(),
+    // Blinks out the most serious fault if anything has gone wrong, and otherwise pulses
+    // when it is time for a typing break.
+    let mut indicator_led = indicator_led_pin!(pins);
+    let mut fault_blinker = FaultBlinker::default();
+    let mut break_reminder = BreakReminder::new(TYPING_BREAK_INTERVAL_MIN * 60 * 1000);
+
+    // Lock LEDs, on the pins the radio would otherwise use. Change them to match the board.
+    #[cfg(feature = "lock-leds")]
+    let (mut caps_lock_led, mut num_lock_led, mut scroll_lock_led) = (
+        pins.gpio4.into_push_pull_output(),
+        pins.gpio5.into_push_pull_output(),
+        pins.gpio6.into_push_pull_output(),
+    );
+    #[cfg(feature = "lock-leds")]
+    let mut lock_led_pins: [(HostLed, &mut dyn OutputPin<Error = Infallible>); 3] = [
+        (HostLed::CapsLock, &mut caps_lock_led),
+        (HostLed::NumLock, &mut num_lock_led),
+        (HostLed::ScrollLock, &mut scroll_lock_led),
     ];
+    #[cfg(feature = "lock-leds")]
+    let mut lock_leds = LockLeds::new(&mut lock_led_pins);
 
     // Initialize a delay for accurate sleeping.
     let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
 
+    let mut keymap = Keymap::load().unwrap_or_else(|| {
+        info!("No keymap saved in flash, using the compiled-in one");
+        Keymap::default()
+    });
+    let mut macros = MacroBuffer::load().unwrap_or_default();
+
     let mut modifier_mask = [[false; NUM_ROWS]; NUM_COLS];
-    for (col, mapping_col) in modifier_mask.iter_mut().zip(key_mapping::NORMAL_LAYER_MAPPING) {
+    for (col, mapping_col) in modifier_mask.iter_mut().zip(keymap.layers()[0]) {
         for (key, mapping_key) in col.iter_mut().zip(mapping_col) {
             *key = mapping_key.is_modifier();
         }
     }
 
+    let mut settings = Settings::load().unwrap_or_default();
+    info!("Loaded settings, output: {}, profile: {}", settings.output, settings.profile());
+
     // Create a global debounce state to prevent unintended rapid key double-presses.
-    let mut debounce: Debounce<NUM_ROWS, NUM_COLS> = Debounce::new(DEBOUNCE_TICKS, modifier_mask);
+    let mut debounce = MatrixDebounce::new(debounce_ticks(settings.profile()), modifier_mask);
 
-    // Do an initial scan of the keys so that we immediately have something to report to the host when asked.
-    let scan = KeyScan::scan(rows, cols, &mut delay, &mut debounce);
+    let mut keyboard = Keyboard::new(settings.profile());
+    keyboard.set_keymap(&keymap);
+    keyboard.set_macros(&macros);
+    keyboard.set_config_locked(settings.config_locked);
+    keyboard.set_socd_mode(settings.socd_mode);
+    keyboard.set_rgb_settings(settings.rgb);
+    keyboard.set_analog_settings(settings.analog);
+    keyboard.set_auto_shift(settings.auto_shift);
+    keyboard.set_unicode_mode(settings.unicode_mode);
+    keyboard.set_default_layer(settings.default_layer as usize);
+    keyboard.set_mod_swaps(settings.mod_swaps);
+    keyboard.set_game_mode(settings.game_mode);
+    #[cfg(all(feature = "analog", not(feature = "capacitive")))]
+    hall_effect_matrix.set_settings(settings.analog);
+
+    // PIO1 runs the backlight on its first state machine, and the matrix scan on its second.
+    #[cfg(any(feature = "rgb", feature = "pio-scan"))]
+    let (mut pio1, _pio1_sm0, _pio1_sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
+
+    // The backlight's data line is on the pin the radio would otherwise use.
+    #[cfg(feature = "rgb")]
+    let backlight = {
+        let _data: Pin<Gpio7, FunctionPio1> = pins.gpio7.into_mode();
+        let buffer = cortex_m::singleton!(: [u32; MAX_LEDS] = [0; MAX_LEDS]).unwrap();
+        let ws2812 = Ws2812::new(
+            &mut pio1,
+            _pio1_sm0,
+            pac.DMA,
+            &mut pac.RESETS,
+            RGB_DATA_PIN,
+            clocks.system_clock.freq().to_Hz(),
+            buffer,
+        );
+        (RgbBacklight::new(&keymap.layers()[0], settings.rgb), ws2812)
+    };
+    #[cfg(all(feature = "rgb", not(feature = "dual-core")))]
+    let (mut rgb_backlight, mut ws2812) = backlight;
+
+    // Or the backlight runs on core 1 from here on, see `dual_core`.
+    #[cfg(feature = "dual-core")]
+    let mut backlight_link = {
+        let mut fifo = sio.fifo;
+        let mut multicore = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut fifo);
+        let (rgb_backlight, ws2812) = backlight;
+        // Note (safety): The stack is only ever handed to core 1, here
+        let stack = unsafe { &mut *addr_of_mut!(CORE1_STACK.mem) };
+        dual_core::spawn_backlight(&mut multicore.cores()[1], stack, rgb_backlight, ws2812)
+            .unwrap();
+        BacklightLink::default()
+    };
+    CONFIG_LOCKED.store(settings.config_locked, Ordering::Relaxed);
+    PANIC_ACTION.store(settings.panic_action.to_u8(), Ordering::Relaxed);
+    keyboard.set_expansion_module(expansion_module);
+
+    #[cfg(feature = "analog")]
+    let mut calibration = CalibrationTable::load().unwrap_or_default();
+    #[cfg(feature = "analog")]
+    let mut calibrator = Calibrator::default();
+
+    let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+    let mut scan_alarm = timer.alarm_0().unwrap();
+    scan_alarm.enable_interrupt();
     critical_section::with(|cs| {
-        KEYBOARD_REPORT.replace(cs, scan.into());
+        SCAN_ALARM.replace(cs, Some(scan_alarm));
     });
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+    }
+    // Scan as fast as this board's matrix allows.
+    #[cfg(not(feature = "analog"))]
+    let settle_us = {
+        let sense_gpios: &[u8] = if MATRIX_WIRING.drives_columns() { &ROW_PINS } else { &COL_PINS };
+        let settle_us = settle_calibration::measure(&timer, sense_gpios, MATRIX_WIRING.sense)
+            .unwrap_or_else(|err| {
+                warn!("Couldn't measure the matrix settle time, GPIO{} is stuck", err.gpio);
+                FAULT.raise(Fault::SelfTest);
+                COLUMN_SETTLE_US
+            });
+        info!("Matrix settle time: {} us", settle_us);
+        settle_us
+    };
+    #[cfg(not(any(feature = "analog", feature = "pio-scan")))]
+    let mut scanner = MatrixScanner::new(settle_us, MATRIX_WIRING);
+    #[cfg(feature = "pio-scan")]
+    let mut scanner = PioScanner::new(
+        &mut pio1,
+        _pio1_sm1,
+        &mut pac.RESETS,
+        MATRIX_WIRING,
+        settle_us,
+        clocks.system_clock.freq().to_Hz(),
+        cortex_m::singleton!(: ScanBuffers = ScanBuffers::new()).unwrap(),
+    );
+    #[cfg(feature = "scan-trace")]
+    let mut scan_trace: scan_trace::ScanTrace<SCAN_TRACE_LEN> = scan_trace::ScanTrace::default();
+
+    // Do an initial scan of the keys so that we immediately have something to report to the host when asked.
+    #[cfg(not(any(feature = "analog", feature = "pio-scan")))]
+    let raw_matrix =
+        KeyScan::read_raw(sense_lines, drive_lines, MATRIX_WIRING, &mut delay, &matrix_mask);
+    #[cfg(feature = "pio-scan")]
+    let raw_matrix = scanner.read_raw(&matrix_mask);
+    #[cfg(feature = "capacitive")]
+    let raw_matrix = capacitive_matrix.read_raw(
+        drive_lines,
+        row_select,
+        discharge,
+        &mut delay,
+        &matrix_mask,
+        &calibration,
+        || adc.read(&mut sense_pin).unwrap(),
+    );
+    #[cfg(all(feature = "analog", not(feature = "capacitive")))]
+    let raw_matrix = hall_effect_matrix.read_raw(
+        drive_lines,
+        row_select,
+        &mut delay,
+        &matrix_mask,
+        &calibration,
+        || adc.read(&mut sense_pin).unwrap(),
+    );
+    #[cfg(feature = "split")]
+    let raw_matrix = split::SIDE.place(&raw_matrix);
+    let scan = KeyScan::from_raw(raw_matrix, &mut debounce);
+    let report = keyboard.report(&scan);
+    unsafe {
+        // Note (safety): Reports are only published here, and read in the USB interrupt
+        KEYBOARD_REPORT.publish(report);
+    }
 
     // If the Escape key is pressed during power-on, we should go into bootloader mode.
     if scan[0][0] {
-        let gpio_activity_pin_mask = 0;
-        let disable_interface_mask = 0;
         info!("Escape key detected on boot, going into bootloader mode.");
-        rp2040_hal::rom_data::reset_to_usb_boot(gpio_activity_pin_mask, disable_interface_mask);
+        bootloader::reboot_to_bootloader();
     }
 
     info!("Initializing USB");
@@ -165,92 +827,1280 @@ fn main() -> ! {
         force_vbus_detect_bit,
         &mut pac.RESETS,
     );
-    let bus_allocator = UsbBusAllocator::new(usb_bus);
-    let bus_ref = unsafe {
-        // Note (safety): This is safe as interrupts haven't been started yet
-        USB_BUS = Some(bus_allocator);
-        // We are promising to the compiler not to take mutable access to this global
-        // variable while this reference exists!
-        USB_BUS.as_ref().unwrap()
-    };
+    // The classes borrow the allocator for as long as the keyboard runs.
+    let bus_ref: &'static UsbBusAllocator<usb::UsbBus> =
+        cortex_m::singleton!(: UsbBusAllocator<usb::UsbBus> = UsbBusAllocator::new(usb_bus))
+            .unwrap();
 
     let hid_endpoint = HIDClass::new_with_settings(
         bus_ref,
         hid_descriptor::KEYBOARD_REPORT_DESCRIPTOR,
         USB_POLL_RATE_MS,
+        // A boot keyboard, so BIOS and UEFI setup screens find it, which switches between
+        // boot and report protocol as the host asks.
         HidClassSettings {
-            subclass: HidSubClass::NoSubClass,
+            subclass: HidSubClass::Boot,
             protocol: HidProtocol::Keyboard,
+            config: ProtocolModeConfig::DefaultBehavior,
+            locale: HidCountryCode::US,
+        },
+    );
+
+    #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+    let mouse_hid_endpoint = HIDClass::new_with_settings(
+        bus_ref,
+        hid_descriptor::MOUSE_REPORT_DESCRIPTOR,
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Mouse,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::NotSupported,
+        },
+    );
+
+    #[cfg(feature = "nkro")]
+    let nkro_hid_endpoint = HIDClass::new_with_settings(
+        bus_ref,
+        hid_descriptor::NKRO_REPORT_DESCRIPTOR,
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Generic,
             config: ProtocolModeConfig::ForceReport,
             locale: HidCountryCode::US,
         },
     );
 
-    // https://github.com/obdev/v-usb/blob/7a28fdc685952412dad2b8842429127bc1cf9fa7/usbdrv/USB-IDs-for-free.txt#L128
-    let keyboard_usb_device = UsbDeviceBuilder::new(bus_ref, UsbVidPid(0x16c0, 0x27db))
-        .manufacturer("bschwind")
-        .product("key ripper")
-        .supports_remote_wakeup(true)
-        .build();
-    unsafe {
-        // Note (safety): This is safe as interrupts haven't been started yet
-        USB_HID = Some(hid_endpoint);
-        USB_DEVICE = Some(keyboard_usb_device);
+    let consumer_hid_endpoint = HIDClass::new_with_settings(
+        bus_ref,
+        hid_descriptor::CONSUMER_REPORT_DESCRIPTOR,
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Generic,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::NotSupported,
+        },
+    );
+
+    let system_hid_endpoint = HIDClass::new_with_settings(
+        bus_ref,
+        hid_descriptor::SYSTEM_REPORT_DESCRIPTOR,
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Generic,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::NotSupported,
+        },
+    );
+
+    let raw_hid_endpoint = HIDClass::new_with_settings(
+        bus_ref,
+        hid_descriptor::RAW_HID_REPORT_DESCRIPTOR,
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Generic,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::NotSupported,
+        },
+    );
+
+    let via_hid_endpoint = HIDClass::new_with_settings(
+        bus_ref,
+        hid_descriptor::VIA_REPORT_DESCRIPTOR,
+        USB_POLL_RATE_MS,
+        HidClassSettings {
+            subclass: HidSubClass::NoSubClass,
+            protocol: HidProtocol::Generic,
+            config: ProtocolModeConfig::ForceReport,
+            locale: HidCountryCode::NotSupported,
+        },
+    );
+
+    let webusb = WebUsbClass::new(bus_ref, &CONFIG_LOCKED, &WEBUSB_REQUEST, &WEBUSB_RESPONSE);
+    let dfu = DfuRuntimeClass::new(bus_ref, &DFU_DETACH_REQUESTED, &CONFIG_LOCKED);
+    #[cfg(feature = "usb-log")]
+    let serial = CdcAcmClass::new(bus_ref);
+
+    // The serial number is the flash chip's unique ID in hex, so keyboards running the same
+    // firmware can be told apart.
+    let serial_number =
+        cortex_m::singleton!(: [u8; 2 * UNIQUE_ID_LEN] = [0; 2 * UNIQUE_ID_LEN]).unwrap();
+    for (digits, byte) in serial_number.chunks_exact_mut(2).zip(flash::unique_id()) {
+        digits[0] = b"0123456789ABCDEF"[(byte >> 4) as usize];
+        digits[1] = b"0123456789ABCDEF"[(byte & 0xF) as usize];
     }
+    let serial_number = core::str::from_utf8(serial_number).unwrap();
+
+    let usb_device_builder =
+        UsbDeviceBuilder::new(bus_ref, UsbVidPid(USB_VENDOR_ID, USB_PRODUCT_ID))
+            .manufacturer(USB_MANUFACTURER)
+            .product(USB_PRODUCT)
+            .serial_number(serial_number)
+            .supports_remote_wakeup(true)
+            .max_power(MAX_POWER_MA)
+            .self_powered(USB_SELF_POWERED);
+    // The serial port's two interfaces are grouped by an interface association descriptor,
+    // which hosts only look for in a device declared as a composite with them.
+    #[cfg(feature = "usb-log")]
+    let usb_device_builder = usb_device_builder.composite_with_iads();
+    let keyboard_usb_device = usb_device_builder.build();
+    critical_section::with(|cs| {
+        USB.replace(
+            cs,
+            Some(Usb {
+                device: keyboard_usb_device,
+                hid: hid_endpoint,
+                webusb,
+                dfu,
+                #[cfg(feature = "nkro")]
+                nkro_hid: nkro_hid_endpoint,
+                consumer_hid: consumer_hid_endpoint,
+                system_hid: system_hid_endpoint,
+                raw_hid: raw_hid_endpoint,
+                via_hid: via_hid_endpoint,
+                #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+                mouse_hid: mouse_hid_endpoint,
+                #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+                resolution_multiplier: ResolutionMultiplierClass::new(MOUSE_INTERFACE),
+                #[cfg(feature = "usb-log")]
+                serial,
+            }),
+        );
+    });
     info!("Enabling USB interrupt handler");
     unsafe {
         pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ);
     }
+
+    #[cfg(feature = "wireless")]
+    let mut radio_link = {
+        let _spi_sclk = pins.gpio6.into_mode::<FunctionSpi>();
+        let _spi_mosi = pins.gpio7.into_mode::<FunctionSpi>();
+        let _spi_miso = pins.gpio4.into_mode::<FunctionSpi>();
+        let spi = Spi::<_, _, 8>::new(pac.SPI0).init(
+            &mut pac.RESETS,
+            clocks.peripheral_clock.freq(),
+            8.MHz(),
+            &embedded_hal::spi::MODE_0,
+        );
+        let csn = pins.gpio5.into_push_pull_output();
+        let ce = pins.gpio8.into_push_pull_output();
+
+        Nrf24::new(spi, csn, ce, Role::Transmitter, &mut delay)
+            .inspect_err(|err| {
+                warn!("Couldn't start the radio: {}", err);
+                FAULT.raise(Fault::SelfTest);
+            })
+            .map(RadioLink::new)
+            .ok()
+    };
+
+    #[cfg(feature = "ble")]
+    let mut ble_link = {
+        let tx = pins.gpio0.into_mode::<FunctionUart>();
+        let rx = pins.gpio1.into_mode::<FunctionUart>();
+        let uart = UartPeripheral::new(pac.UART0, (tx, rx), &mut pac.RESETS)
+            .enable(UartConfig::default(), clocks.peripheral_clock.freq())
+            .unwrap();
+
+        BleLink::new(uart)
+    };
+
+    #[cfg(feature = "split")]
+    let mut split_link = {
+        let tx = pins.gpio0.into_mode::<FunctionUart>();
+        let rx = pins.gpio1.into_mode::<FunctionUart>();
+        let mut config = UartConfig::default();
+        config.baudrate = split::BAUD_RATE.Hz();
+        let uart = UartPeripheral::new(pac.UART0, (tx, rx), &mut pac.RESETS)
+            .enable(config, clocks.peripheral_clock.freq())
+            .unwrap();
+
+        SplitLink::new(uart)
+    };
+
+    // Modules with their own protocol share the connector's two extra pins. The TrackPoint
+    // takes a while to start up, so this happens after USB is up and running in the
+    // interrupt.
+    #[cfg(feature = "trackpoint")]
+    let mut trackpoint = None;
+    let mut macropad = None;
+    match expansion_module {
+        #[cfg(feature = "trackpoint")]
+        Some(Module::TrackPoint) => {
+            let _data: Pin<Gpio2, FunctionPio0> = pins.gpio2.into_mode();
+            let _clock: Pin<Gpio3, FunctionPio0> = pins.gpio3.into_mode();
+            let (mut pio, sm0, sm1, _, _) = pac.PIO0.split(&mut pac.RESETS);
+            let host =
+                Ps2Host::new(&mut pio, sm0, sm1, PS2_DATA_PIN, clocks.system_clock.freq().to_Hz());
+
+            trackpoint = TrackPoint::init(host, &mut delay)
+                .inspect_err(|err| {
+                    warn!("Couldn't start the TrackPoint: {}", err);
+                    FAULT.raise(Fault::SelfTest);
+                })
+                .ok();
+        },
+        Some(Module::MacroPad) => {
+            let i2c = I2C::i2c1(
+                pac.I2C1,
+                pins.gpio2.into_mode::<FunctionI2C>(),
+                pins.gpio3.into_mode::<FunctionI2C>(),
+                MACROPAD_I2C_FREQUENCY_KHZ.kHz(),
+                &mut pac.RESETS,
+                clocks.system_clock.freq(),
+            );
+
+            macropad = MacroPad::init(i2c)
+                .inspect(|pad| info!("Macropad has {} keys", pad.key_count()))
+                .inspect_err(|err| {
+                    warn!("Couldn't start the macropad: {}", err);
+                    FAULT.raise(Fault::SelfTest);
+                })
+                .ok();
+        },
+        _ => {},
+    }
+    #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+    let mut pointer = Pointer::default();
+
+    let mut kvm_hotkey = HotkeyPlayer::default();
+    let mut usb_stress = StressTest::default();
+    #[cfg(feature = "kvm-mux")]
+    let mut kvm_mux_select = pins.gpio0.into_push_pull_output();
+    #[cfg(feature = "kvm-mux")]
+    kvm_mux_select.set_state(PinState::from(settings.output == Output::Secondary)).unwrap();
+
+    let mut usb_stall_detector = StallDetector::default();
+    let mut previous_report_contents = (report.modifier, report.keycodes);
+    // The keys held at boot are already in the first report.
+    let mut key_events: KeyEvents<KEY_EVENT_QUEUE_LEN> = KeyEvents::default();
+    key_events.record(0, &scan);
+    while key_events.advance().is_some() {}
+    let mut report_waiting = false;
+    let mut key_events_full = false;
+    let mut key_events_delay_ms = 0;
+    let mut previous_consumer_usage = 0;
+    let mut previous_system_usage = 0;
+    let mut raw_hid = RawHid::default();
+    raw_hid.set_last_crash(last_crash);
+    raw_hid.set_idle_settings(settings.idle);
+    raw_hid.set_panic_log(PanicLog::load());
+    raw_hid.set_panic_action(settings.panic_action);
+    let mut idle_timer = IdleTimer::new(settings.idle);
+    let mut matrix_check = MatrixCheck::new(cfg!(feature = "ghost-suppression"));
+    let mut bounce_stats = BounceStats::default();
+    let mut press_timer = PressTimer::default();
+    let mut latency_logged_samples = 0;
+    let mut via = Via::new(settings.layout_options);
+    #[cfg(feature = "nkro")]
+    let mut nkro_active = true;
+    #[cfg(feature = "nkro")]
+    let mut previous_nkro_report = NkroReport::EMPTY;
+
+    // From here on, the keyboard resets if the main loop gets stuck. rp2040-hal 0.6 loads
+    // the watchdog with the period in milliseconds, while it counts microseconds, so the
+    // period it's given is a thousand times longer.
+    watchdog.start(MicrosDurationU32::millis(WATCHDOG_TIMEOUT_MS * 1000));
+    crash::watchdog_started();
+
     info!("Entering main loop");
+    let mut next_scan_at = timer.get_counter();
+    let mut was_suspended = false;
+    let mut double_reset_window_open = true;
     loop {
-        let scan = KeyScan::scan(rows, cols, &mut delay, &mut debounce);
-        critical_section::with(|cs| {
-            KEYBOARD_REPORT.replace(cs, scan.into());
+        watchdog.feed();
+
+        // Scan one line of the matrix at a time, sleeping while each settles.
+        #[cfg(not(any(feature = "analog", feature = "pio-scan")))]
+        let mut raw_matrix = loop {
+            match scanner.advance(sense_lines, drive_lines, &matrix_mask) {
+                ScanStep::Wait(settle_us) => sleep(MicrosDurationU32::micros(settle_us)),
+                ScanStep::Done(raw_matrix) => break raw_matrix,
+            }
+        };
+        // Or have the PIO scan the whole matrix, sleeping until it should be done.
+        #[cfg(feature = "pio-scan")]
+        let mut raw_matrix = {
+            scanner.start();
+            sleep(MicrosDurationU32::micros(scanner.scan_time_us()));
+            loop {
+                if let Some(raw_matrix) = scanner.poll(&matrix_mask) {
+                    break raw_matrix;
+                }
+            }
+        };
+        #[cfg(feature = "capacitive")]
+        let mut raw_matrix = capacitive_matrix.read_raw(
+            drive_lines,
+            row_select,
+            discharge,
+            &mut delay,
+            &matrix_mask,
+            &calibration,
+            || adc.read(&mut sense_pin).unwrap(),
+        );
+        #[cfg(all(feature = "analog", not(feature = "capacitive")))]
+        let mut raw_matrix = hall_effect_matrix.read_raw(
+            drive_lines,
+            row_select,
+            &mut delay,
+            &matrix_mask,
+            &calibration,
+            || adc.read(&mut sense_pin).unwrap(),
+        );
+        // A split half's keys move from the columns they're wired to into its own.
+        #[cfg(feature = "split")]
+        {
+            raw_matrix = split::SIDE.place(&raw_matrix);
+        }
+
+        // The macropad's keys are part of the matrix from here on.
+        if let Some(macropad) = &mut macropad {
+            match macropad.read_keys() {
+                Ok(keys) => macropad::apply_keys(keys, &mut raw_matrix),
+                Err(err) => warn!("Macropad error: {}", err),
+            }
+        }
+        // So are the other half's, once it has this half's own keys.
+        #[cfg(feature = "split")]
+        {
+            if let Some(connected) = split_link.poll(timer.get_counter() / 1000, &raw_matrix) {
+                info!("Other half {}", if connected { "connected" } else { "disconnected" });
+            }
+            split::merge(split_link.remote_matrix(), &mut raw_matrix);
+        }
+        #[cfg(feature = "capacitive")]
+        if calibrator.is_running() {
+            calibrator.sample(capacitive_matrix.readings());
+        }
+        #[cfg(all(feature = "analog", not(feature = "capacitive")))]
+        if calibrator.is_running() {
+            calibrator.sample(hall_effect_matrix.readings());
+        }
+        #[cfg(feature = "scan-trace")]
+        scan_trace.record((timer.get_counter() / 1000) as u32, &raw_matrix);
+
+        let now_ms = timer.get_counter() / 1000;
+        if double_reset_window_open && now_ms >= bootloader::DOUBLE_RESET_WINDOW_MS {
+            bootloader::end_double_reset_window();
+            double_reset_window_open = false;
+        }
+
+        let raw_matrix = matrix_check.check(now_ms, &raw_matrix);
+        let scan = KeyScan::from_raw(raw_matrix, &mut debounce);
+        bounce_stats.record(&raw_matrix, &scan);
+        press_timer.scan(timer.get_counter_low(), &raw_matrix);
+        // While the host is asleep, the keyboard scans less often and turns off its LEDs, to
+        // stay within the current a suspended device may draw.
+        let suspended = USB_SUSPENDED.load(Ordering::Relaxed);
+        if suspended != was_suspended {
+            info!("USB {}", if suspended { "suspended" } else { "resumed" });
+            was_suspended = suspended;
+        }
+
+        // No USB interrupts come in while the bus is suspended, so the wakeup is handed to
+        // the interrupt by triggering it.
+        if suspended && scan.iter().flatten().any(|pressed| *pressed) {
+            USB_WAKEUP_REQUESTED.store(true, Ordering::Relaxed);
+            pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+        }
+        break_reminder.update(now_ms, scan.iter().flatten().any(|pressed| *pressed));
+        idle_timer.update(now_ms, scan.iter().flatten().any(|pressed| *pressed));
+        if let Some(index) = idle_timer.take_away_macro() {
+            info!("Keyboard is idle, playing the away macro");
+            keyboard.play_macro(index as usize);
+        }
+        keyboard.set_host_leds(HostLeds::from_report(HOST_LEDS.load(Ordering::Relaxed)));
+        #[cfg(feature = "lock-leds")]
+        lock_leds.show(if suspended { HostLeds::from_report(0) } else { keyboard.host_leds() });
+
+        // Reports are built from the key events, as many as fit in each, and only move on
+        // to the next once the last is queued. While the report queue has room, the events
+        // still waiting get reports of their own in the same scan, so nothing pressed while
+        // the queue is full is lost, and a backlog clears as fast as the host reads it. Only
+        // the first report moves the keyboard's timers on, as the scan is one tick however
+        // many reports it builds.
+        if key_events.record(now_ms, &scan) {
+            key_events_full = false;
+        } else if !key_events_full {
+            warn!("Key event queue full");
+            key_events_full = true;
+        }
+        let mut queue_full = false;
+        let mut ticked = false;
+        // Bluetooth and the radio are sent the last report built in the scan.
+        #[cfg(any(feature = "ble", feature = "wireless"))]
+        let mut link_report;
+        loop {
+            if !report_waiting {
+                if let Some(event) = key_events.advance() {
+                    key_events_delay_ms = key_events_delay_ms.max(now_ms - event.time_ms);
+                }
+            }
+            if !key_events.is_waiting() {
+                if key_events_delay_ms > KEY_EVENT_DELAY_LOG_MS {
+                    info!("Key events caught up, after waiting up to {} ms", key_events_delay_ms);
+                }
+                key_events_delay_ms = 0;
+            }
+            let key_scan = KeyScan::from(*key_events.matrix());
+            let report = if ticked {
+                keyboard.report_within_tick(&key_scan)
+            } else {
+                keyboard.report(&key_scan)
+            };
+            ticked = true;
+
+            // The KVM's hotkey, or the USB stress test, takes over from the keys until it's done.
+            let hotkey_report = kvm_hotkey.next_report().or_else(|| usb_stress.next_report());
+            let report = hotkey_report.unwrap_or(report);
+
+            // A press is timed to the report carrying it, which a hotkey or stress test replaces.
+            let mut pressed_at_us =
+                press_timer.report(&keyboard.nkro_report()).filter(|_| hotkey_report.is_none());
+
+            // With N-key rollover, keys go to the host on the NKRO interface and the boot
+            // keyboard stays empty, except while typing a KVM hotkey, which KVMs only see on the
+            // boot keyboard, or running the stress test, or while the host only reads the boot
+            // keyboard in boot protocol. Game mode turns it on, whatever it was toggled to.
+            #[cfg(feature = "nkro")]
+            let nkro = nkro_active || keyboard.game_mode();
+            #[cfg(feature = "nkro")]
+            let (usb_report, nkro_report) =
+                if nkro && hotkey_report.is_none() && !USB_BOOT_PROTOCOL.load(Ordering::Relaxed) {
+                    let empty =
+                        KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes: [0; 6] };
+                    (empty, keyboard.nkro_report())
+                } else {
+                    (report, NkroReport::EMPTY)
+                };
+            #[cfg(not(feature = "nkro"))]
+            let usb_report = report;
+
+            unsafe {
+                // Note (safety): Reports are only published here, and read in the USB interrupt
+                KEYBOARD_REPORT.publish(usb_report);
+                CONSUMER_REPORT.publish(keyboard.consumer_usage());
+                SYSTEM_REPORT.publish(keyboard.system_usage());
+                #[cfg(feature = "nkro")]
+                NKRO_REPORT.publish(nkro_report);
+            }
+
+            // Queue every change, so the host sees it even if the endpoint is busy for a while.
+            // A change which doesn't fit in a full queue is tried again on the next scan, rather
+            // than counted as sent, and holds back the key events after it.
+            report_waiting = false;
+            let report_contents = (usb_report.modifier, usb_report.keycodes);
+            if report_contents != previous_report_contents && USB_CONFIGURED.load(Ordering::Relaxed)
+            {
+                time_press(&KEYBOARD_REPORT_QUEUE, &KEYBOARD_PRESS, pressed_at_us.take());
+                // Note (safety): Reports are only queued here, and taken in the USB interrupt
+                if unsafe { KEYBOARD_REPORT_QUEUE.push(usb_report) } {
+                    previous_report_contents = report_contents;
+                } else {
+                    warn!("Keyboard report queue full");
+                    queue_full = true;
+                    report_waiting = true;
+                }
+            }
+            if keyboard.consumer_usage() != previous_consumer_usage
+                && USB_CONFIGURED.load(Ordering::Relaxed)
+            {
+                // Note (safety): Reports are only queued here, and taken in the USB interrupt
+                if unsafe { CONSUMER_REPORT_QUEUE.push(keyboard.consumer_usage()) } {
+                    previous_consumer_usage = keyboard.consumer_usage();
+                } else {
+                    warn!("Consumer report queue full");
+                }
+            }
+            if keyboard.system_usage() != previous_system_usage
+                && USB_CONFIGURED.load(Ordering::Relaxed)
+            {
+                // Note (safety): Reports are only queued here, and taken in the USB interrupt
+                if unsafe { SYSTEM_REPORT_QUEUE.push(keyboard.system_usage()) } {
+                    previous_system_usage = keyboard.system_usage();
+                } else {
+                    warn!("System report queue full");
+                }
+            }
+            #[cfg(feature = "nkro")]
+            if nkro_report != previous_nkro_report && USB_CONFIGURED.load(Ordering::Relaxed) {
+                time_press(&NKRO_REPORT_QUEUE, &NKRO_PRESS, pressed_at_us.take());
+                // Note (safety): Reports are only queued here, and taken in the USB interrupt
+                if unsafe { NKRO_REPORT_QUEUE.push(nkro_report) } {
+                    previous_nkro_report = nkro_report;
+                } else {
+                    warn!("N-key rollover report queue full");
+                    report_waiting = true;
+                }
+            }
+
+            #[cfg(any(feature = "ble", feature = "wireless"))]
+            {
+                link_report = report;
+            }
+            if report_waiting || hotkey_report.is_some() || !key_events.is_waiting() {
+                break;
+            }
+        }
+        usb_stress.record(queue_full, USB_REPORT_BLOCKED.load(Ordering::Relaxed));
+        if let Some(stats) = usb_stress.take_stats() {
+            info!("USB stress test finished: {}", stats);
+        }
+
+        #[cfg(feature = "rgb")]
+        {
+            let mut rgb_settings = keyboard.rgb_settings();
+            rgb_settings.enabled &= !suspended;
+            // Until the host configures the keyboard, it hasn't been granted even the 100 mA
+            // `low-power` asks for, so the backlight waits for that.
+            #[cfg(feature = "low-power")]
+            {
+                rgb_settings.enabled &= USB_CONFIGURED.load(Ordering::Relaxed);
+            }
+            match idle_timer.backlight_brightness(rgb_settings.brightness) {
+                Some(brightness) => rgb_settings.brightness = brightness,
+                None => rgb_settings.enabled = false,
+            }
+
+            #[cfg(not(feature = "dual-core"))]
+            {
+                rgb_backlight.set_settings(rgb_settings);
+                rgb_backlight.set_locked_modifiers(keyboard.locked_modifiers());
+                let mapping = keyboard.layer_mapping();
+                if let Some(frame) = rgb_backlight.update(now_ms, &scan, &mapping) {
+                    ws2812.write(frame);
+                }
+            }
+            #[cfg(feature = "dual-core")]
+            backlight_link.update(
+                now_ms,
+                rgb_settings,
+                keyboard.locked_modifiers(),
+                &scan,
+                &keyboard.layer_mapping(),
+            );
+        }
+
+        if keyboard.take_output_switch_request() {
+            settings.output = settings.output.toggled();
+            info!("Switching to output {}", settings.output);
+
+            #[cfg(not(feature = "kvm-mux"))]
+            kvm_hotkey.start(settings.output);
+            #[cfg(feature = "kvm-mux")]
+            kvm_mux_select.set_state(PinState::from(settings.output == Output::Secondary)).unwrap();
+
+            keyboard.set_profile(settings.profile());
+            debounce.set_expiration_ticks(debounce_ticks(settings.profile()));
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if keyboard.take_stress_test_request() {
+            info!("Starting the USB stress test");
+            usb_stress.start();
+        }
+
+        // Reports go over USB when it's connected, then Bluetooth when the module has a
+        // host, and the radio otherwise.
+        #[cfg(any(feature = "wireless", feature = "ble"))]
+        let other_link_active = USB_CONFIGURED.load(Ordering::Relaxed);
+
+        #[cfg(feature = "ble")]
+        if let Some(status) = ble_link.poll() {
+            info!("Bluetooth module is now {}", status);
+        }
+        #[cfg(feature = "ble")]
+        let mut ble_link = Some(&mut ble_link)
+            .filter(|link| !other_link_active && link.status() == BleStatus::Connected);
+        #[cfg(feature = "ble")]
+        if let Some(ble_link) = &mut ble_link {
+            ble_link.send_keyboard(&link_report);
+        }
+        #[cfg(all(feature = "wireless", feature = "ble"))]
+        let other_link_active = other_link_active || ble_link.is_some();
+
+        #[cfg(feature = "wireless")]
+        let mut radio_link = radio_link.as_mut().filter(|_| !other_link_active);
+        #[cfg(feature = "wireless")]
+        if let Some(radio_link) = &mut radio_link {
+            radio_link.send_keyboard(&link_report, &mut delay);
+        }
+
+        #[cfg(feature = "trackpoint")]
+        if let Some(trackpoint) = &mut trackpoint {
+            loop {
+                match trackpoint.poll() {
+                    Ok(Some(packet)) => pointer.motion(packet.buttons, packet.x, packet.y),
+                    Ok(None) => break,
+                    Err(err) => warn!("TrackPoint error: {}", err),
+                }
+            }
+        }
+        #[cfg(feature = "mouse-keys")]
+        pointer.key_motion(keyboard.take_mouse_motion());
+
+        #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+        {
+            let over_usb = true;
+
+            #[cfg(feature = "ble")]
+            let over_usb = over_usb && ble_link.is_none();
+            #[cfg(feature = "ble")]
+            if let Some(ble_link) = &mut ble_link {
+                if let Some(report) = pointer.take_report() {
+                    ble_link.send_mouse(&report);
+                }
+            }
+
+            #[cfg(feature = "wireless")]
+            let over_usb = over_usb && radio_link.is_none();
+            #[cfg(feature = "wireless")]
+            if let Some(radio_link) = &mut radio_link {
+                if let Some(report) = pointer.take_report() {
+                    radio_link.send_mouse(&report, &mut delay);
+                }
+            }
+
+            if over_usb {
+                critical_section::with(|cs| {
+                    pointer.set_resolution_multipliers(*RESOLUTION_MULTIPLIERS.borrow_ref(cs));
+
+                    let mut mouse_report = MOUSE_REPORT.borrow_ref_mut(cs);
+                    if mouse_report.is_none() {
+                        *mouse_report = pointer.take_report();
+                    }
+                });
+            }
+        }
+
+        for (request, response) in
+            [(&RAW_HID_REQUEST, &RAW_HID_RESPONSE), (&WEBUSB_REQUEST, &WEBUSB_RESPONSE)]
+        {
+            if let Some(request) = critical_section::with(|cs| request.take(cs)) {
+                raw_hid.set_matrix_stats(matrix_check.stats());
+                raw_hid.set_bounce_stats(&bounce_stats);
+                raw_hid
+                    .set_latency_stats(critical_section::with(|cs| LATENCY_STATS.borrow(cs).get()));
+                let config_locked = CONFIG_LOCKED.load(Ordering::Relaxed);
+                let response_bytes = raw_hid.handle(&request, &mut keymap, &scan, config_locked);
+                keyboard.set_keymap(&keymap);
+                critical_section::with(|cs| response.replace(cs, Some(response_bytes)));
+            }
+        }
+
+        if let Some(request) = critical_section::with(|cs| VIA_REQUEST.take(cs)) {
+            let response = via.handle(
+                &request,
+                &mut keymap,
+                &mut macros,
+                &scan,
+                now_ms,
+                CONFIG_LOCKED.load(Ordering::Relaxed),
+            );
+            keyboard.set_keymap(&keymap);
+            keyboard.set_macros(&macros);
+            critical_section::with(|cs| VIA_RESPONSE.replace(cs, Some(response)));
+        }
+
+        if raw_hid.take_latency_reset_request() {
+            info!("Resetting the latency stats");
+            critical_section::with(|cs| LATENCY_STATS.borrow(cs).set(LatencyStats::default()));
+            latency_logged_samples = 0;
+        }
+        let latency = critical_section::with(|cs| LATENCY_STATS.borrow(cs).get());
+        if latency.samples >= latency_logged_samples + LATENCY_LOG_SAMPLES {
+            info!(
+                "Latency over {} presses: min {} us, average {} us, max {} us",
+                latency.samples,
+                latency.min_us,
+                latency.average_us(),
+                latency.max_us
+            );
+            latency_logged_samples = latency.samples;
+        }
+
+        if raw_hid.take_keymap_save_request() | via.take_keymap_save_request() {
+            info!("Saving the keymap");
+            keymap.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if keyboard.take_macro_recorded() {
+            macros = *keyboard.macros();
+            #[cfg(feature = "save-recorded-macro")]
+            {
+                info!("Saving the recorded macro");
+                macros.save().unwrap_or_else(flash_write_failed);
+            }
+        }
+
+        if via.take_macros_save_request() {
+            info!("Saving the macros");
+            macros.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if via.layout_options() != settings.layout_options {
+            settings.layout_options = via.layout_options();
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        // Settings changed in settings mode are saved once it's left with them.
+        let settings_mode = keyboard.in_settings_mode();
+
+        if !settings_mode && keyboard.profile() != settings.profile() {
+            info!("Switching to profile {}", keyboard.profile());
+            settings.set_profile(keyboard.profile());
+            debounce.set_expiration_ticks(debounce_ticks(settings.profile()));
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if keyboard.config_locked() != settings.config_locked {
+            info!("Configuration lock is now {}", keyboard.config_locked());
+            settings.config_locked = keyboard.config_locked();
+            CONFIG_LOCKED.store(settings.config_locked, Ordering::Relaxed);
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if !settings_mode && keyboard.socd_mode() != settings.socd_mode {
+            info!("SOCD cleaning is now {}", keyboard.socd_mode());
+            settings.socd_mode = keyboard.socd_mode();
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if !settings_mode && keyboard.rgb_settings() != settings.rgb {
+            info!("Backlight is now {}", keyboard.rgb_settings());
+            settings.rgb = keyboard.rgb_settings();
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if keyboard.analog_settings() != settings.analog {
+            info!("Analog keys are now {}", keyboard.analog_settings());
+            settings.analog = keyboard.analog_settings();
+            #[cfg(all(feature = "analog", not(feature = "capacitive")))]
+            hall_effect_matrix.set_settings(settings.analog);
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if raw_hid.idle_settings() != settings.idle {
+            info!("Idle settings are now {}", raw_hid.idle_settings());
+            settings.idle = raw_hid.idle_settings();
+            idle_timer.set_settings(settings.idle);
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if raw_hid.panic_action() != settings.panic_action {
+            info!("Panics now {}", raw_hid.panic_action());
+            settings.panic_action = raw_hid.panic_action();
+            PANIC_ACTION.store(settings.panic_action.to_u8(), Ordering::Relaxed);
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if !settings_mode && keyboard.auto_shift() != settings.auto_shift {
+            info!("Auto Shift is now {}", keyboard.auto_shift());
+            settings.auto_shift = keyboard.auto_shift();
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if !settings_mode && keyboard.unicode_mode() != settings.unicode_mode {
+            info!("Unicode input is now for {}", keyboard.unicode_mode());
+            settings.unicode_mode = keyboard.unicode_mode();
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if !settings_mode && keyboard.default_layer() != settings.default_layer as usize {
+            info!("The default layer is now {}", keyboard.default_layer());
+            settings.default_layer = keyboard.default_layer() as u8;
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if keyboard.mod_swaps() != settings.mod_swaps {
+            info!("Modifier swaps are now {}", keyboard.mod_swaps());
+            settings.mod_swaps = keyboard.mod_swaps();
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if keyboard.game_mode() != settings.game_mode {
+            info!("Game mode is now {}", keyboard.game_mode());
+            #[cfg(not(feature = "nkro"))]
+            warn!(
+                "Game mode sends the boot keyboard report, as the firmware was built without nkro"
+            );
+            settings.game_mode = keyboard.game_mode();
+            settings.save().unwrap_or_else(flash_write_failed);
+        }
+
+        if keyboard.take_nkro_toggle_request() {
+            #[cfg(feature = "nkro")]
+            {
+                nkro_active = !nkro_active;
+                info!("N-key rollover is now {}", nkro_active);
+            }
+
+            #[cfg(not(feature = "nkro"))]
+            warn!("N-key rollover toggled, but the firmware was built without nkro");
+        }
+
+        if keyboard.take_calibration_request() {
+            #[cfg(feature = "analog")]
+            if calibrator.is_running() {
+                info!("Finishing analog key calibration");
+                calibration = calibrator.finish(&calibration);
+                calibration.save().unwrap_or_else(flash_write_failed);
+            } else {
+                info!("Starting analog key calibration, release all keys");
+                calibrator.start();
+            }
+
+            #[cfg(not(feature = "analog"))]
+            warn!("Analog key calibration requested, but the firmware was built without analog support");
+        }
+
+        if keyboard.take_trace_save_request() {
+            #[cfg(feature = "scan-trace")]
+            {
+                info!("Saving scan trace");
+                scan_trace.save().unwrap_or_else(flash_write_failed);
+            }
+
+            #[cfg(not(feature = "scan-trace"))]
+            warn!("Saving a scan trace requested, but the firmware was built without scan-trace");
+        }
+
+        if keyboard.take_trace_replay_request() {
+            replay_scan_trace(settings.profile(), modifier_mask);
+        }
+
+        if usb_stall_detector.tick(
+            USB_CONFIGURED.load(Ordering::Relaxed),
+            usb_frame_number(),
+            USB_REPORT_BLOCKED.load(Ordering::Relaxed),
+        ) {
+            warn!("USB looks stuck, reconnecting to the host");
+            reconnect_usb(&mut delay);
+        }
+
+        if via.take_bootloader_request() {
+            info!("Host asked for the bootloader over VIA, going into bootloader mode.");
+            delay.delay_ms(DFU_DETACH_DELAY_MS);
+            bootloader::reboot_to_bootloader();
+        }
+
+        if keyboard.take_bootloader_request() {
+            info!("Bootloader key pressed, going into bootloader mode.");
+            bootloader::reboot_to_bootloader();
+        }
+
+        if DFU_DETACH_REQUESTED.load(Ordering::Relaxed) {
+            info!("Host asked to detach over DFU, going into bootloader mode.");
+            // Give the host time to see the request accepted before the keyboard disappears.
+            delay.delay_ms(DFU_DETACH_DELAY_MS);
+            bootloader::reboot_to_bootloader();
+        }
+
+        if keyboard.take_break_snooze_request() {
+            info!("Snoozing the typing break reminder");
+            break_reminder.snooze(now_ms);
+        }
+
+        // Faults take over the indicator from settings mode, both from typing break
+        // reminders, and all of them from game mode.
+        let fault = FAULT.fault();
+        let fault_led_lit = fault_blinker.tick(fault);
+        let indicator_lit = fault_led_lit
+            || (fault.is_none()
+                && keyboard.settings_mode_led_lit().unwrap_or_else(|| {
+                    if break_reminder.is_due(now_ms) {
+                        break_reminder.led_lit(now_ms)
+                    } else {
+                        keyboard.game_mode()
+                    }
+                }));
+        indicator_led.set_state(PinState::from(indicator_lit && !suspended)).unwrap();
+
+        // Scans start on a fixed schedule, so a slow USB write or radio send shortens the
+        // wait for the next scan rather than pushing every later scan back. After falling
+        // more than a whole period behind, such as while saving to flash, start afresh.
+        // Going idle slows the scans down too, though not as far, except in game mode, where
+        // the first press after a pause counts as much as any other.
+        let scan_period_us = match (suspended, idle_timer.state()) {
+            (true, _) => SUSPENDED_SCAN_PERIOD_US,
+            (false, IdleState::Active) => SCAN_PERIOD_US,
+            (false, _) if keyboard.game_mode() => SCAN_PERIOD_US,
+            (false, IdleState::Idle | IdleState::Asleep) => IDLE_SCAN_PERIOD_US,
+        };
+        next_scan_at += scan_period_us;
+        let now = timer.get_counter();
+        if now > next_scan_at + scan_period_us {
+            next_scan_at = now;
+        }
+        sleep_until(&timer, next_scan_at);
+    }
+}
+
+fn flash_write_failed(err: WriteError) {
+    error!("Writing to flash failed: {}", err);
+    FAULT.raise(Fault::FlashWrite);
+}
+
+/// The number of the last USB frame started by the host, which goes up every millisecond
+/// while the bus is active.
+fn usb_frame_number() -> u16 {
+    // Note (safety): This only reads a register
+    unsafe { (*pac::USBCTRL_REGS::ptr()).sof_rd.read().count().bits() }
+}
+
+/// The timer's count in microseconds, wrapping every 71 minutes, for the USB interrupt,
+/// which can't borrow the main loop's `Timer`.
+fn timer_now_us() -> u32 {
+    // Note (safety): This only reads a register
+    unsafe { (*pac::TIMER::ptr()).timerawl.read().bits() }
+}
+
+/// Mark the next report pushed to `queue` as carrying the press scanned at `pressed_at_us`,
+/// if there is one. It stays marked if the queue is full, as the report is tried again.
+fn time_press<T: Copy, const N: usize>(
+    queue: &ReportQueue<T, N>,
+    in_flight: &Mutex<Cell<Option<InFlightPress>>>,
+    pressed_at_us: Option<u32>,
+) {
+    if let Some(pressed_at_us) = pressed_at_us {
+        let press = InFlightPress { pressed_at_us, report_index: queue.pushed() };
+        critical_section::with(|cs| in_flight.borrow(cs).set(Some(press)));
+    }
+}
+
+/// Record the latency of the press marked by `time_press` once the report carrying it has
+/// gone from `queue` to the endpoint.
+fn record_latency<T: Copy, const N: usize>(
+    queue: &ReportQueue<T, N>,
+    in_flight: &Mutex<Cell<Option<InFlightPress>>>,
+) {
+    critical_section::with(|cs| {
+        let in_flight = in_flight.borrow(cs);
+        if let Some(press) = in_flight.get().filter(|press| press.is_sent(queue.popped())) {
+            in_flight.set(None);
+            let stats = LATENCY_STATS.borrow(cs);
+            let mut latency = stats.get();
+            latency.record(timer_now_us().wrapping_sub(press.pressed_at_us));
+            stats.set(latency);
+        }
+    });
+}
+
+/// Disconnect from the host and connect again, so it resets the bus and enumerates the
+/// keyboard from scratch.
+fn reconnect_usb(delay: &mut cortex_m::delay::Delay) {
+    // `UsbBus` doesn't have a way to do this, but it only changes SIE_CTRL within a critical
+    // section too.
+    let set_pullup = |enabled| {
+        critical_section::with(|_| unsafe {
+            (*pac::USBCTRL_REGS::ptr()).sie_ctrl.modify(|_, w| w.pullup_en().bit(enabled));
         });
-        delay.delay_ms(SCAN_LOOP_RATE_MS);
+    };
+
+    set_pullup(false);
+    delay.delay_ms(USB_DISCONNECT_MS);
+    set_pullup(true);
+}
+
+/// Sleep until the timer's counter reaches `deadline`, in microseconds.
+fn sleep_until(timer: &Timer, deadline: u64) {
+    let remaining = deadline.saturating_sub(timer.get_counter());
+    if remaining >= MIN_ALARM_US {
+        sleep(MicrosDurationU32::micros(remaining as u32));
     }
+
+    // The alarm can't be set any closer than `MIN_ALARM_US`, so wait out the rest.
+    while timer.get_counter() < deadline {}
+}
+
+/// Sleep for `duration`, waking up only to handle interrupts.
+fn sleep(duration: MicrosDurationU32) {
+    critical_section::with(|cs| {
+        SCAN_ALARM.borrow_ref_mut(cs).as_mut().unwrap().schedule(duration).unwrap();
+    });
+
+    // Interrupts are masked while checking the alarm, so one can't slip in between the
+    // check and the `wfi`. It still wakes the core, and is handled once they're unmasked.
+    while critical_section::with(|cs| {
+        let finished = SCAN_ALARM.borrow_ref(cs).as_ref().unwrap().finished();
+        if !finished {
+            cortex_m::asm::wfi();
+        }
+        !finished
+    }) {}
+}
+
+/// Replay the saved scan trace through a fresh debouncer and keyboard, logging every report
+/// it produces. Nothing is sent to the host.
+fn replay_scan_trace(profile: Profile, modifier_mask: [[bool; NUM_ROWS]; NUM_COLS]) {
+    let Some(entries) = scan_trace::load() else {
+        warn!("No scan trace has been saved");
+        return;
+    };
+
+    info!("Replaying the saved scan trace");
+    let mut debounce = MatrixDebounce::new(debounce_ticks(profile), modifier_mask);
+    let mut keyboard = Keyboard::new(profile);
+    let settle_ms = profile.settings().debounce_ms as u32;
+
+    scan_trace::replay(entries, &mut debounce, &mut keyboard, settle_ms, |time_ms, report| {
+        info!(
+            "{=u32} ms: modifier {=u8:#04x}, keycodes {=[u8]:#04x}",
+            time_ms, report.modifier, report.keycodes
+        );
+    });
+    info!("Finished replaying the scan trace");
+}
+
+/// The number of scan loop ticks a profile's debounce time lasts for, rounded up so slower
+/// scans never debounce for less time.
+fn debounce_ticks(profile: Profile) -> u8 {
+    ms_to_ticks(profile.settings().debounce_ms.into()) as u8
 }
 
 /// Handle USB interrupts, used by the host to "poll" the keyboard for new inputs.
 #[allow(non_snake_case)]
 #[interrupt]
-unsafe fn USBCTRL_IRQ() {
-    let usb_dev = USB_DEVICE.as_mut().unwrap();
-    let usb_hid = USB_HID.as_mut().unwrap();
+fn USBCTRL_IRQ() {
+    critical_section::with(|cs| {
+        if let Some(usb) = USB.borrow_ref_mut(cs).as_mut() {
+            // Note (safety): Only this interrupt reads the report queues and buffers
+            unsafe { poll_usb(usb) };
+        }
+    });
+}
+
+/// Poll the USB device, and send the host the reports the main loop has left for it.
+///
+/// # Safety
+/// Only the USB interrupt may call this, as the report queues' and buffers' only reader.
+unsafe fn poll_usb(usb: &mut Usb) {
+    let polled = usb.device.poll(&mut [
+        &mut usb.hid,
+        #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+        &mut usb.resolution_multiplier,
+        #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+        &mut usb.mouse_hid,
+        #[cfg(feature = "nkro")]
+        &mut usb.nkro_hid,
+        &mut usb.consumer_hid,
+        &mut usb.system_hid,
+        &mut usb.raw_hid,
+        &mut usb.via_hid,
+        &mut usb.webusb,
+        &mut usb.dfu,
+        #[cfg(feature = "usb-log")]
+        &mut usb.serial,
+    ]);
+    if polled {
+        usb.hid.poll();
+    }
+
+    #[cfg(feature = "usb-log")]
+    critical_section::with(|cs| usb.serial.send(&mut USB_LOG.borrow_ref_mut(cs)));
+
+    let configured = usb.device.state() == UsbDeviceState::Configured;
+    USB_CONFIGURED.store(configured, Ordering::Relaxed);
+
+    // A bus reset puts the keyboard back in report protocol, until the host asks otherwise.
+    if usb.device.state() == UsbDeviceState::Default {
+        usb.hid
+            .set_protocol_mode(HidProtocolMode::Report, ProtocolModeConfig::DefaultBehavior)
+            .ok();
+    }
+    let boot_protocol = matches!(usb.hid.get_protocol_mode(), Ok(HidProtocolMode::Boot));
+    USB_BOOT_PROTOCOL.store(boot_protocol, Ordering::Relaxed);
+
+    #[cfg(any(feature = "trackpoint", feature = "mouse-keys"))]
+    critical_section::with(|cs| {
+        RESOLUTION_MULTIPLIERS.replace(cs, usb.resolution_multiplier.multipliers());
+
+        let mut mouse_report = MOUSE_REPORT.borrow_ref_mut(cs);
+        if let Some(report) = *mouse_report {
+            // Keep the report until the host takes it, so no movement is lost.
+            if usb.mouse_hid.push_input(&report).is_ok() {
+                *mouse_report = None;
+            }
+        }
+    });
+
+    // Reports queued before the host (re)configured the keyboard are stale.
+    if !configured {
+        while KEYBOARD_REPORT_QUEUE.front().is_some() {
+            KEYBOARD_REPORT_QUEUE.pop();
+        }
+        while CONSUMER_REPORT_QUEUE.front().is_some() {
+            CONSUMER_REPORT_QUEUE.pop();
+        }
+        while SYSTEM_REPORT_QUEUE.front().is_some() {
+            SYSTEM_REPORT_QUEUE.pop();
+        }
+        #[cfg(feature = "nkro")]
+        while NKRO_REPORT_QUEUE.front().is_some() {
+            NKRO_REPORT_QUEUE.pop();
+        }
+    }
+
+    let (_, result) = push_queued_report(&KEYBOARD_REPORT_QUEUE, &KEYBOARD_REPORT, |report| {
+        push_keyboard_report(&mut usb.hid, report)
+    });
+    record_latency(&KEYBOARD_REPORT_QUEUE, &KEYBOARD_PRESS);
+    let blocked = matches!(result, Err(UsbError::WouldBlock));
+
+    let (_, consumer_result) =
+        push_queued_report(&CONSUMER_REPORT_QUEUE, &CONSUMER_REPORT, |usage| {
+            usb.consumer_hid.push_raw_input(&usage.to_le_bytes())
+        });
+    let blocked = blocked || matches!(consumer_result, Err(UsbError::WouldBlock));
+
+    let (_, system_result) = push_queued_report(&SYSTEM_REPORT_QUEUE, &SYSTEM_REPORT, |usage| {
+        usb.system_hid.push_raw_input(&[*usage])
+    });
+    let blocked = blocked || matches!(system_result, Err(UsbError::WouldBlock));
+
+    #[cfg(feature = "nkro")]
+    let blocked = {
+        let (_, nkro_result) = push_queued_report(&NKRO_REPORT_QUEUE, &NKRO_REPORT, |report| {
+            usb.nkro_hid.push_raw_input(&report.to_bytes())
+        });
+        record_latency(&NKRO_REPORT_QUEUE, &NKRO_PRESS);
+        blocked || matches!(nkro_result, Err(UsbError::WouldBlock))
+    };
+    USB_REPORT_BLOCKED.store(blocked, Ordering::Relaxed);
+
+    // macOS doesn't like it when you don't pull this, apparently. It's the LED state.
+    let mut output_report = [0; 64];
+    if let Ok(1..) = usb.hid.pull_raw_output(&mut output_report) {
+        HOST_LEDS.store(output_report[0], Ordering::Relaxed);
+    }
+
+    exchange_raw_reports(
+        &usb.raw_hid,
+        &RAW_HID_REQUEST,
+        &RAW_HID_RESPONSE,
+        Some(raw_hid::busy_response),
+    );
+    exchange_raw_reports(&usb.via_hid, &VIA_REQUEST, &VIA_RESPONSE, None);
+
+    // Wake the host if a key was pressed while it was asleep, and it allows being woken.
+    let suspended = usb.device.state() == UsbDeviceState::Suspend;
+    USB_SUSPENDED.store(suspended, Ordering::Relaxed);
+    // There's no atomic swap on the M0+, but only this clears the flag.
+    let wakeup_requested = USB_WAKEUP_REQUESTED.load(Ordering::Relaxed);
+    USB_WAKEUP_REQUESTED.store(false, Ordering::Relaxed);
+    if wakeup_requested && suspended && usb.device.remote_wakeup_enabled() {
+        usb.device.bus().remote_wakeup();
+    }
+}
+
+/// Send a keyboard report in whichever protocol the host has picked. The report is the same
+/// 8 bytes in both, but `HIDClass` only lets a boot keyboard send in boot protocol, so it's
+/// switched to boot protocol just for the write.
+fn push_keyboard_report(
+    hid: &mut HIDClass<usb::UsbBus>,
+    report: &KeyboardReport,
+) -> usb_device::Result<usize> {
+    let Ok(mode) = hid.get_protocol_mode() else {
+        return hid.push_input(report);
+    };
+
+    hid.set_protocol_mode(HidProtocolMode::Boot, ProtocolModeConfig::DefaultBehavior).ok();
+    let result = hid.push_input(report);
+    hid.set_protocol_mode(mode, ProtocolModeConfig::DefaultBehavior).ok();
+    result
+}
 
-    if usb_dev.poll(&mut [usb_hid]) {
-        usb_hid.poll();
+/// Answers a request which can't be handled yet, see `exchange_raw_reports`.
+type BusyResponse<const N: usize> = fn(&[u8; N]) -> [u8; N];
+
+/// Pass a configuration request from the host on to the main loop, which has the keymap and
+/// matrix to answer it with, and send its response back once it's ready.
+///
+/// A request which arrives while the last is still waiting is answered with `busy_response`
+/// straight away, or replaces the waiting one without it.
+fn exchange_raw_reports<const N: usize>(
+    hid: &HIDClass<usb::UsbBus>,
+    request: &Mutex<RefCell<Option<[u8; N]>>>,
+    response: &Mutex<RefCell<Option<[u8; N]>>>,
+    busy_response: Option<BusyResponse<N>>,
+) {
+    let mut bytes = [0; N];
+    if hid.pull_raw_output(&mut bytes).is_ok_and(|len| len == N) {
+        let waiting = critical_section::with(|cs| request.borrow_ref(cs).is_some());
+        match busy_response {
+            Some(busy_response) if waiting => {
+                hid.push_raw_input(&busy_response(&bytes)).ok();
+            },
+            _ => {
+                critical_section::with(|cs| request.replace(cs, Some(bytes)));
+            },
+        }
     }
 
-    let report = critical_section::with(|cs| *KEYBOARD_REPORT.borrow_ref(cs));
-    if let Err(err) = usb_hid.push_input(&report) {
+    critical_section::with(|cs| {
+        let mut response = response.borrow_ref_mut(cs);
+        if let Some(bytes) = *response {
+            if hid.push_raw_input(&bytes).is_ok() {
+                *response = None;
+            }
+        }
+    });
+}
+
+/// Send the oldest report in `queue` the host hasn't seen yet, or otherwise the latest one,
+/// returning the report and how pushing it went.
+///
+/// # Safety
+/// Only the USB interrupt may call this, as the queue's and buffer's only reader.
+unsafe fn push_queued_report<T: Copy, const N: usize>(
+    queue: &ReportQueue<T, N>,
+    latest: &DoubleBuffer<T>,
+    push: impl FnOnce(&T) -> usb_device::Result<usize>,
+) -> (T, usb_device::Result<usize>) {
+    // Note (safety): The main loop can't interrupt this, so it can't publish mid-read
+    let queued_report = queue.front();
+    let report = queued_report.unwrap_or_else(|| latest.read());
+    let result = push(&report);
+    if result.is_ok() && queued_report.is_some() {
+        queue.pop();
+    }
+    if let Err(err) = &result {
         match err {
-            UsbError::WouldBlock => warn!("UsbError::WouldBlock"),
+            // The report is sent again on the next interrupt.
+            UsbError::WouldBlock => {},
             UsbError::ParseError => error!("UsbError::ParseError"),
-            UsbError::BufferOverflow => error!("UsbError::BufferOverflow"),
-            UsbError::EndpointOverflow => error!("UsbError::EndpointOverflow"),
-            UsbError::EndpointMemoryOverflow => error!("UsbError::EndpointMemoryOverflow"),
+            UsbError::BufferOverflow => {
+                error!("UsbError::BufferOverflow");
+                FAULT.raise(Fault::UsbOverflow);
+            },
+            UsbError::EndpointOverflow => {
+                error!("UsbError::EndpointOverflow");
+                FAULT.raise(Fault::UsbOverflow);
+            },
+            UsbError::EndpointMemoryOverflow => {
+                error!("UsbError::EndpointMemoryOverflow");
+                FAULT.raise(Fault::UsbOverflow);
+            },
             UsbError::InvalidEndpoint => error!("UsbError::InvalidEndpoint"),
             UsbError::Unsupported => error!("UsbError::Unsupported"),
             UsbError::InvalidState => error!("UsbError::InvalidState"),
         }
     }
 
-    // macOS doesn't like it when you don't pull this, apparently.
-    // TODO: maybe even parse something here
-    usb_hid.pull_raw_output(&mut [0; 64]).ok();
-
-    // Wake the host if a key is pressed and the device supports
-    // remote wakeup.
-    if !report_is_empty(&report)
-        && usb_dev.state() == UsbDeviceState::Suspend
-        && usb_dev.remote_wakeup_enabled()
-    {
-        usb_dev.bus().remote_wakeup();
-    }
+    (report, result)
 }
 
-fn report_is_empty(report: &KeyboardReport) -> bool {
-    report.modifier != 0
-        || report.keycodes.iter().any(|key| *key != key_codes::KeyCode::Empty as u8)
+/// Handle the scan alarm, which only needs to wake the main loop from `sleep`.
+#[allow(non_snake_case)]
+#[interrupt]
+fn TIMER_IRQ_0() {
+    critical_section::with(|cs| {
+        SCAN_ALARM.borrow_ref_mut(cs).as_mut().unwrap().clear_interrupt();
+    });
 }
@@ -0,0 +1,133 @@
+//! Sanity checks on the raw matrix, before debouncing.
+//!
+//! A matrix without a diode on every switch reads a phantom "ghost" key at the fourth
+//! corner whenever three keys at the corners of a rectangle are held, and there's no
+//! telling it apart from a real press. With ghost suppression on, a key which goes down
+//! while it completes a rectangle of held keys is ignored until the rectangle is broken.
+//! Keys already held stay held, so only the newest key of the chord is lost. Boards with
+//! diodes can read every rectangle correctly, so leave it off for them.
+//!
+//! Keys held for longer than `STUCK_KEY_MS` are logged as possibly stuck, from a worn
+//! switch or something resting on the keyboard, but still reported to the host.
+
+use defmt::{warn, Format};
+
+use crate::{NUM_COLS, NUM_ROWS};
+
+/// How long a key has to be held before it's logged as stuck.
+pub const STUCK_KEY_MS: u64 = 10 * 60 * 1000;
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+/// What the checks have found since the keyboard started.
+#[derive(Copy, Clone, Debug, Default, Format, PartialEq)]
+pub struct MatrixStats {
+    /// Key presses ignored as ghosts.
+    pub ghosts_suppressed: u32,
+
+    /// Keys held for longer than `STUCK_KEY_MS`, each counted once per press.
+    pub stuck_keys: u32,
+}
+
+impl MatrixStats {
+    /// Both counters, little endian.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[0..4].copy_from_slice(&self.ghosts_suppressed.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.stuck_keys.to_le_bytes());
+        bytes
+    }
+}
+
+pub struct MatrixCheck {
+    suppress_ghosts: bool,
+
+    /// The matrix passed on by the last check.
+    accepted: Matrix,
+
+    /// The keys ignored as ghosts in the last check, so each is only counted once.
+    ghosts: Matrix,
+
+    /// When each key held in `accepted` went down.
+    pressed_since_ms: [[Option<u64>; NUM_ROWS]; NUM_COLS],
+
+    /// The keys already logged as stuck in their current press.
+    stuck: Matrix,
+
+    stats: MatrixStats,
+}
+
+impl MatrixCheck {
+    pub fn new(suppress_ghosts: bool) -> Self {
+        Self {
+            suppress_ghosts,
+            accepted: [[false; NUM_ROWS]; NUM_COLS],
+            ghosts: [[false; NUM_ROWS]; NUM_COLS],
+            pressed_since_ms: [[None; NUM_ROWS]; NUM_COLS],
+            stuck: [[false; NUM_ROWS]; NUM_COLS],
+            stats: MatrixStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> MatrixStats {
+        self.stats
+    }
+
+    /// Check a raw scan, returning it with any ghost keys released. Call this once per scan.
+    pub fn check(&mut self, now_ms: u64, raw_matrix: &Matrix) -> Matrix {
+        let mut matrix = *raw_matrix;
+
+        for col in 0..NUM_COLS {
+            for row in 0..NUM_ROWS {
+                let newly_pressed = raw_matrix[col][row] && !self.accepted[col][row];
+                let ghost =
+                    self.suppress_ghosts && newly_pressed && in_rectangle(raw_matrix, col, row);
+                if ghost {
+                    matrix[col][row] = false;
+                    if !self.ghosts[col][row] {
+                        self.stats.ghosts_suppressed += 1;
+                    }
+                }
+                self.ghosts[col][row] = ghost;
+            }
+        }
+
+        for (col, column) in matrix.iter().enumerate() {
+            for (row, pressed) in column.iter().enumerate() {
+                let pressed_since = &mut self.pressed_since_ms[col][row];
+                let stuck = &mut self.stuck[col][row];
+                if !*pressed {
+                    *pressed_since = None;
+                    *stuck = false;
+                    continue;
+                }
+
+                let since = *pressed_since.get_or_insert(now_ms);
+                if !*stuck && now_ms - since >= STUCK_KEY_MS {
+                    warn!(
+                        "Key ({}, {}) has been held for {} minutes, it may be stuck",
+                        col,
+                        row,
+                        STUCK_KEY_MS / 60_000
+                    );
+                    *stuck = true;
+                    self.stats.stuck_keys += 1;
+                }
+            }
+        }
+
+        self.accepted = matrix;
+        matrix
+    }
+}
+
+/// Whether the key at `(col, row)` is held at a corner of a rectangle of held keys.
+fn in_rectangle(matrix: &Matrix, col: usize, row: usize) -> bool {
+    (0..NUM_COLS).filter(|other_col| *other_col != col && matrix[*other_col][row]).any(
+        |other_col| {
+            (0..NUM_ROWS).any(|other_row| {
+                other_row != row && matrix[col][other_row] && matrix[other_col][other_row]
+            })
+        },
+    )
+}
@@ -0,0 +1,63 @@
+//! Options which swap or remap modifiers, for using the keyboard with a different OS or a
+//! game without changing the keymap: Alt and GUI swapped for macOS, Caps Lock as a Ctrl, or
+//! the GUI keys turned off so they can't drop out of a game.
+//!
+//! The swaps apply to the modifiers of the report once it's built, so they cover one-shot and
+//! locked modifiers too, while macros and Unicode input still send what they're made of.
+//! Each option is toggled by a key, such as `KeyCode::ToggleAltGuiSwap`, and saved.
+
+use defmt::Format;
+
+use crate::key_codes::KeyCode;
+
+/// The bits of the left and right Alt keys in a report's modifiers.
+const ALT: u8 = 1 << 2 | 1 << 6;
+
+/// The bits of the left and right GUI keys in a report's modifiers.
+const GUI: u8 = 1 << 3 | 1 << 7;
+
+#[derive(Copy, Clone, Debug, Default, Format, PartialEq)]
+pub struct ModSwaps {
+    /// Alt sends GUI, and GUI sends Alt, on either side.
+    pub alt_gui_swapped: bool,
+
+    /// Caps Lock sends Left Ctrl.
+    pub caps_as_ctrl: bool,
+
+    /// The GUI keys send nothing, after any swap, so with Alt and GUI swapped it's the
+    /// keys labelled Alt which are off.
+    pub gui_disabled: bool,
+}
+
+impl ModSwaps {
+    /// The key a key sends, for the options which remap keys rather than modifiers.
+    pub fn remap(&self, key: KeyCode) -> KeyCode {
+        match key {
+            KeyCode::CapsLock if self.caps_as_ctrl => KeyCode::LeftCtrl,
+            key => key,
+        }
+    }
+
+    /// The modifiers of a report, as a bitmask, with the swaps applied.
+    pub fn apply(&self, mut modifier: u8) -> u8 {
+        if self.alt_gui_swapped {
+            modifier = (modifier & !(ALT | GUI)) | (modifier & ALT) << 1 | (modifier & GUI) >> 1;
+        }
+        if self.gui_disabled {
+            modifier &= !GUI;
+        }
+        modifier
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self.alt_gui_swapped as u8 | (self.caps_as_ctrl as u8) << 1 | (self.gui_disabled as u8) << 2
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        (value < 1 << 3).then_some(Self {
+            alt_gui_swapped: value & 1 != 0,
+            caps_as_ctrl: value & 1 << 1 != 0,
+            gui_disabled: value & 1 << 2 != 0,
+        })
+    }
+}
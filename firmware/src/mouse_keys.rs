@@ -0,0 +1,143 @@
+//! Mouse keys, which move the pointer, scroll and click from the keyboard.
+//!
+//! The pointer starts out slowly, for small adjustments, and speeds up the longer a movement
+//! key is held, reaching full speed after `ACCELERATION_TICKS`. Scrolling speeds up the same
+//! way. Holding keys for opposite directions cancels them out.
+//!
+//! The motion is added to the mouse reports by `pointer::Pointer`, alongside any from a
+//! TrackPoint, and sent with the `mouse-keys` feature.
+
+use defmt::Format;
+
+use crate::{key_codes::KeyCode, ms_to_ticks, SCAN_PERIOD_MS};
+
+/// How many scan ticks a key has to be held to reach full speed, one second.
+pub const ACCELERATION_TICKS: u32 = ms_to_ticks(1000);
+
+/// Pointer speeds in counts per second, when a key is first pressed and at full speed.
+const MOVE_START_SPEED: i32 = 100;
+const MOVE_MAX_SPEED: i32 = 1500;
+
+/// Scrolling speeds in wheel detents per second, when a key is first pressed and at full
+/// speed.
+const WHEEL_START_SPEED: i32 = 8;
+const WHEEL_MAX_SPEED: i32 = 40;
+
+/// The number of mouse buttons with a key, `KeyCode::MouseButton1` to `MouseButton5`.
+pub const MOUSE_BUTTONS: usize = 5;
+
+/// What the mouse keys did in one or more scans.
+#[derive(Copy, Clone, Debug, Default, Format, PartialEq)]
+pub struct MouseMotion {
+    /// The buttons held, with button 1 in bit 0 like in a mouse report.
+    pub buttons: u8,
+
+    /// Pointer movement in counts, with `y` increasing downwards like in a mouse report.
+    pub x: i16,
+    pub y: i16,
+
+    /// Scrolling in wheel detents, up and right being positive like in a mouse report.
+    pub wheel: i16,
+    pub pan: i16,
+}
+
+impl MouseMotion {
+    /// Add the motion of a later scan, keeping its buttons.
+    pub fn add(&mut self, later: MouseMotion) {
+        self.buttons = later.buttons;
+        self.x = self.x.saturating_add(later.x);
+        self.y = self.y.saturating_add(later.y);
+        self.wheel = self.wheel.saturating_add(later.wheel);
+        self.pan = self.pan.saturating_add(later.pan);
+    }
+}
+
+/// Which ways along an axis keys are held for.
+#[derive(Copy, Clone, Default)]
+struct Axis {
+    negative: bool,
+    positive: bool,
+}
+
+impl Axis {
+    fn direction(self) -> i32 {
+        self.positive as i32 - self.negative as i32
+    }
+}
+
+/// Movement along both axes of either the pointer or the wheel, which speeds up while held.
+#[derive(Default)]
+struct Accelerator {
+    x: Axis,
+    y: Axis,
+
+    /// How long the keys have been held, in ticks.
+    held_ticks: u32,
+
+    // Movement which hasn't made a whole step yet, in thousandths of a step.
+    x_remainder: i32,
+    y_remainder: i32,
+}
+
+impl Accelerator {
+    /// Move on by a tick, returning the whole steps made along each axis.
+    fn tick(&mut self, start_speed: i32, max_speed: i32) -> (i16, i16) {
+        let (x, y) = (self.x.direction(), self.y.direction());
+        self.x = Axis::default();
+        self.y = Axis::default();
+
+        if x == 0 && y == 0 {
+            *self = Self::default();
+            return (0, 0);
+        }
+
+        let ramp = self.held_ticks.min(ACCELERATION_TICKS) as i32;
+        let speed = start_speed + (max_speed - start_speed) * ramp / ACCELERATION_TICKS as i32;
+        self.held_ticks = self.held_ticks.saturating_add(1);
+
+        // The speed per second is the thousandths per millisecond.
+        let period_ms = SCAN_PERIOD_MS as i32;
+        self.x_remainder += x * speed * period_ms;
+        self.y_remainder += y * speed * period_ms;
+        let steps = (self.x_remainder / 1000, self.y_remainder / 1000);
+        self.x_remainder %= 1000;
+        self.y_remainder %= 1000;
+        (steps.0 as i16, steps.1 as i16)
+    }
+}
+
+#[derive(Default)]
+pub struct MouseKeys {
+    pointer: Accelerator,
+    wheel: Accelerator,
+    buttons: u8,
+}
+
+impl MouseKeys {
+    /// Note that `key` is held in this scan. Keys other than mouse keys are ignored.
+    pub fn hold(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::MouseUp => self.pointer.y.negative = true,
+            KeyCode::MouseDown => self.pointer.y.positive = true,
+            KeyCode::MouseLeft => self.pointer.x.negative = true,
+            KeyCode::MouseRight => self.pointer.x.positive = true,
+            KeyCode::MouseWheelUp => self.wheel.y.positive = true,
+            KeyCode::MouseWheelDown => self.wheel.y.negative = true,
+            KeyCode::MouseWheelLeft => self.wheel.x.negative = true,
+            KeyCode::MouseWheelRight => self.wheel.x.positive = true,
+            key => {
+                if let Some(button) = key.mouse_button() {
+                    self.buttons |= 1 << button;
+                }
+            },
+        }
+    }
+
+    /// Move on to the next scan, returning what the keys held in this one did.
+    pub fn tick(&mut self) -> MouseMotion {
+        let (x, y) = self.pointer.tick(MOVE_START_SPEED, MOVE_MAX_SPEED);
+        let (pan, wheel) = self.wheel.tick(WHEEL_START_SPEED, WHEEL_MAX_SPEED);
+        let buttons = core::mem::take(&mut self.buttons);
+        MouseMotion { buttons, x, y, wheel, pan }
+    }
+}
@@ -0,0 +1,52 @@
+//! N-key rollover reports, with one bit for every key instead of the boot report's six
+//! keycode slots, so fast chords and stenography-style input never lose a key.
+//!
+//! They're sent on a HID interface of their own, described by
+//! `hid_descriptor::NKRO_REPORT_DESCRIPTOR`, while the boot keyboard interface stays in
+//! place for BIOS screens and KVMs which only understand six keys.
+
+/// The number of keyboard usages the report has a bit for, from `0x00` to `0xDF`. The
+/// modifiers, from `0xE0`, have a byte of their own.
+pub const NKRO_KEYS: usize = 0xE0;
+
+/// The length of the report on the wire.
+pub const NKRO_REPORT_LEN: usize = 1 + NKRO_KEYS / 8;
+
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct NkroReport {
+    /// The modifier bits, the same as in the boot report.
+    pub modifier: u8,
+
+    /// One bit per keyboard usage, usage 0 in the lowest bit of the first byte.
+    pub keys: [u8; NKRO_KEYS / 8],
+}
+
+impl NkroReport {
+    /// A report with nothing pressed.
+    pub const EMPTY: Self = Self { modifier: 0, keys: [0; NKRO_KEYS / 8] };
+
+    /// Mark a keyboard usage as pressed. Usages beyond the bitmap are ignored.
+    pub fn press(&mut self, usage: u8) {
+        if let Some(byte) = self.keys.get_mut(usage as usize / 8) {
+            *byte |= 1 << (usage % 8);
+        }
+    }
+
+    /// Mark a keyboard usage as released.
+    pub fn release(&mut self, usage: u8) {
+        if let Some(byte) = self.keys.get_mut(usage as usize / 8) {
+            *byte &= !(1 << (usage % 8));
+        }
+    }
+
+    pub fn is_pressed(&self, usage: u8) -> bool {
+        self.keys.get(usage as usize / 8).is_some_and(|byte| byte & (1 << (usage % 8)) != 0)
+    }
+
+    pub fn to_bytes(&self) -> [u8; NKRO_REPORT_LEN] {
+        let mut bytes = [0u8; NKRO_REPORT_LEN];
+        bytes[0] = self.modifier;
+        bytes[1..].copy_from_slice(&self.keys);
+        bytes
+    }
+}
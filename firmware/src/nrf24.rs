@@ -0,0 +1,236 @@
+//! A driver for nRF24L01+ 2.4 GHz radios over SPI, used for the wireless link between the
+//! keyboard and its USB dongle.
+//!
+//! Both ends use Enhanced ShockBurst: the radio checks every packet's CRC, the receiver
+//! acknowledges what it gets, and the transmitter retries a few times when an
+//! acknowledgement doesn't arrive. The keyboard is the transmitter and the dongle the
+//! receiver, on a fixed channel and address.
+//!
+//! Only the nRF24L01+ register set is supported. SE8R01 clones need a different power-up
+//! sequence and don't work yet.
+
+use core::convert::Infallible;
+
+use cortex_m::delay::Delay;
+use defmt::Format;
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+
+use crate::wireless::FRAME_SIZE;
+
+/// The channel both ends use, 2.4 GHz plus this many MHz. It's above most Wi-Fi traffic.
+const CHANNEL: u8 = 76;
+
+/// The address of the keyboard's packets.
+const ADDRESS: [u8; 5] = *b"kripr";
+
+// Commands.
+const R_REGISTER: u8 = 0x00;
+const W_REGISTER: u8 = 0x20;
+const R_RX_PAYLOAD: u8 = 0x61;
+const W_TX_PAYLOAD: u8 = 0xA0;
+const FLUSH_TX: u8 = 0xE1;
+const FLUSH_RX: u8 = 0xE2;
+const NOP: u8 = 0xFF;
+
+// Registers.
+const CONFIG: u8 = 0x00;
+const EN_AA: u8 = 0x01;
+const EN_RXADDR: u8 = 0x02;
+const SETUP_AW: u8 = 0x03;
+const SETUP_RETR: u8 = 0x04;
+const RF_CH: u8 = 0x05;
+const RF_SETUP: u8 = 0x06;
+const STATUS: u8 = 0x07;
+const RX_ADDR_P0: u8 = 0x0A;
+const TX_ADDR: u8 = 0x10;
+const RX_PW_P0: u8 = 0x11;
+const FIFO_STATUS: u8 = 0x17;
+
+// CONFIG bits. The radio always uses a 2 byte CRC.
+const CONFIG_CRC: u8 = 0b1100;
+const CONFIG_PWR_UP: u8 = 0b0010;
+const CONFIG_PRIM_RX: u8 = 0b0001;
+
+// STATUS bits, which are cleared by writing 1 to them.
+const STATUS_RX_DR: u8 = 1 << 6;
+const STATUS_TX_DS: u8 = 1 << 5;
+const STATUS_MAX_RT: u8 = 1 << 4;
+
+/// FIFO_STATUS bit set while there's nothing to read.
+const FIFO_STATUS_RX_EMPTY: u8 = 0b1;
+
+/// 5 byte addresses.
+const SETUP_AW_5_BYTES: u8 = 0b11;
+
+/// Retry every 500 µs, up to 5 times.
+const SETUP_RETR_VALUE: u8 = 0x15;
+
+/// 2 Mbps at full power.
+const RF_SETUP_VALUE: u8 = 0b0000_1110;
+
+/// How long the radio takes to start its oscillator after powering up.
+const POWER_UP_DELAY_US: u32 = 2_000;
+
+/// How long CE has to stay high to start sending a packet.
+const CE_PULSE_US: u32 = 15;
+
+/// How long sending a packet can take, including all of its retries.
+const SEND_TIMEOUT_US: u32 = 5_000;
+const POLL_INTERVAL_US: u32 = 20;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum RadioError {
+    /// The radio didn't hold on to its configuration, so it's most likely missing.
+    NotFound,
+
+    /// The receiver didn't acknowledge the packet, even after retrying.
+    NotAcknowledged,
+
+    /// The radio never reported the outcome of sending a packet.
+    Timeout,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Role {
+    Transmitter,
+    Receiver,
+}
+
+pub struct Nrf24<SPI, CSN, CE> {
+    spi: SPI,
+    csn: CSN,
+    ce: CE,
+}
+
+impl<SPI, CSN, CE> Nrf24<SPI, CSN, CE>
+where
+    SPI: Transfer<u8, Error = Infallible> + Write<u8, Error = Infallible>,
+    CSN: OutputPin<Error = Infallible>,
+    CE: OutputPin<Error = Infallible>,
+{
+    /// Configure the radio for the keyboard link, and start listening if it's the receiver.
+    pub fn new(
+        spi: SPI,
+        mut csn: CSN,
+        mut ce: CE,
+        role: Role,
+        delay: &mut Delay,
+    ) -> Result<Self, RadioError> {
+        csn.set_high().unwrap();
+        ce.set_low().unwrap();
+        let mut radio = Self { spi, csn, ce };
+
+        radio.write_register(SETUP_AW, &[SETUP_AW_5_BYTES]);
+        if radio.read_register(SETUP_AW) != SETUP_AW_5_BYTES {
+            return Err(RadioError::NotFound);
+        }
+
+        radio.write_register(EN_AA, &[0b1]);
+        radio.write_register(EN_RXADDR, &[0b1]);
+        radio.write_register(SETUP_RETR, &[SETUP_RETR_VALUE]);
+        radio.write_register(RF_CH, &[CHANNEL]);
+        radio.write_register(RF_SETUP, &[RF_SETUP_VALUE]);
+        radio.write_register(RX_PW_P0, &[FRAME_SIZE as u8]);
+
+        // The transmitter receives acknowledgements on pipe 0, so it needs the same address
+        // as the packets it sends.
+        radio.write_register(RX_ADDR_P0, &ADDRESS);
+        radio.write_register(TX_ADDR, &ADDRESS);
+
+        radio.command(FLUSH_TX);
+        radio.command(FLUSH_RX);
+        radio.write_register(STATUS, &[STATUS_RX_DR | STATUS_TX_DS | STATUS_MAX_RT]);
+
+        let config = match role {
+            Role::Transmitter => CONFIG_CRC | CONFIG_PWR_UP,
+            Role::Receiver => CONFIG_CRC | CONFIG_PWR_UP | CONFIG_PRIM_RX,
+        };
+        radio.write_register(CONFIG, &[config]);
+        delay.delay_us(POWER_UP_DELAY_US);
+
+        if role == Role::Receiver {
+            radio.ce.set_high().unwrap();
+        }
+
+        Ok(radio)
+    }
+
+    /// Send a frame and wait for the receiver to acknowledge it.
+    pub fn send(&mut self, frame: &[u8; FRAME_SIZE], delay: &mut Delay) -> Result<(), RadioError> {
+        self.csn.set_low().unwrap();
+        self.spi.write(&[W_TX_PAYLOAD]).unwrap();
+        self.spi.write(frame).unwrap();
+        self.csn.set_high().unwrap();
+
+        self.ce.set_high().unwrap();
+        delay.delay_us(CE_PULSE_US);
+        self.ce.set_low().unwrap();
+
+        for _ in 0..SEND_TIMEOUT_US / POLL_INTERVAL_US {
+            let status = self.command(NOP);
+            if status & STATUS_TX_DS != 0 {
+                self.write_register(STATUS, &[STATUS_TX_DS]);
+                return Ok(());
+            }
+
+            if status & STATUS_MAX_RT != 0 {
+                // The packet stays in the TX FIFO after running out of retries.
+                self.command(FLUSH_TX);
+                self.write_register(STATUS, &[STATUS_MAX_RT]);
+                return Err(RadioError::NotAcknowledged);
+            }
+
+            delay.delay_us(POLL_INTERVAL_US);
+        }
+
+        self.command(FLUSH_TX);
+        Err(RadioError::Timeout)
+    }
+
+    /// The next frame received, if there is one.
+    pub fn receive(&mut self) -> Option<[u8; FRAME_SIZE]> {
+        if self.read_register(FIFO_STATUS) & FIFO_STATUS_RX_EMPTY != 0 {
+            return None;
+        }
+
+        let mut buf = [0u8; FRAME_SIZE + 1];
+        buf[0] = R_RX_PAYLOAD;
+        self.csn.set_low().unwrap();
+        self.spi.transfer(&mut buf).unwrap();
+        self.csn.set_high().unwrap();
+
+        // Nothing uses the IRQ pin, but clear the flag to keep it from staying asserted.
+        self.write_register(STATUS, &[STATUS_RX_DR]);
+
+        let mut frame = [0u8; FRAME_SIZE];
+        frame.copy_from_slice(&buf[1..]);
+        Some(frame)
+    }
+
+    /// Send a single byte command, returning the STATUS register.
+    fn command(&mut self, command: u8) -> u8 {
+        let mut buf = [command];
+        self.csn.set_low().unwrap();
+        self.spi.transfer(&mut buf).unwrap();
+        self.csn.set_high().unwrap();
+        buf[0]
+    }
+
+    fn read_register(&mut self, register: u8) -> u8 {
+        let mut buf = [R_REGISTER | register, 0];
+        self.csn.set_low().unwrap();
+        self.spi.transfer(&mut buf).unwrap();
+        self.csn.set_high().unwrap();
+        buf[1]
+    }
+
+    fn write_register(&mut self, register: u8, value: &[u8]) {
+        self.csn.set_low().unwrap();
+        self.spi.write(&[W_REGISTER | register]).unwrap();
+        self.spi.write(value).unwrap();
+        self.csn.set_high().unwrap();
+    }
+}
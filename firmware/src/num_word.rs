@@ -0,0 +1,75 @@
+//! Num Word: a momentary number layer which stays active until a terminating key is
+//! pressed, for typing a quick number without holding down a layer key.
+
+use crate::{key_codes::KeyCode, NUM_COLS, NUM_ROWS};
+
+/// Tracks whether Num Word is active.
+///
+/// Tapping `KeyCode::NumWord` activates the num layer. It stays active while numbers and
+/// the punctuation commonly typed alongside them are pressed, and deactivates as soon as
+/// any other key is pressed (or `KeyCode::NumWord` is tapped again). The terminating key
+/// is resolved on the normal layer, so typing `1 2 3 Space` sends the space as expected.
+#[derive(Default)]
+pub struct NumWord {
+    active: bool,
+
+    /// The matrix from the previous scan, used to find newly pressed keys.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl NumWord {
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Update the Num Word state with a debounced scan, and `layer_mapping`, the keys it
+    /// resolves to with the layers active so far. The caller keeps `key_mapping::NUM_LAYER`
+    /// active while `is_active`.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        layer_mapping: &[[KeyCode; NUM_ROWS]; NUM_COLS],
+    ) {
+        for col in 0..NUM_COLS {
+            for row in 0..NUM_ROWS {
+                if !matrix[col][row] || self.previous_matrix[col][row] {
+                    continue;
+                }
+
+                let key = layer_mapping[col][row];
+                if key == KeyCode::NumWord {
+                    self.active = !self.active;
+                } else if self.active && !continues_num_word(key) {
+                    self.active = false;
+                }
+            }
+        }
+
+        self.previous_matrix = *matrix;
+    }
+}
+
+/// Keys which keep Num Word active when pressed.
+fn continues_num_word(key: KeyCode) -> bool {
+    matches!(
+        key,
+        KeyCode::Num0
+            | KeyCode::Num1
+            | KeyCode::Num2
+            | KeyCode::Num3
+            | KeyCode::Num4
+            | KeyCode::Num5
+            | KeyCode::Num6
+            | KeyCode::Num7
+            | KeyCode::Num8
+            | KeyCode::Num9
+            | KeyCode::Minus
+            | KeyCode::Equals
+            | KeyCode::Period
+            | KeyCode::Comma
+            | KeyCode::ForwardSlash
+            | KeyCode::Backspace
+            | KeyCode::Delete
+            | KeyCode::Empty
+    ) || key.is_modifier()
+}
@@ -0,0 +1,108 @@
+//! One-shot modifiers, which apply to the next key pressed after they're tapped, so a
+//! capital letter doesn't need Shift held with it.
+//!
+//! Tapping a one-shot modifier key (`KeyCode::OneShotShift` and so on) arms its modifier,
+//! which is then held along with the next other key pressed, until that key is released.
+//! Tapping it again before then cancels it, as does waiting for `ONE_SHOT_TIMEOUT_TICKS`.
+//! Held down while another key is pressed, it works like the plain modifier instead.
+//!
+//! One-shot layers work the same way, see `layers::Layers`.
+
+use crate::{key_codes::KeyCode, ms_to_ticks, NUM_COLS, NUM_ROWS};
+
+/// How long a tapped one-shot modifier or layer waits for the next key, three seconds.
+pub const ONE_SHOT_TIMEOUT_TICKS: u32 = ms_to_ticks(3000);
+
+#[derive(Default)]
+pub struct OneShotMods {
+    /// The modifiers of the one-shot keys held down.
+    held: u8,
+
+    /// Whether another key was pressed while one-shot keys were held, so releasing them
+    /// doesn't arm their modifiers.
+    interrupted: bool,
+
+    /// The modifiers of one-shot keys pressed to cancel them, which don't arm again when
+    /// they're released.
+    cancelling: u8,
+
+    /// The modifiers tapped, waiting for the next key, and how long they've waited.
+    armed: u8,
+    armed_ticks: u32,
+
+    /// The modifiers held along with the next key, and its position, until it's released.
+    applied: Option<(u8, (usize, usize))>,
+
+    /// The matrix from the previous update, used to act on keys once per press.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl OneShotMods {
+    /// Update from a debounced scan, with `mapping` the keys resolved through the layers,
+    /// `elapsed_ticks` after the last update. Returns the modifiers to add to the report.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        mapping: &[[KeyCode; NUM_ROWS]; NUM_COLS],
+        elapsed_ticks: u32,
+    ) -> u8 {
+        if let Some((_, (col, row))) = self.applied {
+            if !matrix[col][row] {
+                self.applied = None;
+            }
+        }
+
+        let mut held = 0;
+        for (col, (column, previous_column)) in matrix.iter().zip(self.previous_matrix).enumerate()
+        {
+            for (row, (pressed, was_pressed)) in column.iter().zip(previous_column).enumerate() {
+                if !pressed {
+                    continue;
+                }
+
+                let key = mapping[col][row];
+                if let Some(modifier) = key.one_shot_modifier() {
+                    held |= modifier;
+                    if !was_pressed && self.armed & modifier != 0 {
+                        self.armed &= !modifier;
+                        self.cancelling |= modifier;
+                    }
+                } else if !was_pressed && !key.is_modifier() && key.layer_action().is_none() {
+                    self.interrupted |= self.held != 0;
+                    if self.armed != 0 && self.applied.is_none() {
+                        self.applied = Some((self.armed, (col, row)));
+                        self.armed = 0;
+                    }
+                }
+            }
+        }
+
+        // Released without another key pressed meanwhile, so they were tapped.
+        let released = self.held & !held;
+        let tapped = released & !self.cancelling;
+        if !self.interrupted && tapped != 0 {
+            self.armed |= tapped;
+            self.armed_ticks = 0;
+        }
+        self.cancelling &= held;
+        if held == 0 {
+            self.interrupted = false;
+        }
+        self.held = held;
+
+        if self.armed != 0 {
+            self.armed_ticks += elapsed_ticks;
+            if self.armed_ticks >= ONE_SHOT_TIMEOUT_TICKS {
+                self.armed = 0;
+            }
+        }
+
+        self.previous_matrix = *matrix;
+        held | self.applied.map_or(0, |(modifiers, _)| modifiers)
+    }
+
+    /// The modifiers tapped and waiting for the next key.
+    pub fn armed(&self) -> u8 {
+        self.armed
+    }
+}
@@ -0,0 +1,255 @@
+//! Scanning the key matrix with a PIO state machine, so the CPU doesn't drive each line and
+//! wait for it to settle itself.
+//!
+//! A DMA channel feeds the state machine a word for each step of the scan: a mask driving
+//! one line, then an empty one releasing it again. The state machine waits for the line to
+//! settle after each, and samples every GPIO while the line is driven, which a second DMA
+//! channel copies into a buffer. Once that channel is done, the samples are turned into the
+//! same raw matrix `key_scan::MatrixScanner` reads, so debouncing and everything after work
+//! the same.
+//!
+//! The lines are driven by switching their pins between input and output, with the output
+//! level set once at the start, so released lines float like the drive lines of a diode
+//! matrix can. The state machine writes the direction of every pin from the lowest drive
+//! line to the highest, so no other pin on the same PIO block (like the backlight's data
+//! line) can be in between. The sense lines stay SIO inputs with their pulls, as the state
+//! machine can read any GPIO.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use pio::{
+    Assembler, InSource, JmpCondition, MovDestination, MovOperation, MovSource, OutDestination,
+};
+use rp2040_hal::{
+    pac,
+    pio::{
+        Buffers, PIOBuilder, PIOExt, PinDir, PinState, Running, Rx, ShiftDirection, StateMachine,
+        StateMachineIndex, Tx, UninitStateMachine, PIO,
+    },
+};
+
+use crate::{
+    key_scan::{MatrixWiring, Sense, SwitchScanner},
+    COL_PINS, NUM_COLS, NUM_ROWS, ROW_PINS,
+};
+
+/// The most lines a matrix can drive, whichever way it's wired.
+const MAX_LINES: usize = if NUM_ROWS > NUM_COLS { NUM_ROWS } else { NUM_COLS };
+
+/// The DMA channels feeding the state machine and reading its samples. Channel 0 is the
+/// backlight's, see `rgb::Ws2812`.
+const TX_DMA_CHANNEL: usize = 1;
+const RX_DMA_CHANNEL: usize = 2;
+
+/// The GPIOs sampled for each line, all of them.
+const GPIO_COUNT: u8 = 30;
+
+/// The state machine runs at 1 MHz, so settle times are counted in microseconds.
+const STATE_MACHINE_HZ: u32 = 1_000_000;
+
+/// The state machine cycles each line takes, on top of the settle times.
+const CYCLES_PER_LINE: u32 = 5;
+
+/// The memory the DMA channels use, which has to stay put while a scan is running.
+pub struct ScanBuffers {
+    /// The words feeding the state machine: a mask for each line, each followed by zero.
+    steps: [u32; 2 * MAX_LINES],
+
+    /// What every GPIO read while each line was driven.
+    samples: [u32; MAX_LINES],
+}
+
+impl ScanBuffers {
+    pub const fn new() -> Self {
+        Self { steps: [0; 2 * MAX_LINES], samples: [0; MAX_LINES] }
+    }
+}
+
+impl Default for ScanBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Scans the matrix from `board.toml` on a PIO state machine, see the module docs.
+pub struct PioScanner<P: PIOExt, SM: StateMachineIndex> {
+    _state_machine: StateMachine<(P, SM), Running>,
+    tx: Tx<(P, SM)>,
+    rx: Rx<(P, SM)>,
+    buffers: &'static mut ScanBuffers,
+    wiring: MatrixWiring,
+    settle_us: u32,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> PioScanner<P, SM> {
+    /// Start the state machine for a matrix wired like `wiring`, waiting `settle_us`
+    /// microseconds for each line to settle. The drive lines' pins must be set to the
+    /// function of the PIO block, and the sense lines set up as inputs pulled the way
+    /// `wiring` says. This uses DMA channels `TX_DMA_CHANNEL` and `RX_DMA_CHANNEL`.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        resets: &mut pac::RESETS,
+        wiring: MatrixWiring,
+        settle_us: u32,
+        system_clock_hz: u32,
+        buffers: &'static mut ScanBuffers,
+    ) -> Self {
+        resets.reset.modify(|_, w| w.dma().clear_bit());
+        while resets.reset_done.read().dma().bit_is_clear() {}
+
+        let drive_gpios: &[u8] = if wiring.drives_columns() { &COL_PINS } else { &ROW_PINS };
+        let base = drive_gpios.iter().copied().min().unwrap_or(0);
+        let count = drive_gpios.iter().copied().max().unwrap_or(0) - base + 1;
+        for (line, gpio) in drive_gpios.iter().enumerate() {
+            buffers.steps[2 * line] = 1 << (gpio - base);
+            buffers.steps[2 * line + 1] = 0;
+        }
+
+        // After taking the settle time, each word drives or releases lines, waits for them
+        // to settle, and samples the GPIOs after driving. Zero words release everything.
+        let mut program = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+        let mut wrap_target = program.label();
+        let mut wrap_source = program.label();
+        let mut settle_driven = program.label();
+        let mut settle_released = program.label();
+        program.pull(false, true);
+        program.mov(MovDestination::Y, MovOperation::None, MovSource::OSR);
+        program.bind(&mut wrap_target);
+        program.out(OutDestination::PINDIRS, count);
+        program.mov(MovDestination::X, MovOperation::None, MovSource::Y);
+        program.bind(&mut settle_driven);
+        program.jmp(JmpCondition::XDecNonZero, &mut settle_driven);
+        program.r#in(InSource::PINS, GPIO_COUNT);
+        program.out(OutDestination::PINDIRS, count);
+        program.mov(MovDestination::X, MovOperation::None, MovSource::Y);
+        program.bind(&mut settle_released);
+        program.jmp(JmpCondition::XDecNonZero, &mut settle_released);
+        program.bind(&mut wrap_source);
+        let program = pio.install(&program.assemble_with_wrap(wrap_source, wrap_target)).unwrap();
+
+        let clock_divisor = system_clock_hz as f32 / STATE_MACHINE_HZ as f32;
+        let (mut state_machine, rx, mut tx) = PIOBuilder::from_program(program)
+            .out_pins(base, count)
+            .in_pin_base(0)
+            .out_shift_direction(ShiftDirection::Right)
+            .autopull(true)
+            .pull_threshold(count)
+            .in_shift_direction(ShiftDirection::Left)
+            .autopush(true)
+            .push_threshold(GPIO_COUNT)
+            .buffers(Buffers::RxTx)
+            .clock_divisor(clock_divisor)
+            .build(sm);
+
+        // Lines are driven high into pulled down sense lines, and low into pulled up ones.
+        let level = if wiring.sense == Sense::PullUp { PinState::Low } else { PinState::High };
+        state_machine.set_pins(drive_gpios.iter().map(|gpio| (*gpio, level)));
+        state_machine.set_pindirs(drive_gpios.iter().map(|gpio| (*gpio, PinDir::Input)));
+
+        // The first word is the settle time, less the cycle `jmp` takes to fall through.
+        tx.write(settle_us.saturating_sub(1));
+
+        Self { _state_machine: state_machine.start(), tx, rx, buffers, wiring, settle_us }
+    }
+
+    fn lines(&self) -> usize {
+        self.wiring.drive_lines(NUM_ROWS, NUM_COLS)
+    }
+
+    /// Roughly how long a scan takes, to sleep for after starting one.
+    pub fn scan_time_us(&self) -> u32 {
+        self.lines() as u32 * (2 * self.settle_us + CYCLES_PER_LINE)
+    }
+
+    /// Whether a scan is running.
+    pub fn is_busy(&self) -> bool {
+        dma().ch[RX_DMA_CHANNEL].ch_ctrl_trig.read().busy().bit_is_set()
+    }
+
+    /// Start a scan, unless one is already running.
+    pub fn start(&mut self) {
+        if self.is_busy() {
+            return;
+        }
+
+        compiler_fence(Ordering::SeqCst);
+        let lines = self.lines() as u32;
+        let dma = dma();
+
+        let rx = &dma.ch[RX_DMA_CHANNEL];
+        rx.ch_read_addr.write(|w| unsafe { w.bits(self.rx.fifo_address() as u32) });
+        rx.ch_write_addr.write(|w| unsafe { w.bits(self.buffers.samples.as_ptr() as u32) });
+        rx.ch_trans_count.write(|w| unsafe { w.bits(lines) });
+        rx.ch_ctrl_trig.write(|w| unsafe {
+            w.data_size().size_word();
+            w.incr_read().clear_bit();
+            w.incr_write().set_bit();
+            w.treq_sel().bits(self.rx.dreq_value());
+            // Chaining to itself is how a channel is kept from chaining to another.
+            w.chain_to().bits(RX_DMA_CHANNEL as u8);
+            w.en().set_bit()
+        });
+
+        let tx = &dma.ch[TX_DMA_CHANNEL];
+        tx.ch_read_addr.write(|w| unsafe { w.bits(self.buffers.steps.as_ptr() as u32) });
+        tx.ch_write_addr.write(|w| unsafe { w.bits(self.tx.fifo_address() as u32) });
+        tx.ch_trans_count.write(|w| unsafe { w.bits(2 * lines) });
+        tx.ch_ctrl_trig.write(|w| unsafe {
+            w.data_size().size_word();
+            w.incr_read().set_bit();
+            w.incr_write().clear_bit();
+            w.treq_sel().bits(self.tx.dreq_value());
+            w.chain_to().bits(TX_DMA_CHANNEL as u8);
+            w.en().set_bit()
+        });
+    }
+
+    /// The raw matrix from the last scan once it's finished, or `None` while it's running.
+    /// Positions which are false in `matrix_mask` always read as released.
+    pub fn poll(
+        &mut self,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> Option<[[bool; NUM_ROWS]; NUM_COLS]> {
+        if self.is_busy() {
+            return None;
+        }
+        compiler_fence(Ordering::SeqCst);
+
+        let pulled_up = self.wiring.sense == Sense::PullUp;
+        let mut raw_matrix = [[false; NUM_ROWS]; NUM_COLS];
+        for (col, column) in raw_matrix.iter_mut().enumerate() {
+            for (row, pressed) in column.iter_mut().enumerate() {
+                let (line, sense_gpio) = if self.wiring.drives_columns() {
+                    (col, ROW_PINS[row])
+                } else {
+                    (row, COL_PINS[col])
+                };
+                let high = self.buffers.samples[line] & (1 << sense_gpio) != 0;
+                *pressed = matrix_mask[col][row] && high != pulled_up;
+            }
+        }
+        Some(raw_matrix)
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> SwitchScanner<NUM_ROWS, NUM_COLS> for PioScanner<P, SM> {
+    /// Run a whole scan, waiting for it to finish.
+    fn read_raw(
+        &mut self,
+        matrix_mask: &[[bool; NUM_ROWS]; NUM_COLS],
+    ) -> [[bool; NUM_ROWS]; NUM_COLS] {
+        self.start();
+        loop {
+            if let Some(raw_matrix) = self.poll(matrix_mask) {
+                return raw_matrix;
+            }
+        }
+    }
+}
+
+fn dma() -> &'static pac::dma::RegisterBlock {
+    // Note (safety): Only `TX_DMA_CHANNEL` and `RX_DMA_CHANNEL` are used through this, and
+    // nothing else uses them
+    unsafe { &*pac::DMA::ptr() }
+}
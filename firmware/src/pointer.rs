@@ -0,0 +1,159 @@
+//! Turning pointer movement into USB mouse reports.
+//!
+//! Motion from a pointing device is accumulated between USB polls and handed out as reports
+//! whose fields fit in the mouse report's 8 bits. Like on a ThinkPad, the middle button
+//! scrolls: moving the pointer while it's held scrolls instead of moving, and a middle click
+//! is only sent if the button is released without having scrolled.
+//!
+//! Mouse keys (see `mouse_keys`) add their own movement, scrolling and buttons to the same
+//! reports.
+//!
+//! If the host turns on high-resolution scrolling with the Resolution Multiplier, scrolling
+//! is reported in fractions of a wheel detent, for smooth scrolling rather than jumps of
+//! whole lines.
+
+use usbd_hid::descriptor::MouseReport;
+
+use crate::mouse_keys::MouseMotion;
+
+pub const LEFT_BUTTON: u8 = 1 << 0;
+pub const RIGHT_BUTTON: u8 = 1 << 1;
+pub const MIDDLE_BUTTON: u8 = 1 << 2;
+
+/// Pointer counts per wheel step when scrolling with the middle button.
+const SCROLL_DIVISOR: i32 = 8;
+
+/// The number of high-resolution steps per wheel detent. This has to match the Physical
+/// Maximum of the Resolution Multipliers in `hid_descriptor::MOUSE_REPORT_DESCRIPTOR`.
+pub const RESOLUTION_MULTIPLIER: i32 = 8;
+
+/// How far the pointer has to move with the middle button held for it to count as scrolling
+/// rather than a middle click.
+const SCROLL_THRESHOLD: i32 = 3;
+
+/// Whether the host has turned on high-resolution scrolling for the wheel and pan.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct ResolutionMultipliers {
+    pub wheel: bool,
+    pub pan: bool,
+}
+
+#[derive(Default)]
+pub struct Pointer {
+    /// The buttons being held, apart from the middle button.
+    buttons: u8,
+    middle_held: bool,
+
+    /// Set once the pointer moves far enough while the middle button is held.
+    scrolling: bool,
+
+    /// A middle click waiting to be reported, after the middle button was released without
+    /// scrolling.
+    middle_click: bool,
+
+    /// The buttons held with mouse keys.
+    key_buttons: u8,
+
+    reported_buttons: u8,
+    resolution_multipliers: ResolutionMultipliers,
+
+    // Motion which hasn't been reported yet, in pointer counts.
+    x: i32,
+    y: i32,
+    scroll_x: i32,
+    scroll_y: i32,
+
+    // Scrolling from mouse keys which hasn't been reported yet, in wheel steps.
+    key_wheel: i32,
+    key_pan: i32,
+}
+
+impl Pointer {
+    /// Add movement from the device, with `y` increasing downwards like in a HID report.
+    pub fn motion(&mut self, buttons: u8, x: i16, y: i16) {
+        let middle_held = buttons & MIDDLE_BUTTON != 0;
+        if middle_held && !self.middle_held {
+            self.scrolling = false;
+            self.scroll_x = 0;
+            self.scroll_y = 0;
+        } else if !middle_held && self.middle_held && !self.scrolling {
+            self.middle_click = true;
+        }
+
+        self.middle_held = middle_held;
+        self.buttons = buttons & !MIDDLE_BUTTON;
+
+        if middle_held {
+            self.scroll_x += x as i32;
+            self.scroll_y += y as i32;
+            self.scrolling |= self.scroll_x.abs() + self.scroll_y.abs() >= SCROLL_THRESHOLD;
+        } else {
+            self.x += x as i32;
+            self.y += y as i32;
+        }
+    }
+
+    /// Add what the mouse keys did.
+    pub fn key_motion(&mut self, motion: MouseMotion) {
+        let steps_per_detent = |high_resolution| {
+            if high_resolution {
+                RESOLUTION_MULTIPLIER
+            } else {
+                1
+            }
+        };
+
+        self.key_buttons = motion.buttons;
+        self.x += motion.x as i32;
+        self.y += motion.y as i32;
+        self.key_wheel += motion.wheel as i32 * steps_per_detent(self.resolution_multipliers.wheel);
+        self.key_pan += motion.pan as i32 * steps_per_detent(self.resolution_multipliers.pan);
+    }
+
+    pub fn set_resolution_multipliers(&mut self, multipliers: ResolutionMultipliers) {
+        self.resolution_multipliers = multipliers;
+    }
+
+    /// The next report to send, if anything has changed since the last one.
+    pub fn take_report(&mut self) -> Option<MouseReport> {
+        let buttons = if self.middle_click { self.buttons | MIDDLE_BUTTON } else { self.buttons };
+        let buttons = buttons | self.key_buttons;
+        let x = take_steps(&mut self.x, 1);
+        let y = take_steps(&mut self.y, 1);
+        let (wheel, pan) = if self.scrolling {
+            // Pushing the pointer down scrolls down, which is a negative wheel movement.
+            let divisor = |high_resolution| {
+                if high_resolution {
+                    SCROLL_DIVISOR / RESOLUTION_MULTIPLIER
+                } else {
+                    SCROLL_DIVISOR
+                }
+            };
+
+            (
+                -take_steps(&mut self.scroll_y, divisor(self.resolution_multipliers.wheel)),
+                take_steps(&mut self.scroll_x, divisor(self.resolution_multipliers.pan)),
+            )
+        } else {
+            (0, 0)
+        };
+        let wheel = wheel.saturating_add(take_steps(&mut self.key_wheel, 1));
+        let pan = pan.saturating_add(take_steps(&mut self.key_pan, 1));
+
+        if buttons == self.reported_buttons && x == 0 && y == 0 && wheel == 0 && pan == 0 {
+            return None;
+        }
+
+        self.middle_click = false;
+        self.reported_buttons = buttons;
+        Some(MouseReport { buttons, x, y, wheel, pan })
+    }
+}
+
+/// Take as many whole steps of `divisor` counts out of `counts` as fit in a report field,
+/// leaving the rest for later.
+fn take_steps(counts: &mut i32, divisor: i32) -> i8 {
+    let steps = (*counts / divisor).clamp(-127, 127);
+    *counts -= steps * divisor;
+    steps as i8
+}
@@ -0,0 +1,63 @@
+//! Preset bundles of settings, tuned for either typing or gaming.
+
+use defmt::Format;
+
+/// A preset profile, switched between with `KeyCode::ToggleProfile`.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Profile {
+    /// A conservative debounce time to filter out chattering switches.
+    Typing,
+
+    /// A short debounce time for the lowest possible latency, with the GUI keys disabled so
+    /// they can't accidentally pull focus away from a game, and modifiers which can't be
+    /// locked by double tapping them.
+    Gaming,
+}
+
+/// The settings a `Profile` applies.
+pub struct ProfileSettings {
+    /// The number of milliseconds to wait until a "key-off-then-key-on" in quick succession
+    /// is allowed.
+    pub debounce_ms: u8,
+
+    /// Whether the left and right GUI (Cmd) keys are ignored.
+    pub gui_locked: bool,
+
+    /// Whether double tapping a modifier locks it, see `locking_mods`.
+    pub locking_mods: bool,
+}
+
+impl Profile {
+    pub fn settings(&self) -> ProfileSettings {
+        match self {
+            Profile::Typing => {
+                ProfileSettings { debounce_ms: 6, gui_locked: false, locking_mods: true }
+            },
+            Profile::Gaming => {
+                ProfileSettings { debounce_ms: 2, gui_locked: true, locking_mods: false }
+            },
+        }
+    }
+
+    pub fn toggled(&self) -> Self {
+        match self {
+            Profile::Typing => Profile::Gaming,
+            Profile::Gaming => Profile::Typing,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Profile::Typing => 0,
+            Profile::Gaming => 1,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Profile::Typing),
+            1 => Some(Profile::Gaming),
+            _ => None,
+        }
+    }
+}
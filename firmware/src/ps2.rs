@@ -0,0 +1,318 @@
+//! A PS/2 host running on a PIO block, for TrackPoint modules.
+//!
+//! PS/2 is a two wire open-collector bus: both ends only ever pull the clock and data lines
+//! low, and pull-up resistors bring them back high. The device always generates the clock.
+//! Every frame is a start bit (0), eight data bits least significant first, an odd parity
+//! bit, and a stop bit (1).
+//!
+//! Two state machines share the data pin and the clock pin, which must be the next GPIO
+//! after data. One continuously receives frames, sampling data on each falling clock edge.
+//! The other sends commands: it holds clock low to interrupt the device, puts the start bit
+//! on the data line, and lets the device clock the rest of the frame in before it
+//! acknowledges it with an extra clock.
+
+use cortex_m::delay::Delay;
+use defmt::Format;
+use pio::{
+    Assembler, InSource, InstructionOperands, JmpCondition, OutDestination, SetDestination,
+    WaitSource,
+};
+use rp2040_hal::pio::{
+    PIOBuilder, PIOExt, PinDir, PinState, Running, Rx, ShiftDirection, StateMachine, Tx,
+    UninitStateMachine, PIO, SM0, SM1,
+};
+
+/// Each state machine cycle lasts a microsecond, which makes the hold time easy to count
+/// and is still far faster than the PS/2 clock (10 to 16.7 kHz).
+const PIO_FREQUENCY_HZ: u32 = 1_000_000;
+
+/// How often to check whether the device has responded.
+const POLL_INTERVAL_US: u32 = 10;
+
+/// How long the device has to clock in a command and acknowledge it, or to answer.
+const RESPONSE_TIMEOUT_US: u32 = 25_000;
+
+/// How long a TrackPoint can take to run its self test after a reset.
+const SELF_TEST_TIMEOUT_US: u32 = 1_000_000;
+
+const COMMAND_RESET: u8 = 0xFF;
+const COMMAND_ENABLE_DATA_REPORTING: u8 = 0xF4;
+
+const RESPONSE_ACKNOWLEDGE: u8 = 0xFA;
+const RESPONSE_SELF_TEST_PASSED: u8 = 0xAA;
+const DEVICE_ID_MOUSE: u8 = 0x00;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Ps2Error {
+    /// A received frame had a bad start or stop bit.
+    Framing,
+
+    /// A received frame had the wrong parity.
+    Parity,
+
+    /// The device didn't clock in a command, or didn't answer in time.
+    Timeout,
+
+    /// The device clocked in a command but didn't acknowledge it.
+    NotAcknowledged,
+
+    /// The device answered with something other than what the command should return.
+    UnexpectedResponse(u8),
+}
+
+/// Check an 11 bit frame, with the start bit in the least significant bit, and return its
+/// data byte.
+pub fn decode_frame(frame: u16) -> Result<u8, Ps2Error> {
+    let start = frame & 1;
+    let data = (frame >> 1) as u8;
+    let parity = (frame >> 9) & 1;
+    let stop = (frame >> 10) & 1;
+
+    if start != 0 || stop != 1 {
+        return Err(Ps2Error::Framing);
+    }
+
+    if (data.count_ones() + parity as u32).is_multiple_of(2) {
+        return Err(Ps2Error::Parity);
+    }
+
+    Ok(data)
+}
+
+pub struct Ps2Host<P: PIOExt> {
+    receiver: StateMachine<(P, SM0), Running>,
+    receiver_rx: Rx<(P, SM0)>,
+    sender: StateMachine<(P, SM1), Running>,
+    sender_rx: Rx<(P, SM1)>,
+    sender_tx: Tx<(P, SM1)>,
+}
+
+impl<P: PIOExt> Ps2Host<P> {
+    /// Start the PS/2 host on the GPIO `data_pin` and the one after it, which must both be
+    /// set to the function of the PIO block.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm0: UninitStateMachine<(P, SM0)>,
+        sm1: UninitStateMachine<(P, SM1)>,
+        data_pin: u8,
+        system_clock_hz: u32,
+    ) -> Self {
+        let clock_pin = data_pin + 1;
+        let clock_divisor = (system_clock_hz / PIO_FREQUENCY_HZ) as f32;
+
+        // Wait pin indexes are relative to the input base, which is the data pin, so the
+        // clock is pin 1.
+        let mut receive = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+        receive.wait(0, WaitSource::PIN, 1, false);
+        receive.r#in(InSource::PINS, 1);
+        receive.wait(1, WaitSource::PIN, 1, false);
+        let receive = pio.install(&receive.assemble_program()).unwrap();
+
+        // The pin output values are always low, so setting a pin's direction to output
+        // pulls its line low and setting it to input releases it. `SET PINDIRS` covers both
+        // lines, with data in bit 0 and clock in bit 1.
+        let mut send = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+        let mut hold_clock = send.label();
+        let mut next_bit = send.label();
+        send.pull(false, true);
+        // Hold clock low for 125 µs, then pull data low for the start bit and let go of clock.
+        send.set(SetDestination::PINDIRS, 0b10);
+        send.set(SetDestination::X, 24);
+        send.bind(&mut hold_clock);
+        send.jmp_with_delay(JmpCondition::XDecNonZero, &mut hold_clock, 4);
+        send.set(SetDestination::PINDIRS, 0b11);
+        send.set(SetDestination::PINDIRS, 0b01);
+        // The eight data bits and the parity bit, already inverted to pin directions.
+        send.set(SetDestination::X, 8);
+        send.bind(&mut next_bit);
+        send.wait(0, WaitSource::PIN, 1, false);
+        send.out(OutDestination::PINDIRS, 1);
+        send.wait(1, WaitSource::PIN, 1, false);
+        send.jmp(JmpCondition::XDecNonZero, &mut next_bit);
+        // Release data for the stop bit, then sample the device's acknowledge bit.
+        send.wait(0, WaitSource::PIN, 1, false);
+        send.set(SetDestination::PINDIRS, 0);
+        send.wait(1, WaitSource::PIN, 1, false);
+        send.wait(0, WaitSource::PIN, 1, false);
+        send.r#in(InSource::PINS, 1);
+        send.wait(1, WaitSource::PIN, 1, false);
+        send.push(false, true);
+        let send = pio.install(&send.assemble_program()).unwrap();
+
+        let (receiver, receiver_rx, _) = PIOBuilder::from_program(receive)
+            .in_pin_base(data_pin)
+            .in_shift_direction(ShiftDirection::Right)
+            .autopush(true)
+            .push_threshold(11)
+            .clock_divisor(clock_divisor)
+            .build(sm0);
+
+        let (mut sender, sender_rx, sender_tx) = PIOBuilder::from_program(send)
+            .in_pin_base(data_pin)
+            .set_pins(data_pin, 2)
+            .out_pins(data_pin, 1)
+            .in_shift_direction(ShiftDirection::Right)
+            .out_shift_direction(ShiftDirection::Right)
+            .clock_divisor(clock_divisor)
+            .build(sm1);
+        sender.set_pins([(data_pin, PinState::Low), (clock_pin, PinState::Low)]);
+        sender.set_pindirs([(data_pin, PinDir::Input), (clock_pin, PinDir::Input)]);
+
+        Self {
+            receiver: receiver.start(),
+            receiver_rx,
+            sender: sender.start(),
+            sender_rx,
+            sender_tx,
+        }
+    }
+
+    /// The next byte received from the device, if there is one.
+    pub fn read(&mut self) -> Option<Result<u8, Ps2Error>> {
+        let frame = (self.receiver_rx.read()? >> 21) as u16;
+        let byte = decode_frame(frame);
+
+        // A bad frame most likely means the receiver lost track of where frames start, so
+        // start again from the next falling clock edge.
+        if byte == Err(Ps2Error::Framing) {
+            self.receiver.restart();
+        }
+
+        Some(byte)
+    }
+
+    /// Send a byte to the device, waiting until it has acknowledged receiving it. This
+    /// doesn't wait for the device's response, which can be read afterwards.
+    pub fn write(&mut self, byte: u8, delay: &mut Delay) -> Result<(), Ps2Error> {
+        let parity = byte.count_ones().is_multiple_of(2) as u32;
+        self.sender_tx.write(!(byte as u32 | parity << 8) & 0x1FF);
+
+        let mut acknowledge = None;
+        for _ in 0..RESPONSE_TIMEOUT_US / POLL_INTERVAL_US {
+            acknowledge = self.sender_rx.read();
+            if acknowledge.is_some() {
+                break;
+            }
+            delay.delay_us(POLL_INTERVAL_US);
+        }
+
+        // The receiver saw the clock edges of the command too, so throw away whatever it
+        // made of them.
+        self.receiver.restart();
+        while self.receiver_rx.read().is_some() {}
+
+        match acknowledge {
+            // The acknowledge bit is the only one shifted in, at the top of the word.
+            Some(0) => Ok(()),
+            Some(_) => Err(Ps2Error::NotAcknowledged),
+            None => {
+                // Let go of both lines and get ready for the next command.
+                self.sender.exec_instruction(
+                    InstructionOperands::SET { destination: SetDestination::PINDIRS, data: 0 }
+                        .encode(),
+                );
+                self.sender.restart();
+                Err(Ps2Error::Timeout)
+            },
+        }
+    }
+
+    /// Wait up to `timeout_us` for the next byte from the device.
+    pub fn read_blocking(&mut self, timeout_us: u32, delay: &mut Delay) -> Result<u8, Ps2Error> {
+        for _ in 0..timeout_us / POLL_INTERVAL_US {
+            if let Some(byte) = self.read() {
+                return byte;
+            }
+            delay.delay_us(POLL_INTERVAL_US);
+        }
+
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Send a command and check that the device acknowledges it.
+    pub fn command(&mut self, command: u8, delay: &mut Delay) -> Result<(), Ps2Error> {
+        self.write(command, delay)?;
+        self.expect(RESPONSE_ACKNOWLEDGE, RESPONSE_TIMEOUT_US, delay)
+    }
+
+    fn expect(&mut self, expected: u8, timeout_us: u32, delay: &mut Delay) -> Result<(), Ps2Error> {
+        match self.read_blocking(timeout_us, delay)? {
+            byte if byte == expected => Ok(()),
+            byte => Err(Ps2Error::UnexpectedResponse(byte)),
+        }
+    }
+}
+
+/// One movement report from a PS/2 mouse.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub struct MousePacket {
+    /// The held buttons, in the same bit order as `pointer::LEFT_BUTTON` and friends.
+    pub buttons: u8,
+    pub x: i16,
+
+    /// Movement down, like in a HID report. PS/2 counts upward movement as positive.
+    pub y: i16,
+}
+
+impl MousePacket {
+    /// Parse a standard 3 byte packet: the buttons, sign and overflow bits, then the X and
+    /// Y movement. Movement which overflowed is dropped.
+    pub fn from_bytes(bytes: [u8; 3]) -> Self {
+        let [flags, x, y] = bytes;
+
+        let axis = |value: u8, sign_bit: u8, overflow_bit: u8| -> i16 {
+            if flags & (1 << overflow_bit) != 0 {
+                0
+            } else if flags & (1 << sign_bit) != 0 {
+                value as i16 - 256
+            } else {
+                value as i16
+            }
+        };
+
+        Self { buttons: flags & 0b111, x: axis(x, 4, 6), y: -axis(y, 5, 7) }
+    }
+}
+
+/// A TrackPoint, or any other PS/2 mouse, reporting movement in stream mode.
+pub struct TrackPoint<P: PIOExt> {
+    host: Ps2Host<P>,
+    packet: [u8; 3],
+    packet_len: usize,
+}
+
+impl<P: PIOExt> TrackPoint<P> {
+    /// Reset the device and turn on movement reporting. This takes a while, as the device
+    /// runs a self test after being reset.
+    pub fn init(mut host: Ps2Host<P>, delay: &mut Delay) -> Result<Self, Ps2Error> {
+        host.command(COMMAND_RESET, delay)?;
+        host.expect(RESPONSE_SELF_TEST_PASSED, SELF_TEST_TIMEOUT_US, delay)?;
+        host.expect(DEVICE_ID_MOUSE, RESPONSE_TIMEOUT_US, delay)?;
+        host.command(COMMAND_ENABLE_DATA_REPORTING, delay)?;
+
+        Ok(Self { host, packet: [0; 3], packet_len: 0 })
+    }
+
+    /// The next complete movement packet, if one has arrived.
+    pub fn poll(&mut self) -> Result<Option<MousePacket>, Ps2Error> {
+        while let Some(byte) = self.host.read() {
+            let byte = byte.inspect_err(|_| self.packet_len = 0)?;
+
+            // The first byte of a packet always has bit 3 set, which is the only way to
+            // find the start of a packet again after losing a byte.
+            if self.packet_len == 0 && byte & (1 << 3) == 0 {
+                continue;
+            }
+
+            self.packet[self.packet_len] = byte;
+            self.packet_len += 1;
+
+            if self.packet_len == self.packet.len() {
+                self.packet_len = 0;
+                return Ok(Some(MousePacket::from_bytes(self.packet)));
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,477 @@
+//! A small request/response protocol for configuration tools, over a raw HID interface
+//! described by `hid_descriptor::RAW_HID_REPORT_DESCRIPTOR`. Unlike the vendor interface in
+//! `webusb`, raw HID needs no driver or permissions on any OS, and browsers can open it with
+//! WebHID. The same requests can also be made over the vendor interface, with WebUSB.
+//!
+//! Every report is `RAW_REPORT_LEN` bytes, in both directions:
+//!
+//! | Bytes    | Contents                                                          |
+//! |----------|-------------------------------------------------------------------|
+//! | `0`      | The command, see `Command`                                        |
+//! | `1`      | A sequence number picked by the host, echoed in the response      |
+//! | `2`      | The status of a response, see `Status`, and zero in requests      |
+//! | `3`      | The payload length                                                |
+//! | `4..28`  | The payload, padded with zeros                                    |
+//! | `28..32` | The CRC-32 of bytes `0..28`, little endian                        |
+//!
+//! The host sends one request at a time, and waits for the response with the same command
+//! and sequence number before sending the next. Every host with the interface open sees every
+//! response, so one which isn't its own is skipped. A request which arrives while another is
+//! still being handled is answered with `Status::Busy`, and can be sent again.
+//!
+//! Requests have a status of zero and zeros after the payload, and anything else is answered
+//! with `Status::InvalidArgument`. While the configuration is locked (see
+//! `KeyCode::ToggleConfigLock`), every command which would change something is answered with
+//! `Status::Locked`.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use defmt::Format;
+
+use crate::{
+    build_info::BUILD_INFO,
+    config_block::crc32,
+    crash::{Crash, PanicAction, PanicLog},
+    debounce::{BounceStats, BOUNCE_BUCKETS},
+    idle::IdleSettings,
+    key_codes::KeyCode,
+    key_mapping::NUM_LAYERS,
+    keymap::Keymap,
+    latency::LatencyStats,
+    macros::MACRO_COUNT,
+    matrix_check::MatrixStats,
+    NUM_COLS, NUM_ROWS,
+};
+
+/// The length of every report, in both directions.
+pub const RAW_REPORT_LEN: usize = 32;
+
+/// The space left for the payload, after the header and CRC.
+pub const MAX_PAYLOAD_LEN: usize = RAW_REPORT_LEN - HEADER_LEN - 4;
+
+/// Bumped whenever a command changes in a way host tools have to know about.
+pub const PROTOCOL_VERSION: u8 = 10;
+
+const HEADER_LEN: usize = 4;
+
+/// Where a request waits for the main loop, or its response for the host, shared between
+/// the USB interrupt and the main loop.
+pub type ReportSlot = Mutex<RefCell<Option<[u8; RAW_REPORT_LEN]>>>;
+
+/// The payload of a `Command::GetMatrix` response, one bit per key, column by column.
+const MATRIX_BITMAP_LEN: usize = (NUM_COLS * NUM_ROWS).div_ceil(8);
+
+const _: () = assert!(MATRIX_BITMAP_LEN <= MAX_PAYLOAD_LEN);
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Command {
+    /// Responds with `PROTOCOL_VERSION`, followed by the firmware version as text.
+    GetVersion,
+
+    /// Responds with a bitmap of the keys held in the last scan, key `(col, row)` in bit
+    /// `col * NUM_ROWS + row`.
+    GetMatrix,
+
+    /// Takes `[layer, col, row]`, and responds with the `KeyCode` mapped there, as two bytes,
+    /// little endian.
+    GetKey,
+
+    /// Takes `[layer, col, row, key_low, key_high]`, and maps the key there. The change takes effect
+    /// straight away, but is lost on reboot unless followed by `SaveKeymap`.
+    SetKey,
+
+    /// Saves the keymap to flash, see `keymap::Keymap::save`.
+    SaveKeymap,
+
+    /// Responds with the counters of `matrix_check::MatrixStats`: ghost presses ignored,
+    /// then stuck keys, each four bytes, little endian.
+    GetMatrixStats,
+
+    /// Responds with why the keyboard last restarted, see `crash::Crash`: 0 for a normal
+    /// start, 1 for a panic and 2 for the watchdog. A panic is followed by its line, four
+    /// bytes little endian, and as much of the end of its file name as fits.
+    GetLastCrash,
+
+    /// Responds with the idle timeout in minutes and the away macro's index, or `0xFF` for
+    /// none, see `idle::IdleSettings`.
+    GetIdleSettings,
+
+    /// Takes `[timeout_min, away_macro]`, as `GetIdleSettings` responds with, and saves them
+    /// with the other settings.
+    SetIdleSettings,
+
+    /// Takes `[offset]`, and responds with as much of the saved panic's text from `offset`
+    /// as fits, see `crash::PanicLog`. Nothing comes back past the end, or if there's no
+    /// panic saved.
+    GetPanicLog,
+
+    /// Responds with what the keyboard does after a panic, see `crash::PanicAction`: 0 to
+    /// reset, 1 for the bootloader and 2 to halt.
+    GetPanicAction,
+
+    /// Takes `[action]`, as `GetPanicAction` responds with, and saves it with the other
+    /// settings.
+    SetPanicAction,
+
+    /// Responds with what the firmware was built from, see `build_info::BuildInfo::to_bytes`,
+    /// so tools can check a keymap matches the keyboard's matrix before writing it.
+    Identify,
+
+    /// Takes `[col, row]`, and responds with whether the key is chattering, as 0 or 1, then
+    /// its histogram of bounces per press, see `debounce::BounceStats`, each bucket two bytes,
+    /// little endian.
+    GetBounceStats,
+
+    /// Responds with the latency of key presses from the scan to the endpoint, see
+    /// `latency::LatencyStats::to_bytes`.
+    GetLatencyStats,
+
+    /// Starts the latency stats over, such as after changing the debounce time.
+    ResetLatencyStats,
+}
+
+impl Command {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Command::GetVersion => 0x01,
+            Command::GetMatrix => 0x02,
+            Command::GetKey => 0x03,
+            Command::SetKey => 0x04,
+            Command::SaveKeymap => 0x05,
+            Command::GetMatrixStats => 0x06,
+            Command::GetLastCrash => 0x07,
+            Command::GetIdleSettings => 0x08,
+            Command::SetIdleSettings => 0x09,
+            Command::GetPanicLog => 0x0A,
+            Command::GetPanicAction => 0x0B,
+            Command::SetPanicAction => 0x0C,
+            Command::Identify => 0x0D,
+            Command::GetBounceStats => 0x0E,
+            Command::GetLatencyStats => 0x0F,
+            Command::ResetLatencyStats => 0x10,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Command::GetVersion),
+            0x02 => Some(Command::GetMatrix),
+            0x03 => Some(Command::GetKey),
+            0x04 => Some(Command::SetKey),
+            0x05 => Some(Command::SaveKeymap),
+            0x06 => Some(Command::GetMatrixStats),
+            0x07 => Some(Command::GetLastCrash),
+            0x08 => Some(Command::GetIdleSettings),
+            0x09 => Some(Command::SetIdleSettings),
+            0x0A => Some(Command::GetPanicLog),
+            0x0B => Some(Command::GetPanicAction),
+            0x0C => Some(Command::SetPanicAction),
+            0x0D => Some(Command::Identify),
+            0x0E => Some(Command::GetBounceStats),
+            0x0F => Some(Command::GetLatencyStats),
+            0x10 => Some(Command::ResetLatencyStats),
+            _ => None,
+        }
+    }
+
+    /// Whether the command changes the configuration, and is refused while it's locked.
+    fn changes_config(self) -> bool {
+        matches!(
+            self,
+            Command::SetKey
+                | Command::SaveKeymap
+                | Command::SetIdleSettings
+                | Command::SetPanicAction
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Status {
+    Ok,
+
+    /// The request's CRC didn't match, so none of it can be trusted.
+    BadCrc,
+
+    UnknownCommand,
+
+    /// The payload was the wrong length, or out of range, like a layer which doesn't exist.
+    InvalidArgument,
+
+    /// The configuration is locked.
+    Locked,
+
+    /// Another request was still being handled, so this one was dropped.
+    Busy,
+}
+
+impl Status {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Status::Ok => 0,
+            Status::BadCrc => 1,
+            Status::UnknownCommand => 2,
+            Status::InvalidArgument => 3,
+            Status::Locked => 4,
+            Status::Busy => 5,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Status::Ok),
+            1 => Some(Status::BadCrc),
+            2 => Some(Status::UnknownCommand),
+            3 => Some(Status::InvalidArgument),
+            4 => Some(Status::Locked),
+            5 => Some(Status::Busy),
+            _ => None,
+        }
+    }
+}
+
+/// A request or response, as laid out in the module documentation.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Packet {
+    pub command: u8,
+    pub sequence: u8,
+    pub status: Status,
+    len: u8,
+    payload: [u8; MAX_PAYLOAD_LEN],
+}
+
+impl Packet {
+    /// A packet with `payload`, which is truncated to `MAX_PAYLOAD_LEN`.
+    pub fn new(command: u8, sequence: u8, status: Status, payload: &[u8]) -> Self {
+        let len = payload.len().min(MAX_PAYLOAD_LEN);
+        let mut packet =
+            Self { command, sequence, status, len: len as u8, payload: [0; MAX_PAYLOAD_LEN] };
+        packet.payload[..len].copy_from_slice(&payload[..len]);
+        packet
+    }
+
+    /// Read a packet from a report, or the status to respond with if it's invalid.
+    pub fn parse(bytes: &[u8; RAW_REPORT_LEN]) -> Result<Self, Status> {
+        let (data, crc) = bytes.split_at(RAW_REPORT_LEN - 4);
+        if crc32(&[data]) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err(Status::BadCrc);
+        }
+
+        let status = Status::from_u8(data[2]).ok_or(Status::InvalidArgument)?;
+        let (payload, padding) =
+            data[HEADER_LEN..].split_at_checked(data[3] as usize).ok_or(Status::InvalidArgument)?;
+        if padding.iter().any(|byte| *byte != 0) {
+            return Err(Status::InvalidArgument);
+        }
+        Ok(Self::new(data[0], data[1], status, payload))
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.len as usize]
+    }
+
+    pub fn to_bytes(&self) -> [u8; RAW_REPORT_LEN] {
+        let mut bytes = [0u8; RAW_REPORT_LEN];
+        bytes[0] = self.command;
+        bytes[1] = self.sequence;
+        bytes[2] = self.status.to_u8();
+        bytes[3] = self.len;
+        bytes[HEADER_LEN..HEADER_LEN + MAX_PAYLOAD_LEN].copy_from_slice(&self.payload);
+
+        let crc = crc32(&[&bytes[..RAW_REPORT_LEN - 4]]);
+        bytes[RAW_REPORT_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// The response to a request which arrived while another was still being handled, sent
+/// without waiting for the main loop.
+pub fn busy_response(request: &[u8; RAW_REPORT_LEN]) -> [u8; RAW_REPORT_LEN] {
+    Packet::new(request[0], request[1], Status::Busy, &[]).to_bytes()
+}
+
+/// Answers requests from the host, on behalf of the main loop.
+#[derive(Default)]
+pub struct RawHid {
+    keymap_save_requested: bool,
+    matrix_stats: MatrixStats,
+    bounce_stats: BounceStats<NUM_ROWS, NUM_COLS>,
+    latency_stats: LatencyStats,
+    latency_reset_requested: bool,
+    last_crash: Option<Crash>,
+    idle_settings: IdleSettings,
+    panic_log: Option<PanicLog>,
+    panic_action: PanicAction,
+}
+
+impl RawHid {
+    /// Update the counters `Command::GetMatrixStats` responds with.
+    pub fn set_matrix_stats(&mut self, stats: MatrixStats) {
+        self.matrix_stats = stats;
+    }
+
+    /// Update the histograms `Command::GetBounceStats` responds with.
+    pub fn set_bounce_stats(&mut self, stats: &BounceStats<NUM_ROWS, NUM_COLS>) {
+        self.bounce_stats = stats.clone();
+    }
+
+    /// Update the stats `Command::GetLatencyStats` responds with.
+    pub fn set_latency_stats(&mut self, stats: LatencyStats) {
+        self.latency_stats = stats;
+    }
+
+    /// Set the crash `Command::GetLastCrash` responds with.
+    pub fn set_last_crash(&mut self, crash: Option<Crash>) {
+        self.last_crash = crash;
+    }
+
+    /// The idle settings, as last set by `set_idle_settings` or the host.
+    pub fn idle_settings(&self) -> IdleSettings {
+        self.idle_settings
+    }
+
+    pub fn set_idle_settings(&mut self, settings: IdleSettings) {
+        self.idle_settings = settings;
+    }
+
+    /// Set the panic `Command::GetPanicLog` responds with.
+    pub fn set_panic_log(&mut self, log: Option<PanicLog>) {
+        self.panic_log = log;
+    }
+
+    /// The panic action, as last set by `set_panic_action` or the host.
+    pub fn panic_action(&self) -> PanicAction {
+        self.panic_action
+    }
+
+    pub fn set_panic_action(&mut self, action: PanicAction) {
+        self.panic_action = action;
+    }
+
+    /// Handle one request report, returning the response report. `matrix` is the last
+    /// debounced scan, and changes to the keymap are made to `keymap`.
+    pub fn handle(
+        &mut self,
+        request: &[u8; RAW_REPORT_LEN],
+        keymap: &mut Keymap,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        config_locked: bool,
+    ) -> [u8; RAW_REPORT_LEN] {
+        let respond = |status, payload: &[u8]| {
+            Packet::new(request[0], request[1], status, payload).to_bytes()
+        };
+
+        let request = match Packet::parse(request) {
+            Ok(request) if request.status == Status::Ok => request,
+            Ok(_) => return respond(Status::InvalidArgument, &[]),
+            Err(status) => return respond(status, &[]),
+        };
+        let Some(command) = Command::from_u8(request.command) else {
+            return respond(Status::UnknownCommand, &[]);
+        };
+        if command.changes_config() && config_locked {
+            return respond(Status::Locked, &[]);
+        }
+
+        let in_range = |layer: u8, col: u8, row: u8| {
+            (layer as usize) < NUM_LAYERS && (col as usize) < NUM_COLS && (row as usize) < NUM_ROWS
+        };
+
+        match (command, request.payload()) {
+            (Command::GetVersion, []) => {
+                let version = env!("CARGO_PKG_VERSION").as_bytes();
+                let mut payload = [0u8; MAX_PAYLOAD_LEN];
+                payload[0] = PROTOCOL_VERSION;
+                let len = (1 + version.len()).min(MAX_PAYLOAD_LEN);
+                payload[1..len].copy_from_slice(&version[..len - 1]);
+                respond(Status::Ok, &payload[..len])
+            },
+            (Command::GetMatrix, []) => {
+                let mut bitmap = [0u8; MATRIX_BITMAP_LEN];
+                for (i, pressed) in matrix.iter().flatten().enumerate() {
+                    bitmap[i / 8] |= (*pressed as u8) << (i % 8);
+                }
+                respond(Status::Ok, &bitmap)
+            },
+            (Command::GetKey, [layer, col, row]) if in_range(*layer, *col, *row) => {
+                let key = keymap.key(*layer as usize, *col as usize, *row as usize);
+                respond(Status::Ok, &(key as u16).to_le_bytes())
+            },
+            (Command::SetKey, [layer, col, row, low, high]) if in_range(*layer, *col, *row) => {
+                let Some(key) = KeyCode::from_u16(u16::from_le_bytes([*low, *high])) else {
+                    return respond(Status::InvalidArgument, &[]);
+                };
+                keymap.set_key(*layer as usize, *col as usize, *row as usize, key);
+                respond(Status::Ok, &[])
+            },
+            (Command::SaveKeymap, []) => {
+                self.keymap_save_requested = true;
+                respond(Status::Ok, &[])
+            },
+            (Command::GetMatrixStats, []) => respond(Status::Ok, &self.matrix_stats.to_bytes()),
+            (Command::GetLastCrash, []) => match self.last_crash {
+                None => respond(Status::Ok, &[0]),
+                Some(Crash::Watchdog) => respond(Status::Ok, &[2]),
+                Some(Crash::Panic { file, line }) => {
+                    let mut payload = [0u8; MAX_PAYLOAD_LEN];
+                    payload[0] = 1;
+                    payload[1..5].copy_from_slice(&line.to_le_bytes());
+                    let file = file.as_bytes();
+                    let file = &file[file.len().saturating_sub(MAX_PAYLOAD_LEN - 5)..];
+                    payload[5..5 + file.len()].copy_from_slice(file);
+                    respond(Status::Ok, &payload[..5 + file.len()])
+                },
+            },
+            (Command::GetIdleSettings, []) => respond(Status::Ok, &self.idle_settings.to_bytes()),
+            (Command::SetIdleSettings, [timeout_min, away_macro])
+                if *away_macro == 0xFF || (*away_macro as usize) < MACRO_COUNT =>
+            {
+                self.idle_settings = IdleSettings::from_bytes([*timeout_min, *away_macro]);
+                respond(Status::Ok, &[])
+            },
+            (Command::GetPanicLog, [offset]) => {
+                let text = self.panic_log.as_ref().map_or(&[][..], PanicLog::text);
+                respond(Status::Ok, text.get(*offset as usize..).unwrap_or(&[]))
+            },
+            (Command::GetPanicAction, []) => respond(Status::Ok, &[self.panic_action.to_u8()]),
+            (Command::SetPanicAction, [action]) => match PanicAction::from_u8(*action) {
+                Some(action) => {
+                    self.panic_action = action;
+                    respond(Status::Ok, &[])
+                },
+                None => respond(Status::InvalidArgument, &[]),
+            },
+            (Command::Identify, []) => respond(Status::Ok, &BUILD_INFO.to_bytes()),
+            (Command::GetBounceStats, [col, row])
+                if (*col as usize) < NUM_COLS && (*row as usize) < NUM_ROWS =>
+            {
+                let (col, row) = (*col as usize, *row as usize);
+                let mut payload = [0u8; 1 + 2 * BOUNCE_BUCKETS];
+                payload[0] = self.bounce_stats.is_chattering(col, row) as u8;
+                for (i, count) in self.bounce_stats.histogram(col, row).iter().enumerate() {
+                    payload[1 + 2 * i..3 + 2 * i].copy_from_slice(&count.to_le_bytes());
+                }
+                respond(Status::Ok, &payload)
+            },
+            (Command::GetLatencyStats, []) => respond(Status::Ok, &self.latency_stats.to_bytes()),
+            (Command::ResetLatencyStats, []) => {
+                self.latency_reset_requested = true;
+                respond(Status::Ok, &[])
+            },
+            _ => respond(Status::InvalidArgument, &[]),
+        }
+    }
+
+    /// Whether the host asked for the keymap to be saved since this was last called. Saving
+    /// is left to the main loop, which reports any flash failure like other writes.
+    pub fn take_keymap_save_request(&mut self) -> bool {
+        core::mem::take(&mut self.keymap_save_requested)
+    }
+
+    /// Whether the host asked for the latency stats to be reset since this was last called.
+    /// The stats are kept by the USB interrupt, so the main loop resets them.
+    pub fn take_latency_reset_request(&mut self) -> bool {
+        core::mem::take(&mut self.latency_reset_requested)
+    }
+}
@@ -0,0 +1,50 @@
+//! The repeat key, `KeyCode::Repeat`, which sends the last key sent again, with whatever
+//! modifiers are held now, so a doubled letter or a run of arrows doesn't need a finger to
+//! go back to the key.
+//!
+//! The last key is taken from the reports as they're built, so it's the last one the host
+//! saw pressed, whether it was typed, played by a macro or resolved through a tap-hold or
+//! conditional key. Modifiers don't count, so `Shift` and then the repeat key sends the key
+//! before, shifted.
+
+use crate::nkro::{NkroReport, NKRO_KEYS};
+
+#[derive(Default)]
+pub struct RepeatKey {
+    /// The usage of the last key newly pressed in a report, other than by the repeat key.
+    last: Option<u8>,
+
+    /// The usage the repeat key is sending, while it's held.
+    repeating: Option<u8>,
+}
+
+impl RepeatKey {
+    /// Update with whether a repeat key is held, returning the usage it sends, to add to the
+    /// report. It's the last key as of the press, until the repeat key is released.
+    pub fn update(&mut self, held: bool) -> Option<u8> {
+        if !held {
+            self.repeating = None;
+        } else if self.repeating.is_none() {
+            self.repeating = self.last;
+        }
+        self.repeating
+    }
+
+    /// Note the keys newly pressed in `report` since `previous`, to repeat the last of them.
+    /// Keys pressed in the same report go in order of usage.
+    pub fn record(&mut self, previous: &NkroReport, report: &NkroReport) {
+        let pressed = (0..NKRO_KEYS as u8).rev().find(|usage| {
+            report.is_pressed(*usage)
+                && !previous.is_pressed(*usage)
+                && Some(*usage) != self.repeating
+        });
+        if pressed.is_some() {
+            self.last = pressed;
+        }
+    }
+
+    /// The usage the repeat key would send if pressed now.
+    pub fn last(&self) -> Option<u8> {
+        self.last
+    }
+}
@@ -0,0 +1,89 @@
+//! A fixed-capacity, lock-free queue of reports from the main loop to the USB interrupt,
+//! so a report the endpoint isn't ready for can wait for the next interrupt instead of
+//! being dropped.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub struct ReportQueue<T, const N: usize> {
+    slots: UnsafeCell<[T; N]>,
+
+    /// The number of reports ever popped, only changed by the consumer.
+    head: AtomicUsize,
+
+    /// The number of reports ever pushed, only changed by the producer.
+    tail: AtomicUsize,
+}
+
+// Note (safety): Each slot is only written by the producer while it's outside the range
+// between `head` and `tail`, and only read by the consumer while it's inside.
+unsafe impl<T: Send, const N: usize> Sync for ReportQueue<T, N> {}
+
+impl<T: Copy, const N: usize> ReportQueue<T, N> {
+    /// An empty queue. `filler` is never read, it only initializes the slots.
+    pub const fn new(filler: T) -> Self {
+        Self {
+            slots: UnsafeCell::new([filler; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a report to the back of the queue, returning false if the queue is full.
+    ///
+    /// # Safety
+    /// Only one context may push.
+    pub unsafe fn push(&self, report: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return false;
+        }
+
+        self.slot(tail).write(report);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// The report at the front of the queue, which stays there until it's popped.
+    ///
+    /// # Safety
+    /// Only one context may read from the queue, with `front` and `pop`.
+    pub unsafe fn front(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        Some(self.slot(head).read())
+    }
+
+    /// Remove the report at the front of the queue.
+    ///
+    /// # Safety
+    /// Only one context may read from the queue, with `front` and `pop`.
+    pub unsafe fn pop(&self) {
+        let head = self.head.load(Ordering::Relaxed);
+        if head != self.tail.load(Ordering::Acquire) {
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    /// The number of reports ever pushed, which is the index the next one pushed gets.
+    pub fn pushed(&self) -> usize {
+        self.tail.load(Ordering::Acquire)
+    }
+
+    /// The number of reports ever popped, and so sent.
+    pub fn popped(&self) -> usize {
+        self.head.load(Ordering::Acquire)
+    }
+
+    /// The slot for the report with this index. Slots are accessed through raw pointers,
+    /// so the producer and consumer never hold references to the same array.
+    fn slot(&self, index: usize) -> *mut T {
+        // Note (safety): `index % N` is always within the array
+        unsafe { self.slots.get().cast::<T>().add(index % N) }
+    }
+}
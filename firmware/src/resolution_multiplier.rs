@@ -0,0 +1,74 @@
+//! Handles the mouse interface's Resolution Multiplier feature report, which the host sets
+//! to switch the wheel and pan to high-resolution scrolling.
+//!
+//! `usbd_hid` doesn't support feature reports, so this class answers the interface's
+//! GET_REPORT and SET_REPORT requests for it instead. It has to come before the mouse's
+//! `HIDClass` in the list of classes given to `UsbDevice::poll`, so it sees the requests
+//! first.
+
+use usb_device::{
+    class::{ControlIn, ControlOut, UsbClass},
+    class_prelude::UsbBus,
+    control::{Recipient, RequestType},
+};
+
+use crate::pointer::ResolutionMultipliers;
+
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const REPORT_TYPE_FEATURE: u16 = 3;
+
+pub struct ResolutionMultiplierClass {
+    interface: u16,
+    feature_report: u8,
+}
+
+impl ResolutionMultiplierClass {
+    /// Handle the feature report of the mouse HID interface numbered `interface`.
+    pub fn new(interface: u16) -> Self {
+        Self { interface, feature_report: 0 }
+    }
+
+    /// The multipliers last set by the host.
+    pub fn multipliers(&self) -> ResolutionMultipliers {
+        ResolutionMultipliers {
+            wheel: self.feature_report & 0b0011 != 0,
+            pan: self.feature_report & 0b1100 != 0,
+        }
+    }
+
+    fn is_feature_request(&self, request: &usb_device::control::Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == self.interface
+            && request.value >> 8 == REPORT_TYPE_FEATURE
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for ResolutionMultiplierClass {
+    fn reset(&mut self) {
+        self.feature_report = 0;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if request.request == HID_REQ_GET_REPORT && self.is_feature_request(request) {
+            xfer.accept_with(&[self.feature_report]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if request.request == HID_REQ_SET_REPORT && self.is_feature_request(request) {
+            match xfer.data() {
+                [report] => {
+                    self.feature_report = *report;
+                    xfer.accept().ok();
+                },
+                _ => {
+                    xfer.reject().ok();
+                },
+            }
+        }
+    }
+}
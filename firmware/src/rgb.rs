@@ -0,0 +1,376 @@
+//! Per-key RGB backlighting, on a chain of WS2812 (or SK6812) LEDs with one under each key.
+//!
+//! The chain is driven by a PIO state machine generating the WS2812 bit timing, which a DMA
+//! channel feeds from a frame buffer, so sending a frame costs the scan loop no more than
+//! starting the transfer. The chain runs through the keys row by row, left to right, skipping
+//! positions with no key on the base layer. Change `RgbBacklight::new` for a board wired
+//! another way.
+//!
+//! Each key's color comes from what it's mapped to on the active layer (see `key_color`), so
+//! holding Fn shows which keys do something on the Fn layer. The brightness and animation
+//! are changed with keys on the Fn layer, and saved with the other settings. The keys of
+//! locked modifiers (see `locking_mods`) stay fully lit in `LOCKED_COLOR` whatever the
+//! animation.
+
+use defmt::Format;
+use pio::{Assembler, JmpCondition, OutDestination, SideSet};
+use rp2040_hal::{
+    pac,
+    pio::{
+        Buffers, PIOBuilder, PIOExt, PinDir, Running, ShiftDirection, StateMachine,
+        StateMachineIndex, Tx, UninitStateMachine, PIO,
+    },
+};
+
+use crate::{key_codes::KeyCode, NUM_COLS, NUM_ROWS};
+
+/// The most LEDs the chain can have, one for every matrix position.
+pub const MAX_LEDS: usize = NUM_COLS * NUM_ROWS;
+
+/// How often a new frame is sent to the chain.
+pub const FRAME_INTERVAL_MS: u64 = 10;
+
+/// How much each press of `KeyCode::RgbBrightnessUp` or `RgbBrightnessDown` changes the
+/// brightness by.
+pub const BRIGHTNESS_STEP: u8 = 32;
+
+/// How long one breath of `Animation::Breathing` takes.
+const BREATHING_PERIOD_MS: u64 = 4000;
+
+/// How long a key pressed with `Animation::Reactive` takes to fade back.
+const REACTIVE_FADE_MS: u64 = 500;
+
+/// The level keys which haven't been pressed lately are lit at with `Animation::Reactive`,
+/// out of 255.
+const REACTIVE_IDLE_LEVEL: u8 = 32;
+
+/// The color the keys of locked modifiers are lit with.
+pub const LOCKED_COLOR: Color = Color::new(0, 255, 96);
+
+/// The WS2812 bit rate.
+const BIT_FREQUENCY_HZ: u32 = 800_000;
+
+/// The state machine cycles in each bit, split into the three phases of the program: low
+/// after the previous bit, high at the start of every bit, and then high for a one or low
+/// for a zero.
+const CYCLES_T1: u8 = 2;
+const CYCLES_T2: u8 = 5;
+const CYCLES_T3: u8 = 3;
+const CYCLES_PER_BIT: u32 = (CYCLES_T1 + CYCLES_T2 + CYCLES_T3) as u32;
+
+/// The DMA channel which feeds the state machine.
+const DMA_CHANNEL: usize = 0;
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    pub const OFF: Self = Self::new(0, 0, 0);
+
+    pub const fn new(red: u8, green: u8, blue: u8) -> Self {
+        Self { red, green, blue }
+    }
+
+    /// The color at `level` out of 255 of its brightness.
+    pub fn scaled(self, level: u8) -> Self {
+        let scale = |channel: u8| (channel as u16 * level as u16 / 255) as u8;
+        Self::new(scale(self.red), scale(self.green), scale(self.blue))
+    }
+
+    /// The color as the chain takes it: green, red and blue, in the top 24 bits of the word
+    /// shifted out to the LEDs.
+    pub fn to_grb_word(self) -> u32 {
+        (self.green as u32) << 24 | (self.red as u32) << 16 | (self.blue as u32) << 8
+    }
+}
+
+/// How the backlight changes over time.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Animation {
+    /// Every key lit steadily.
+    Static,
+
+    /// The whole keyboard slowly fading in and out.
+    Breathing,
+
+    /// Keys dimly lit, lighting up as they're pressed and fading back afterwards.
+    Reactive,
+}
+
+impl Animation {
+    /// The animation after this one, cycled through with `KeyCode::RgbNextAnimation`.
+    pub fn next(self) -> Self {
+        match self {
+            Animation::Static => Animation::Breathing,
+            Animation::Breathing => Animation::Reactive,
+            Animation::Reactive => Animation::Static,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Animation::Static => 0,
+            Animation::Breathing => 1,
+            Animation::Reactive => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Animation::Static),
+            1 => Some(Animation::Breathing),
+            2 => Some(Animation::Reactive),
+            _ => None,
+        }
+    }
+}
+
+/// The backlight settings changed from the keyboard, which persist across reboots.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub struct RgbSettings {
+    pub enabled: bool,
+
+    /// Out of 255.
+    pub brightness: u8,
+
+    pub animation: Animation,
+}
+
+impl Default for RgbSettings {
+    fn default() -> Self {
+        Self { enabled: true, brightness: 128, animation: Animation::Static }
+    }
+}
+
+impl RgbSettings {
+    pub fn brighter(self) -> Self {
+        Self { brightness: self.brightness.saturating_add(BRIGHTNESS_STEP), ..self }
+    }
+
+    /// Dims the backlight, without going completely dark, which `enabled` is for.
+    pub fn dimmer(self) -> Self {
+        Self { brightness: self.brightness.saturating_sub(BRIGHTNESS_STEP).max(1), ..self }
+    }
+}
+
+/// The color a key is lit with, by what it does.
+pub fn key_color(key: KeyCode) -> Color {
+    match key {
+        KeyCode::Empty | KeyCode::Transparent => Color::OFF,
+        KeyCode::Escape => Color::new(255, 0, 0),
+        _ if key.modifier_bitmask().is_some() => Color::new(0, 96, 255),
+        _ if key.consumer_usage().is_some() => Color::new(160, 0, 255),
+        _ if key.is_firmware_key() => Color::new(255, 96, 0),
+        _ => Color::new(255, 255, 255),
+    }
+}
+
+/// Works out the frames of the backlight, from the keys pressed and the layer they're on.
+pub struct RgbBacklight {
+    settings: RgbSettings,
+
+    /// The matrix position of each LED, in chain order.
+    positions: [(usize, usize); MAX_LEDS],
+    num_leds: usize,
+
+    /// When each key was last seen pressed, for `Animation::Reactive`.
+    last_pressed_ms: [[Option<u64>; NUM_ROWS]; NUM_COLS],
+
+    /// The locked modifiers, as a report bitmask.
+    locked_modifiers: u8,
+
+    next_frame_ms: u64,
+    frame: [u32; MAX_LEDS],
+}
+
+impl RgbBacklight {
+    /// A backlight with an LED under every key on `base_layer`.
+    pub fn new(base_layer: &[[KeyCode; NUM_ROWS]; NUM_COLS], settings: RgbSettings) -> Self {
+        let mut positions = [(0, 0); MAX_LEDS];
+        let mut num_leds = 0;
+        for row in 0..NUM_ROWS {
+            for (col, column) in base_layer.iter().enumerate() {
+                if column[row] != KeyCode::Empty {
+                    positions[num_leds] = (col, row);
+                    num_leds += 1;
+                }
+            }
+        }
+
+        Self {
+            settings,
+            positions,
+            num_leds,
+            last_pressed_ms: [[None; NUM_ROWS]; NUM_COLS],
+            locked_modifiers: 0,
+            next_frame_ms: 0,
+            frame: [0; MAX_LEDS],
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: RgbSettings) {
+        self.settings = settings;
+    }
+
+    /// Show which modifiers are locked, see `Keyboard::locked_modifiers`.
+    pub fn set_locked_modifiers(&mut self, locked_modifiers: u8) {
+        self.locked_modifiers = locked_modifiers;
+    }
+
+    /// The matrix position of each LED, in chain order.
+    pub fn positions(&self) -> &[(usize, usize)] {
+        &self.positions[..self.num_leds]
+    }
+
+    /// Update the backlight from a debounced scan, with the keys resolved through the active
+    /// layers. Returns the next frame to send to the chain, one `Color::to_grb_word` per
+    /// LED, once every `FRAME_INTERVAL_MS`.
+    pub fn update(
+        &mut self,
+        now_ms: u64,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        mapping: &[[KeyCode; NUM_ROWS]; NUM_COLS],
+    ) -> Option<&[u32]> {
+        for (last_pressed_column, matrix_column) in self.last_pressed_ms.iter_mut().zip(matrix) {
+            for (last_pressed, pressed) in last_pressed_column.iter_mut().zip(matrix_column) {
+                if *pressed {
+                    *last_pressed = Some(now_ms);
+                }
+            }
+        }
+
+        if now_ms < self.next_frame_ms {
+            return None;
+        }
+        self.next_frame_ms = now_ms + FRAME_INTERVAL_MS;
+
+        for (word, (col, row)) in self.frame.iter_mut().zip(&self.positions[..self.num_leds]) {
+            let level = match self.settings.animation {
+                Animation::Static => 255,
+                Animation::Breathing => {
+                    let phase = now_ms % BREATHING_PERIOD_MS;
+                    let rising = phase.min(BREATHING_PERIOD_MS - phase);
+                    (rising * 2 * 255 / BREATHING_PERIOD_MS) as u8
+                },
+                Animation::Reactive => {
+                    let elapsed = self.last_pressed_ms[*col][*row]
+                        .map_or(REACTIVE_FADE_MS, |ms| (now_ms - ms).min(REACTIVE_FADE_MS));
+                    let remaining = REACTIVE_FADE_MS - elapsed;
+                    let boost = (255 - REACTIVE_IDLE_LEVEL) as u64 * remaining / REACTIVE_FADE_MS;
+                    REACTIVE_IDLE_LEVEL + boost as u8
+                },
+            };
+
+            let key = mapping[*col][*row];
+            let locked = key.modifier_bitmask().is_some_and(|bit| bit & self.locked_modifiers != 0);
+            let color = if self.settings.enabled && locked {
+                LOCKED_COLOR.scaled(self.settings.brightness)
+            } else if self.settings.enabled {
+                key_color(key).scaled(level).scaled(self.settings.brightness)
+            } else {
+                Color::OFF
+            };
+            *word = color.to_grb_word();
+        }
+
+        Some(&self.frame[..self.num_leds])
+    }
+}
+
+/// Sends frames to the LED chain, from a PIO state machine fed by a DMA channel.
+pub struct Ws2812<P: PIOExt, SM: StateMachineIndex> {
+    _state_machine: StateMachine<(P, SM), Running>,
+    tx: Tx<(P, SM)>,
+    dma: pac::DMA,
+
+    /// The frame being sent, which the DMA channel reads from until it's done.
+    buffer: &'static mut [u32; MAX_LEDS],
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> Ws2812<P, SM> {
+    /// Start driving the chain on GPIO `data_pin`, which must be set to the function of the
+    /// PIO block. This takes over the DMA block, for channel `DMA_CHANNEL`.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        dma: pac::DMA,
+        resets: &mut pac::RESETS,
+        data_pin: u8,
+        system_clock_hz: u32,
+        buffer: &'static mut [u32; MAX_LEDS],
+    ) -> Self {
+        resets.reset.modify(|_, w| w.dma().clear_bit());
+        while resets.reset_done.read().dma().bit_is_clear() {}
+
+        // Each bit starts low for T3, goes high for T1, and then stays high for T2 for a one
+        // or goes back low for a zero. The pin is side-set, so it changes in the same cycle
+        // as each instruction, even while `out` waits for the next word.
+        let mut program = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new_with_side_set(
+            SideSet::new(false, 1, false),
+        );
+        let mut wrap_target = program.label();
+        let mut wrap_source = program.label();
+        let mut do_zero = program.label();
+        program.bind(&mut wrap_target);
+        program.out_with_delay_and_side_set(OutDestination::X, 1, CYCLES_T3 - 1, 0);
+        program.jmp_with_delay_and_side_set(JmpCondition::XIsZero, &mut do_zero, CYCLES_T1 - 1, 1);
+        program.jmp_with_delay_and_side_set(
+            JmpCondition::Always,
+            &mut wrap_target,
+            CYCLES_T2 - 1,
+            1,
+        );
+        program.bind(&mut do_zero);
+        program.nop_with_delay_and_side_set(CYCLES_T2 - 1, 0);
+        program.bind(&mut wrap_source);
+        let program = pio.install(&program.assemble_with_wrap(wrap_source, wrap_target)).unwrap();
+
+        let clock_divisor = system_clock_hz as f32 / (BIT_FREQUENCY_HZ * CYCLES_PER_BIT) as f32;
+        let (mut state_machine, _, tx) = PIOBuilder::from_program(program)
+            .side_set_pin_base(data_pin)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .pull_threshold(24)
+            .buffers(Buffers::OnlyTx)
+            .clock_divisor(clock_divisor)
+            .build(sm);
+        state_machine.set_pindirs([(data_pin, PinDir::Output)]);
+
+        Self { _state_machine: state_machine.start(), tx, dma, buffer }
+    }
+
+    /// Whether the last frame is still being sent.
+    pub fn is_busy(&self) -> bool {
+        self.dma.ch[DMA_CHANNEL].ch_ctrl_trig.read().busy().bit_is_set()
+    }
+
+    /// Start sending a frame, unless the last one is still being sent. Returns whether it
+    /// started. LEDs past the end of `frame` keep their colors.
+    pub fn write(&mut self, frame: &[u32]) -> bool {
+        if self.is_busy() {
+            return false;
+        }
+
+        let len = frame.len().min(MAX_LEDS);
+        self.buffer[..len].copy_from_slice(&frame[..len]);
+
+        let channel = &self.dma.ch[DMA_CHANNEL];
+        channel.ch_read_addr.write(|w| unsafe { w.bits(self.buffer.as_ptr() as u32) });
+        channel.ch_write_addr.write(|w| unsafe { w.bits(self.tx.fifo_address() as u32) });
+        channel.ch_trans_count.write(|w| unsafe { w.bits(len as u32) });
+        channel.ch_ctrl_trig.write(|w| unsafe {
+            w.data_size().size_word();
+            w.incr_read().set_bit();
+            w.incr_write().clear_bit();
+            w.treq_sel().bits(self.tx.dreq_value());
+            // Chaining to itself is how a channel is kept from chaining to another.
+            w.chain_to().bits(DMA_CHANNEL as u8);
+            w.en().set_bit()
+        });
+        true
+    }
+}
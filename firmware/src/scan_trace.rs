@@ -0,0 +1,212 @@
+//! Recording and replaying raw key scans, for capturing intermittent bugs such as missed
+//! taps or chattering switches and reproducing them later.
+//!
+//! While recording, every change to the raw (not yet debounced) matrix is kept in a ring
+//! buffer in RAM along with a timestamp. `KeyCode::SaveScanTrace` writes the buffer to the
+//! scan trace partition, from where it can be replayed through debouncing and report
+//! building again, either on the keyboard or on the host with the simulator.
+//!
+//! # Format
+//! A saved trace is a 16 byte header followed by the entries, oldest first. The header
+//! holds the magic `KRTR`, the format version (u16), two reserved bytes, the entry count
+//! (u32), and a CRC-32 over the count and entries. Each entry is 16 bytes: the time in
+//! milliseconds (u32), then the matrix as one bit per key, column by column, starting at
+//! the least significant bit of the first byte. Everything is little endian.
+
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::{
+    config_block::Crc32,
+    debounce::Debouncer,
+    flash::{self, Partition, WriteError},
+    key_scan::KeyScan,
+    keyboard::Keyboard,
+    NUM_COLS, NUM_ROWS, SCAN_PERIOD_MS,
+};
+
+const MAGIC: [u8; 4] = *b"KRTR";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+
+/// The size of a serialized `TraceEntry`.
+pub const ENTRY_SIZE: usize = 16;
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+/// The raw matrix at one point in time.
+#[derive(Copy, Clone)]
+pub struct TraceEntry {
+    pub time_ms: u32,
+    pub matrix: Matrix,
+}
+
+impl TraceEntry {
+    pub fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[..4].copy_from_slice(&self.time_ms.to_le_bytes());
+
+        for (i, pressed) in self.matrix.iter().flatten().enumerate() {
+            bytes[4 + i / 8] |= (*pressed as u8) << (i % 8);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; ENTRY_SIZE]) -> Self {
+        let mut matrix = [[false; NUM_ROWS]; NUM_COLS];
+        for (i, pressed) in matrix.iter_mut().flatten().enumerate() {
+            *pressed = bytes[4 + i / 8] & (1 << (i % 8)) != 0;
+        }
+
+        let time_ms = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        Self { time_ms, matrix }
+    }
+}
+
+/// Keeps the most recent `N` changes to the raw matrix.
+pub struct ScanTrace<const N: usize> {
+    entries: [TraceEntry; N],
+
+    /// The index the next entry is written to.
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for ScanTrace<N> {
+    fn default() -> Self {
+        let entry = TraceEntry { time_ms: 0, matrix: [[false; NUM_ROWS]; NUM_COLS] };
+        Self { entries: [entry; N], next: 0, len: 0 }
+    }
+}
+
+impl<const N: usize> ScanTrace<N> {
+    /// Record a raw scan, which is only stored if it differs from the previous one.
+    pub fn record(&mut self, time_ms: u32, matrix: &Matrix) {
+        if self.len > 0 && self.entries[(self.next + N - 1) % N].matrix == *matrix {
+            return;
+        }
+
+        self.entries[self.next] = TraceEntry { time_ms, matrix: *matrix };
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// The recorded entries, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        let start = (self.next + N - self.len) % N;
+        (0..self.len).map(move |i| &self.entries[(start + i) % N])
+    }
+
+    /// Write the trace to the scan trace partition, replacing whatever was saved before.
+    /// This blocks with interrupts disabled while each sector is written, which takes a
+    /// noticeable amount of time for a full trace.
+    pub fn save(&self) -> Result<(), WriteError> {
+        let mut crc = Crc32::new();
+        crc.update(&(self.len as u32).to_le_bytes());
+        for entry in self.entries() {
+            crc.update(&entry.to_bytes());
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        header[8..12].copy_from_slice(&(self.len as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&crc.finish().to_le_bytes());
+
+        // Assemble and write one sector at a time, rather than the whole trace at once.
+        let mut chunks = core::iter::once(header).chain(self.entries().map(TraceEntry::to_bytes));
+        let mut offset = 0;
+        loop {
+            let mut sector = [0xFFu8; flash::SECTOR_SIZE];
+            let mut used = 0;
+            for (slot, chunk) in sector.chunks_exact_mut(ENTRY_SIZE).zip(&mut chunks) {
+                slot.copy_from_slice(&chunk);
+                used += ENTRY_SIZE;
+            }
+
+            if used == 0 {
+                break;
+            }
+
+            let written = used.div_ceil(flash::PAGE_SIZE) * flash::PAGE_SIZE;
+            Partition::ScanTrace.write(offset, &sector[..written])?;
+            offset += flash::SECTOR_SIZE;
+        }
+
+        Ok(())
+    }
+}
+
+/// The entries of the trace saved in the scan trace partition, if there is a valid one.
+pub fn load() -> Option<impl Iterator<Item = TraceEntry>> {
+    let header = Partition::ScanTrace.read(0, HEADER_SIZE);
+    let len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    let available = (Partition::ScanTrace.size() - HEADER_SIZE) / ENTRY_SIZE;
+    if header[0..4] != MAGIC || len > available {
+        return None;
+    }
+
+    parse(Partition::ScanTrace.read(0, HEADER_SIZE + len * ENTRY_SIZE))
+}
+
+/// The entries of a saved trace, such as a dump of the scan trace partition. Returns
+/// `None` if the data doesn't start with a valid trace.
+pub fn parse(data: &[u8]) -> Option<impl Iterator<Item = TraceEntry> + '_> {
+    let header = data.get(..HEADER_SIZE)?;
+    if header[0..4] != MAGIC || u16::from_le_bytes([header[4], header[5]]) != VERSION {
+        return None;
+    }
+
+    let len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    let entries = data.get(HEADER_SIZE..HEADER_SIZE + len.checked_mul(ENTRY_SIZE)?)?;
+
+    let mut expected_crc = Crc32::new();
+    expected_crc.update(&header[8..12]);
+    expected_crc.update(entries);
+    if expected_crc.finish() != crc {
+        return None;
+    }
+
+    Some(entries.chunks_exact(ENTRY_SIZE).map(|chunk| {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes.copy_from_slice(chunk);
+        TraceEntry::from_bytes(&bytes)
+    }))
+}
+
+/// Run recorded entries back through debouncing and report building, one scan every
+/// `SCAN_PERIOD_MS` just like the firmware's scan loop, calling `on_report` with the time of
+/// every scan whose report differs from the previous one.
+///
+/// After the last entry, scanning continues for `settle_ms` to let the debounce finish.
+pub fn replay(
+    entries: impl IntoIterator<Item = TraceEntry>,
+    debounce: &mut impl Debouncer<NUM_ROWS, NUM_COLS>,
+    keyboard: &mut Keyboard,
+    settle_ms: u32,
+    mut on_report: impl FnMut(u32, &KeyboardReport),
+) {
+    let mut previous: Option<(u8, [u8; 6])> = None;
+    let mut entries = entries.into_iter().peekable();
+
+    while let Some(entry) = entries.next() {
+        let end_ms = match entries.peek() {
+            Some(next) => next.time_ms,
+            None => entry.time_ms.wrapping_add(settle_ms),
+        };
+
+        let mut time_ms = entry.time_ms;
+        while (end_ms.wrapping_sub(time_ms) as i32) > 0 {
+            let scan = KeyScan::from_raw(entry.matrix, debounce);
+            let report = keyboard.report(&scan);
+
+            if previous != Some((report.modifier, report.keycodes)) {
+                previous = Some((report.modifier, report.keycodes));
+                on_report(time_ms, &report);
+            }
+
+            time_ms = time_ms.wrapping_add(SCAN_PERIOD_MS);
+        }
+    }
+}
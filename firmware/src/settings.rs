@@ -0,0 +1,229 @@
+//! User settings which persist across reboots, stored in the settings flash partition.
+
+use crate::{
+    config_block::ConfigBlock,
+    crash::PanicAction,
+    flash::Partition,
+    hall_effect::AnalogSettings,
+    idle::IdleSettings,
+    kvm::{Output, NUM_OUTPUTS},
+    mod_swaps::ModSwaps,
+    profile::Profile,
+    rgb::{Animation, RgbSettings},
+    socd::SocdMode,
+    unicode::UnicodeMode,
+};
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Settings {
+    /// The profile used with each output, indexed by `Output::index`.
+    pub profiles: [Profile; NUM_OUTPUTS],
+
+    /// The output the keyboard is switched to.
+    pub output: Output,
+
+    /// Whether the host is kept from changing the keyboard's configuration, so that a lock
+    /// set on a shared machine survives unplugging the keyboard.
+    pub config_locked: bool,
+
+    /// How opposing direction keys are cleaned.
+    pub socd_mode: SocdMode,
+
+    /// The layout options picked in VIA, which only VIA itself reads, see `via`.
+    pub layout_options: u32,
+
+    /// The backlight, see `rgb`.
+    pub rgb: RgbSettings,
+
+    /// How analog keys actuate, see `hall_effect`.
+    pub analog: AnalogSettings,
+
+    /// Whether Auto Shift is on, see `auto_shift`.
+    pub auto_shift: bool,
+
+    /// How the host takes Unicode input, see `unicode`.
+    pub unicode_mode: UnicodeMode,
+
+    /// When the keyboard goes idle, and what it does, see `idle`.
+    pub idle: IdleSettings,
+
+    /// What the keyboard does after a panic, see `crash`.
+    pub panic_action: PanicAction,
+
+    /// The layer used as the base layer, see `layers`.
+    pub default_layer: u8,
+
+    /// The modifier swaps, see `mod_swaps`.
+    pub mod_swaps: ModSwaps,
+
+    /// Whether game mode is on, see `Keyboard::game_mode`.
+    pub game_mode: bool,
+}
+
+impl Settings {
+    /// The profile of the current output.
+    pub fn profile(&self) -> Profile {
+        self.profiles[self.output.index()]
+    }
+
+    /// Change the profile of the current output.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profiles[self.output.index()] = profile;
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            profiles: [Profile::Typing; NUM_OUTPUTS],
+            output: Output::Primary,
+            config_locked: false,
+            socd_mode: SocdMode::Off,
+            layout_options: 0,
+            rgb: RgbSettings::default(),
+            analog: AnalogSettings::default(),
+            auto_shift: false,
+            unicode_mode: UnicodeMode::Linux,
+            idle: IdleSettings::default(),
+            panic_action: PanicAction::default(),
+            default_layer: 0,
+            mod_swaps: ModSwaps::default(),
+            game_mode: false,
+        }
+    }
+}
+
+impl ConfigBlock for Settings {
+    const MAGIC: [u8; 4] = *b"KRST";
+    const PARTITION: Partition = Partition::Settings;
+    const VERSION: u16 = 14;
+
+    fn serialize(&self, buffer: &mut [u8]) -> usize {
+        buffer[0] = self.profiles[0].to_u8();
+        buffer[1] = self.config_locked as u8;
+        buffer[2] = self.output.to_u8();
+        buffer[3] = self.profiles[1].to_u8();
+        buffer[4] = self.socd_mode.to_u8();
+        buffer[5..9].copy_from_slice(&self.layout_options.to_le_bytes());
+        buffer[9] = self.rgb.enabled as u8;
+        buffer[10] = self.rgb.brightness;
+        buffer[11] = self.rgb.animation.to_u8();
+        buffer[12] = self.analog.actuation;
+        buffer[13] = self.analog.rapid_trigger;
+        buffer[14] = self.auto_shift as u8;
+        buffer[15] = self.unicode_mode.to_u8();
+        buffer[16..18].copy_from_slice(&self.idle.to_bytes());
+        buffer[18] = self.panic_action.to_u8();
+        buffer[19] = self.default_layer;
+        buffer[20] = self.mod_swaps.to_u8();
+        buffer[21] = self.game_mode as u8;
+        22
+    }
+
+    fn deserialize(version: u16, payload: &[u8]) -> Option<Self> {
+        // Older versions only had one profile, which both outputs start with.
+        let (profiles, output, config_locked, socd_mode, layout_options) = match (version, payload)
+        {
+            (1, [profile, ..]) => ([*profile; NUM_OUTPUTS], 0, false, 0, [0; 4]),
+            (2, [profile, locked, ..]) => ([*profile; NUM_OUTPUTS], 0, *locked != 0, 0, [0; 4]),
+            (3, [primary, locked, output, secondary, ..]) => {
+                ([*primary, *secondary], *output, *locked != 0, 0, [0; 4])
+            },
+            (4, [primary, locked, output, secondary, socd_mode, ..]) => {
+                ([*primary, *secondary], *output, *locked != 0, *socd_mode, [0; 4])
+            },
+            (5..=14, [primary, locked, output, secondary, socd_mode, a, b, c, d, ..]) => {
+                ([*primary, *secondary], *output, *locked != 0, *socd_mode, [*a, *b, *c, *d])
+            },
+            _ => return None,
+        };
+
+        // The backlight was added in version 6.
+        let rgb = match (version, payload.get(9..12)) {
+            (6..=14, Some([enabled, brightness, animation])) => RgbSettings {
+                enabled: *enabled != 0,
+                brightness: *brightness,
+                animation: Animation::from_u8(*animation)?,
+            },
+            (6..=14, _) => return None,
+            _ => RgbSettings::default(),
+        };
+
+        // And the analog settings in version 7.
+        let analog = match (version, payload.get(12..14)) {
+            (7..=14, Some([actuation, rapid_trigger])) => {
+                AnalogSettings { actuation: *actuation, rapid_trigger: *rapid_trigger }
+            },
+            (7..=14, _) => return None,
+            _ => AnalogSettings::default(),
+        };
+
+        // And Auto Shift in version 8.
+        let auto_shift = match (version, payload.get(14)) {
+            (8..=14, Some(auto_shift)) => *auto_shift != 0,
+            (8..=14, None) => return None,
+            _ => false,
+        };
+
+        // And the Unicode mode in version 9.
+        let unicode_mode = match (version, payload.get(15)) {
+            (9..=14, Some(mode)) => UnicodeMode::from_u8(*mode)?,
+            (9..=14, None) => return None,
+            _ => UnicodeMode::Linux,
+        };
+
+        // And the idle timeout in version 10.
+        let idle = match (version, payload.get(16..18)) {
+            (10..=14, Some([timeout_min, away_macro])) => {
+                IdleSettings::from_bytes([*timeout_min, *away_macro])
+            },
+            (10..=14, _) => return None,
+            _ => IdleSettings::default(),
+        };
+
+        // And the panic action in version 11.
+        let panic_action = match (version, payload.get(18)) {
+            (11..=14, Some(action)) => PanicAction::from_u8(*action)?,
+            (11..=14, None) => return None,
+            _ => PanicAction::default(),
+        };
+
+        // And the default layer in version 12.
+        let default_layer = match (version, payload.get(19)) {
+            (12..=14, Some(layer)) => *layer,
+            (12..=14, None) => return None,
+            _ => 0,
+        };
+
+        // And the modifier swaps in version 13.
+        let mod_swaps = match (version, payload.get(20)) {
+            (13 | 14, Some(mod_swaps)) => ModSwaps::from_u8(*mod_swaps)?,
+            (13 | 14, None) => return None,
+            _ => ModSwaps::default(),
+        };
+
+        // And game mode in version 14.
+        let game_mode = match (version, payload.get(21)) {
+            (14, Some(game_mode)) => *game_mode != 0,
+            (14, None) => return None,
+            _ => false,
+        };
+
+        Some(Self {
+            profiles: [Profile::from_u8(profiles[0])?, Profile::from_u8(profiles[1])?],
+            output: Output::from_u8(output)?,
+            config_locked,
+            socd_mode: SocdMode::from_u8(socd_mode)?,
+            layout_options: u32::from_le_bytes(layout_options),
+            rgb,
+            analog,
+            auto_shift,
+            unicode_mode,
+            idle,
+            panic_action,
+            default_layer,
+            mod_swaps,
+            game_mode,
+        })
+    }
+}
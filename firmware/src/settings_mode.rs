@@ -0,0 +1,185 @@
+//! Changing settings from the keyboard itself, for anyone who'd rather not run host tools.
+//!
+//! `KeyCode::SettingsMode` enters the mode. While it's on, nothing is sent to the host and
+//! the indicator LED stays lit, while keys on the base layer change settings instead (see
+//! `Adjustment::for_key`). The changes take effect straight away, so the backlight can be
+//! seen as it's changed, and each one blinks the LED off for a moment.
+//!
+//! `Enter` leaves the mode, and the main loop saves the changes. `Escape`, or leaving the
+//! keys alone for `TIMEOUT_MS`, puts everything back as it was. Either way, the mode only
+//! ends once every key is released, so the key which ended it isn't typed.
+
+use defmt::Format;
+
+use crate::{
+    key_codes::KeyCode, key_mapping, ms_to_ticks, profile::Profile, rgb::RgbSettings,
+    socd::SocdMode, unicode::UnicodeMode, NUM_COLS, NUM_ROWS,
+};
+
+/// How long the mode waits for a key before giving up, undoing any changes.
+pub const TIMEOUT_MS: u32 = 30_000;
+
+/// How long the indicator LED goes off for after a change.
+const BLINK_MS: u32 = 150;
+
+/// A setting changed by a key in settings mode.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Adjustment {
+    /// Switches profile, which changes the debounce time, see `profile`.
+    ToggleProfile,
+    ToggleBacklight,
+    BacklightBrighter,
+    BacklightDimmer,
+    NextAnimation,
+    NextSocdMode,
+    ToggleAutoShift,
+    NextUnicodeMode,
+
+    /// Cycles through the base layers, see `key_mapping::next_base_layer`.
+    NextDefaultLayer,
+}
+
+impl Adjustment {
+    /// The adjustment a key on the base layer makes, mostly by the setting's initial.
+    pub fn for_key(key: KeyCode) -> Option<Self> {
+        match key {
+            KeyCode::D => Some(Adjustment::ToggleProfile),
+            KeyCode::B => Some(Adjustment::ToggleBacklight),
+            KeyCode::Up => Some(Adjustment::BacklightBrighter),
+            KeyCode::Down => Some(Adjustment::BacklightDimmer),
+            KeyCode::R => Some(Adjustment::NextAnimation),
+            KeyCode::S => Some(Adjustment::NextSocdMode),
+            KeyCode::A => Some(Adjustment::ToggleAutoShift),
+            KeyCode::U => Some(Adjustment::NextUnicodeMode),
+            KeyCode::L => Some(Adjustment::NextDefaultLayer),
+            _ => None,
+        }
+    }
+}
+
+/// What a key press in settings mode does.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Action {
+    Adjust(Adjustment),
+
+    /// Leave, keeping the changes.
+    Save,
+
+    /// Leave, putting back the settings from when the mode was entered.
+    Cancel,
+}
+
+/// The settings the mode can change, kept from when it was entered to put back on `Cancel`.
+#[derive(Copy, Clone, PartialEq)]
+pub struct ModeSettings {
+    pub profile: Profile,
+    pub rgb: RgbSettings,
+    pub socd_mode: SocdMode,
+    pub auto_shift: bool,
+    pub unicode_mode: UnicodeMode,
+    pub default_layer: usize,
+}
+
+impl ModeSettings {
+    pub fn adjust(&mut self, adjustment: Adjustment) {
+        match adjustment {
+            Adjustment::ToggleProfile => self.profile = self.profile.toggled(),
+            Adjustment::ToggleBacklight => self.rgb.enabled = !self.rgb.enabled,
+            Adjustment::BacklightBrighter => self.rgb = self.rgb.brighter(),
+            Adjustment::BacklightDimmer => self.rgb = self.rgb.dimmer(),
+            Adjustment::NextAnimation => self.rgb.animation = self.rgb.animation.next(),
+            Adjustment::NextSocdMode => self.socd_mode = self.socd_mode.next(),
+            Adjustment::ToggleAutoShift => self.auto_shift = !self.auto_shift,
+            Adjustment::NextUnicodeMode => self.unicode_mode = self.unicode_mode.next(),
+            Adjustment::NextDefaultLayer => {
+                self.default_layer = key_mapping::next_base_layer(self.default_layer)
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SettingsMode {
+    /// The settings when the mode was entered, while it's on.
+    entered_with: Option<ModeSettings>,
+
+    /// Whether the mode has been left, and is waiting for every key to be released.
+    leaving: bool,
+
+    /// Scans since the last key press.
+    idle_ticks: u32,
+
+    /// Scans left with the LED off after a change.
+    blink_ticks: u32,
+
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl SettingsMode {
+    pub fn is_active(&self) -> bool {
+        self.entered_with.is_some()
+    }
+
+    /// Turn the mode on, with the keys held now (like the one which entered it) ignored
+    /// until they're pressed again.
+    pub fn enter(&mut self, settings: ModeSettings, matrix: &[[bool; NUM_ROWS]; NUM_COLS]) {
+        *self = Self { entered_with: Some(settings), previous_matrix: *matrix, ..Self::default() };
+    }
+
+    /// The settings from when the mode was entered, while it's on.
+    pub fn entered_with(&self) -> Option<ModeSettings> {
+        self.entered_with
+    }
+
+    /// Take a scan while the mode is on, `elapsed_ticks` after the last, returning what the
+    /// keys newly pressed on it do, mapped through `base_key`. Only the first key with
+    /// something to do counts.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        base_key: impl Fn(usize, usize) -> KeyCode,
+        elapsed_ticks: u32,
+    ) -> Option<Action> {
+        let previous_matrix = core::mem::replace(&mut self.previous_matrix, *matrix);
+        self.blink_ticks = self.blink_ticks.saturating_sub(elapsed_ticks);
+
+        let any_pressed = matrix.iter().flatten().any(|pressed| *pressed);
+        if self.leaving {
+            if !any_pressed {
+                *self = Self::default();
+            }
+            return None;
+        }
+
+        let mut action = None;
+        for (col, (column, previous_column)) in matrix.iter().zip(previous_matrix).enumerate() {
+            for (row, (pressed, was_pressed)) in column.iter().zip(previous_column).enumerate() {
+                if *pressed && !was_pressed {
+                    self.idle_ticks = 0;
+                    action = action.or(match base_key(col, row) {
+                        KeyCode::Enter => Some(Action::Save),
+                        KeyCode::Escape => Some(Action::Cancel),
+                        key => Adjustment::for_key(key).map(Action::Adjust),
+                    });
+                }
+            }
+        }
+
+        self.idle_ticks += elapsed_ticks;
+        if action.is_none() && self.idle_ticks >= ms_to_ticks(TIMEOUT_MS) {
+            action = Some(Action::Cancel);
+        }
+
+        match action {
+            Some(Action::Adjust(_)) => self.blink_ticks = ms_to_ticks(BLINK_MS),
+            Some(Action::Save | Action::Cancel) => self.leaving = true,
+            None => {},
+        }
+        action
+    }
+
+    /// Whether the indicator LED should be lit while the mode is on, or `None` when it's off.
+    pub fn led_lit(&self) -> Option<bool> {
+        self.is_active().then_some(self.blink_ticks == 0)
+    }
+}
@@ -0,0 +1,94 @@
+//! Measures how long the key matrix takes to settle on this particular board, so columns
+//! can be scanned as quickly as the hardware allows rather than with a hand-tuned delay.
+//!
+//! A driven column pulls a row high through a diode and a closed switch, which happens
+//! almost instantly. The slow part is afterwards: once the column is released, the row
+//! only falls back low through its pull-down, at a rate set by the row's capacitance. A
+//! row that hasn't fallen yet reads as a ghost press on the next column. Boards wired the
+//! other way around are the same, with the columns read, or with pull-ups instead.
+//!
+//! That doesn't need any keys to be pressed to measure. Each row is briefly driven away
+//! from its pull as an output, then handed back to it, timing how long it takes to return.
+
+use rp2040_hal::{pac, Timer};
+
+use crate::key_scan::Sense;
+
+/// The number of times each row is measured, keeping the slowest.
+const ROUNDS: u32 = 16;
+
+/// How long a row can take to charge or discharge before it's taken to be stuck.
+const TIMEOUT_US: u64 = 1000;
+
+/// The settle delay is this many times the slowest discharge measured, to allow for
+/// temperature and the extra capacitance of a pressed switch.
+const SAFETY_FACTOR: u32 = 2;
+
+/// The shortest settle delay to use, however fast the rows are.
+pub const MIN_SETTLE_US: u32 = 2;
+
+/// The measurement found a row that didn't follow being driven and released, which could
+/// be a short or a missing pull resistor.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct StuckRow {
+    pub gpio: u8,
+}
+
+/// Measure the rows on the GPIOs `row_gpios`, which need to be SIO inputs pulled as `sense`
+/// says, with every column released. Returns the settle delay to use, in microseconds.
+/// Boards which read the columns pass those instead.
+pub fn measure(timer: &Timer, row_gpios: &[u8], sense: Sense) -> Result<u32, StuckRow> {
+    // Note (safety): SIO's set and clear registers only touch the bits written, and the
+    // rows are left as inputs with the output low, as they were found.
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let pulled_up = sense == Sense::PullUp;
+
+    let mut slowest_us = 0;
+    for _ in 0..ROUNDS {
+        for &gpio in row_gpios {
+            let mask = 1 << gpio;
+
+            // Keep interrupts from stretching the measurement.
+            let discharge_us = critical_section::with(|_| {
+                let is_high = || sio.gpio_in.read().bits() & mask != 0;
+                if !pulled_up {
+                    sio.gpio_out_set.write(|w| unsafe { w.bits(mask) });
+                }
+                sio.gpio_oe_set.write(|w| unsafe { w.bits(mask) });
+                let charged = wait_for(timer, || is_high() != pulled_up);
+
+                sio.gpio_oe_clr.write(|w| unsafe { w.bits(mask) });
+                sio.gpio_out_clr.write(|w| unsafe { w.bits(mask) });
+                let discharged = wait_for(timer, || is_high() == pulled_up);
+
+                charged.and(discharged)
+            });
+
+            slowest_us = slowest_us.max(discharge_us.ok_or(StuckRow { gpio })?);
+        }
+    }
+
+    Ok(settle_delay_us(slowest_us))
+}
+
+/// Poll `condition` until it's true, returning how many microseconds that took, or `None`
+/// if it's still false after `TIMEOUT_US`.
+fn wait_for(timer: &Timer, mut condition: impl FnMut() -> bool) -> Option<u32> {
+    let start = timer.get_counter();
+    loop {
+        let elapsed = timer.get_counter() - start;
+        if condition() {
+            return Some(elapsed as u32);
+        }
+        if elapsed > TIMEOUT_US {
+            return None;
+        }
+    }
+}
+
+/// The settle delay to use when the slowest row took `slowest_us` to discharge.
+///
+/// The timer only counts whole microseconds, so a measurement of 0 means less than one.
+pub fn settle_delay_us(slowest_us: u32) -> u32 {
+    ((slowest_us + 1) * SAFETY_FACTOR).max(MIN_SETTLE_US)
+}
@@ -0,0 +1,117 @@
+//! SOCD (simultaneous opposing cardinal directions) cleaning, for games which misbehave
+//! when both keys of a direction pair are held, such as `A` and `D`.
+//!
+//! Pairs are matched by the keys they resolve to rather than their positions, so they
+//! follow the keymap onto any layer. `KeyCode::CycleSocd` steps through the modes.
+
+use defmt::Format;
+
+use crate::key_codes::KeyCode;
+
+/// The opposing keys which are cleaned.
+pub const PAIRS: [(KeyCode, KeyCode); 4] = [
+    (KeyCode::A, KeyCode::D),
+    (KeyCode::W, KeyCode::S),
+    (KeyCode::Left, KeyCode::Right),
+    (KeyCode::Up, KeyCode::Down),
+];
+
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum SocdMode {
+    /// Both keys are sent, as pressed.
+    Off,
+
+    /// Only the key pressed last is sent, until it's released.
+    LastInput,
+
+    /// Neither key is sent while both are held.
+    Neutral,
+}
+
+impl SocdMode {
+    /// The next mode, in the order `KeyCode::CycleSocd` steps through them.
+    pub fn next(self) -> Self {
+        match self {
+            SocdMode::Off => SocdMode::LastInput,
+            SocdMode::LastInput => SocdMode::Neutral,
+            SocdMode::Neutral => SocdMode::Off,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            SocdMode::Off => 0,
+            SocdMode::LastInput => 1,
+            SocdMode::Neutral => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SocdMode::Off),
+            1 => Some(SocdMode::LastInput),
+            2 => Some(SocdMode::Neutral),
+            _ => None,
+        }
+    }
+}
+
+/// Decides which keys of each pair make it into the report.
+pub struct SocdCleaner {
+    mode: SocdMode,
+
+    /// Whether each key of each pair was held in the previous scan.
+    held: [[bool; 2]; PAIRS.len()],
+
+    /// Which key of each pair was pressed most recently.
+    last_pressed: [usize; PAIRS.len()],
+}
+
+impl SocdCleaner {
+    pub fn new(mode: SocdMode) -> Self {
+        Self { mode, held: [[false; 2]; PAIRS.len()], last_pressed: [0; PAIRS.len()] }
+    }
+
+    pub fn mode(&self) -> SocdMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: SocdMode) {
+        self.mode = mode;
+    }
+
+    /// Update the pairs with the keys held in this scan, as found by `is_held`.
+    pub fn update(&mut self, is_held: impl Fn(KeyCode) -> bool) {
+        for (pair, (first, second)) in PAIRS.iter().enumerate() {
+            let held = [is_held(*first), is_held(*second)];
+            for (side, (held, was_held)) in held.iter().zip(self.held[pair]).enumerate() {
+                if *held && !was_held {
+                    self.last_pressed[pair] = side;
+                }
+            }
+            self.held[pair] = held;
+        }
+    }
+
+    /// Whether `key` is held back from the report, as of the last `update`.
+    pub fn suppresses(&self, key: KeyCode) -> bool {
+        let Some((pair, side)) = PAIRS.iter().enumerate().find_map(|(pair, (first, second))| {
+            if key == *first {
+                Some((pair, 0))
+            } else if key == *second {
+                Some((pair, 1))
+            } else {
+                None
+            }
+        }) else {
+            return false;
+        };
+
+        let both_held = self.held[pair] == [true, true];
+        match self.mode {
+            SocdMode::Off => false,
+            SocdMode::LastInput => both_held && self.last_pressed[pair] != side,
+            SocdMode::Neutral => both_held,
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Split keyboards: two halves, each with its own RP2040 scanning its own keys, joined by a
+//! serial cable.
+//!
+//! Both halves are built from the same board definition and keymap, one image with the `left`
+//! feature and one with `right`. The left half's keys are the first `SPLIT_LEFT_COLS` columns
+//! of the matrix and the right half's the rest, but each half's columns are wired to the
+//! first column pins, so the halves can share a PCB, and `Side::place` moves what a half
+//! scans into its own columns. Each half sends the other its raw matrix, and merges what it
+//! receives into its own before debouncing. Whichever half is plugged into USB then reports
+//! every key, and the other's reports go nowhere.
+//!
+//! # Link
+//! The halves are joined by a UART at `BAUD_RATE`, 8N1, TX on GPIO0 and RX on GPIO1 of each
+//! half, crossed over in the cable. Every frame is the start byte `0x5A`, the matrix as a
+//! bitmap with key `(col, row)` in bit `col * NUM_ROWS + row`, and a CRC-8 of the bitmap
+//! (see `ble::crc8`). A half sends a frame whenever its matrix changes, and at least every
+//! `KEEPALIVE_INTERVAL_MS` otherwise.
+//!
+//! A half which hears nothing valid from the other for `LINK_TIMEOUT_MS` treats it as
+//! unplugged, and drops its keys so none of them stay stuck down. Plugging it back in needs
+//! nothing more than its next frame.
+
+#[cfg(feature = "split")]
+use core::convert::Infallible;
+use core::ops::Range;
+
+use defmt::Format;
+
+#[cfg(feature = "split")]
+use embedded_hal::serial::{Read, Write};
+
+use crate::{ble::crc8, NUM_COLS, NUM_ROWS, SPLIT_LEFT_COLS};
+
+/// The UART baud rate, fast enough for a frame to take a fraction of a scan.
+pub const BAUD_RATE: u32 = 1_000_000;
+
+/// The longest a half goes without sending a frame, so the other knows it's still there.
+pub const KEEPALIVE_INTERVAL_MS: u64 = 10;
+
+/// How long without a valid frame before the other half counts as unplugged.
+pub const LINK_TIMEOUT_MS: u64 = 50;
+
+const START: u8 = 0x5A;
+
+/// The matrix bitmap, one bit per key.
+const BITMAP_LEN: usize = (NUM_COLS * NUM_ROWS).div_ceil(8);
+
+/// The size of a frame: start byte, bitmap and CRC.
+pub const FRAME_SIZE: usize = BITMAP_LEN + 2;
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+/// Which half of the keyboard this is.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[cfg(all(feature = "split", not(any(feature = "left", feature = "right"))))]
+compile_error!(
+    "Pick which half of the split keyboard to build with the `left` or `right` feature."
+);
+
+#[cfg(all(feature = "left", feature = "right"))]
+compile_error!("Only one of the `left` and `right` features can be enabled.");
+
+/// The half this firmware is built for, picked with the `left` or `right` feature.
+#[cfg(feature = "left")]
+pub const SIDE: Side = Side::Left;
+#[cfg(all(feature = "right", not(feature = "left")))]
+pub const SIDE: Side = Side::Right;
+
+impl Side {
+    /// The columns of the matrix this half has switches on.
+    pub fn columns(self) -> Range<usize> {
+        match self {
+            Side::Left => 0..SPLIT_LEFT_COLS,
+            Side::Right => SPLIT_LEFT_COLS..NUM_COLS,
+        }
+    }
+
+    /// The part of `mask` this half scans, moved onto the first columns where its switches
+    /// are wired.
+    pub fn scan_mask(self, mask: &Matrix) -> Matrix {
+        let columns = self.columns();
+        let mut scan_mask = [[false; NUM_ROWS]; NUM_COLS];
+        scan_mask[..columns.len()].copy_from_slice(&mask[columns]);
+        scan_mask
+    }
+
+    /// Move the keys this half scanned with `scan_mask` into its own columns of the matrix.
+    pub fn place(self, scanned: &Matrix) -> Matrix {
+        let columns = self.columns();
+        let mut matrix = [[false; NUM_ROWS]; NUM_COLS];
+        matrix[columns.clone()].copy_from_slice(&scanned[..columns.len()]);
+        matrix
+    }
+}
+
+/// The frame carrying `matrix`.
+pub fn encode_frame(matrix: &Matrix) -> [u8; FRAME_SIZE] {
+    let mut frame = [0u8; FRAME_SIZE];
+    frame[0] = START;
+    for (i, pressed) in matrix.iter().flatten().enumerate() {
+        frame[1 + i / 8] |= (*pressed as u8) << (i % 8);
+    }
+    frame[FRAME_SIZE - 1] = crc8(&frame[1..FRAME_SIZE - 1]);
+    frame
+}
+
+/// Add the keys held on the other half to this half's raw matrix.
+pub fn merge(remote: &Matrix, raw_matrix: &mut Matrix) {
+    for (raw_column, remote_column) in raw_matrix.iter_mut().zip(remote) {
+        for (raw, remote) in raw_column.iter_mut().zip(remote_column) {
+            *raw |= *remote;
+        }
+    }
+}
+
+/// Picks the other half's frames out of the bytes coming from the UART.
+#[derive(Default)]
+pub struct FrameParser {
+    buf: [u8; FRAME_SIZE],
+    len: usize,
+}
+
+impl FrameParser {
+    /// Add a received byte, returning the other half's matrix if it completes a valid frame.
+    pub fn push(&mut self, byte: u8) -> Option<Matrix> {
+        if self.len == 0 && byte != START {
+            return None;
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.len < FRAME_SIZE {
+            return None;
+        }
+
+        // A bad frame, like one which started on a bitmap byte which happened to match the
+        // start byte, is dropped, and the keepalive gets things lined up again.
+        self.len = 0;
+        let bitmap = &self.buf[1..FRAME_SIZE - 1];
+        if crc8(bitmap) != self.buf[FRAME_SIZE - 1] {
+            return None;
+        }
+
+        let mut matrix = [[false; NUM_ROWS]; NUM_COLS];
+        for (i, pressed) in matrix.iter_mut().flatten().enumerate() {
+            *pressed = bitmap[i / 8] & (1 << (i % 8)) != 0;
+        }
+        Some(matrix)
+    }
+}
+
+/// Keeps the two halves in touch over the UART.
+#[cfg(feature = "split")]
+pub struct SplitLink<UART> {
+    uart: UART,
+    parser: FrameParser,
+
+    /// The keys held on the other half, all released while it's unplugged.
+    remote_matrix: Matrix,
+
+    /// When the last valid frame arrived, or `None` while the other half is unplugged.
+    last_received_ms: Option<u64>,
+
+    sent_matrix: Option<Matrix>,
+    last_sent_ms: u64,
+}
+
+#[cfg(feature = "split")]
+impl<UART> SplitLink<UART>
+where
+    UART: Read<u8> + Write<u8, Error = Infallible>,
+{
+    pub fn new(uart: UART) -> Self {
+        Self {
+            uart,
+            parser: FrameParser::default(),
+            remote_matrix: [[false; NUM_ROWS]; NUM_COLS],
+            last_received_ms: None,
+            sent_matrix: None,
+            last_sent_ms: 0,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.last_received_ms.is_some()
+    }
+
+    /// The keys held on the other half.
+    pub fn remote_matrix(&self) -> &Matrix {
+        &self.remote_matrix
+    }
+
+    /// Send this half's raw matrix if the other half needs it, and handle everything the
+    /// other half has sent. Call this once per scan, with the matrix before merging. Returns
+    /// whether the other half is connected, if that changed.
+    pub fn poll(&mut self, now_ms: u64, local_matrix: &Matrix) -> Option<bool> {
+        if self.sent_matrix.as_ref() != Some(local_matrix)
+            || now_ms - self.last_sent_ms >= KEEPALIVE_INTERVAL_MS
+        {
+            for byte in encode_frame(local_matrix) {
+                nb::block!(self.uart.write(byte)).unwrap();
+            }
+            self.sent_matrix = Some(*local_matrix);
+            self.last_sent_ms = now_ms;
+        }
+
+        let was_connected = self.is_connected();
+        loop {
+            match self.uart.read() {
+                Ok(byte) => {
+                    if let Some(matrix) = self.parser.push(byte) {
+                        self.remote_matrix = matrix;
+                        self.last_received_ms = Some(now_ms);
+                    }
+                },
+                // Framing and overrun errors just lose bytes, which the CRC catches.
+                Err(nb::Error::Other(_)) => {},
+                Err(nb::Error::WouldBlock) => break,
+            }
+        }
+
+        if self.last_received_ms.is_some_and(|ms| now_ms - ms >= LINK_TIMEOUT_MS) {
+            self.last_received_ms = None;
+            self.remote_matrix = [[false; NUM_ROWS]; NUM_COLS];
+        }
+
+        (self.is_connected() != was_connected).then_some(self.is_connected())
+    }
+}
@@ -0,0 +1,167 @@
+//! Tap-dance keys, which do something different depending on how many times they're tapped
+//! in quick succession, such as Escape on one tap, Caps Lock on two and `ToggleLayer1` on
+//! three.
+//!
+//! Each tap has to come within the key's tap window of the one before. Once the window
+//! passes with the key released, it acts as the key for the number of taps for one scan,
+//! like a tap. If it's still held when the window passes, it acts as that key until it's
+//! released, so a layer key can be held on the second tap. Pressing another key ends the
+//! dance early the same way, and that key is held back for a scan so the host sees it
+//! after the dance's key. The report is held back while a dance is undecided.
+
+use crate::{key_codes::KeyCode, ms_to_ticks, NUM_COLS, NUM_ROWS};
+
+/// The most taps a dance can count.
+pub const MAX_TAPS: usize = 3;
+
+/// A tap window which suits most typists.
+pub const TAP_DANCE_WINDOW_MS: u32 = 200;
+
+/// The most tap-dance keys a layout can have.
+pub const MAX_TAP_DANCE_KEYS: usize = 8;
+
+/// A key which acts as `taps[0]` when tapped once, `taps[1]` when tapped twice and so on.
+/// Counts past the last key which isn't `KeyCode::Empty` are never reached. For example,
+/// `TapDance { position: (0, 3), taps: [KeyCode::Escape, KeyCode::CapsLock, KeyCode::Empty],
+/// window_ms: TAP_DANCE_WINDOW_MS }` makes caps lock an Escape key unless it's double
+/// tapped.
+pub struct TapDance {
+    pub position: (usize, usize),
+    pub taps: [KeyCode; MAX_TAPS],
+
+    /// How long after each press or release the next one has to come, in milliseconds.
+    pub window_ms: u32,
+}
+
+impl TapDance {
+    /// The most taps this dance counts.
+    fn max_taps(&self) -> u8 {
+        self.taps.iter().rposition(|key| *key != KeyCode::Empty).map_or(1, |last| last + 1) as u8
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Released,
+
+    /// Tapped this many times so far, with the key pressed or released since `ticks` ago.
+    Dancing {
+        taps: u8,
+        pressed: bool,
+        ticks: u32,
+    },
+
+    /// Decided on this many taps while held.
+    Held(u8),
+
+    /// Decided on this many taps once released, and sending it for one scan.
+    Tapped(u8),
+}
+
+pub struct TapDanceKeys {
+    bindings: &'static [TapDance],
+    states: [State; MAX_TAP_DANCE_KEYS],
+
+    /// The matrix from the previous update, used to find newly pressed keys.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl TapDanceKeys {
+    /// Bindings past `MAX_TAP_DANCE_KEYS` are ignored.
+    pub fn new(bindings: &'static [TapDance]) -> Self {
+        Self {
+            bindings: &bindings[..bindings.len().min(MAX_TAP_DANCE_KEYS)],
+            states: [State::Released; MAX_TAP_DANCE_KEYS],
+            previous_matrix: [[false; NUM_ROWS]; NUM_COLS],
+        }
+    }
+
+    /// Advance the tap-dance keys by `elapsed_ticks` scan ticks, zero for another report in
+    /// the same scan. Returns the matrix to build the report from, with decided keys pressed
+    /// while they act as a key, or `None` while a dance is undecided and the report should be
+    /// held back.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        elapsed_ticks: u32,
+    ) -> Option<[[bool; NUM_ROWS]; NUM_COLS]> {
+        let mut other_keys_pressed = [[false; NUM_ROWS]; NUM_COLS];
+        for (col, column) in other_keys_pressed.iter_mut().enumerate() {
+            for (row, other_key_pressed) in column.iter_mut().enumerate() {
+                *other_key_pressed = matrix[col][row]
+                    && !self.previous_matrix[col][row]
+                    && self.bindings.iter().all(|binding| binding.position != (col, row));
+            }
+        }
+        let interrupted = other_keys_pressed.iter().flatten().any(|pressed| *pressed);
+        self.previous_matrix = *matrix;
+
+        let mut interrupted_dance = false;
+        for (binding, state) in self.bindings.iter().zip(&mut self.states) {
+            let (col, row) = binding.position;
+            let pressed = matrix[col][row];
+            let window_ticks = ms_to_ticks(binding.window_ms);
+
+            *state = match *state {
+                State::Released | State::Tapped(_) if pressed => {
+                    State::Dancing { taps: 1, pressed, ticks: 0 }
+                },
+                State::Released | State::Tapped(_) => State::Released,
+                State::Dancing { taps, pressed: false, .. } if pressed => {
+                    State::Dancing { taps: taps + 1, pressed, ticks: 0 }
+                },
+                State::Dancing { taps, pressed: true, .. } if !pressed => {
+                    if taps >= binding.max_taps() {
+                        State::Tapped(taps)
+                    } else {
+                        State::Dancing { taps, pressed, ticks: 0 }
+                    }
+                },
+                State::Dancing { taps, pressed, ticks }
+                    if interrupted || ticks + elapsed_ticks >= window_ticks =>
+                {
+                    interrupted_dance |= interrupted;
+                    if pressed {
+                        State::Held(taps)
+                    } else {
+                        State::Tapped(taps)
+                    }
+                },
+                State::Dancing { taps, pressed, ticks } => {
+                    State::Dancing { taps, pressed, ticks: ticks + elapsed_ticks }
+                },
+                State::Held(taps) if pressed => State::Held(taps),
+                State::Held(_) => State::Released,
+            };
+        }
+
+        if self.states.iter().any(|state| matches!(state, State::Dancing { .. })) {
+            return None;
+        }
+
+        let mut matrix = *matrix;
+        if interrupted_dance {
+            for (pressed, other_key_pressed) in
+                matrix.iter_mut().flatten().zip(other_keys_pressed.iter().flatten())
+            {
+                *pressed &= !other_key_pressed;
+            }
+        }
+        for (binding, state) in self.bindings.iter().zip(self.states) {
+            let (col, row) = binding.position;
+            matrix[col][row] = matches!(state, State::Held(_) | State::Tapped(_));
+        }
+        Some(matrix)
+    }
+
+    /// The position of each tap-dance key, and the key it's acting as, if it's decided.
+    pub fn keys(&self) -> impl Iterator<Item = ((usize, usize), Option<KeyCode>)> + '_ {
+        self.bindings.iter().zip(self.states).map(|(binding, state)| {
+            let key = match state {
+                State::Held(taps) | State::Tapped(taps) => Some(binding.taps[taps as usize - 1]),
+                State::Released | State::Dancing { .. } => None,
+            };
+            (binding.position, key)
+        })
+    }
+}
@@ -0,0 +1,115 @@
+//! Tap-hold keys, which do one thing when tapped and another when held, such as Escape on
+//! a tap and Ctrl while held (mod-tap), or a layer key while held (layer-tap).
+//!
+//! A tap-hold key is undecided from when it's pressed until it's released (a tap), held for
+//! `TAPPING_TERM_TICKS`, or another key is pressed while it's down (both holds). The report
+//! is held back while it's undecided, so a quick `Ctrl + C` still arrives with the Ctrl.
+
+use crate::{key_codes::KeyCode, ms_to_ticks, NUM_COLS, NUM_ROWS};
+
+/// How many scan ticks a tap-hold key has to be held for to count as held, 200 ms.
+pub const TAPPING_TERM_TICKS: u16 = ms_to_ticks(200) as u16;
+
+/// The most tap-hold keys a layout can have.
+pub const MAX_TAP_HOLD_KEYS: usize = 8;
+
+/// A key which sends `tap` when tapped, and acts as `hold` while held. For example,
+/// `TapHold { position: (0, 3), tap: KeyCode::Escape, hold: KeyCode::LeftCtrl }` makes caps
+/// lock an Escape and Ctrl key.
+pub struct TapHold {
+    pub position: (usize, usize),
+    pub tap: KeyCode,
+    pub hold: KeyCode,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Released,
+
+    /// Pressed, but not yet decided, for this many ticks.
+    Undecided(u16),
+
+    Held,
+
+    /// Tapped, and sending the tap for one scan.
+    Tapped,
+}
+
+pub struct TapHoldKeys {
+    bindings: &'static [TapHold],
+    states: [State; MAX_TAP_HOLD_KEYS],
+
+    /// The matrix from the previous update, used to find newly pressed keys.
+    previous_matrix: [[bool; NUM_ROWS]; NUM_COLS],
+}
+
+impl TapHoldKeys {
+    /// Bindings past `MAX_TAP_HOLD_KEYS` are ignored.
+    pub fn new(bindings: &'static [TapHold]) -> Self {
+        Self {
+            bindings: &bindings[..bindings.len().min(MAX_TAP_HOLD_KEYS)],
+            states: [State::Released; MAX_TAP_HOLD_KEYS],
+            previous_matrix: [[false; NUM_ROWS]; NUM_COLS],
+        }
+    }
+
+    /// Advance the tap-hold keys by `elapsed_ticks` scan ticks, zero for another report in
+    /// the same scan. Returns the matrix to build the report from, with tapped keys pressed
+    /// for one report after they're released, or `None` while a key is undecided and the
+    /// report should be held back.
+    pub fn update(
+        &mut self,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        elapsed_ticks: u32,
+    ) -> Option<[[bool; NUM_ROWS]; NUM_COLS]> {
+        let other_key_pressed = (0..NUM_COLS)
+            .flat_map(|col| (0..NUM_ROWS).map(move |row| (col, row)))
+            .filter(|(col, row)| matrix[*col][*row] && !self.previous_matrix[*col][*row])
+            .any(|position| self.bindings.iter().all(|binding| binding.position != position));
+        self.previous_matrix = *matrix;
+
+        for (binding, state) in self.bindings.iter().zip(&mut self.states) {
+            let (col, row) = binding.position;
+            let pressed = matrix[col][row];
+
+            *state = match *state {
+                State::Released | State::Tapped if pressed => State::Undecided(0),
+                State::Released | State::Tapped => State::Released,
+                State::Undecided(_) if !pressed => State::Tapped,
+                State::Undecided(ticks) => {
+                    let ticks = ticks.saturating_add(elapsed_ticks as u16);
+                    if other_key_pressed || ticks >= TAPPING_TERM_TICKS {
+                        State::Held
+                    } else {
+                        State::Undecided(ticks)
+                    }
+                },
+                State::Held if pressed => State::Held,
+                State::Held => State::Released,
+            };
+        }
+
+        if self.states.iter().any(|state| matches!(state, State::Undecided(_))) {
+            return None;
+        }
+
+        let mut matrix = *matrix;
+        for (binding, state) in self.bindings.iter().zip(self.states) {
+            let (col, row) = binding.position;
+            matrix[col][row] = matches!(state, State::Held | State::Tapped);
+        }
+        Some(matrix)
+    }
+
+    /// The position of each tap-hold key, and the key it's acting as, if it's decided.
+    pub fn keys(&self) -> impl Iterator<Item = ((usize, usize), Option<KeyCode>)> + '_ {
+        self.bindings.iter().zip(self.states).map(|(binding, state)| {
+            let key = match state {
+                State::Held => Some(binding.hold),
+                State::Tapped => Some(binding.tap),
+                State::Released | State::Undecided(_) => None,
+            };
+            (binding.position, key)
+        })
+    }
+}
@@ -0,0 +1,65 @@
+//! Reminders to take a break after typing for a long stretch without one.
+//!
+//! A typing session starts with the first key press after a rest, and ends once no keys
+//! have been pressed for `REST_MS`. Once a session has gone on for the reminder interval,
+//! the indicator LED pulses until the session ends, or for a while after it's snoozed with
+//! `KeyCode::SnoozeBreak`.
+
+/// How long the keys have to be left alone for it to count as a break.
+pub const REST_MS: u64 = 5 * 60 * 1000;
+
+/// How long snoozing puts off the reminder for.
+pub const SNOOZE_MS: u64 = 10 * 60 * 1000;
+
+/// The time the LED is on, and then off, while pulsing a reminder.
+const PULSE_MS: u64 = 1000;
+
+pub struct BreakReminder {
+    interval_ms: u64,
+
+    /// When the current session started, if there is one.
+    session_start_ms: Option<u64>,
+    last_key_ms: u64,
+    snoozed_until_ms: u64,
+}
+
+impl BreakReminder {
+    /// A reminder after `interval_ms` of typing, or never if it's zero.
+    pub fn new(interval_ms: u64) -> Self {
+        Self { interval_ms, session_start_ms: None, last_key_ms: 0, snoozed_until_ms: 0 }
+    }
+
+    /// Keep track of typing, with the time in milliseconds and whether any key is pressed.
+    pub fn update(&mut self, now_ms: u64, key_pressed: bool) {
+        if self.session_start_ms.is_some() && now_ms - self.last_key_ms >= REST_MS {
+            self.session_start_ms = None;
+            self.snoozed_until_ms = 0;
+        }
+
+        if key_pressed {
+            self.session_start_ms.get_or_insert(now_ms);
+            self.last_key_ms = now_ms;
+        }
+    }
+
+    /// Put off the reminder for `SNOOZE_MS`.
+    pub fn snooze(&mut self, now_ms: u64) {
+        self.snoozed_until_ms = now_ms + SNOOZE_MS;
+    }
+
+    /// Whether it's time for a break.
+    pub fn is_due(&self, now_ms: u64) -> bool {
+        let Some(session_start_ms) = self.session_start_ms else {
+            return false;
+        };
+
+        self.interval_ms > 0
+            && now_ms - session_start_ms >= self.interval_ms
+            && now_ms >= self.snoozed_until_ms
+    }
+
+    /// Whether the indicator LED should be lit to show the reminder.
+    pub fn led_lit(&self, now_ms: u64) -> bool {
+        self.is_due(now_ms) && (now_ms / PULSE_MS).is_multiple_of(2)
+    }
+}
@@ -0,0 +1,189 @@
+//! Typing Unicode characters, with `KeyCode::Unicode0` to `Unicode7` typing the characters
+//! in the layout's `UNICODE_CHARS`.
+//!
+//! A keyboard can only send key usages, so each character is typed as the sequence of keys
+//! the host's Unicode input method takes, which depends on the host. The sequence is built in
+//! the macro format (see `macros`) and played alongside the keys held, one step per scan, by
+//! a `MacroPlayer`. `KeyCode::CycleUnicodeMode` steps through the input methods, and the one
+//! picked is saved with the other settings.
+
+use defmt::Format;
+
+use crate::{
+    macros::{MacroPlayer, MACRO_DOWN, MACRO_PREFIX, MACRO_TAP, MACRO_UP},
+    nkro::NkroReport,
+};
+
+/// The number of Unicode keys, `KeyCode::Unicode0` to `Unicode7`.
+pub const UNICODE_KEYS: usize = 8;
+
+/// The longest sequence a character takes, with six hex digits typed on the keypad.
+const MAX_SEQUENCE_LEN: usize = 32;
+
+/// The usages of the keys the sequences use.
+const LEFT_CTRL: u8 = 0xE0;
+const LEFT_SHIFT: u8 = 0xE1;
+const LEFT_ALT: u8 = 0xE2;
+const RIGHT_ALT: u8 = 0xE6;
+const KEYPAD_PLUS: u8 = 0x57;
+const KEYPAD_1: u8 = 0x59;
+const KEYPAD_0: u8 = 0x62;
+
+/// How the host takes Unicode input.
+#[derive(Copy, Clone, Debug, Format, PartialEq)]
+pub enum UnicodeMode {
+    /// Ctrl+Shift+U, the code point in hex and Space, for IBus on Linux.
+    Linux,
+
+    /// The code point in hex, UTF-16 encoded, with Option held, for macOS's Unicode Hex
+    /// Input source.
+    MacOs,
+
+    /// The compose key (Right Alt), `u`, the code point in hex and Enter, for WinCompose on
+    /// Windows.
+    WinCompose,
+
+    /// Keypad `+` and the code point in hex with Alt held, for Windows with hex numpad
+    /// input turned on in the registry (`EnableHexNumpad`). Only reaches the Basic
+    /// Multilingual Plane.
+    WindowsAltCodes,
+}
+
+impl UnicodeMode {
+    /// The next mode, in the order `KeyCode::CycleUnicodeMode` steps through them.
+    pub fn next(self) -> Self {
+        match self {
+            UnicodeMode::Linux => UnicodeMode::MacOs,
+            UnicodeMode::MacOs => UnicodeMode::WinCompose,
+            UnicodeMode::WinCompose => UnicodeMode::WindowsAltCodes,
+            UnicodeMode::WindowsAltCodes => UnicodeMode::Linux,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            UnicodeMode::Linux => 0,
+            UnicodeMode::MacOs => 1,
+            UnicodeMode::WinCompose => 2,
+            UnicodeMode::WindowsAltCodes => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(UnicodeMode::Linux),
+            1 => Some(UnicodeMode::MacOs),
+            2 => Some(UnicodeMode::WinCompose),
+            3 => Some(UnicodeMode::WindowsAltCodes),
+            _ => None,
+        }
+    }
+}
+
+/// A character's input sequence, in the macro format.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Sequence {
+    bytes: [u8; MAX_SEQUENCE_LEN],
+    len: usize,
+}
+
+impl Sequence {
+    /// The sequence typing `character` with the input method of `mode`.
+    pub fn new(character: char, mode: UnicodeMode) -> Self {
+        let mut sequence = Self { bytes: [0; MAX_SEQUENCE_LEN], len: 0 };
+        let code_point = character as u32;
+
+        match mode {
+            UnicodeMode::Linux => {
+                sequence.push(&[MACRO_PREFIX, MACRO_DOWN, LEFT_CTRL]);
+                sequence.push(&[MACRO_PREFIX, MACRO_DOWN, LEFT_SHIFT]);
+                sequence.push(b"u");
+                sequence.push(&[MACRO_PREFIX, MACRO_UP, LEFT_SHIFT]);
+                sequence.push(&[MACRO_PREFIX, MACRO_UP, LEFT_CTRL]);
+                sequence.push_hex(code_point, false);
+                sequence.push(b" ");
+            },
+            UnicodeMode::MacOs => {
+                sequence.push(&[MACRO_PREFIX, MACRO_DOWN, LEFT_ALT]);
+                let mut units = [0; 2];
+                for unit in character.encode_utf16(&mut units) {
+                    sequence.push_hex_digits(*unit as u32, 4, false);
+                }
+                sequence.push(&[MACRO_PREFIX, MACRO_UP, LEFT_ALT]);
+            },
+            UnicodeMode::WinCompose => {
+                sequence.push(&[MACRO_PREFIX, MACRO_TAP, RIGHT_ALT]);
+                sequence.push(b"u");
+                sequence.push_hex(code_point, false);
+                sequence.push(b"\n");
+            },
+            UnicodeMode::WindowsAltCodes => {
+                sequence.push(&[MACRO_PREFIX, MACRO_DOWN, LEFT_ALT]);
+                sequence.push(&[MACRO_PREFIX, MACRO_TAP, KEYPAD_PLUS]);
+                sequence.push_hex(code_point, true);
+                sequence.push(&[MACRO_PREFIX, MACRO_UP, LEFT_ALT]);
+            },
+        }
+        sequence
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if let Some(space) = self.bytes.get_mut(self.len..self.len + bytes.len()) {
+            space.copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+    }
+
+    /// Push `value` in lowercase hex, with at least four digits as input methods expect.
+    fn push_hex(&mut self, value: u32, keypad: bool) {
+        let digits = (32 - value.leading_zeros() as usize).div_ceil(4).max(4);
+        self.push_hex_digits(value, digits, keypad);
+    }
+
+    /// Push the last `digits` hex digits of `value`, with the numbers on the keypad if
+    /// `keypad` is set.
+    fn push_hex_digits(&mut self, value: u32, digits: usize, keypad: bool) {
+        for digit in (0..digits).rev().map(|index| (value >> (index * 4)) & 0xF) {
+            match (digit, keypad) {
+                (0, true) => self.push(&[MACRO_PREFIX, MACRO_TAP, KEYPAD_0]),
+                (1..=9, true) => self.push(&[MACRO_PREFIX, MACRO_TAP, KEYPAD_1 + digit as u8 - 1]),
+                _ => self.push(&[char::from_digit(digit, 16).unwrap_or('0') as u8]),
+            }
+        }
+    }
+}
+
+/// Types Unicode characters, one at a time.
+#[derive(Default)]
+pub struct UnicodePlayer {
+    sequence: Option<Sequence>,
+    player: MacroPlayer,
+}
+
+impl UnicodePlayer {
+    /// Start typing `character`, abandoning (and releasing) any character still being typed.
+    pub fn start(&mut self, character: char, mode: UnicodeMode) {
+        self.sequence = Some(Sequence::new(character, mode));
+        self.player.start_sequence();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.player.is_playing()
+    }
+
+    /// The keys held down to type the character, as of the last `tick`.
+    pub fn held(&self) -> &NkroReport {
+        self.player.held()
+    }
+
+    /// Advance the character being typed by one scan tick.
+    pub fn tick(&mut self) {
+        if let Some(sequence) = &self.sequence {
+            self.player.tick_sequence(sequence.bytes());
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! A USB serial port (CDC-ACM) which carries the firmware's log, with the `usb-log` feature,
+//! for keyboards without a debug probe attached.
+//!
+//! The log is the same defmt stream RTT would carry, so it's decoded on the host against the
+//! firmware's ELF file, such as with `defmt-print -e <elf> < /dev/ttyACM0`. Messages are
+//! kept in a `LogBuffer` until a terminal opens the port, which the host signals by setting
+//! DTR, with the oldest dropped to make room once it's full. A panic can't be sent before
+//! the keyboard resets, but it's recorded and logged again on the next boot, see `crash`.
+
+use usb_device::{
+    bus::{InterfaceNumber, UsbBusAllocator},
+    class::{ControlIn, ControlOut, UsbClass},
+    class_prelude::UsbBus,
+    control::{Recipient, RequestType},
+    descriptor::DescriptorWriter,
+    endpoint::{EndpointIn, EndpointOut},
+};
+
+const CLASS_CDC: u8 = 0x02;
+const CLASS_CDC_DATA: u8 = 0x0A;
+const SUBCLASS_ACM: u8 = 0x02;
+const PROTOCOL_NONE: u8 = 0x00;
+
+const DESCRIPTOR_CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+/// The ACM capabilities: SET_LINE_CODING, GET_LINE_CODING and SET_CONTROL_LINE_STATE.
+const ACM_CAPABILITY_LINE: u8 = 1 << 1;
+
+const CDC_VERSION: u16 = 0x0110;
+
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// The DTR bit of SET_CONTROL_LINE_STATE, set while a terminal has the port open.
+const CONTROL_LINE_DTR: u16 = 1 << 0;
+
+const MAX_PACKET_SIZE: u16 = 64;
+
+/// Packets are kept short of `MAX_PACKET_SIZE`, as a full packet doesn't end the host's
+/// read, which would hold the log back until the next message.
+const MAX_LOG_PACKET: usize = MAX_PACKET_SIZE as usize - 1;
+
+/// A ring buffer of defmt frames waiting to be sent, which drops the oldest bytes to fit new
+/// ones once it's full. The decoder skips a frame cut short, and carries on from the next.
+pub struct LogBuffer<const N: usize> {
+    bytes: [u8; N],
+    start: usize,
+    len: usize,
+    encoder: defmt::Encoder,
+}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        Self { bytes: [0; N], start: 0, len: 0, encoder: defmt::Encoder::new() }
+    }
+
+    /// Begin a log message, from `defmt::Logger::acquire`.
+    pub fn start_frame(&mut self) {
+        let mut encoder = core::mem::replace(&mut self.encoder, defmt::Encoder::new());
+        encoder.start_frame(|bytes| self.push(bytes));
+        self.encoder = encoder;
+    }
+
+    /// Add to the log message, from `defmt::Logger::write`.
+    pub fn write(&mut self, data: &[u8]) {
+        let mut encoder = core::mem::replace(&mut self.encoder, defmt::Encoder::new());
+        encoder.write(data, |bytes| self.push(bytes));
+        self.encoder = encoder;
+    }
+
+    /// Finish the log message, from `defmt::Logger::release`.
+    pub fn end_frame(&mut self) {
+        let mut encoder = core::mem::replace(&mut self.encoder, defmt::Encoder::new());
+        encoder.end_frame(|bytes| self.push(bytes));
+        self.encoder = encoder;
+    }
+
+    /// The number of bytes waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy the oldest bytes into `buf`, without taking them, returning how many were copied.
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.bytes[(self.start + i) % N];
+        }
+        count
+    }
+
+    /// Drop the oldest `count` bytes, once they've been sent.
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.start = (self.start + count) % N;
+        self.len -= count;
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if self.len == N {
+                self.consume(1);
+            }
+            self.bytes[(self.start + self.len) % N] = *byte;
+            self.len += 1;
+        }
+    }
+}
+
+impl<const N: usize> Default for LogBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct CdcAcmClass<'a, B: UsbBus> {
+    comm_interface: InterfaceNumber,
+    data_interface: InterfaceNumber,
+    notification_endpoint: EndpointIn<'a, B>,
+    write_endpoint: EndpointIn<'a, B>,
+    read_endpoint: EndpointOut<'a, B>,
+
+    /// The line coding the host last set. It's only kept to be read back, as there's no
+    /// actual serial line.
+    line_coding: [u8; 7],
+
+    /// Whether a terminal has the port open.
+    dtr: bool,
+}
+
+impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            comm_interface: alloc.interface(),
+            data_interface: alloc.interface(),
+            notification_endpoint: alloc.interrupt(8, 255),
+            write_endpoint: alloc.bulk(MAX_PACKET_SIZE),
+            read_endpoint: alloc.bulk(MAX_PACKET_SIZE),
+            // 115200 baud, one stop bit, no parity, eight data bits.
+            line_coding: [0x00, 0xC2, 0x01, 0x00, 0, 0, 8],
+            dtr: false,
+        }
+    }
+
+    /// Send the host the next packet of the log, if a terminal is open and the last packet
+    /// has gone.
+    pub fn send<const N: usize>(&mut self, log: &mut LogBuffer<N>) {
+        if !self.dtr || log.is_empty() {
+            return;
+        }
+
+        let mut packet = [0u8; MAX_LOG_PACKET];
+        let len = log.peek(&mut packet);
+        if let Ok(sent) = self.write_endpoint.write(&packet[..len]) {
+            log.consume(sent);
+        }
+    }
+
+    fn is_cdc_request(&self, request: &usb_device::control::Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.comm_interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.iad(self.comm_interface, 2, CLASS_CDC, SUBCLASS_ACM, PROTOCOL_NONE)?;
+        writer.interface(self.comm_interface, CLASS_CDC, SUBCLASS_ACM, PROTOCOL_NONE)?;
+
+        let [version_low, version_high] = CDC_VERSION.to_le_bytes();
+        writer.write(DESCRIPTOR_CS_INTERFACE, &[CDC_TYPE_HEADER, version_low, version_high])?;
+        writer.write(
+            DESCRIPTOR_CS_INTERFACE,
+            &[CDC_TYPE_CALL_MANAGEMENT, 0x00, self.data_interface.into()],
+        )?;
+        writer.write(DESCRIPTOR_CS_INTERFACE, &[CDC_TYPE_ACM, ACM_CAPABILITY_LINE])?;
+        writer.write(
+            DESCRIPTOR_CS_INTERFACE,
+            &[CDC_TYPE_UNION, self.comm_interface.into(), self.data_interface.into()],
+        )?;
+        writer.endpoint(&self.notification_endpoint)?;
+
+        writer.interface(self.data_interface, CLASS_CDC_DATA, 0x00, PROTOCOL_NONE)?;
+        writer.endpoint(&self.write_endpoint)?;
+        writer.endpoint(&self.read_endpoint)
+    }
+
+    fn reset(&mut self) {
+        self.dtr = false;
+    }
+
+    fn endpoint_out(&mut self, addr: usb_device::endpoint::EndpointAddress) {
+        // Anything typed into the terminal is ignored.
+        if addr == self.read_endpoint.address() {
+            self.read_endpoint.read(&mut [0; MAX_PACKET_SIZE as usize]).ok();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if !self.is_cdc_request(request) {
+            return;
+        }
+
+        match request.request {
+            GET_LINE_CODING => xfer.accept_with(&self.line_coding).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if !self.is_cdc_request(request) {
+            return;
+        }
+
+        match request.request {
+            SET_LINE_CODING if xfer.data().len() == self.line_coding.len() => {
+                self.line_coding.copy_from_slice(xfer.data());
+                xfer.accept().ok();
+            },
+            SET_CONTROL_LINE_STATE => {
+                self.dtr = request.value & CONTROL_LINE_DTR != 0;
+                xfer.accept().ok();
+            },
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+}
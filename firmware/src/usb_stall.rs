@@ -0,0 +1,41 @@
+//! Notices when the USB connection has wedged, so the keyboard can disconnect and let the
+//! host enumerate it again instead of needing to be unplugged.
+//!
+//! A configured device gets a start-of-frame from the host every millisecond, and the host
+//! keeps taking reports from its interrupt endpoint. A glitched hub or a botched resume can
+//! leave the device thinking it's configured while neither happens any more.
+
+use crate::SCAN_PERIOD_MS;
+
+/// How long frames have to stop, or reports go untaken, before reconnecting.
+pub const STALL_TIMEOUT_MS: u32 = 1000;
+
+#[derive(Default)]
+pub struct StallDetector {
+    last_frame: u16,
+    stalled_ms: u32,
+}
+
+impl StallDetector {
+    /// Check on the connection once a scan with whether the host has configured the
+    /// keyboard (and not suspended it), the number of the last USB frame, and whether the
+    /// last report pushed to the host got `WouldBlock`. Returns true when the connection
+    /// has been stuck for `STALL_TIMEOUT_MS`, and needs resetting.
+    pub fn tick(&mut self, configured: bool, frame: u16, report_blocked: bool) -> bool {
+        let frame_started = frame != self.last_frame;
+        self.last_frame = frame;
+
+        if !configured || (frame_started && !report_blocked) {
+            self.stalled_ms = 0;
+            return false;
+        }
+
+        self.stalled_ms += SCAN_PERIOD_MS;
+        if self.stalled_ms >= STALL_TIMEOUT_MS {
+            self.stalled_ms = 0;
+            return true;
+        }
+
+        false
+    }
+}
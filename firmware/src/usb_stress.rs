@@ -0,0 +1,94 @@
+//! A stress test of the path reports take to the host, which types a rolling chord as fast
+//! as the keyboard scans, so a report lost anywhere along the way shows up as a missing
+//! letter.
+//!
+//! `KeyCode::UsbStressTest` starts it. Each scan presses the next letter of the alphabet and
+//! releases the one pressed `STRESS_ROLLOVER` scans before, so every report is a change and
+//! a chord is held throughout, like a fast typist rolling from key to key. Typed into a text
+//! editor, it comes out as the alphabet `STRESS_ROUNDS` times, with nothing missing or
+//! doubled. Along the way the main loop counts the reports which had to wait for room in the
+//! report queue, and the scans where the endpoint was still busy, logged once it's over.
+
+use defmt::Format;
+use usbd_hid::descriptor::KeyboardReport;
+
+/// How many letters are held at once.
+pub const STRESS_ROLLOVER: usize = 5;
+
+/// How many times the alphabet is typed.
+pub const STRESS_ROUNDS: usize = 4;
+
+const LETTERS: usize = 26;
+
+/// The usage of `A`, which the other letters follow.
+const KEY_A: u8 = 0x04;
+
+/// What happened to the reports during a stress test.
+#[derive(Copy, Clone, Debug, Default, Format, PartialEq)]
+pub struct StressStats {
+    pub reports: u32,
+
+    /// Scans where the report queue was full, so the report waited for the next scan.
+    pub queue_full: u32,
+
+    /// Scans where the last push to the endpoint got `WouldBlock`.
+    pub blocked: u32,
+}
+
+#[derive(Default)]
+pub struct StressTest {
+    /// The number of scans since the test started, while it's running.
+    step: Option<usize>,
+    stats: StressStats,
+    finished: bool,
+}
+
+impl StressTest {
+    /// Start the test, or start it over if it's running.
+    pub fn start(&mut self) {
+        self.step = Some(0);
+        self.stats = StressStats::default();
+        self.finished = false;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.step.is_some()
+    }
+
+    /// The report to send for this scan in place of the keyboard's own, or `None` once the
+    /// test is over.
+    pub fn next_report(&mut self) -> Option<KeyboardReport> {
+        let step = self.step?;
+        let letters = LETTERS * STRESS_ROUNDS;
+
+        // The last letters are released one a scan too, ending with nothing held.
+        if step >= letters + STRESS_ROLLOVER {
+            self.step = None;
+            self.finished = true;
+            return None;
+        }
+        self.step = Some(step + 1);
+        self.stats.reports += 1;
+
+        let mut keycodes = [0u8; 6];
+        let held = (step + 1).saturating_sub(STRESS_ROLLOVER)..=step.min(letters - 1);
+        for (keycode, letter) in keycodes.iter_mut().zip(held) {
+            *keycode = KEY_A + (letter % LETTERS) as u8;
+        }
+
+        Some(KeyboardReport { modifier: 0, reserved: 0, leds: 0, keycodes })
+    }
+
+    /// Count how the report path coped with this scan's report, while the test is running.
+    pub fn record(&mut self, queue_full: bool, blocked: bool) {
+        if self.is_running() {
+            self.stats.queue_full += queue_full as u32;
+            self.stats.blocked += blocked as u32;
+        }
+    }
+
+    /// The counts from the test, once, after it's finished.
+    pub fn take_stats(&mut self) -> Option<StressStats> {
+        core::mem::take(&mut self.finished).then_some(self.stats)
+    }
+}
@@ -0,0 +1,380 @@
+//! The VIA configuration protocol, so the keyboard can be remapped with the VIA desktop and
+//! web apps, or Vial by sideloading the keyboard's VIA definition.
+//!
+//! VIA talks to its own raw HID interface, described by `hid_descriptor::VIA_REPORT_DESCRIPTOR`,
+//! with 32 byte reports. Each request starts with a command ID, and is answered by the same
+//! report with the results written over its arguments, or with the ID replaced by
+//! `ID_UNHANDLED` for anything the keyboard doesn't support. Keys are addressed by
+//! `(row, col)`, and travel as QMK keycodes, see `to_qmk_keycode`.
+//!
+//! While the configuration is locked (see `KeyCode::ToggleConfigLock`), every command which
+//! would change something is answered with `ID_UNHANDLED`.
+
+use crate::{
+    key_codes::KeyCode,
+    key_mapping::NUM_LAYERS,
+    keymap::Keymap,
+    layers::LayerAction,
+    macros::{MacroBuffer, MACRO_BUFFER_SIZE, MACRO_COUNT},
+    NUM_COLS, NUM_ROWS,
+};
+
+/// The length of every report, in both directions.
+pub const VIA_REPORT_LEN: usize = 32;
+
+/// The version of the protocol implemented, which VIA checks before anything else.
+pub const VIA_PROTOCOL_VERSION: u16 = 0x000C;
+
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_EEPROM_RESET: u8 = 0x0A;
+const ID_BOOTLOADER_JUMP: u8 = 0x0B;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const ID_UNHANDLED: u8 = 0xFF;
+
+const ID_UPTIME: u8 = 0x01;
+const ID_LAYOUT_OPTIONS: u8 = 0x02;
+const ID_SWITCH_MATRIX_STATE: u8 = 0x03;
+const ID_FIRMWARE_VERSION: u8 = 0x04;
+const ID_DEVICE_INDICATION: u8 = 0x05;
+
+/// The most data a buffer request can carry, after its four byte header.
+const MAX_BUFFER_CHUNK: usize = VIA_REPORT_LEN - 4;
+
+/// The firmware version, as `0x00MMmmpp`.
+const FIRMWARE_VERSION: u32 = (parse_version_number(env!("CARGO_PKG_VERSION_MAJOR")) << 16)
+    | (parse_version_number(env!("CARGO_PKG_VERSION_MINOR")) << 8)
+    | parse_version_number(env!("CARGO_PKG_VERSION_PATCH"));
+
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_DEF_LAYER: u16 = 0x5240;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_ONE_SHOT_LAYER: u16 = 0x5280;
+const QK_MACRO: u16 = 0x7700;
+const QK_KB: u16 = 0x7E00;
+
+/// The firmware keys, sent to VIA as the keyboard's custom keycodes (`QK_KB` onwards). The
+/// keyboard definition's `customKeycodes` has to list them in the same order.
+pub const CUSTOM_KEYCODES: [KeyCode; 29] = [
+    KeyCode::NumWord,
+    KeyCode::ToggleProfile,
+    KeyCode::CalibrateAnalog,
+    KeyCode::SaveScanTrace,
+    KeyCode::ReplayScanTrace,
+    KeyCode::ToggleConfigLock,
+    KeyCode::SwitchOutput,
+    KeyCode::SnoozeBreak,
+    KeyCode::CycleSocd,
+    KeyCode::ToggleNkro,
+    KeyCode::ActuationDeeper,
+    KeyCode::ActuationShallower,
+    KeyCode::ToggleRapidTrigger,
+    KeyCode::ToggleAutoShift,
+    KeyCode::Unicode0,
+    KeyCode::Unicode1,
+    KeyCode::Unicode2,
+    KeyCode::Unicode3,
+    KeyCode::Unicode4,
+    KeyCode::Unicode5,
+    KeyCode::Unicode6,
+    KeyCode::Unicode7,
+    KeyCode::CycleUnicodeMode,
+    KeyCode::UsbStressTest,
+    KeyCode::SettingsMode,
+    KeyCode::ToggleAltGuiSwap,
+    KeyCode::ToggleCapsAsCtrl,
+    KeyCode::ToggleGuiDisabled,
+    KeyCode::ToggleGameMode,
+];
+
+/// The keys whose QMK keycode isn't their value in `KeyCode`.
+const QMK_KEYCODES: [(KeyCode, u16); 46] = [
+    (KeyCode::LeftCtrl, 0xE0),
+    (KeyCode::LeftShift, 0xE1),
+    (KeyCode::LeftAlt, 0xE2),
+    (KeyCode::LeftCmd, 0xE3),
+    (KeyCode::RightCtrl, 0xE4),
+    (KeyCode::RightShift, 0xE5),
+    (KeyCode::RightAlt, 0xE6),
+    (KeyCode::RightCmd, 0xE7),
+    (KeyCode::VolumeMute, 0xA8),
+    (KeyCode::VolumeUp, 0xA9),
+    (KeyCode::VolumeDown, 0xAA),
+    (KeyCode::NextTrack, 0xAB),
+    (KeyCode::PreviousTrack, 0xAC),
+    (KeyCode::PlayPause, 0xAE),
+    (KeyCode::BrightnessUp, 0xBD),
+    (KeyCode::BrightnessDown, 0xBE),
+    (KeyCode::RecordMacro, 0x7C53),
+    (KeyCode::StopMacroRecording, 0x7C55),
+    (KeyCode::PlayRecordedMacro, 0x7C56),
+    (KeyCode::RgbToggle, 0x7820),
+    (KeyCode::RgbNextAnimation, 0x7821),
+    (KeyCode::RgbBrightnessUp, 0x7827),
+    (KeyCode::RgbBrightnessDown, 0x7828),
+    (KeyCode::Bootloader, 0x7C00),
+    (KeyCode::GraveEscape, 0x7C16),
+    (KeyCode::Repeat, 0x7C79),
+    (KeyCode::MouseUp, 0xCD),
+    (KeyCode::MouseDown, 0xCE),
+    (KeyCode::MouseLeft, 0xCF),
+    (KeyCode::MouseRight, 0xD0),
+    (KeyCode::MouseButton1, 0xD1),
+    (KeyCode::MouseButton2, 0xD2),
+    (KeyCode::MouseButton3, 0xD3),
+    (KeyCode::MouseButton4, 0xD4),
+    (KeyCode::MouseButton5, 0xD5),
+    (KeyCode::MouseWheelUp, 0xD9),
+    (KeyCode::MouseWheelDown, 0xDA),
+    (KeyCode::MouseWheelLeft, 0xDB),
+    (KeyCode::MouseWheelRight, 0xDC),
+    (KeyCode::SystemPower, 0xA5),
+    (KeyCode::SystemSleep, 0xA6),
+    (KeyCode::SystemWake, 0xA7),
+    (KeyCode::OneShotCtrl, 0x52A1),
+    (KeyCode::OneShotShift, 0x52A2),
+    (KeyCode::OneShotAlt, 0x52A4),
+    (KeyCode::OneShotCmd, 0x52A8),
+];
+
+/// The QMK keycode VIA shows for a key. Plain keys are their HID usage in both.
+pub fn to_qmk_keycode(key: KeyCode) -> u16 {
+    if let Some((_, code)) = QMK_KEYCODES.iter().find(|(qmk_key, _)| *qmk_key == key) {
+        return *code;
+    }
+
+    if let Some(index) = key.macro_index() {
+        return QK_MACRO + index as u16;
+    }
+
+    if let Some(index) = CUSTOM_KEYCODES.iter().position(|custom| *custom == key) {
+        return QK_KB + index as u16;
+    }
+
+    match key.layer_action() {
+        Some(LayerAction::Momentary(layer)) => QK_MOMENTARY | layer as u16,
+        Some(LayerAction::Toggle(layer)) => QK_TOGGLE_LAYER | layer as u16,
+        Some(LayerAction::OneShot(layer)) => QK_ONE_SHOT_LAYER | layer as u16,
+        Some(LayerAction::Default(layer)) => QK_DEF_LAYER | layer as u16,
+        None => key as u16,
+    }
+}
+
+/// The key for a QMK keycode, if there's one which does the same thing.
+pub fn from_qmk_keycode(code: u16) -> Option<KeyCode> {
+    // Searched from the top, so the Fn layer's momentary key comes back as `KeyCode::Fn`
+    // rather than the numbered layer key it's equivalent to.
+    (0..=KeyCode::MAX_VALUE)
+        .rev()
+        .filter_map(KeyCode::from_u16)
+        .find(|key| to_qmk_keycode(*key) == code)
+}
+
+/// Answers VIA's requests, on behalf of the main loop.
+pub struct Via {
+    /// The layout options picked in VIA. The keyboard only stores them, for VIA to show
+    /// the right layout the next time it connects.
+    layout_options: u32,
+
+    keymap_save_requested: bool,
+    macros_save_requested: bool,
+    bootloader_requested: bool,
+}
+
+impl Via {
+    pub fn new(layout_options: u32) -> Self {
+        Self {
+            layout_options,
+            keymap_save_requested: false,
+            macros_save_requested: false,
+            bootloader_requested: false,
+        }
+    }
+
+    pub fn layout_options(&self) -> u32 {
+        self.layout_options
+    }
+
+    /// Handle one request report, returning the response report. `matrix` is the last
+    /// debounced scan, and changes are made to `keymap` and `macros`.
+    pub fn handle(
+        &mut self,
+        request: &[u8; VIA_REPORT_LEN],
+        keymap: &mut Keymap,
+        macros: &mut MacroBuffer,
+        matrix: &[[bool; NUM_ROWS]; NUM_COLS],
+        uptime_ms: u64,
+        config_locked: bool,
+    ) -> [u8; VIA_REPORT_LEN] {
+        let mut response = *request;
+        let data = &mut response;
+
+        if config_locked && changes_config(data[0], data[1]) {
+            data[0] = ID_UNHANDLED;
+            return response;
+        }
+
+        let position = |layer: u8, row: u8, col: u8| {
+            let (layer, row, col) = (layer as usize, row as usize, col as usize);
+            (layer < NUM_LAYERS && row < NUM_ROWS && col < NUM_COLS).then_some((layer, col, row))
+        };
+        let buffer_offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let buffer_size = data[3] as usize;
+
+        match data[0] {
+            ID_GET_PROTOCOL_VERSION => {
+                data[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes());
+            },
+            ID_GET_KEYBOARD_VALUE => match data[1] {
+                ID_UPTIME => data[2..6].copy_from_slice(&(uptime_ms as u32).to_be_bytes()),
+                ID_LAYOUT_OPTIONS => data[2..6].copy_from_slice(&self.layout_options.to_be_bytes()),
+                ID_SWITCH_MATRIX_STATE => {
+                    for (row, chunk) in data[2..].chunks_exact_mut(2).take(NUM_ROWS).enumerate() {
+                        let bits = (0..NUM_COLS)
+                            .filter(|col| matrix[*col][row])
+                            .fold(0u16, |bits, col| bits | 1 << col);
+                        chunk.copy_from_slice(&bits.to_be_bytes());
+                    }
+                },
+                ID_FIRMWARE_VERSION => data[2..6].copy_from_slice(&FIRMWARE_VERSION.to_be_bytes()),
+                _ => data[0] = ID_UNHANDLED,
+            },
+            ID_SET_KEYBOARD_VALUE => match data[1] {
+                ID_LAYOUT_OPTIONS => {
+                    self.layout_options = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+                },
+                // There's no backlight to flash, so the keyboard can't point itself out.
+                ID_DEVICE_INDICATION => {},
+                _ => data[0] = ID_UNHANDLED,
+            },
+            ID_DYNAMIC_KEYMAP_GET_KEYCODE => {
+                if let Some((layer, col, row)) = position(data[1], data[2], data[3]) {
+                    let code = to_qmk_keycode(keymap.key(layer, col, row));
+                    data[4..6].copy_from_slice(&code.to_be_bytes());
+                }
+            },
+            ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+                let code = u16::from_be_bytes([data[4], data[5]]);
+                if let (Some((layer, col, row)), Some(key)) =
+                    (position(data[1], data[2], data[3]), from_qmk_keycode(code))
+                {
+                    keymap.set_key(layer, col, row, key);
+                    self.keymap_save_requested = true;
+                }
+            },
+            ID_DYNAMIC_KEYMAP_RESET => {
+                *keymap = Keymap::default();
+                self.keymap_save_requested = true;
+            },
+            ID_EEPROM_RESET => {
+                *keymap = Keymap::default();
+                *macros = MacroBuffer::default();
+                self.layout_options = 0;
+                self.keymap_save_requested = true;
+                self.macros_save_requested = true;
+            },
+            ID_BOOTLOADER_JUMP => self.bootloader_requested = true,
+            ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => data[1] = MACRO_COUNT as u8,
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                data[1..3].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes());
+            },
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER if buffer_size <= MAX_BUFFER_CHUNK => {
+                macros.read(buffer_offset, &mut data[4..4 + buffer_size]);
+            },
+            ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER if buffer_size <= MAX_BUFFER_CHUNK => {
+                if macros.write(buffer_offset, &data[4..4 + buffer_size]) {
+                    self.macros_save_requested = true;
+                }
+            },
+            ID_DYNAMIC_KEYMAP_MACRO_RESET => {
+                *macros = MacroBuffer::default();
+                self.macros_save_requested = true;
+            },
+            ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => data[1] = NUM_LAYERS as u8,
+            ID_DYNAMIC_KEYMAP_GET_BUFFER if buffer_size <= MAX_BUFFER_CHUNK => {
+                for (i, chunk) in data[4..4 + buffer_size].chunks_exact_mut(2).enumerate() {
+                    if let Some((layer, col, row)) = buffer_position(buffer_offset / 2 + i) {
+                        let code = to_qmk_keycode(keymap.key(layer, col, row));
+                        chunk.copy_from_slice(&code.to_be_bytes());
+                    }
+                }
+            },
+            ID_DYNAMIC_KEYMAP_SET_BUFFER if buffer_size <= MAX_BUFFER_CHUNK => {
+                for (i, chunk) in data[4..4 + buffer_size].chunks_exact(2).enumerate() {
+                    let code = u16::from_be_bytes([chunk[0], chunk[1]]);
+                    if let (Some((layer, col, row)), Some(key)) =
+                        (buffer_position(buffer_offset / 2 + i), from_qmk_keycode(code))
+                    {
+                        keymap.set_key(layer, col, row, key);
+                    }
+                }
+                self.keymap_save_requested = true;
+            },
+            _ => data[0] = ID_UNHANDLED,
+        }
+
+        response
+    }
+
+    /// Whether VIA changed the keymap since this was last called. VIA expects every change
+    /// to stick without being asked, so the main loop saves after each one.
+    pub fn take_keymap_save_request(&mut self) -> bool {
+        core::mem::take(&mut self.keymap_save_requested)
+    }
+
+    /// Whether VIA changed the macros since this was last called.
+    pub fn take_macros_save_request(&mut self) -> bool {
+        core::mem::take(&mut self.macros_save_requested)
+    }
+
+    /// Whether VIA asked for the keyboard to restart into the bootloader.
+    pub fn take_bootloader_request(&mut self) -> bool {
+        core::mem::take(&mut self.bootloader_requested)
+    }
+}
+
+/// Whether a command changes the configuration, and is refused while it's locked.
+fn changes_config(command: u8, value_id: u8) -> bool {
+    match command {
+        ID_SET_KEYBOARD_VALUE => value_id != ID_DEVICE_INDICATION,
+        ID_DYNAMIC_KEYMAP_SET_KEYCODE
+        | ID_DYNAMIC_KEYMAP_RESET
+        | ID_EEPROM_RESET
+        | ID_BOOTLOADER_JUMP
+        | ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER
+        | ID_DYNAMIC_KEYMAP_MACRO_RESET
+        | ID_DYNAMIC_KEYMAP_SET_BUFFER => true,
+        _ => false,
+    }
+}
+
+/// The `(layer, col, row)` of a key in the keymap buffer, where keys are stored layer by
+/// layer, then row by row.
+fn buffer_position(index: usize) -> Option<(usize, usize, usize)> {
+    let layer = index / (NUM_ROWS * NUM_COLS);
+    let row = index / NUM_COLS % NUM_ROWS;
+    let col = index % NUM_COLS;
+    (layer < NUM_LAYERS).then_some((layer, col, row))
+}
+
+const fn parse_version_number(number: &str) -> u32 {
+    let digits = number.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
@@ -0,0 +1,291 @@
+//! A vendor-specific configuration interface, along with the WebUSB and Microsoft OS 2.0
+//! descriptors which let hosts find it without any setup.
+//!
+//! The Microsoft OS 2.0 descriptors tell Windows to bind WinUSB to the interface, so
+//! configuration tools can open it without installing a driver. The WebUSB descriptors let
+//! browsers offer a link to the web configurator when the keyboard is plugged in.
+//!
+//! Both are found through platform capabilities in the BOS descriptor, which name a vendor
+//! request the host then sends to the device to fetch the rest.
+//!
+//! The interface's string is `build_info::BUILD_INFO`, so tools can see what firmware and
+//! keymap layout the keyboard has from its descriptors alone.
+//!
+//! It carries the `raw_hid` protocol over control transfers to the interface, for hosts
+//! which can reach it with WebUSB but not the raw HID interface with WebHID. A request is
+//! sent as the data of a `RAW_HID_REQUEST` vendor request, which stalls while another is
+//! still being handled. The response is read back with `RAW_HID_RESPONSE`, which comes back
+//! empty until it's ready.
+//!
+//! While the configuration is locked (see `KeyCode::ToggleConfigLock`), every vendor
+//! request which would change something is rejected, apart from `raw_hid` requests, which
+//! are checked command by command.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use usb_device::{
+    bus::{InterfaceNumber, StringIndex, UsbBusAllocator},
+    class::{ControlIn, ControlOut, UsbClass},
+    class_prelude::UsbBus,
+    control::{Recipient, RequestType},
+    descriptor::{BosWriter, DescriptorWriter},
+};
+
+use crate::{
+    build_info::{Description, BUILD_INFO},
+    raw_hid::{ReportSlot, RAW_REPORT_LEN},
+};
+
+/// Where browsers point people when the keyboard is plugged in, without the `https://`.
+pub const LANDING_PAGE_URL: &str = "github.com/bschwind/key-ripper";
+
+/// The GUID configuration tools look for to find the interface on Windows.
+const DEVICE_INTERFACE_GUID: &str = "{905B0DDC-E377-4E24-9244-2324A9784111}";
+
+/// The vendor requests the host sends to fetch the WebUSB and Microsoft OS 2.0 descriptors.
+const WEBUSB_VENDOR_CODE: u8 = 0x01;
+const MS_OS_VENDOR_CODE: u8 = 0x02;
+
+/// The vendor requests to the interface which carry `raw_hid` requests and responses.
+pub const RAW_HID_REQUEST: u8 = 0x03;
+pub const RAW_HID_RESPONSE: u8 = 0x04;
+
+const CAPABILITY_PLATFORM: u8 = 0x05;
+
+/// {3408B638-09A9-47A0-8BFD-A0768815B665}, in the byte order of the descriptor.
+const WEBUSB_PLATFORM_UUID: [u8; 16] = [
+    0x38, 0xB6, 0x08, 0x34, 0xA9, 0x09, 0xA0, 0x47, 0x8B, 0xFD, 0xA0, 0x76, 0x88, 0x15, 0xB6, 0x65,
+];
+
+/// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}, in the byte order of the descriptor.
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+
+/// The `wIndex` of the WebUSB GET_URL request.
+const WEBUSB_GET_URL: u16 = 2;
+const WEBUSB_URL_DESCRIPTOR: u8 = 3;
+const WEBUSB_URL_SCHEME_HTTPS: u8 = 1;
+const LANDING_PAGE_INDEX: u8 = 1;
+
+/// The `wIndex` of the request for the Microsoft OS 2.0 descriptor set.
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+
+/// Windows 8.1, the first version to support Microsoft OS 2.0 descriptors.
+const WINDOWS_VERSION: u32 = 0x0603_0000;
+
+const MS_OS_20_SET_HEADER: u16 = 0x00;
+const MS_OS_20_SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const MS_OS_20_SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const MS_OS_20_FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const MS_OS_20_FEATURE_REG_PROPERTY: u16 = 0x04;
+const REG_MULTI_SZ: u16 = 7;
+
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+
+/// The registry property's name and value are both null-terminated UTF-16, and the value
+/// is a list, which ends with another null.
+const PROPERTY_NAME_LEN: usize = (PROPERTY_NAME.len() + 1) * 2;
+const PROPERTY_DATA_LEN: usize = (DEVICE_INTERFACE_GUID.len() + 2) * 2;
+const REG_PROPERTY_LEN: usize = 10 + PROPERTY_NAME_LEN + PROPERTY_DATA_LEN;
+const FUNCTION_SUBSET_LEN: usize = 8 + 20 + REG_PROPERTY_LEN;
+const CONFIGURATION_SUBSET_LEN: usize = 8 + FUNCTION_SUBSET_LEN;
+
+/// The length of the whole Microsoft OS 2.0 descriptor set.
+pub const MS_OS_DESCRIPTOR_SET_LEN: usize = 10 + CONFIGURATION_SUBSET_LEN;
+
+pub struct WebUsbClass {
+    interface: InterfaceNumber,
+    descriptor_set: [u8; MS_OS_DESCRIPTOR_SET_LEN],
+    description_index: StringIndex,
+    description: Description,
+
+    /// Whether the keyboard's configuration is locked against changes from the host.
+    config_locked: &'static AtomicBool,
+
+    /// The `raw_hid` request waiting for the main loop, and its response.
+    raw_request: &'static ReportSlot,
+    raw_response: &'static ReportSlot,
+}
+
+impl WebUsbClass {
+    pub fn new<B: UsbBus>(
+        alloc: &UsbBusAllocator<B>,
+        config_locked: &'static AtomicBool,
+        raw_request: &'static ReportSlot,
+        raw_response: &'static ReportSlot,
+    ) -> Self {
+        let interface = alloc.interface();
+        Self {
+            interface,
+            descriptor_set: ms_os_descriptor_set(interface.into()),
+            description_index: alloc.string(),
+            description: Description::new(&BUILD_INFO),
+            config_locked,
+            raw_request,
+            raw_response,
+        }
+    }
+
+    fn is_vendor_request(request: &usb_device::control::Request, vendor_code: u8) -> bool {
+        request.request_type == RequestType::Vendor
+            && request.recipient == Recipient::Device
+            && request.request == vendor_code
+    }
+
+    fn is_interface_request(&self, request: &usb_device::control::Request, code: u8) -> bool {
+        request.request_type == RequestType::Vendor
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+            && request.request == code
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for WebUsbClass {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        // No endpoints yet, configuration happens over control transfers.
+        writer.interface_alt(self.interface, 0, 0xFF, 0x00, 0x00, Some(self.description_index))
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        (index == self.description_index).then(|| self.description.as_str())
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> usb_device::Result<()> {
+        let mut webusb = [0u8; 21];
+        webusb[1..17].copy_from_slice(&WEBUSB_PLATFORM_UUID);
+        webusb[17..19].copy_from_slice(&0x0100u16.to_le_bytes());
+        webusb[19] = WEBUSB_VENDOR_CODE;
+        webusb[20] = LANDING_PAGE_INDEX;
+        writer.capability(CAPABILITY_PLATFORM, &webusb)?;
+
+        let mut ms_os = [0u8; 25];
+        ms_os[1..17].copy_from_slice(&MS_OS_20_PLATFORM_UUID);
+        ms_os[17..21].copy_from_slice(&WINDOWS_VERSION.to_le_bytes());
+        ms_os[21..23].copy_from_slice(&(MS_OS_DESCRIPTOR_SET_LEN as u16).to_le_bytes());
+        ms_os[23] = MS_OS_VENDOR_CODE;
+        writer.capability(CAPABILITY_PLATFORM, &ms_os)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+
+        if self.is_interface_request(request, RAW_HID_RESPONSE) {
+            let response = critical_section::with(|cs| self.raw_response.take(cs));
+            xfer.accept_with(response.as_ref().map_or(&[], |response| &response[..])).ok();
+        } else if Self::is_vendor_request(request, MS_OS_VENDOR_CODE)
+            && request.index == MS_OS_20_DESCRIPTOR_INDEX
+        {
+            xfer.accept_with(&self.descriptor_set).ok();
+        } else if Self::is_vendor_request(request, WEBUSB_VENDOR_CODE)
+            && request.index == WEBUSB_GET_URL
+        {
+            if request.value != LANDING_PAGE_INDEX as u16 {
+                xfer.reject().ok();
+                return;
+            }
+
+            let mut descriptor = [0u8; 3 + LANDING_PAGE_URL.len()];
+            descriptor[0] = descriptor.len() as u8;
+            descriptor[1] = WEBUSB_URL_DESCRIPTOR;
+            descriptor[2] = WEBUSB_URL_SCHEME_HTTPS;
+            descriptor[3..].copy_from_slice(LANDING_PAGE_URL.as_bytes());
+            xfer.accept_with(&descriptor).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+
+        if self.is_interface_request(request, RAW_HID_REQUEST) {
+            let Ok(report) = <[u8; RAW_REPORT_LEN]>::try_from(xfer.data()) else {
+                xfer.reject().ok();
+                return;
+            };
+            // A response nobody read is dropped, so a host which went away can't block others.
+            let accepted = critical_section::with(|cs| {
+                let mut raw_request = self.raw_request.borrow_ref_mut(cs);
+                if raw_request.is_some() {
+                    return false;
+                }
+                *raw_request = Some(report);
+                self.raw_response.take(cs);
+                true
+            });
+            if accepted {
+                xfer.accept().ok();
+            } else {
+                xfer.reject().ok();
+            }
+        } else if request.request_type == RequestType::Vendor
+            && self.config_locked.load(Ordering::Relaxed)
+        {
+            xfer.reject().ok();
+        }
+    }
+}
+
+/// The Microsoft OS 2.0 descriptor set, asking Windows to use WinUSB for `interface`.
+pub fn ms_os_descriptor_set(interface: u8) -> [u8; MS_OS_DESCRIPTOR_SET_LEN] {
+    let mut set = [0u8; MS_OS_DESCRIPTOR_SET_LEN];
+    let mut writer = SetWriter { buf: &mut set, position: 0 };
+
+    writer.u16(10);
+    writer.u16(MS_OS_20_SET_HEADER);
+    writer.bytes(&WINDOWS_VERSION.to_le_bytes());
+    writer.u16(MS_OS_DESCRIPTOR_SET_LEN as u16);
+
+    writer.u16(8);
+    writer.u16(MS_OS_20_SUBSET_HEADER_CONFIGURATION);
+    writer.bytes(&[0, 0]);
+    writer.u16(CONFIGURATION_SUBSET_LEN as u16);
+
+    writer.u16(8);
+    writer.u16(MS_OS_20_SUBSET_HEADER_FUNCTION);
+    writer.bytes(&[interface, 0]);
+    writer.u16(FUNCTION_SUBSET_LEN as u16);
+
+    writer.u16(20);
+    writer.u16(MS_OS_20_FEATURE_COMPATIBLE_ID);
+    writer.bytes(b"WINUSB\0\0");
+    writer.bytes(&[0; 8]);
+
+    writer.u16(REG_PROPERTY_LEN as u16);
+    writer.u16(MS_OS_20_FEATURE_REG_PROPERTY);
+    writer.u16(REG_MULTI_SZ);
+    writer.u16(PROPERTY_NAME_LEN as u16);
+    writer.utf16(PROPERTY_NAME);
+    writer.u16(0);
+    writer.u16(PROPERTY_DATA_LEN as u16);
+    writer.utf16(DEVICE_INTERFACE_GUID);
+    writer.u16(0);
+    writer.u16(0);
+
+    set
+}
+
+struct SetWriter<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl SetWriter<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Write an ASCII string as UTF-16, without a terminating null.
+    fn utf16(&mut self, string: &str) {
+        for byte in string.bytes() {
+            self.u16(byte as u16);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! The frames the keyboard sends its USB dongle over the radio.
+//!
+//! # Format
+//! Every frame is `FRAME_SIZE` bytes: the frame kind, a sequence number, then the report,
+//! padded with zeroes. The sequence number goes up by one for every new frame, so the
+//! dongle can drop a frame it has already seen when the keyboard sends it again because the
+//! acknowledgement got lost.
+//!
+//! | Kind | Report                                           |
+//! |------|--------------------------------------------------|
+//! | 1    | Keyboard: modifier, reserved, six keycodes       |
+//! | 2    | Mouse: buttons, X, Y, wheel, pan                 |
+
+#[cfg(feature = "wireless")]
+use core::convert::Infallible;
+
+#[cfg(feature = "wireless")]
+use cortex_m::delay::Delay;
+#[cfg(feature = "wireless")]
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+use usbd_hid::descriptor::{KeyboardReport, MouseReport};
+
+#[cfg(feature = "wireless")]
+use crate::nrf24::{Nrf24, RadioError};
+
+pub const FRAME_SIZE: usize = 10;
+
+const KIND_KEYBOARD: u8 = 1;
+const KIND_MOUSE: u8 = 2;
+
+#[derive(Copy, Clone)]
+pub enum Frame {
+    Keyboard(KeyboardReport),
+    Mouse(MouseReport),
+}
+
+impl Frame {
+    pub fn to_bytes(&self, sequence: u8) -> [u8; FRAME_SIZE] {
+        let mut bytes = [0u8; FRAME_SIZE];
+        bytes[1] = sequence;
+
+        match self {
+            Frame::Keyboard(report) => {
+                bytes[0] = KIND_KEYBOARD;
+                bytes[2] = report.modifier;
+                bytes[3] = report.reserved;
+                bytes[4..10].copy_from_slice(&report.keycodes);
+            },
+            Frame::Mouse(report) => {
+                bytes[0] = KIND_MOUSE;
+                bytes[2] = report.buttons;
+                bytes[3] = report.x as u8;
+                bytes[4] = report.y as u8;
+                bytes[5] = report.wheel as u8;
+                bytes[6] = report.pan as u8;
+            },
+        }
+
+        bytes
+    }
+
+    /// The sequence number and frame, or `None` if it's not a kind of frame this version of
+    /// the firmware knows about.
+    pub fn from_bytes(bytes: &[u8; FRAME_SIZE]) -> Option<(u8, Self)> {
+        let frame = match bytes[0] {
+            KIND_KEYBOARD => {
+                let mut keycodes = [0u8; 6];
+                keycodes.copy_from_slice(&bytes[4..10]);
+                Frame::Keyboard(KeyboardReport {
+                    modifier: bytes[2],
+                    reserved: bytes[3],
+                    leds: 0,
+                    keycodes,
+                })
+            },
+            KIND_MOUSE => Frame::Mouse(MouseReport {
+                buttons: bytes[2],
+                x: bytes[3] as i8,
+                y: bytes[4] as i8,
+                wheel: bytes[5] as i8,
+                pan: bytes[6] as i8,
+            }),
+            _ => return None,
+        };
+
+        Some((bytes[1], frame))
+    }
+}
+
+/// How many scans to wait before trying the radio again after a frame wasn't acknowledged,
+/// so a missing dongle doesn't slow down scanning with retries.
+#[cfg(feature = "wireless")]
+const RETRY_INTERVAL_SCANS: u32 = 100;
+
+/// Sends reports to the dongle: keyboard reports whenever they change, and mouse reports as
+/// they come.
+#[cfg(feature = "wireless")]
+pub struct RadioLink<SPI, CSN, CE> {
+    radio: Nrf24<SPI, CSN, CE>,
+    sequence: u8,
+
+    /// The last keyboard report the dongle acknowledged.
+    sent_keyboard_report: Option<(u8, [u8; 6])>,
+
+    /// The number of scans left before trying again after a failed send.
+    retry_in: u32,
+}
+
+#[cfg(feature = "wireless")]
+impl<SPI, CSN, CE> RadioLink<SPI, CSN, CE>
+where
+    SPI: Transfer<u8, Error = Infallible> + Write<u8, Error = Infallible>,
+    CSN: OutputPin<Error = Infallible>,
+    CE: OutputPin<Error = Infallible>,
+{
+    pub fn new(radio: Nrf24<SPI, CSN, CE>) -> Self {
+        Self { radio, sequence: 0, sent_keyboard_report: None, retry_in: 0 }
+    }
+
+    /// Send the keyboard report if the dongle doesn't have it yet. Call this once per scan.
+    pub fn send_keyboard(&mut self, report: &KeyboardReport, delay: &mut Delay) {
+        if self.retry_in > 0 {
+            self.retry_in -= 1;
+            return;
+        }
+
+        let contents = (report.modifier, report.keycodes);
+        if self.sent_keyboard_report != Some(contents)
+            && self.send(Frame::Keyboard(*report), delay).is_ok()
+        {
+            self.sent_keyboard_report = Some(contents);
+        }
+    }
+
+    /// Send a mouse report. It's dropped if the dongle doesn't acknowledge it.
+    pub fn send_mouse(&mut self, report: &MouseReport, delay: &mut Delay) {
+        if self.retry_in == 0 {
+            self.send(Frame::Mouse(*report), delay).ok();
+        }
+    }
+
+    fn send(&mut self, frame: Frame, delay: &mut Delay) -> Result<(), RadioError> {
+        let result = self.radio.send(&frame.to_bytes(self.sequence), delay);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        if result.is_err() {
+            self.retry_in = RETRY_INTERVAL_SCANS;
+        }
+
+        result
+    }
+}
@@ -0,0 +1,260 @@
+//! Hardware-in-the-loop tests of the whole scan → debounce → report chain, run on a bare
+//! board with jumper wires standing in for switches.
+//!
+//! The bench fixture connects two column outputs straight to row inputs:
+//!
+//! - GPIO16 (column 1) to GPIO28 (row 3), the position of `A`.
+//! - GPIO29 (column 0) to GPIO15 (row 4), the position of `LeftShift`.
+//!
+//! The firmware "presses" one of these keys by driving its column during the scan, and
+//! "releases" it by leaving the column low. Everything after that is the real firmware
+//! path: the GPIO reads, the layout mask, debouncing, and the keymap lookups.
+//!
+//! With the jumpers in place and a debug probe attached, run them with:
+//!
+//! ```
+//! cargo test --features loopback-test --test loopback \
+//!     --config 'target.thumbv6m-none-eabi.runner = "probe-rs run --chip RP2040"'
+//! ```
+
+#![no_std]
+#![no_main]
+
+use core::{cell::Cell, convert::Infallible};
+
+use cortex_m::delay::Delay;
+use defmt::{assert, assert_eq, info};
+use defmt_rtt as _;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use key_ripper::{
+    debounce::Debounce,
+    key_codes::KeyCode,
+    key_mapping,
+    key_scan::{KeyScan, MatrixWiring},
+    keyboard::Keyboard,
+    profile::Profile,
+    NUM_COLS, NUM_ROWS,
+};
+use panic_probe as _;
+use rp2040_hal::{pac, Clock, Watchdog};
+
+const EXTERNAL_CRYSTAL_FREQUENCY_HZ: u32 = 12_000_000;
+
+/// The number of scans a released key is still reported as held for.
+const DEBOUNCE_TICKS: u8 = 5;
+
+/// The matrix positions of the jumpered keys, as `(column, row)`.
+const A: (usize, usize) = (1, 3);
+const LEFT_SHIFT: (usize, usize) = (0, 4);
+
+/// A column output jumpered to a row input. The column is only driven high during a scan
+/// while the key is "pressed", so the row reads the key as pressed through the jumper.
+struct JumperedColumn<'a, P> {
+    pin: P,
+    pressed: &'a Cell<bool>,
+}
+
+impl<P: OutputPin<Error = Infallible>> OutputPin for JumperedColumn<'_, P> {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        if self.pressed.get() {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        }
+    }
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.pin.set_low()
+    }
+}
+
+/// Everything needed to run a scan, along with switches for the jumpered keys.
+struct Fixture<'a> {
+    rows: &'a [&'a dyn InputPin<Error = Infallible>],
+    cols: &'a mut [&'a mut dyn OutputPin<Error = Infallible>],
+    delay: &'a mut Delay,
+    a: &'a Cell<bool>,
+    left_shift: &'a Cell<bool>,
+}
+
+impl Fixture<'_> {
+    fn scan(&mut self, debounce: &mut Debounce<NUM_ROWS, NUM_COLS>) -> KeyScan<NUM_ROWS, NUM_COLS> {
+        // The jumpers always run from a column to a row, whatever `board.toml` says.
+        let wiring = MatrixWiring::default();
+        KeyScan::scan(self.rows, self.cols, wiring, self.delay, &key_mapping::MATRIX_MASK, debounce)
+    }
+
+    fn release_all(&self) {
+        self.a.set(false);
+        self.left_shift.set(false);
+    }
+}
+
+type Test = fn(&mut Fixture);
+
+const TESTS: &[(&str, Test)] = &[
+    ("nothing_pressed_reads_empty", nothing_pressed_reads_empty),
+    ("press_reaches_report", press_reaches_report),
+    ("release_is_debounced", release_is_debounced),
+    ("modifier_sets_report_bit", modifier_sets_report_bit),
+    ("modifier_release_is_not_debounced", modifier_release_is_not_debounced),
+];
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let mut pac = pac::Peripherals::take().unwrap();
+    let core = pac::CorePeripherals::take().unwrap();
+
+    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+
+    let clocks = rp2040_hal::clocks::init_clocks_and_plls(
+        EXTERNAL_CRYSTAL_FREQUENCY_HZ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+
+    let sio = rp2040_hal::Sio::new(pac.SIO);
+
+    let pins =
+        rp2040_hal::gpio::Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+
+    let a = Cell::new(false);
+    let left_shift = Cell::new(false);
+
+    // The same pins as the firmware, with the jumpered columns gated.
+    let rows: &[&dyn InputPin<Error = Infallible>] = &[
+        &pins.gpio26.into_pull_down_input(),
+        &pins.gpio25.into_pull_down_input(),
+        &pins.gpio27.into_pull_down_input(),
+        &pins.gpio28.into_pull_down_input(),
+        &pins.gpio15.into_pull_down_input(),
+        &pins.gpio24.into_pull_down_input(),
+    ];
+
+    let cols: &mut [&mut dyn OutputPin<Error = Infallible>] = &mut [
+        &mut JumperedColumn { pin: pins.gpio29.into_push_pull_output(), pressed: &left_shift },
+        &mut JumperedColumn { pin: pins.gpio16.into_push_pull_output(), pressed: &a },
+        &mut pins.gpio17.into_push_pull_output(),
+        &mut pins.gpio18.into_push_pull_output(),
+        &mut pins.gpio9.into_push_pull_output(),
+        &mut pins.gpio10.into_push_pull_output(),
+        &mut pins.gpio19.into_push_pull_output(),
+        &mut pins.gpio11.into_push_pull_output(),
+        &mut pins.gpio12.into_push_pull_output(),
+        &mut pins.gpio13.into_push_pull_output(),
+        &mut pins.gpio14.into_push_pull_output(),
+        &mut pins.gpio20.into_push_pull_output(),
+        &mut pins.gpio22.into_push_pull_output(),
+        &mut pins.gpio23.into_push_pull_output(),
+    ];
+
+    let mut delay = Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+
+    let mut fixture = Fixture { rows, cols, delay: &mut delay, a: &a, left_shift: &left_shift };
+
+    for (name, test) in TESTS {
+        info!("running {}", name);
+        fixture.release_all();
+        test(&mut fixture);
+        info!("{} ... ok", name);
+    }
+
+    info!("all {} loopback tests passed", TESTS.len());
+
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
+
+fn debounce() -> Debounce<NUM_ROWS, NUM_COLS> {
+    let mut modifier_mask = [[false; NUM_ROWS]; NUM_COLS];
+    for (col, mapping_col) in modifier_mask.iter_mut().zip(key_mapping::NORMAL_LAYER_MAPPING) {
+        for (key, mapping_key) in col.iter_mut().zip(mapping_col) {
+            *key = mapping_key.is_modifier();
+        }
+    }
+
+    Debounce::new(DEBOUNCE_TICKS, modifier_mask)
+}
+
+fn nothing_pressed_reads_empty(fixture: &mut Fixture) {
+    let mut debounce = debounce();
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    let scan = fixture.scan(&mut debounce);
+    assert!(scan.iter().flatten().all(|pressed| !pressed), "a key reads as pressed");
+
+    let report = keyboard.report(&scan);
+    assert_eq!(report.modifier, 0);
+    assert_eq!(report.keycodes, [0; 6]);
+}
+
+fn press_reaches_report(fixture: &mut Fixture) {
+    let mut debounce = debounce();
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    fixture.a.set(true);
+    let scan = fixture.scan(&mut debounce);
+
+    // Only the jumpered position reads as pressed, nothing leaks into neighbouring keys.
+    for (col, rows) in scan.iter().enumerate() {
+        for (row, pressed) in rows.iter().enumerate() {
+            assert_eq!(*pressed, (col, row) == A, "unexpected state at ({}, {})", col, row);
+        }
+    }
+
+    let report = keyboard.report(&scan);
+    assert_eq!(report.keycodes, [KeyCode::A as u8, 0, 0, 0, 0, 0]);
+}
+
+fn release_is_debounced(fixture: &mut Fixture) {
+    let mut debounce = debounce();
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    fixture.a.set(true);
+    fixture.scan(&mut debounce);
+    fixture.a.set(false);
+
+    for _ in 1..DEBOUNCE_TICKS {
+        let report = keyboard.report(&fixture.scan(&mut debounce));
+        assert_eq!(report.keycodes[0], KeyCode::A as u8, "released before the debounce expired");
+    }
+
+    let report = keyboard.report(&fixture.scan(&mut debounce));
+    assert_eq!(report.keycodes, [0; 6]);
+}
+
+fn modifier_sets_report_bit(fixture: &mut Fixture) {
+    let mut debounce = debounce();
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    fixture.left_shift.set(true);
+    fixture.a.set(true);
+
+    let report = keyboard.report(&fixture.scan(&mut debounce));
+    assert_eq!(report.modifier, KeyCode::LeftShift.modifier_bitmask().unwrap());
+    assert_eq!(report.keycodes, [KeyCode::A as u8, 0, 0, 0, 0, 0]);
+}
+
+fn modifier_release_is_not_debounced(fixture: &mut Fixture) {
+    let mut debounce = debounce();
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    fixture.left_shift.set(true);
+    fixture.scan(&mut debounce);
+    fixture.left_shift.set(false);
+
+    let scan = fixture.scan(&mut debounce);
+    assert!(!scan[LEFT_SHIFT.0][LEFT_SHIFT.1]);
+
+    let report = keyboard.report(&scan);
+    assert_eq!(report.modifier, 0);
+}
@@ -0,0 +1,2151 @@
+//! Tests which run on the board itself, exercising the parts of the firmware which are
+//! hard to trust off-target: debounce timing, report building, and flash round-trips.
+//!
+//! Run them with a debug probe attached:
+//!
+//! ```
+//! cargo test --config 'target.thumbv6m-none-eabi.runner = "probe-rs run --chip RP2040"'
+//! ```
+//!
+//! Each test is a plain function which panics on failure. The panic is printed over RTT and
+//! stops the run, and a breakpoint at the end signals to the probe that all tests passed.
+
+#![no_std]
+#![no_main]
+
+use core::convert::Infallible;
+
+use defmt::{assert, assert_eq, info};
+use defmt_rtt as _;
+use embedded_hal::{
+    blocking::i2c::WriteRead,
+    digital::v2::{InputPin, OutputPin},
+};
+use key_ripper::{
+    auto_shift::{AutoShift, AUTO_SHIFT_TICKS},
+    ble::{BleStatus, FrameParser, Message, MAX_FRAME_SIZE},
+    calibration::CalibrationTable,
+    combos::{Combo, Combos, COMBO_WINDOW_TICKS},
+    conditional_keys::ConditionalKeys,
+    config_block::{crc32, ConfigBlock, Crc32, MAX_BLOCK_SIZE},
+    crash::{Crash, PanicAction, PanicLog, PANIC_LOG_LEN},
+    debounce::{BounceStats, Debounce, Debouncer, DeferredDebounce, Integrator, CHATTER_PRESSES},
+    direct_pins::{DirectPin, DirectPins},
+    double_buffer::DoubleBuffer,
+    dual_core,
+    expansion::Module,
+    fault::{Fault, FaultBlinker, FaultLatch, BLINK_MS, PAUSE_MS},
+    flash::Partition,
+    hall_effect::{AnalogSettings, HallEffectMatrix},
+    host_leds::{HostLed, HostLeds, LockLeds},
+    idle::{IdleSettings, IdleState, IdleTimer},
+    key_codes::KeyCode,
+    key_events::KeyEvents,
+    key_mapping::{self, LedBinding},
+    key_scan::{DiodeDirection, KeyScan, MatrixWiring, Sense, SwitchScanner},
+    keyboard::Keyboard,
+    keymap::{Keymap, SLOTS_PER_SECTOR},
+    kvm::{HotkeyPlayer, Output, HOTKEY_HOLD_TICKS},
+    latency::{InFlightPress, LatencyStats, PressTimer},
+    layers::Layers,
+    locking_mods::{LockingMods, DOUBLE_TAP_TICKS},
+    macropad::{self, MacroPad},
+    macros::{
+        MacroBuffer, MacroPlayer, MacroRecorder, MACRO_BUFFER_SIZE, MACRO_COUNT, MACRO_TAP_TICKS,
+        RECORDED_MACRO_INDEX,
+    },
+    matrix_check::{MatrixCheck, MatrixStats, STUCK_KEY_MS},
+    mod_swaps::ModSwaps,
+    mouse_keys::{MouseKeys, MouseMotion, ACCELERATION_TICKS},
+    ms_to_ticks,
+    nkro::NkroReport,
+    one_shot::{OneShotMods, ONE_SHOT_TIMEOUT_TICKS},
+    pointer::{Pointer, ResolutionMultipliers, LEFT_BUTTON, MIDDLE_BUTTON},
+    profile::Profile,
+    raw_hid::{busy_response, Command, Packet, RawHid, Status, RAW_REPORT_LEN},
+    repeat_key::RepeatKey,
+    report_queue::ReportQueue,
+    rgb::{key_color, Animation, Color, RgbBacklight, RgbSettings, FRAME_INTERVAL_MS},
+    scan_trace::{ScanTrace, TraceEntry},
+    settings::Settings,
+    settle_calibration::{settle_delay_us, MIN_SETTLE_US},
+    socd::SocdMode,
+    split::{self, encode_frame, Side},
+    tap_dance::{TapDance, TapDanceKeys, TAP_DANCE_WINDOW_MS},
+    tap_hold::{TapHold, TapHoldKeys, TAPPING_TERM_TICKS},
+    typing_break::{BreakReminder, REST_MS, SNOOZE_MS},
+    unicode::{Sequence, UnicodeMode, UnicodePlayer},
+    usb_log::LogBuffer,
+    usb_stall::{StallDetector, STALL_TIMEOUT_MS},
+    usb_stress::{StressTest, STRESS_ROLLOVER, STRESS_ROUNDS},
+    via::{from_qmk_keycode, to_qmk_keycode, Via, VIA_PROTOCOL_VERSION},
+    webusb::{ms_os_descriptor_set, MS_OS_DESCRIPTOR_SET_LEN},
+    wireless::{Frame, FRAME_SIZE},
+    NUM_COLS, NUM_ROWS, SPLIT_LEFT_COLS,
+};
+use panic_probe as _;
+use rp2040_hal as _;
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+const RELEASED: Matrix = [[false; NUM_ROWS]; NUM_COLS];
+
+/// The positions of a few keys in the stock layout, as `(column, row)`.
+const ESCAPE: (usize, usize) = (0, 0);
+const LEFT_SHIFT: (usize, usize) = (0, 4);
+const FN: (usize, usize) = (0, 5);
+const A: (usize, usize) = (1, 3);
+const S: (usize, usize) = (2, 3);
+const D: (usize, usize) = (3, 3);
+const LEFT_CMD: (usize, usize) = (3, 5);
+const J: (usize, usize) = (7, 3);
+const L: (usize, usize) = (9, 3);
+const N: (usize, usize) = (7, 4);
+const ENTER: (usize, usize) = (12, 3);
+const SPACE: (usize, usize) = (6, 5);
+const F10: (usize, usize) = (11, 0);
+
+const TESTS: &[(&str, fn())] = &[
+    ("debounce_reports_presses_immediately", debounce_reports_presses_immediately),
+    ("debounce_suppresses_quick_repress", debounce_suppresses_quick_repress),
+    ("debounce_passes_through_masked_keys", debounce_passes_through_masked_keys),
+    ("bounce_stats_flag_chattering_keys", bounce_stats_flag_chattering_keys),
+    ("latency_times_presses_to_reports", latency_times_presses_to_reports),
+    ("matrix_check_suppresses_ghosts", matrix_check_suppresses_ghosts),
+    ("matrix_check_counts_stuck_keys", matrix_check_counts_stuck_keys),
+    ("integrator_rides_out_glitches", integrator_rides_out_glitches),
+    ("deferred_debounce_ignores_short_glitches", deferred_debounce_ignores_short_glitches),
+    ("settle_delay_leaves_margin", settle_delay_leaves_margin),
+    ("matrix_wiring_picks_driven_lines", matrix_wiring_picks_driven_lines),
+    ("direct_pins_read_each_switch", direct_pins_read_each_switch),
+    ("hall_effect_actuates_with_rapid_trigger", hall_effect_actuates_with_rapid_trigger),
+    ("double_buffer_reads_latest_value", double_buffer_reads_latest_value),
+    ("report_queue_keeps_reports_until_popped", report_queue_keeps_reports_until_popped),
+    ("key_events_outlast_blocked_poll", key_events_outlast_blocked_poll),
+    ("key_events_advance_chords_together", key_events_advance_chords_together),
+    ("key_events_drained_in_one_tick_keep_a_tap", key_events_drained_in_one_tick_keep_a_tap),
+    ("report_contains_pressed_keys", report_contains_pressed_keys),
+    ("report_sets_modifier_bits", report_sets_modifier_bits),
+    ("report_uses_fn_layer", report_uses_fn_layer),
+    ("fn_escape_requests_bootloader", fn_escape_requests_bootloader),
+    ("report_sends_system_keys", report_sends_system_keys),
+    (
+        "settings_mode_changes_settings_without_typing",
+        settings_mode_changes_settings_without_typing,
+    ),
+    ("report_limits_to_six_keys", report_limits_to_six_keys),
+    ("layers_resolve_through_active_layers", layers_resolve_through_active_layers),
+    ("default_layer_replaces_base_layer", default_layer_replaces_base_layer),
+    ("num_word_turns_on_from_fn_layer", num_word_turns_on_from_fn_layer),
+    ("num_word_types_digits", num_word_types_digits),
+    ("num_word_ends_on_other_keys", num_word_ends_on_other_keys),
+    ("one_shot_modifier_applies_to_next_key", one_shot_modifier_applies_to_next_key),
+    ("modifier_locks_on_double_tap", modifier_locks_on_double_tap),
+    ("grave_escape_follows_modifiers", grave_escape_follows_modifiers),
+    ("mod_swaps_change_modifiers", mod_swaps_change_modifiers),
+    ("repeat_key_sends_last_key", repeat_key_sends_last_key),
+    ("tap_hold_decides_tap_or_hold", tap_hold_decides_tap_or_hold),
+    ("tap_dance_counts_taps", tap_dance_counts_taps),
+    ("combo_replaces_keys", combo_replaces_keys),
+    ("auto_shift_shifts_held_keys", auto_shift_shifts_held_keys),
+    ("config_lock_toggles_on_press", config_lock_toggles_on_press),
+    ("game_mode_masks_gui", game_mode_masks_gui),
+    ("kvm_hotkey_types_each_key", kvm_hotkey_types_each_key),
+    ("usb_stress_test_types_rolling_chord", usb_stress_test_types_rolling_chord),
+    ("usb_log_buffer_keeps_newest_bytes", usb_log_buffer_keeps_newest_bytes),
+    ("macro_player_types_sequence", macro_player_types_sequence),
+    ("unicode_sequence_suits_input_mode", unicode_sequence_suits_input_mode),
+    ("macro_recorder_records_presses_and_releases", macro_recorder_records_presses_and_releases),
+    ("nkro_report_has_every_key", nkro_report_has_every_key),
+    ("report_ignores_gui_in_gaming_profile", report_ignores_gui_in_gaming_profile),
+    ("socd_cleans_opposing_keys", socd_cleans_opposing_keys),
+    ("led_bindings_apply_while_lit", led_bindings_apply_while_lit),
+    ("lock_leds_follow_host_leds", lock_leds_follow_host_leds),
+    ("rgb_backlight_reacts_to_presses", rgb_backlight_reacts_to_presses),
+    ("dual_core_messages_fit_in_a_word", dual_core_messages_fit_in_a_word),
+    ("expansion_module_identified_by_reading", expansion_module_identified_by_reading),
+    ("expansion_module_keys_are_reported", expansion_module_keys_are_reported),
+    ("macropad_keys_join_matrix", macropad_keys_join_matrix),
+    ("pointer_accumulates_motion", pointer_accumulates_motion),
+    ("pointer_scrolls_with_middle_button", pointer_scrolls_with_middle_button),
+    ("pointer_middle_click_without_motion", pointer_middle_click_without_motion),
+    ("pointer_scrolls_in_high_resolution", pointer_scrolls_in_high_resolution),
+    ("mouse_keys_accelerate", mouse_keys_accelerate),
+    ("pointer_adds_mouse_keys", pointer_adds_mouse_keys),
+    ("raw_hid_handles_keymap_requests", raw_hid_handles_keymap_requests),
+    ("key_codes_classify_keys", key_codes_classify_keys),
+    ("via_translates_qmk_keycodes", via_translates_qmk_keycodes),
+    ("via_edits_keymap", via_edits_keymap),
+    ("crc32_matches_reference", crc32_matches_reference),
+    ("scan_trace_keeps_latest_changes", scan_trace_keeps_latest_changes),
+    ("wireless_frame_round_trip", wireless_frame_round_trip),
+    ("ble_status_frame_parses", ble_status_frame_parses),
+    ("split_frame_carries_matrix", split_frame_carries_matrix),
+    ("split_halves_scan_their_own_columns", split_halves_scan_their_own_columns),
+    ("ms_os_descriptor_set_is_consistent", ms_os_descriptor_set_is_consistent),
+    ("usb_stall_detected_without_frames", usb_stall_detected_without_frames),
+    ("fault_blinks_most_serious_fault", fault_blinks_most_serious_fault),
+    ("typing_break_due_after_interval", typing_break_due_after_interval),
+    ("idle_timer_dims_and_plays_away_macro", idle_timer_dims_and_plays_away_macro),
+    ("settings_round_trip_through_flash", settings_round_trip_through_flash),
+    ("panic_log_round_trip_through_flash", panic_log_round_trip_through_flash),
+    ("keymap_round_trip_through_flash", keymap_round_trip_through_flash),
+    ("calibration_round_trip_through_flash", calibration_round_trip_through_flash),
+    ("calibration_ignores_bad_block", calibration_ignores_bad_block),
+];
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    for (name, test) in TESTS {
+        info!("running {}", name);
+        test();
+        info!("{} ... ok", name);
+    }
+
+    info!("all {} tests passed", TESTS.len());
+
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
+
+fn pressed(keys: &[(usize, usize)]) -> Matrix {
+    let mut matrix = RELEASED;
+    for (col, row) in keys {
+        matrix[*col][*row] = true;
+    }
+    matrix
+}
+
+fn debounce_reports_presses_immediately() {
+    let mut debounce = Debounce::new(5, RELEASED);
+    assert!(debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+}
+
+fn debounce_suppresses_quick_repress() {
+    let mut debounce = Debounce::new(5, RELEASED);
+    debounce.report_and_tick(&pressed(&[A]));
+
+    // Released for fewer ticks than the expiration, the key still reads as held.
+    for _ in 0..4 {
+        assert!(debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+    }
+
+    assert!(!debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+}
+
+fn debounce_passes_through_masked_keys() {
+    let mut debounce = Debounce::new(5, pressed(&[LEFT_SHIFT]));
+    debounce.report_and_tick(&pressed(&[LEFT_SHIFT]));
+    assert!(!debounce.report_and_tick(&RELEASED)[LEFT_SHIFT.0][LEFT_SHIFT.1]);
+}
+
+fn bounce_stats_flag_chattering_keys() {
+    // A press of `A` whose contacts part and close again `bounces` times before it's let go.
+    fn press(
+        debounce: &mut Debounce<NUM_ROWS, NUM_COLS>,
+        stats: &mut BounceStats<NUM_ROWS, NUM_COLS>,
+        bounces: usize,
+    ) {
+        for i in 0..2 * bounces + 7 {
+            let raw = if i <= 2 * bounces && i % 2 == 0 { pressed(&[A]) } else { RELEASED };
+            stats.record(&raw, &debounce.report_and_tick(&raw));
+        }
+    }
+    let mut debounce = Debounce::new(5, RELEASED);
+    let mut stats = BounceStats::default();
+
+    press(&mut debounce, &mut stats, 0);
+    press(&mut debounce, &mut stats, 1);
+    press(&mut debounce, &mut stats, 3);
+    assert_eq!(stats.histogram(A.0, A.1), [1, 1, 0, 1, 0]);
+    assert_eq!(stats.histogram(D.0, D.1), [0; 5]);
+    assert!(!stats.is_chattering(A.0, A.1));
+
+    for _ in 1..CHATTER_PRESSES {
+        press(&mut debounce, &mut stats, 5);
+    }
+    assert!(stats.is_chattering(A.0, A.1));
+}
+
+fn latency_times_presses_to_reports() {
+    let mut a = NkroReport::EMPTY;
+    a.press(KeyCode::A as u8);
+    let mut timer = PressTimer::default();
+
+    // Timed from the first scan of the press, through debouncing, to the report with it.
+    timer.scan(100, &pressed(&[A]));
+    assert_eq!(timer.report(&NkroReport::EMPTY), None);
+    timer.scan(1100, &pressed(&[A, S]));
+    assert_eq!(timer.report(&a), Some(100));
+    timer.scan(2100, &pressed(&[A, S]));
+    assert_eq!(timer.report(&a), None);
+
+    // A press which never reaches a report is forgotten once every key is released.
+    timer.scan(3100, &RELEASED);
+    timer.report(&NkroReport::EMPTY);
+    timer.scan(4100, &pressed(&[FN]));
+    timer.report(&NkroReport::EMPTY);
+    timer.scan(5100, &RELEASED);
+    timer.report(&NkroReport::EMPTY);
+    timer.scan(6100, &pressed(&[A]));
+    assert_eq!(timer.report(&a), Some(6100));
+
+    let press = InFlightPress { pressed_at_us: 6100, report_index: 3 };
+    assert!(!press.is_sent(3));
+    assert!(press.is_sent(4));
+    let press = InFlightPress { pressed_at_us: 6100, report_index: usize::MAX };
+    assert!(press.is_sent(0));
+
+    let mut stats = LatencyStats::default();
+    assert_eq!(stats.average_us(), 0);
+    for latency_us in [1500, 900, 2100] {
+        stats.record(latency_us);
+    }
+    assert_eq!(
+        (stats.samples, stats.min_us, stats.average_us(), stats.max_us),
+        (3, 900, 1500, 2100)
+    );
+    assert_eq!(stats.to_bytes()[4..8], 900u32.to_le_bytes());
+}
+
+fn matrix_check_suppresses_ghosts() {
+    const ONE: (usize, usize) = (1, 1);
+    const THREE: (usize, usize) = (3, 1);
+    let mut check = MatrixCheck::new(true);
+
+    let corners = pressed(&[A, D, ONE]);
+    assert!(check.check(0, &corners) == corners);
+
+    // The fourth corner can't be told apart from a ghost, however long it's held.
+    for now_ms in 1..3 {
+        assert!(check.check(now_ms, &pressed(&[A, D, ONE, THREE])) == corners);
+    }
+    assert_eq!(check.stats().ghosts_suppressed, 1);
+
+    // Once the rectangle is broken, it's a key like any other.
+    assert!(check.check(3, &pressed(&[A, ONE, THREE])) == pressed(&[A, ONE, THREE]));
+
+    // Without suppression, rectangles are left alone.
+    let mut check = MatrixCheck::new(false);
+    let rectangle = pressed(&[A, D, ONE, THREE]);
+    assert!(check.check(0, &rectangle) == rectangle);
+}
+
+fn matrix_check_counts_stuck_keys() {
+    let mut check = MatrixCheck::new(false);
+    check.check(0, &pressed(&[A]));
+    check.check(STUCK_KEY_MS - 1, &pressed(&[A, D]));
+    assert_eq!(check.stats(), MatrixStats::default());
+
+    // Each press is only counted once, however much longer it's held.
+    check.check(STUCK_KEY_MS, &pressed(&[A, D]));
+    check.check(STUCK_KEY_MS + 1, &pressed(&[A, D]));
+    assert_eq!(check.stats().stuck_keys, 1);
+    assert_eq!(check.stats().to_bytes(), [0, 0, 0, 0, 1, 0, 0, 0]);
+}
+
+fn integrator_rides_out_glitches() {
+    let mut debounce = Integrator::new(3, RELEASED);
+    assert!(!debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+    assert!(!debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+    assert!(debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+
+    // A single released reading doesn't release the key.
+    assert!(debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+    assert!(debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+
+    for _ in 0..2 {
+        assert!(debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+    }
+    assert!(!debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+}
+
+fn deferred_debounce_ignores_short_glitches() {
+    let mut debounce = DeferredDebounce::new(3, RELEASED);
+
+    // A press shorter than the debounce time is never reported.
+    for _ in 0..2 {
+        assert!(!debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+    }
+    assert!(!debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+
+    for _ in 0..2 {
+        assert!(!debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+    }
+    assert!(debounce.report_and_tick(&pressed(&[A]))[A.0][A.1]);
+
+    for _ in 0..2 {
+        assert!(debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+    }
+    assert!(!debounce.report_and_tick(&RELEASED)[A.0][A.1]);
+}
+
+fn settle_delay_leaves_margin() {
+    // Rows faster than the timer can measure still get the minimum.
+    assert_eq!(settle_delay_us(0), MIN_SETTLE_US);
+
+    for slowest_us in 1..50 {
+        assert!(settle_delay_us(slowest_us) > slowest_us * 3 / 2);
+    }
+}
+
+fn matrix_wiring_picks_driven_lines() {
+    let wiring = |diode_direction, sense| MatrixWiring { diode_direction, sense };
+
+    assert!(MatrixWiring::default().drives_columns());
+    assert!(wiring(DiodeDirection::RowToCol, Sense::PullUp).drives_columns());
+    assert!(!wiring(DiodeDirection::ColToRow, Sense::PullUp).drives_columns());
+    assert!(!wiring(DiodeDirection::RowToCol, Sense::PullDown).drives_columns());
+
+    assert_eq!(MatrixWiring::default().drive_lines(6, 14), 14);
+    assert_eq!(wiring(DiodeDirection::ColToRow, Sense::PullUp).drive_lines(6, 14), 6);
+}
+
+/// An input pin stuck at one level.
+struct FakeInput {
+    high: bool,
+}
+
+impl InputPin for FakeInput {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.high)
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.high)
+    }
+}
+
+fn direct_pins_read_each_switch() {
+    let (pulled_down, pulled_up) = (FakeInput { high: true }, FakeInput { high: false });
+    let (released, off_matrix) = (FakeInput { high: true }, FakeInput { high: true });
+    let pins = [
+        DirectPin { pin: &pulled_down, position: ESCAPE, sense: Sense::PullDown },
+        DirectPin { pin: &pulled_up, position: A, sense: Sense::PullUp },
+        DirectPin { pin: &released, position: S, sense: Sense::PullUp },
+        DirectPin { pin: &off_matrix, position: (NUM_COLS, 0), sense: Sense::PullDown },
+    ];
+    let mut direct_pins = DirectPins::new(&pins);
+
+    let mut debounce = Debounce::new(5, RELEASED);
+    let scan = direct_pins.scan(&[[true; NUM_ROWS]; NUM_COLS], &mut debounce);
+    assert_eq!(*scan, pressed(&[ESCAPE, A]));
+
+    // Masked positions read as released, like in a matrix.
+    let mut mask = [[true; NUM_ROWS]; NUM_COLS];
+    mask[A.0][A.1] = false;
+    assert_eq!(direct_pins.read_raw(&mask), pressed(&[ESCAPE]));
+}
+
+fn hall_effect_actuates_with_rapid_trigger() {
+    // Readings for a travel with the default calibration, resting at 2048 and rising by
+    // about 4 for each step of travel.
+    let calibration = CalibrationTable::default();
+    let mask = [[true; NUM_ROWS]; NUM_COLS];
+    let mut matrix = HallEffectMatrix::default();
+    let press_to = |matrix: &mut HallEffectMatrix, travel: u16| {
+        let mut readings = [[2048; NUM_ROWS]; NUM_COLS];
+        readings[A.0][A.1] = 2048 + travel * 4;
+        matrix.update(&readings, &mask, &calibration)[A.0][A.1]
+    };
+
+    // Pressed past the actuation point, and released a little above it.
+    assert!(!press_to(&mut matrix, 100));
+    assert!(press_to(&mut matrix, 140));
+    assert!(press_to(&mut matrix, 120));
+    assert!(!press_to(&mut matrix, 100));
+
+    // With rapid trigger, moving back up or down again is enough, wherever the key is.
+    matrix.set_settings(AnalogSettings::default().toggle_rapid_trigger());
+    assert!(press_to(&mut matrix, 200));
+    assert!(press_to(&mut matrix, 190));
+    assert!(!press_to(&mut matrix, 180));
+    assert!(!press_to(&mut matrix, 190));
+    assert!(press_to(&mut matrix, 200));
+
+    // The actuation point only moves so far either way.
+    let mut settings = AnalogSettings::default();
+    for _ in 0..16 {
+        settings = settings.deeper();
+    }
+    assert_eq!(settings.actuation, 224);
+    for _ in 0..16 {
+        settings = settings.shallower();
+    }
+    assert_eq!(settings.actuation, 32);
+}
+
+fn double_buffer_reads_latest_value() {
+    let buffer = DoubleBuffer::new(0u32);
+    unsafe {
+        assert_eq!(buffer.read(), 0);
+        for value in 1..=3 {
+            buffer.publish(value);
+            assert_eq!(buffer.read(), value);
+        }
+    }
+}
+
+fn report_queue_keeps_reports_until_popped() {
+    let queue: ReportQueue<u8, 2> = ReportQueue::new(0);
+    unsafe {
+        assert!(queue.front().is_none());
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+
+        // A report the endpoint wasn't ready for is still at the front.
+        assert_eq!(queue.front(), Some(1));
+        assert_eq!(queue.front(), Some(1));
+        queue.pop();
+        assert!(queue.push(3));
+        assert_eq!(queue.front(), Some(2));
+        queue.pop();
+        assert_eq!(queue.front(), Some(3));
+        queue.pop();
+        assert!(queue.front().is_none());
+    }
+}
+
+fn key_events_outlast_blocked_poll() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+    let mut events: KeyEvents<4> = KeyEvents::default();
+    let queue: ReportQueue<[u8; 6], 1> = ReportQueue::new([0; 6]);
+    let mut previous = [0u8; 6];
+    let mut waiting = false;
+    let mut sent = [[0u8; 6]; 6];
+    let mut sent_len = 0;
+
+    // `S` fills the queue, and `A` is pressed and released while the host isn't polling.
+    // Built straight from the scans, the reports would only ever have `A` released.
+    let scans = [
+        (pressed(&[S]), false),
+        (RELEASED, false),
+        (pressed(&[A]), false),
+        (RELEASED, false),
+        (RELEASED, true),
+        (RELEASED, true),
+        (RELEASED, true),
+        (RELEASED, true),
+    ];
+    for (ms, (scan, host_polls)) in scans.iter().enumerate() {
+        // As the main loop does it.
+        assert!(events.record(ms as u64, scan));
+        if !waiting {
+            events.advance();
+        }
+        let keys = keyboard.report(&KeyScan::from(*events.matrix())).keycodes;
+        waiting = keys != previous && !unsafe { queue.push(keys) };
+        if !waiting {
+            previous = keys;
+        }
+
+        if let (true, Some(report)) = (*host_polls, unsafe { queue.front() }) {
+            sent[sent_len] = report;
+            sent_len += 1;
+            unsafe { queue.pop() };
+        }
+    }
+
+    let s = [KeyCode::S as u8, 0, 0, 0, 0, 0];
+    let a = [KeyCode::A as u8, 0, 0, 0, 0, 0];
+    assert_eq!(sent[..sent_len], [s, [0; 6], a, [0; 6]]);
+}
+
+fn key_events_advance_chords_together() {
+    let mut events: KeyEvents<4> = KeyEvents::default();
+
+    // `A` and `S` are pressed in one scan, and `A` released and pressed again before any
+    // advance. The chord is taken in together, but `A`'s release waits for a report of its own.
+    assert!(events.record(0, &pressed(&[A, S])));
+    assert!(events.record(1, &pressed(&[S])));
+    assert!(events.record(2, &pressed(&[A, S])));
+
+    assert_eq!(events.advance().map(|event| event.position), Some(A));
+    assert_eq!(*events.matrix(), pressed(&[A, S]));
+    assert!(events.is_waiting());
+
+    assert_eq!(events.advance().map(|event| event.pressed), Some(false));
+    assert_eq!(*events.matrix(), pressed(&[S]));
+    assert_eq!(events.advance().map(|event| event.time_ms), Some(2));
+    assert_eq!(*events.matrix(), pressed(&[A, S]));
+    assert!(!events.is_waiting());
+    assert!(events.advance().is_none());
+}
+
+fn key_events_drained_in_one_tick_keep_a_tap() {
+    static ESCAPE_CTRL_TAB_ALT: [TapHold; 2] = [
+        TapHold { position: A, tap: KeyCode::Escape, hold: KeyCode::LeftCtrl },
+        TapHold { position: S, tap: KeyCode::Tab, hold: KeyCode::LeftAlt },
+    ];
+    let mut keys = TapHoldKeys::new(&ESCAPE_CTRL_TAB_ALT);
+    let mut events: KeyEvents<512> = KeyEvents::default();
+
+    // While the report queue is full, `A` is tapped with `S` tapped over and over inside it.
+    // `S` is another tap-hold key, so it doesn't decide `A`, but every one of its presses and
+    // releases needs a report of its own, more than the tapping term has ticks.
+    let taps = TAPPING_TERM_TICKS as u64;
+    assert!(events.record(0, &pressed(&[A])));
+    for tap in 0..taps {
+        assert!(events.record(2 * tap + 1, &pressed(&[A, S])));
+        assert!(events.record(2 * tap + 2, &pressed(&[A])));
+    }
+    assert!(events.record(2 * taps + 1, &RELEASED));
+
+    // The queue empties in a single scan, so only its first report moves time on.
+    let mut elapsed_ticks = 1;
+    let mut last_matrix = None;
+    while events.is_waiting() {
+        events.advance();
+        last_matrix = keys.update(events.matrix(), elapsed_ticks);
+        elapsed_ticks = 0;
+    }
+
+    assert!(last_matrix.expect("A's release decides it")[A.0][A.1]);
+    assert!(keys.keys().any(|(position, key)| position == A && key == Some(KeyCode::Escape)));
+}
+
+fn report_contains_pressed_keys() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[ESCAPE, A])));
+    assert_eq!(report.keycodes, [KeyCode::Escape as u8, KeyCode::A as u8, 0, 0, 0, 0]);
+
+    let report = keyboard.report(&KeyScan::from(RELEASED));
+    assert_eq!(report.keycodes, [0; 6]);
+}
+
+fn report_sets_modifier_bits() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[LEFT_SHIFT, LEFT_CMD])));
+    assert_eq!(report.modifier, (1 << 1) | (1 << 3));
+    assert_eq!(report.keycodes, [0; 6]);
+}
+
+fn report_uses_fn_layer() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[FN, F10])));
+    assert_eq!(report.keycodes, [0; 6]);
+    assert_eq!(keyboard.consumer_usage(), KeyCode::VolumeMute.consumer_usage().unwrap());
+
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert_eq!(keyboard.consumer_usage(), 0);
+}
+
+fn fn_escape_requests_bootloader() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[FN, ESCAPE])));
+    assert_eq!(report.keycodes, [0; 6]);
+    assert!(keyboard.take_bootloader_request());
+    assert!(!keyboard.take_bootloader_request());
+}
+
+fn report_sends_system_keys() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[FN, S])));
+    assert_eq!(report.keycodes, [0; 6]);
+    assert_eq!(keyboard.system_usage(), 0x82);
+
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert_eq!(keyboard.system_usage(), 0);
+}
+
+fn settings_mode_changes_settings_without_typing() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    keyboard.report(&KeyScan::from(pressed(&[FN, SPACE])));
+    assert!(keyboard.in_settings_mode());
+    keyboard.report(&KeyScan::from(RELEASED));
+
+    // Changes apply straight away, without the keys reaching the host.
+    let report = keyboard.report(&KeyScan::from(pressed(&[D])));
+    assert_eq!(report.keycodes, [0; 6]);
+    assert_eq!(keyboard.profile(), Profile::Gaming);
+    keyboard.report(&KeyScan::from(RELEASED));
+
+    // Escape puts them back, and the mode ends once it's released.
+    keyboard.report(&KeyScan::from(pressed(&[ESCAPE])));
+    assert_eq!(keyboard.profile(), Profile::Typing);
+    assert!(keyboard.in_settings_mode());
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert!(!keyboard.in_settings_mode());
+
+    keyboard.report(&KeyScan::from(pressed(&[FN, SPACE])));
+    keyboard.report(&KeyScan::from(RELEASED));
+    keyboard.report(&KeyScan::from(pressed(&[S])));
+    keyboard.report(&KeyScan::from(RELEASED));
+    let report = keyboard.report(&KeyScan::from(pressed(&[ENTER])));
+    assert_eq!(report.keycodes, [0; 6]);
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert!(!keyboard.in_settings_mode());
+    assert_eq!(keyboard.socd_mode(), SocdMode::Off.next());
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[A])));
+    assert_eq!(report.keycodes[0], KeyCode::A as u8);
+}
+
+fn layers_resolve_through_active_layers() {
+    let mut base = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+    base[A.0][A.1] = KeyCode::A;
+    base[D.0][D.1] = KeyCode::D;
+    base[FN.0][FN.1] = KeyCode::Layer1;
+    base[ESCAPE.0][ESCAPE.1] = KeyCode::ToggleLayer1;
+    base[LEFT_SHIFT.0][LEFT_SHIFT.1] = KeyCode::OneShotLayer1;
+    let mut upper = [[KeyCode::Transparent; NUM_ROWS]; NUM_COLS];
+    upper[A.0][A.1] = KeyCode::B;
+    let mut layers = Layers::new([base, upper]);
+
+    // Held, with the keys it doesn't define falling through to the base layer.
+    layers.update(&pressed(&[FN]));
+    assert_eq!(layers.active_layer(), 1);
+    assert_eq!(layers.key(A.0, A.1), KeyCode::B);
+    assert_eq!(layers.key(D.0, D.1), KeyCode::D);
+    layers.update(&RELEASED);
+    assert_eq!(layers.key(A.0, A.1), KeyCode::A);
+
+    // A key keeps the layer it was pressed on until it's released, even after the layer key.
+    layers.update(&pressed(&[FN]));
+    layers.update(&pressed(&[FN, A]));
+    layers.update(&pressed(&[A]));
+    assert_eq!(layers.active_layer(), 0);
+    assert_eq!(layers.key(A.0, A.1), KeyCode::B);
+    layers.update(&RELEASED);
+    assert_eq!(layers.key(A.0, A.1), KeyCode::A);
+
+    // Toggled on by one press, and off by the next.
+    layers.update(&pressed(&[ESCAPE]));
+    layers.update(&RELEASED);
+    assert_eq!(layers.key(A.0, A.1), KeyCode::B);
+    layers.update(&pressed(&[ESCAPE]));
+    layers.update(&RELEASED);
+    assert_eq!(layers.key(A.0, A.1), KeyCode::A);
+
+    // One-shot, for the next key until it's released.
+    layers.update(&pressed(&[LEFT_SHIFT]));
+    layers.update(&RELEASED);
+    layers.update(&pressed(&[A]));
+    assert_eq!(layers.key(A.0, A.1), KeyCode::B);
+    layers.update(&RELEASED);
+    assert_eq!(layers.active_layer(), 0);
+
+    // Tapped again it's cancelled, and left alone it times out.
+    for _ in 0..2 {
+        layers.update(&pressed(&[LEFT_SHIFT]));
+        layers.update(&RELEASED);
+    }
+    assert_eq!(layers.active_layer(), 0);
+    layers.update(&pressed(&[LEFT_SHIFT]));
+    for _ in 0..ONE_SHOT_TIMEOUT_TICKS {
+        layers.tick(1);
+        layers.update(&RELEASED);
+    }
+    assert_eq!(layers.active_layer(), 0);
+}
+
+fn default_layer_replaces_base_layer() {
+    let mut base = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+    base[A.0][A.1] = KeyCode::A;
+    base[D.0][D.1] = KeyCode::D;
+    base[FN.0][FN.1] = KeyCode::Layer1;
+    base[ESCAPE.0][ESCAPE.1] = KeyCode::DefaultLayer2;
+    let mut upper = [[KeyCode::Transparent; NUM_ROWS]; NUM_COLS];
+    upper[A.0][A.1] = KeyCode::B;
+    upper[ESCAPE.0][ESCAPE.1] = KeyCode::DefaultLayer0;
+    let mut colemak = base;
+    colemak[D.0][D.1] = KeyCode::S;
+    let mut layers = Layers::new([base, upper, colemak]);
+
+    layers.update(&pressed(&[ESCAPE]));
+    layers.update(&RELEASED);
+    assert_eq!(layers.default_layer(), 2);
+    assert_eq!(layers.active_layer(), 0);
+    assert_eq!(layers.key(D.0, D.1), KeyCode::S);
+    assert_eq!(layers.base_key(D.0, D.1), KeyCode::S);
+
+    // The layers above still apply on top of it, falling through to it rather than layer 0.
+    layers.update(&pressed(&[FN]));
+    assert_eq!(layers.key(A.0, A.1), KeyCode::B);
+    assert_eq!(layers.key(D.0, D.1), KeyCode::S);
+    layers.update(&pressed(&[FN, ESCAPE]));
+    layers.update(&RELEASED);
+    assert_eq!(layers.default_layer(), 0);
+    assert_eq!(layers.key(D.0, D.1), KeyCode::D);
+
+    // A saved default layer which no longer exists is ignored.
+    layers.set_default_layer(3);
+    assert_eq!(layers.default_layer(), 0);
+}
+
+/// A keyboard with Num Word turned on with `Fn + N`, and every key released.
+fn num_word_keyboard() -> Keyboard {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+    keyboard.report(&KeyScan::from(pressed(&[FN])));
+    let report = keyboard.report(&KeyScan::from(pressed(&[FN, N])));
+    assert_eq!(report.keycodes, [0; 6]);
+    keyboard.report(&KeyScan::from(RELEASED));
+    keyboard
+}
+
+fn num_word_turns_on_from_fn_layer() {
+    let mut keyboard = num_word_keyboard();
+    assert!(keyboard.num_word_active());
+
+    // Tapping it again turns it back off.
+    keyboard.report(&KeyScan::from(pressed(&[FN])));
+    keyboard.report(&KeyScan::from(pressed(&[FN, N])));
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert!(!keyboard.num_word_active());
+}
+
+fn num_word_types_digits() {
+    let mut keyboard = num_word_keyboard();
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[J])));
+    assert_eq!(report.keycodes, [KeyCode::Num1 as u8, 0, 0, 0, 0, 0]);
+    keyboard.report(&KeyScan::from(RELEASED));
+    let report = keyboard.report(&KeyScan::from(pressed(&[L])));
+    assert_eq!(report.keycodes, [KeyCode::Num3 as u8, 0, 0, 0, 0, 0]);
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert!(keyboard.num_word_active());
+}
+
+fn num_word_ends_on_other_keys() {
+    let mut keyboard = num_word_keyboard();
+    keyboard.report(&KeyScan::from(pressed(&[J])));
+    keyboard.report(&KeyScan::from(RELEASED));
+
+    // The key ending it is typed from the base layer.
+    let report = keyboard.report(&KeyScan::from(pressed(&[SPACE])));
+    assert_eq!(report.keycodes, [KeyCode::Space as u8, 0, 0, 0, 0, 0]);
+    assert!(!keyboard.num_word_active());
+    keyboard.report(&KeyScan::from(RELEASED));
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[J])));
+    assert_eq!(report.keycodes, [KeyCode::J as u8, 0, 0, 0, 0, 0]);
+}
+
+fn one_shot_modifier_applies_to_next_key() {
+    let mut mapping = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+    mapping[A.0][A.1] = KeyCode::A;
+    mapping[S.0][S.1] = KeyCode::S;
+    mapping[LEFT_SHIFT.0][LEFT_SHIFT.1] = KeyCode::OneShotShift;
+    let shift = KeyCode::LeftShift.modifier_bitmask().unwrap();
+    let mut mods = OneShotMods::default();
+
+    // Tapped, then held with the next key until it's released, but not with the one after.
+    assert_eq!(mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1), shift);
+    assert_eq!(mods.update(&RELEASED, &mapping, 1), 0);
+    assert_eq!(mods.update(&pressed(&[A]), &mapping, 1), shift);
+    assert_eq!(mods.update(&pressed(&[A, S]), &mapping, 1), shift);
+    assert_eq!(mods.update(&pressed(&[S]), &mapping, 1), 0);
+    mods.update(&RELEASED, &mapping, 1);
+
+    // Tapped twice, it's cancelled.
+    for _ in 0..2 {
+        mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+        mods.update(&RELEASED, &mapping, 1);
+    }
+    assert_eq!(mods.armed(), 0);
+
+    // Held while another key is pressed, it's a plain modifier.
+    mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+    assert_eq!(mods.update(&pressed(&[LEFT_SHIFT, A]), &mapping, 1), shift);
+    mods.update(&RELEASED, &mapping, 1);
+    assert_eq!(mods.armed(), 0);
+
+    // Left alone after a tap, it times out.
+    mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+    mods.update(&RELEASED, &mapping, 1);
+    assert_eq!(mods.armed(), shift);
+    for _ in 0..ONE_SHOT_TIMEOUT_TICKS {
+        mods.update(&RELEASED, &mapping, 1);
+    }
+    assert_eq!(mods.armed(), 0);
+}
+
+fn modifier_locks_on_double_tap() {
+    let mut mapping = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+    mapping[A.0][A.1] = KeyCode::A;
+    mapping[LEFT_SHIFT.0][LEFT_SHIFT.1] = KeyCode::LeftShift;
+    let shift = KeyCode::LeftShift.modifier_bitmask().unwrap();
+    let mut mods = LockingMods::default();
+
+    // Tapped twice, it's locked until it's tapped again.
+    for _ in 0..2 {
+        mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+        mods.update(&RELEASED, &mapping, 1);
+    }
+    assert_eq!(mods.update(&pressed(&[A]), &mapping, 1), shift);
+    assert_eq!(mods.update(&RELEASED, &mapping, 1), shift);
+    mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+    assert_eq!(mods.update(&RELEASED, &mapping, 1), 0);
+
+    // Too slow, or with a key typed in between, the taps don't lock it.
+    mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+    for _ in 0..=DOUBLE_TAP_TICKS {
+        mods.update(&RELEASED, &mapping, 1);
+    }
+    assert_eq!(mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1), 0);
+    mods.update(&pressed(&[LEFT_SHIFT, A]), &mapping, 1);
+    mods.update(&RELEASED, &mapping, 1);
+    mods.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+    assert_eq!(mods.update(&RELEASED, &mapping, 1), 0);
+    assert_eq!(mods.locked(), 0);
+}
+
+fn grave_escape_follows_modifiers() {
+    let mut mapping = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+    mapping[ESCAPE.0][ESCAPE.1] = KeyCode::GraveEscape;
+    mapping[A.0][A.1] = KeyCode::A;
+    let shift = KeyCode::LeftShift.modifier_bitmask().unwrap();
+    let ctrl = KeyCode::LeftCtrl.modifier_bitmask().unwrap();
+    let gui = KeyCode::RightCmd.modifier_bitmask().unwrap();
+    let mut keys = ConditionalKeys::default();
+    let mut update = |matrix: &Matrix, modifiers| {
+        let mut sent = None;
+        keys.update(matrix, &mapping, modifiers, |key| sent = Some(key));
+        sent
+    };
+
+    assert_eq!(update(&pressed(&[ESCAPE]), 0), Some(KeyCode::Escape));
+    assert_eq!(update(&pressed(&[ESCAPE]), ctrl), Some(KeyCode::Escape));
+    update(&RELEASED, 0);
+    assert_eq!(update(&pressed(&[ESCAPE]), gui), Some(KeyCode::Tilde));
+    update(&RELEASED, 0);
+
+    // Resolved when pressed, it stays the same key while held, whatever the modifiers do.
+    assert_eq!(update(&pressed(&[ESCAPE]), shift), Some(KeyCode::Tilde));
+    assert_eq!(update(&pressed(&[ESCAPE]), 0), Some(KeyCode::Tilde));
+    assert_eq!(update(&RELEASED, 0), None);
+
+    // Other keys aren't conditional, and are left to the report.
+    assert_eq!(update(&pressed(&[A]), shift), None);
+    assert!(KeyCode::GraveEscape.is_firmware_key());
+}
+
+fn mod_swaps_change_modifiers() {
+    let bit = |key: KeyCode| key.modifier_bitmask().unwrap();
+    let swaps = ModSwaps { alt_gui_swapped: true, caps_as_ctrl: true, gui_disabled: false };
+    assert_eq!(swaps.apply(bit(KeyCode::LeftAlt)), bit(KeyCode::LeftCmd));
+    assert_eq!(swaps.apply(bit(KeyCode::RightCmd)), bit(KeyCode::RightAlt));
+    assert_eq!(
+        swaps.apply(bit(KeyCode::LeftShift) | bit(KeyCode::LeftAlt) | bit(KeyCode::LeftCmd)),
+        bit(KeyCode::LeftShift) | bit(KeyCode::LeftAlt) | bit(KeyCode::LeftCmd)
+    );
+    assert_eq!(swaps.remap(KeyCode::CapsLock), KeyCode::LeftCtrl);
+    assert_eq!(swaps.remap(KeyCode::A), KeyCode::A);
+    assert_eq!(ModSwaps::from_u8(swaps.to_u8()), Some(swaps));
+    assert_eq!(ModSwaps::from_u8(0xFF), None);
+
+    // GUI is turned off after the swap, so it's the keys sending GUI which go quiet.
+    let swaps = ModSwaps { gui_disabled: true, ..swaps };
+    assert_eq!(swaps.apply(bit(KeyCode::LeftAlt) | bit(KeyCode::LeftCmd)), bit(KeyCode::LeftAlt));
+
+    let mut keyboard = Keyboard::new(Profile::Typing);
+    keyboard.set_mod_swaps(ModSwaps { alt_gui_swapped: true, ..ModSwaps::default() });
+    let report = keyboard.report(&KeyScan::from(pressed(&[LEFT_CMD])));
+    assert_eq!(report.modifier, bit(KeyCode::LeftAlt));
+    keyboard.set_mod_swaps(ModSwaps { gui_disabled: true, ..ModSwaps::default() });
+    let report = keyboard.report(&KeyScan::from(pressed(&[LEFT_CMD])));
+    assert_eq!(report.modifier, 0);
+}
+
+fn repeat_key_sends_last_key() {
+    let report = |usages: &[KeyCode]| {
+        let mut report = NkroReport::EMPTY;
+        for usage in usages {
+            report.press(*usage as u8);
+        }
+        report
+    };
+    let mut repeat = RepeatKey::default();
+    assert_eq!(repeat.update(true), None);
+    repeat.update(false);
+
+    // The last key newly pressed, even with others still held.
+    repeat.record(&report(&[]), &report(&[KeyCode::A]));
+    repeat.record(&report(&[KeyCode::A]), &report(&[KeyCode::A, KeyCode::S]));
+    repeat.record(&report(&[KeyCode::A, KeyCode::S]), &report(&[KeyCode::A]));
+    assert_eq!(repeat.last(), Some(KeyCode::S as u8));
+
+    // Held, it keeps sending that key, and its own presses aren't the last key.
+    assert_eq!(repeat.update(true), Some(KeyCode::S as u8));
+    repeat.record(&report(&[]), &report(&[KeyCode::S]));
+    assert_eq!(repeat.update(true), Some(KeyCode::S as u8));
+    assert_eq!(repeat.update(false), None);
+    repeat.record(&report(&[]), &report(&[KeyCode::D]));
+    assert_eq!(repeat.update(true), Some(KeyCode::D as u8));
+}
+
+fn tap_hold_decides_tap_or_hold() {
+    static ESCAPE_CTRL: [TapHold; 1] =
+        [TapHold { position: A, tap: KeyCode::Escape, hold: KeyCode::LeftCtrl }];
+    let mut keys = TapHoldKeys::new(&ESCAPE_CTRL);
+
+    // Undecided while pressed, then tapped for one scan once released.
+    assert!(keys.update(&pressed(&[A]), 1).is_none());
+    assert!(keys.update(&RELEASED, 1).unwrap()[A.0][A.1]);
+    assert_eq!(keys.keys().next(), Some((A, Some(KeyCode::Escape))));
+    assert!(!keys.update(&RELEASED, 1).unwrap()[A.0][A.1]);
+
+    // Held for the tapping term.
+    for _ in 0..TAPPING_TERM_TICKS {
+        assert!(keys.update(&pressed(&[A]), 1).is_none());
+    }
+    assert!(keys.update(&pressed(&[A]), 1).unwrap()[A.0][A.1]);
+    assert_eq!(keys.keys().next(), Some((A, Some(KeyCode::LeftCtrl))));
+    keys.update(&RELEASED, 1);
+
+    // Pressing another key decides on a hold straight away, in the same scan as the key.
+    keys.update(&pressed(&[A]), 1);
+    assert!(keys.update(&pressed(&[A, D]), 1).unwrap()[D.0][D.1]);
+    assert_eq!(keys.keys().next(), Some((A, Some(KeyCode::LeftCtrl))));
+}
+
+fn tap_dance_counts_taps() {
+    static ESCAPE_CAPS_FN: [TapDance; 1] = [TapDance {
+        position: A,
+        taps: [KeyCode::Escape, KeyCode::CapsLock, KeyCode::Fn],
+        window_ms: TAP_DANCE_WINDOW_MS,
+    }];
+    let window_ticks = ms_to_ticks(TAP_DANCE_WINDOW_MS);
+    let mut keys = TapDanceKeys::new(&ESCAPE_CAPS_FN);
+
+    // Tapped once, then sent for one scan once the window has passed.
+    assert!(keys.update(&pressed(&[A]), 1).is_none());
+    assert!(keys.update(&RELEASED, 1).is_none());
+    for _ in 1..window_ticks {
+        assert!(keys.update(&RELEASED, 1).is_none());
+    }
+    assert!(keys.update(&RELEASED, 1).unwrap()[A.0][A.1]);
+    assert_eq!(keys.keys().next(), Some((A, Some(KeyCode::Escape))));
+    assert!(!keys.update(&RELEASED, 1).unwrap()[A.0][A.1]);
+
+    // Tapped twice and held, it acts as the second key until it's released.
+    keys.update(&pressed(&[A]), 1);
+    keys.update(&RELEASED, 1);
+    for _ in 0..window_ticks {
+        assert!(keys.update(&pressed(&[A]), 1).is_none());
+    }
+    assert!(keys.update(&pressed(&[A]), 1).unwrap()[A.0][A.1]);
+    assert_eq!(keys.keys().next(), Some((A, Some(KeyCode::CapsLock))));
+    assert!(!keys.update(&RELEASED, 1).unwrap()[A.0][A.1]);
+
+    // The last tap there's a key for doesn't wait for the window.
+    for _ in 0..2 {
+        keys.update(&pressed(&[A]), 1);
+        keys.update(&RELEASED, 1);
+    }
+    keys.update(&pressed(&[A]), 1);
+    assert!(keys.update(&RELEASED, 1).unwrap()[A.0][A.1]);
+    assert_eq!(keys.keys().next(), Some((A, Some(KeyCode::Fn))));
+    keys.update(&RELEASED, 1);
+
+    // Pressing another key ends the dance, and that key follows a scan later.
+    keys.update(&pressed(&[A]), 1);
+    keys.update(&RELEASED, 1);
+    let matrix = keys.update(&pressed(&[D]), 1).unwrap();
+    assert!(matrix[A.0][A.1] && !matrix[D.0][D.1]);
+    assert_eq!(keys.keys().next(), Some((A, Some(KeyCode::Escape))));
+    let matrix = keys.update(&pressed(&[D]), 1).unwrap();
+    assert!(!matrix[A.0][A.1] && matrix[D.0][D.1]);
+}
+
+fn combo_replaces_keys() {
+    static ESCAPE: [Combo; 1] = [Combo { positions: &[S, D], key: KeyCode::Escape }];
+    let mut combos = Combos::new(&ESCAPE);
+
+    // Pressed together, the combo is sent in place of both keys until one is released.
+    assert!(combos.update(&pressed(&[S]), 1).is_none());
+    let matrix = combos.update(&pressed(&[S, D]), 1).unwrap();
+    assert!(matrix[S.0][S.1] && !matrix[D.0][D.1]);
+    assert_eq!(combos.keys().next(), Some((S, Some(KeyCode::Escape))));
+    assert!(!combos.update(&pressed(&[S]), 1).unwrap()[S.0][S.1]);
+    assert_eq!(combos.keys().next(), Some((S, None)));
+    combos.update(&RELEASED, 1);
+
+    // Tapped alone, the key is sent for one scan once it's released.
+    assert!(combos.update(&pressed(&[S]), 1).is_none());
+    assert!(combos.update(&RELEASED, 1).unwrap()[S.0][S.1]);
+    assert!(!combos.update(&RELEASED, 1).unwrap()[S.0][S.1]);
+
+    // Held alone, the key is pressed once the window has passed.
+    for _ in 1..COMBO_WINDOW_TICKS {
+        assert!(combos.update(&pressed(&[S]), 1).is_none());
+    }
+    assert!(combos.update(&pressed(&[S]), 1).unwrap()[S.0][S.1]);
+    combos.update(&RELEASED, 1);
+
+    // Pressing another key sends the held back key, and that key follows a scan later.
+    combos.update(&pressed(&[S]), 1);
+    let matrix = combos.update(&pressed(&[S, A]), 1).unwrap();
+    assert!(matrix[S.0][S.1] && !matrix[A.0][A.1]);
+    let matrix = combos.update(&pressed(&[S, A]), 1).unwrap();
+    assert!(matrix[S.0][S.1] && matrix[A.0][A.1]);
+}
+
+fn auto_shift_shifts_held_keys() {
+    let mut mapping = [[KeyCode::Empty; NUM_ROWS]; NUM_COLS];
+    mapping[A.0][A.1] = KeyCode::A;
+    mapping[S.0][S.1] = KeyCode::S;
+    mapping[LEFT_SHIFT.0][LEFT_SHIFT.1] = KeyCode::LeftShift;
+    let shift = KeyCode::LeftShift.modifier_bitmask().unwrap();
+    let mut auto_shift = AutoShift::default();
+
+    // Off, keys go straight through.
+    assert!(auto_shift.update(&pressed(&[A]), &mapping, 1).unwrap()[A.0][A.1]);
+    auto_shift.update(&RELEASED, &mapping, 1);
+    auto_shift.set_enabled(true);
+
+    // Tapped, the key is sent unshifted for one scan once it's released.
+    assert!(auto_shift.update(&pressed(&[A]), &mapping, 1).is_none());
+    assert!(auto_shift.update(&RELEASED, &mapping, 1).unwrap()[A.0][A.1]);
+    assert_eq!(auto_shift.modifiers(), 0);
+    assert!(!auto_shift.update(&RELEASED, &mapping, 1).unwrap()[A.0][A.1]);
+
+    // Held, it's pressed with Shift until it's released.
+    for _ in 1..AUTO_SHIFT_TICKS {
+        assert!(auto_shift.update(&pressed(&[A]), &mapping, 1).is_none());
+    }
+    assert!(auto_shift.update(&pressed(&[A]), &mapping, 1).unwrap()[A.0][A.1]);
+    assert_eq!(auto_shift.modifiers(), shift);
+    auto_shift.update(&RELEASED, &mapping, 1);
+    assert_eq!(auto_shift.modifiers(), 0);
+
+    // Pressing another key sends it unshifted, and that key follows a scan later.
+    auto_shift.update(&pressed(&[A]), &mapping, 1);
+    let matrix = auto_shift.update(&pressed(&[A, S]), &mapping, 1).unwrap();
+    assert!(matrix[A.0][A.1] && !matrix[S.0][S.1]);
+    assert_eq!(auto_shift.modifiers(), 0);
+    auto_shift.update(&RELEASED, &mapping, 1);
+
+    // Along with a modifier, it isn't held back.
+    auto_shift.update(&pressed(&[LEFT_SHIFT]), &mapping, 1);
+    assert!(auto_shift.update(&pressed(&[LEFT_SHIFT, A]), &mapping, 1).unwrap()[A.0][A.1]);
+}
+
+fn config_lock_toggles_on_press() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+    assert!(!keyboard.config_locked());
+
+    // Holding the combo only toggles the lock once, and nothing is sent to the host.
+    for _ in 0..3 {
+        let report = keyboard.report(&KeyScan::from(pressed(&[FN, L])));
+        assert_eq!(report.keycodes, [0; 6]);
+        assert!(keyboard.config_locked());
+    }
+
+    keyboard.report(&KeyScan::from(RELEASED));
+    keyboard.report(&KeyScan::from(pressed(&[FN, L])));
+    assert!(!keyboard.config_locked());
+}
+
+fn game_mode_masks_gui() {
+    const H: (usize, usize) = (6, 3);
+    let mut keyboard = Keyboard::new(Profile::Typing);
+    let gui = KeyCode::LeftCmd.modifier_bitmask().unwrap();
+    assert_eq!(keyboard.report(&KeyScan::from(pressed(&[LEFT_CMD]))).modifier, gui);
+    keyboard.report(&KeyScan::from(RELEASED));
+
+    keyboard.report(&KeyScan::from(pressed(&[FN, H])));
+    assert!(keyboard.game_mode());
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert_eq!(keyboard.report(&KeyScan::from(pressed(&[LEFT_CMD]))).modifier, 0);
+    keyboard.report(&KeyScan::from(RELEASED));
+
+    keyboard.report(&KeyScan::from(pressed(&[FN, H])));
+    assert!(!keyboard.game_mode());
+}
+
+fn kvm_hotkey_types_each_key() {
+    let mut player = HotkeyPlayer::default();
+    assert!(player.next_report().is_none());
+
+    player.start(Output::Secondary);
+    let mut typed = [0u8; 3];
+    let mut typed_len = 0;
+    let mut previous = 0;
+    while let Some(report) = player.next_report() {
+        // Each key is released before the next one, so the KVM sees separate presses.
+        let key = report.keycodes[0];
+        if key != 0 && previous == 0 {
+            typed[typed_len] = key;
+            typed_len += 1;
+        }
+        previous = key;
+    }
+
+    assert!(!player.is_playing());
+    assert_eq!(previous, 0);
+    assert_eq!(typed, Output::Secondary.hotkey().map(|key| key as u8));
+    assert!(HOTKEY_HOLD_TICKS > 1);
+}
+
+fn usb_stress_test_types_rolling_chord() {
+    let mut test = StressTest::default();
+    assert!(test.next_report().is_none());
+
+    // Every report presses one new letter, so the letters typed follow the alphabet.
+    test.start();
+    let mut previous = [0u8; 6];
+    let mut typed = 0;
+    while let Some(report) = test.next_report() {
+        assert!(report.keycodes.iter().filter(|key| **key != 0).count() <= STRESS_ROLLOVER);
+        let new_keys =
+            report.keycodes.iter().filter(|key| **key != 0 && !previous.contains(key)).count();
+        if new_keys > 0 {
+            assert_eq!(new_keys, 1);
+            assert!(report.keycodes.contains(&(0x04 + (typed % 26) as u8)));
+            typed += 1;
+        }
+        assert!(report.keycodes != previous);
+        previous = report.keycodes;
+        test.record(typed == 1, false);
+    }
+
+    assert_eq!(typed, 26 * STRESS_ROUNDS);
+    assert_eq!(previous, [0; 6]);
+    let stats = test.take_stats().unwrap();
+    assert_eq!(stats.reports as usize, 26 * STRESS_ROUNDS + STRESS_ROLLOVER);
+    assert_eq!((stats.queue_full, stats.blocked), (1, 0));
+    assert!(test.take_stats().is_none());
+}
+
+fn usb_log_buffer_keeps_newest_bytes() {
+    let mut log = LogBuffer::<16>::new();
+    assert!(log.is_empty());
+
+    // Each frame ends with a zero, which the host splits frames on.
+    log.start_frame();
+    log.write(&[1, 2, 3]);
+    log.end_frame();
+    let mut bytes = [0xFF; 16];
+    let len = log.peek(&mut bytes);
+    assert!(len > 3 && len == log.len());
+    assert_eq!(bytes[len - 1], 0);
+
+    // Peeking doesn't take the bytes, consuming does.
+    log.consume(2);
+    assert_eq!(log.len(), len - 2);
+
+    // Once it's full, the oldest bytes make way for the newest frame.
+    for _ in 0..8 {
+        log.start_frame();
+        log.write(&[4, 5, 6]);
+        log.end_frame();
+    }
+    assert_eq!(log.len(), 16);
+    let mut frame = LogBuffer::<16>::new();
+    frame.start_frame();
+    frame.write(&[4, 5, 6]);
+    frame.end_frame();
+    let mut expected = [0; 16];
+    let frame_len = frame.peek(&mut expected);
+    let mut newest = [0xFF; 16];
+    log.peek(&mut newest);
+    assert_eq!(&newest[16 - frame_len..], &expected[..frame_len]);
+}
+
+fn macro_player_types_sequence() {
+    // Macro 0 is empty, and macro 1 types "Hi" and then Ctrl + C.
+    let mut macros = MacroBuffer::default();
+    macros.write(1, b"Hi\x01\x02\xE0\x01\x0450|c\x01\x03\xE0");
+    let mut player = MacroPlayer::default();
+
+    player.start(&macros, 0);
+    player.tick(&macros);
+    assert!(!player.is_playing());
+
+    player.start(&macros, 1);
+    let mut typed = [(0u8, 0u8); 3];
+    let mut typed_len = 0;
+    let mut ticks = 0;
+    let mut previous = NkroReport::EMPTY;
+    while player.is_playing() {
+        player.tick(&macros);
+        ticks += 1;
+        let held = *player.held();
+        for usage in [KeyCode::H, KeyCode::I, KeyCode::C].map(|key| key as u8) {
+            if held.is_pressed(usage) && !previous.is_pressed(usage) {
+                typed[typed_len] = (usage, held.modifier);
+                typed_len += 1;
+            }
+        }
+        previous = held;
+    }
+
+    assert_eq!(
+        typed,
+        [(KeyCode::H as u8, 0x02), (KeyCode::I as u8, 0x00), (KeyCode::C as u8, 0x01)]
+    );
+    assert!(*player.held() == NkroReport::EMPTY);
+    assert!(ticks > (ms_to_ticks(50) + 3 * MACRO_TAP_TICKS as u32 * 2) as usize);
+}
+
+fn unicode_sequence_suits_input_mode() {
+    let linux = Sequence::new('é', UnicodeMode::Linux);
+    assert_eq!(linux.bytes(), b"\x01\x02\xE0\x01\x02\xE1u\x01\x03\xE1\x01\x03\xE000e9 ");
+    let mac = Sequence::new('😀', UnicodeMode::MacOs);
+    assert_eq!(mac.bytes(), b"\x01\x02\xE2d83dde00\x01\x03\xE2");
+    let win_compose = Sequence::new('😀', UnicodeMode::WinCompose);
+    assert_eq!(win_compose.bytes(), b"\x01\x01\xE6u1f600\n");
+    let alt_codes = Sequence::new('é', UnicodeMode::WindowsAltCodes);
+    assert_eq!(
+        alt_codes.bytes(),
+        b"\x01\x02\xE2\x01\x01\x57\x01\x01\x62\x01\x01\x62e\x01\x01\x61\x01\x03\xE2"
+    );
+
+    // Played through, it leaves nothing held.
+    let mut player = UnicodePlayer::default();
+    player.start('é', UnicodeMode::Linux);
+    let mut ctrl_shift_held = false;
+    while player.is_playing() {
+        player.tick();
+        ctrl_shift_held |= player.held().modifier == 0x03;
+    }
+    assert!(ctrl_shift_held);
+    assert!(*player.held() == NkroReport::EMPTY);
+}
+
+fn macro_recorder_records_presses_and_releases() {
+    let (a, b) = (KeyCode::A as u8, KeyCode::B as u8);
+    let mut recorder = MacroRecorder::default();
+
+    // A is already held, so neither its press nor its release is recorded.
+    let mut report = NkroReport::EMPTY;
+    report.press(a);
+    recorder.start(&report);
+    report.modifier = 0x02;
+    recorder.record(&report);
+    report.release(a);
+    report.press(b);
+    recorder.record(&report);
+    recorder.record(&NkroReport::EMPTY);
+
+    let steps = [1, 2, 0xE1, 1, 2, b, 1, 3, b, 1, 3, 0xE1];
+    assert_eq!(recorder.stop(), &steps);
+    assert!(!recorder.is_recording());
+
+    // Setting an earlier macro moves the recorded one along.
+    let mut macros = MacroBuffer::default();
+    assert!(macros.set_macro(RECORDED_MACRO_INDEX, &steps));
+    assert!(macros.set_macro(0, b"hi"));
+    assert_eq!(&macros.bytes()[..3], b"hi\0");
+    let start = 3 + RECORDED_MACRO_INDEX - 1;
+    assert_eq!(&macros.bytes()[start..start + steps.len()], &steps);
+    assert_eq!(macros.bytes()[start + steps.len()], 0);
+    assert!(!macros.set_macro(MACRO_COUNT, b"x"));
+    assert!(!macros.set_macro(1, &[b'x'; MACRO_BUFFER_SIZE]));
+}
+
+fn report_limits_to_six_keys() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    // The number row, from 1 to 0.
+    let keys: [(usize, usize); 10] = core::array::from_fn(|i| (i + 1, 1));
+    let report = keyboard.report(&KeyScan::from(pressed(&keys)));
+    assert_eq!(
+        report.keycodes,
+        [
+            KeyCode::Num1 as u8,
+            KeyCode::Num2 as u8,
+            KeyCode::Num3 as u8,
+            KeyCode::Num4 as u8,
+            KeyCode::Num5 as u8,
+            KeyCode::Num6 as u8,
+        ]
+    );
+}
+
+fn nkro_report_has_every_key() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+
+    // The number row, from 1 to 0, along with a modifier.
+    let mut keys = [LEFT_SHIFT; 11];
+    for (i, key) in keys.iter_mut().skip(1).enumerate() {
+        *key = (i + 1, 1);
+    }
+    keyboard.report(&KeyScan::from(pressed(&keys)));
+
+    let report = keyboard.nkro_report();
+    assert_eq!(report.modifier, 1 << 1);
+    assert!(report.is_pressed(KeyCode::Num1 as u8));
+    assert!(report.is_pressed(KeyCode::Num0 as u8));
+    assert!(!report.is_pressed(KeyCode::A as u8));
+
+    keyboard.report(&KeyScan::from(RELEASED));
+    assert!(keyboard.nkro_report() == NkroReport::EMPTY);
+}
+
+fn report_ignores_gui_in_gaming_profile() {
+    let mut keyboard = Keyboard::new(Profile::Gaming);
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[LEFT_CMD, A])));
+    assert_eq!(report.modifier, 0);
+    assert_eq!(report.keycodes[0], KeyCode::A as u8);
+}
+
+fn socd_cleans_opposing_keys() {
+    let mut keyboard = Keyboard::new(Profile::Gaming);
+
+    keyboard.set_socd_mode(SocdMode::LastInput);
+    keyboard.report(&KeyScan::from(pressed(&[A])));
+    let report = keyboard.report(&KeyScan::from(pressed(&[A, D])));
+    assert_eq!(report.keycodes[0], KeyCode::D as u8);
+
+    // Releasing the later key brings back the one still held.
+    let report = keyboard.report(&KeyScan::from(pressed(&[A])));
+    assert_eq!(report.keycodes[0], KeyCode::A as u8);
+
+    keyboard.set_socd_mode(SocdMode::Neutral);
+    let report = keyboard.report(&KeyScan::from(pressed(&[A, D])));
+    assert_eq!(report.keycodes, [0; 6]);
+
+    // Cycling the mode with Fn + D turns cleaning off.
+    keyboard.report(&KeyScan::from(pressed(&[FN])));
+    keyboard.report(&KeyScan::from(pressed(&[FN, D])));
+    assert_eq!(keyboard.socd_mode(), SocdMode::Off);
+    keyboard.report(&KeyScan::from(RELEASED));
+    let report = keyboard.report(&KeyScan::from(pressed(&[A, D])));
+    assert_eq!(report.keycodes[..2], [KeyCode::A as u8, KeyCode::D as u8]);
+}
+
+fn led_bindings_apply_while_lit() {
+    let bindings = [LedBinding { led: HostLed::CapsLock, position: ESCAPE, key: KeyCode::Tilde }];
+
+    let mut layer = key_mapping::NORMAL_LAYER_MAPPING;
+    key_mapping::apply_led_bindings(&mut layer, HostLeds::from_report(0b001), &bindings);
+    assert_eq!(layer[ESCAPE.0][ESCAPE.1], KeyCode::Escape);
+
+    key_mapping::apply_led_bindings(&mut layer, HostLeds::from_report(0b011), &bindings);
+    assert_eq!(layer[ESCAPE.0][ESCAPE.1], KeyCode::Tilde);
+}
+
+/// Remembers the last state it was set to.
+#[derive(Default)]
+struct FakeLed {
+    lit: bool,
+}
+
+impl OutputPin for FakeLed {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.lit = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.lit = true;
+        Ok(())
+    }
+}
+
+fn lock_leds_follow_host_leds() {
+    let (mut caps_lock, mut num_lock) = (FakeLed { lit: true }, FakeLed::default());
+    {
+        let mut leds: [(HostLed, &mut dyn OutputPin<Error = Infallible>); 2] =
+            [(HostLed::CapsLock, &mut caps_lock), (HostLed::NumLock, &mut num_lock)];
+        let mut lock_leds = LockLeds::new(&mut leds);
+        lock_leds.show(HostLeds::from_report(HostLed::NumLock as u8));
+    }
+    assert!(!caps_lock.lit);
+    assert!(num_lock.lit);
+}
+
+fn rgb_backlight_reacts_to_presses() {
+    let settings = RgbSettings { enabled: true, brightness: 255, animation: Animation::Reactive };
+    let mut backlight = RgbBacklight::new(&key_mapping::NORMAL_LAYER_MAPPING, settings);
+    let mapping = key_mapping::NORMAL_LAYER_MAPPING;
+
+    // Escape is the first LED in the chain.
+    assert_eq!(backlight.positions()[0], ESCAPE);
+    let idle = backlight.update(0, &RELEASED, &mapping).unwrap()[0];
+    assert!(backlight.update(1, &RELEASED, &mapping).is_none());
+
+    let pressed_escape = pressed(&[ESCAPE]);
+    let lit = backlight.update(FRAME_INTERVAL_MS, &pressed_escape, &mapping).unwrap()[0];
+    assert_eq!(lit, key_color(KeyCode::Escape).to_grb_word());
+    assert!(lit > idle);
+
+    // It fades back once released, and the backlight can be switched off.
+    let faded = backlight.update(10_000, &RELEASED, &mapping).unwrap()[0];
+    assert_eq!(faded, idle);
+    backlight.set_settings(RgbSettings { enabled: false, ..settings });
+    let frame = backlight.update(20_000, &RELEASED, &mapping).unwrap();
+    assert!(frame.iter().all(|word| *word == Color::OFF.to_grb_word()));
+}
+
+fn dual_core_messages_fit_in_a_word() {
+    let settings = RgbSettings { enabled: true, brightness: 200, animation: Animation::Breathing };
+    for message in [
+        dual_core::Message::Frame,
+        dual_core::Message::Settings(settings),
+        dual_core::Message::Settings(RgbSettings { enabled: false, ..settings }),
+        dual_core::Message::LockedModifiers(0x22),
+        dual_core::Message::Pause,
+        dual_core::Message::Paused,
+        dual_core::Message::Resume,
+    ] {
+        assert_eq!(dual_core::Message::from_word(message.to_word()), Some(message));
+    }
+    assert_eq!(dual_core::Message::from_word(0), None);
+    assert_eq!(dual_core::Message::from_word(0xFF00_0000), None);
+}
+
+fn expansion_module_identified_by_reading() {
+    assert!(Module::from_id_reading(12).unwrap().is_none());
+    assert!(Module::from_id_reading(2048).unwrap() == Some(Module::Numpad));
+    assert!(Module::from_id_reading(1250).unwrap() == Some(Module::EncoderCluster));
+    assert!(Module::from_id_reading(2800).unwrap() == Some(Module::Trackball));
+    assert!(Module::from_id_reading(700).unwrap() == Some(Module::TrackPoint));
+    assert!(Module::from_id_reading(380).unwrap() == Some(Module::MacroPad));
+    assert!(Module::from_id_reading(3900).is_err());
+}
+
+fn expansion_module_keys_are_reported() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+    let numpad_enter = (9, 5);
+
+    let report = keyboard.report(&KeyScan::from(pressed(&[numpad_enter])));
+    assert_eq!(report.keycodes, [0; 6]);
+
+    keyboard.set_expansion_module(Some(Module::Numpad));
+    let report = keyboard.report(&KeyScan::from(pressed(&[numpad_enter])));
+    assert_eq!(report.keycodes[0], KeyCode::Enter as u8);
+}
+
+/// Answers I2C reads like a macropad with four keys, the first and last of them pressed.
+struct FakeMacroPad;
+
+impl WriteRead for FakeMacroPad {
+    type Error = ();
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+        if address != macropad::ADDRESS {
+            return Err(());
+        }
+
+        match bytes {
+            [0x00] => buffer.copy_from_slice(&[b'K', b'P', macropad::PROTOCOL_VERSION, 4]),
+            // Bits above the pad's key count are ignored.
+            [0x01] => buffer.copy_from_slice(&[0b1111_1001]),
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+}
+
+fn macropad_keys_join_matrix() {
+    let mut pad = MacroPad::init(FakeMacroPad).unwrap();
+    assert_eq!(pad.key_count(), 4);
+
+    let keys = pad.read_keys().unwrap();
+    assert_eq!(keys, 0b1001);
+
+    let mut raw_matrix = RELEASED;
+    macropad::apply_keys(keys, &mut raw_matrix);
+    let positions = Module::MacroPad.keys();
+    let pressed_positions = [positions[0].0, positions[3].0];
+    assert!(raw_matrix == pressed(&pressed_positions));
+}
+
+fn pointer_accumulates_motion() {
+    let mut pointer = Pointer::default();
+    assert!(pointer.take_report().is_none());
+
+    pointer.motion(LEFT_BUTTON, 100, -5);
+    pointer.motion(LEFT_BUTTON, 100, -5);
+    let report = pointer.take_report().unwrap();
+    assert_eq!((report.buttons, report.x, report.y), (LEFT_BUTTON, 127, -10));
+
+    // Whatever didn't fit in the first report comes in the next one.
+    let report = pointer.take_report().unwrap();
+    assert_eq!((report.buttons, report.x, report.y), (LEFT_BUTTON, 73, 0));
+    assert!(pointer.take_report().is_none());
+}
+
+fn pointer_scrolls_with_middle_button() {
+    let mut pointer = Pointer::default();
+
+    pointer.motion(MIDDLE_BUTTON, 0, 20);
+    let report = pointer.take_report().unwrap();
+    assert_eq!((report.buttons, report.x, report.y, report.wheel), (0, 0, 0, -2));
+
+    // Scrolling doesn't send a middle click on release.
+    pointer.motion(0, 0, 0);
+    assert!(pointer.take_report().is_none());
+}
+
+fn pointer_middle_click_without_motion() {
+    let mut pointer = Pointer::default();
+
+    pointer.motion(MIDDLE_BUTTON, 1, 0);
+    pointer.motion(0, 0, 0);
+    assert_eq!(pointer.take_report().unwrap().buttons, MIDDLE_BUTTON);
+    assert_eq!(pointer.take_report().unwrap().buttons, 0);
+    assert!(pointer.take_report().is_none());
+}
+
+fn pointer_scrolls_in_high_resolution() {
+    let mut pointer = Pointer::default();
+    pointer.set_resolution_multipliers(ResolutionMultipliers { wheel: true, pan: false });
+
+    pointer.motion(MIDDLE_BUTTON, 4, 5);
+    let report = pointer.take_report().unwrap();
+    assert_eq!((report.wheel, report.pan), (-5, 0));
+}
+
+fn mouse_keys_accelerate() {
+    let mut mouse_keys = MouseKeys::default();
+    let mut hold_for = |keys: &[KeyCode], ticks: u32| {
+        let mut motion = MouseMotion::default();
+        for _ in 0..ticks {
+            keys.iter().for_each(|key| mouse_keys.hold(*key));
+            motion.add(mouse_keys.tick());
+        }
+        motion
+    };
+
+    let first = hold_for(&[KeyCode::MouseRight, KeyCode::MouseButton1], 100);
+    assert!(first.x > 0 && first.y == 0);
+    assert_eq!(first.buttons, 1);
+    let later = hold_for(&[KeyCode::MouseRight], 100);
+    assert!(later.x > first.x);
+    assert_eq!(later.buttons, 0);
+
+    let cancelled = hold_for(&[KeyCode::MouseUp, KeyCode::MouseDown], 100);
+    assert_eq!(cancelled, MouseMotion::default());
+
+    let scrolled = hold_for(&[KeyCode::MouseWheelDown], ACCELERATION_TICKS);
+    assert!(scrolled.wheel < 0 && scrolled.pan == 0);
+}
+
+fn pointer_adds_mouse_keys() {
+    let mut pointer = Pointer::default();
+    pointer.set_resolution_multipliers(ResolutionMultipliers { wheel: true, pan: false });
+
+    pointer.motion(0, 3, 0);
+    pointer.key_motion(MouseMotion { buttons: LEFT_BUTTON, x: 2, y: -1, wheel: 1, pan: 1 });
+    let report = pointer.take_report().unwrap();
+    assert_eq!(report.buttons, LEFT_BUTTON);
+    assert_eq!((report.x, report.y, report.wheel, report.pan), (5, -1, 8, 1));
+
+    pointer.key_motion(MouseMotion::default());
+    assert_eq!(pointer.take_report().unwrap().buttons, 0);
+    assert!(pointer.take_report().is_none());
+}
+
+fn raw_hid_handles_keymap_requests() {
+    let mut raw_hid = RawHid::default();
+    let mut keymap = Keymap::default();
+    let request = |command: Command, payload: &[u8]| {
+        Packet::new(command.to_u8(), 7, Status::Ok, payload).to_bytes()
+    };
+
+    let get_escape = request(Command::GetKey, &[0, ESCAPE.0 as u8, ESCAPE.1 as u8]);
+    let response = Packet::parse(&raw_hid.handle(&get_escape, &mut keymap, &RELEASED, false));
+    let response = response.unwrap();
+    assert_eq!(response.sequence, 7);
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.payload(), &(KeyCode::Escape as u16).to_le_bytes());
+
+    let [low, high] = (KeyCode::Macro1 as u16).to_le_bytes();
+    let set_escape = request(Command::SetKey, &[0, ESCAPE.0 as u8, ESCAPE.1 as u8, low, high]);
+    let response = Packet::parse(&raw_hid.handle(&set_escape, &mut keymap, &RELEASED, true));
+    assert_eq!(response.unwrap().status, Status::Locked);
+    assert_eq!(keymap.key(0, ESCAPE.0, ESCAPE.1), KeyCode::Escape);
+    let response = Packet::parse(&raw_hid.handle(&set_escape, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().status, Status::Ok);
+    assert_eq!(keymap.key(0, ESCAPE.0, ESCAPE.1), KeyCode::Macro1);
+
+    let get_matrix = request(Command::GetMatrix, &[]);
+    let response = Packet::parse(&raw_hid.handle(&get_matrix, &mut keymap, &pressed(&[A]), false));
+    let bit = A.0 * NUM_ROWS + A.1;
+    assert!(response.unwrap().payload()[bit / 8] & (1 << (bit % 8)) != 0);
+
+    let mut corrupted = get_escape;
+    corrupted[5] ^= 1;
+    let response = Packet::parse(&raw_hid.handle(&corrupted, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().status, Status::BadCrc);
+
+    // Requests carry no status, and nothing after their payload.
+    let with_status = Packet::new(Command::GetVersion.to_u8(), 7, Status::Busy, &[]).to_bytes();
+    let response = Packet::parse(&raw_hid.handle(&with_status, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().status, Status::InvalidArgument);
+    let mut trailing = request(Command::GetKey, &[0, 0, 0, 1]);
+    trailing[3] = 3;
+    let crc = crc32(&[&trailing[..RAW_REPORT_LEN - 4]]);
+    trailing[RAW_REPORT_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+    let response = Packet::parse(&raw_hid.handle(&trailing, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().status, Status::InvalidArgument);
+
+    let busy = Packet::parse(&busy_response(&get_escape)).unwrap();
+    assert_eq!((busy.command, busy.sequence), (Command::GetKey.to_u8(), 7));
+    assert_eq!(busy.status, Status::Busy);
+
+    raw_hid.set_matrix_stats(MatrixStats { ghosts_suppressed: 2, stuck_keys: 1 });
+    let get_stats = request(Command::GetMatrixStats, &[]);
+    let response = Packet::parse(&raw_hid.handle(&get_stats, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().payload(), &[2, 0, 0, 0, 1, 0, 0, 0]);
+
+    let get_crash = request(Command::GetLastCrash, &[]);
+    let response = Packet::parse(&raw_hid.handle(&get_crash, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().payload(), &[0]);
+    raw_hid.set_last_crash(Some(Crash::Panic { file: "src/main.rs", line: 300 }));
+    let response = Packet::parse(&raw_hid.handle(&get_crash, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().payload(), b"\x01\x2c\x01\x00\x00src/main.rs");
+
+    let set_idle = request(Command::SetIdleSettings, &[15, 4]);
+    raw_hid.handle(&set_idle, &mut keymap, &RELEASED, false);
+    assert_eq!(raw_hid.idle_settings(), IdleSettings { timeout_min: 15, away_macro: Some(4) });
+    let get_idle = request(Command::GetIdleSettings, &[]);
+    let response = Packet::parse(&raw_hid.handle(&get_idle, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().payload(), &[15, 4]);
+
+    // The saved panic comes back a payload at a time.
+    let get_panic_log = |offset| request(Command::GetPanicLog, &[offset]);
+    let response = Packet::parse(&raw_hid.handle(&get_panic_log(0), &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().payload(), &[]);
+    raw_hid.set_panic_log(Some(PanicLog::new("src/main.rs", 300, "index out of bounds")));
+    let response = Packet::parse(&raw_hid.handle(&get_panic_log(0), &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().payload(), b"src/main.rs:300: index o");
+    let response =
+        Packet::parse(&raw_hid.handle(&get_panic_log(24), &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().payload(), b"ut of bounds");
+
+    let set_panic_action = request(Command::SetPanicAction, &[2]);
+    raw_hid.handle(&set_panic_action, &mut keymap, &RELEASED, false);
+    assert_eq!(raw_hid.panic_action(), PanicAction::Halt);
+    let set_panic_action = request(Command::SetPanicAction, &[3]);
+    let response = Packet::parse(&raw_hid.handle(&set_panic_action, &mut keymap, &RELEASED, false));
+    assert_eq!(response.unwrap().status, Status::InvalidArgument);
+
+    let save = request(Command::SaveKeymap, &[]);
+    raw_hid.handle(&save, &mut keymap, &RELEASED, false);
+    assert!(raw_hid.take_keymap_save_request());
+    assert!(!raw_hid.take_keymap_save_request());
+
+    // The keymap CRC worked out at compile time matches the compiled-in keymap.
+    let identify = request(Command::Identify, &[]);
+    let response = Packet::parse(&raw_hid.handle(&identify, &mut keymap, &RELEASED, false));
+    let response = response.unwrap();
+    let payload = response.payload();
+    assert_eq!(payload[0..3], [NUM_ROWS as u8, NUM_COLS as u8, key_mapping::NUM_LAYERS as u8]);
+    let mut crc = Crc32::new();
+    for key in key_mapping::LAYERS.iter().flatten().flatten() {
+        crc.update(&(*key as u16).to_le_bytes());
+    }
+    assert_eq!(payload[3..7], crc.finish().to_le_bytes());
+}
+
+fn key_codes_classify_keys() {
+    assert!(KeyCode::Keypad0.is_keypad() && KeyCode::KeypadEquals.is_keypad());
+    assert!(!KeyCode::Num0.is_keypad());
+    assert!(KeyCode::International1.is_international() && KeyCode::Lang9.is_international());
+    assert!(KeyCode::NonUsBackslash.is_international());
+    assert!(!KeyCode::F24.is_international());
+
+    // Every usage from `A` up to `Lang9` is a key, apart from Keyboard Power and the
+    // obsolete locking and AS/400 keys.
+    for usage in KeyCode::A as u16..=KeyCode::Lang9 as u16 {
+        let key = KeyCode::from_u16(usage);
+        assert!(
+            key.map(|key| key as u16) == Some(usage) || matches!(usage, 0x66 | 0x82..=0x84 | 0x86)
+        );
+    }
+}
+
+fn via_translates_qmk_keycodes() {
+    assert_eq!(to_qmk_keycode(KeyCode::A), 0x04);
+    assert_eq!(to_qmk_keycode(KeyCode::LeftCtrl), 0xE0);
+    assert_eq!(to_qmk_keycode(KeyCode::VolumeUp), 0xA9);
+    assert_eq!(to_qmk_keycode(KeyCode::Fn), 0x5220 | key_mapping::FN_LAYER as u16);
+    assert_eq!(to_qmk_keycode(KeyCode::ToggleLayer1), 0x5261);
+    assert_eq!(to_qmk_keycode(KeyCode::International3), 0x89);
+    assert_eq!(to_qmk_keycode(KeyCode::Lang1), 0x90);
+
+    assert_eq!(to_qmk_keycode(KeyCode::Macro2), 0x7702);
+    for key in (0..=KeyCode::MAX_VALUE).filter_map(KeyCode::from_u16) {
+        let translated = from_qmk_keycode(to_qmk_keycode(key));
+        assert!(translated.is_some_and(|translated| {
+            translated == key || translated.layer_action() == key.layer_action()
+        }));
+    }
+    assert_eq!(from_qmk_keycode(0x5220 | key_mapping::FN_LAYER as u16), Some(KeyCode::Fn));
+    assert_eq!(from_qmk_keycode(0x7F), None);
+}
+
+fn via_edits_keymap() {
+    let mut via = Via::new(0);
+    let mut keymap = Keymap::default();
+    let mut macros = MacroBuffer::default();
+    let (col, row) = (ESCAPE.0 as u8, ESCAPE.1 as u8);
+    let mut request = |bytes: &[u8], locked: bool| {
+        let mut report = [0u8; 32];
+        report[..bytes.len()].copy_from_slice(bytes);
+        via.handle(&report, &mut keymap, &mut macros, &RELEASED, 0, locked)
+    };
+
+    let response = request(&[0x01], false);
+    assert_eq!(u16::from_be_bytes([response[1], response[2]]), VIA_PROTOCOL_VERSION);
+
+    // Set Escape to Grave, which is `KeyCode::Tilde`.
+    assert_eq!(request(&[0x05, 0, row, col, 0x00, 0x35], true)[0], 0xFF);
+    assert_eq!(request(&[0x04, 0, row, col], false)[4..6], [0x00, 0x29]);
+    assert_eq!(request(&[0x05, 0, row, col, 0x00, 0x35], false)[0], 0x05);
+    assert_eq!(request(&[0x04, 0, row, col], false)[4..6], [0x00, 0x35]);
+
+    // The first key of the buffer is (layer 0, row 0, column 0), which is Escape.
+    assert_eq!(request(&[0x12, 0, 0, 2], false)[4..6], [0x00, 0x35]);
+    assert_eq!(request(&[0x11], false)[1], key_mapping::NUM_LAYERS as u8);
+
+    assert!(via.take_keymap_save_request());
+    assert_eq!(keymap.key(0, ESCAPE.0, ESCAPE.1), KeyCode::Tilde);
+}
+
+fn crc32_matches_reference() {
+    assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+}
+
+fn scan_trace_keeps_latest_changes() {
+    let mut trace: ScanTrace<2> = ScanTrace::default();
+    trace.record(0, &pressed(&[A]));
+    trace.record(1, &pressed(&[A]));
+    trace.record(2, &RELEASED);
+    trace.record(3, &pressed(&[ESCAPE, F10]));
+
+    // The unchanged scan isn't recorded, and the oldest change was overwritten.
+    let times: [u32; 2] = core::array::from_fn(|i| trace.entries().nth(i).unwrap().time_ms);
+    assert_eq!(times, [2, 3]);
+    assert_eq!(trace.entries().count(), 2);
+
+    let entry = trace.entries().last().unwrap();
+    let decoded = TraceEntry::from_bytes(&entry.to_bytes());
+    assert_eq!(decoded.time_ms, 3);
+    assert!(decoded.matrix == pressed(&[ESCAPE, F10]));
+}
+
+fn wireless_frame_round_trip() {
+    let mut keyboard = Keyboard::new(Profile::Typing);
+    let report = keyboard.report(&KeyScan::from(pressed(&[LEFT_SHIFT, A])));
+
+    let bytes = Frame::Keyboard(report).to_bytes(7);
+    let Some((7, Frame::Keyboard(decoded))) = Frame::from_bytes(&bytes) else {
+        panic!("keyboard frame didn't decode");
+    };
+    assert_eq!((decoded.modifier, decoded.keycodes), (report.modifier, report.keycodes));
+
+    let mut unknown = [0u8; FRAME_SIZE];
+    unknown[0] = 0xFF;
+    assert!(Frame::from_bytes(&unknown).is_none());
+}
+
+fn ble_status_frame_parses() {
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    assert_eq!(Message::StatusRequest.encode(&mut buf), &[0x7E, 0x03, 0x00, 0x3F]);
+
+    // A keyboard frame isn't a status, and stray bytes before a frame are skipped.
+    let mut parser = FrameParser::default();
+    let keyboard =
+        Message::Keyboard(Keyboard::new(Profile::Typing).report(&KeyScan::from(RELEASED)));
+    for byte in keyboard.encode(&mut buf) {
+        assert!(parser.push(*byte).is_none());
+    }
+    let statuses =
+        [0x00, 0x7E, 0x81, 0x01, 0x02, 0x7B].iter().filter_map(|byte| parser.push(*byte));
+    assert!(statuses.eq([BleStatus::Connected]));
+
+    // A corrupted frame is dropped.
+    for byte in [0x7E, 0x81, 0x01, 0x02, 0x00] {
+        assert!(parser.push(byte).is_none());
+    }
+}
+
+fn split_frame_carries_matrix() {
+    let remote = pressed(&[A, L]);
+    let frame = encode_frame(&remote);
+
+    // Stray bytes before a frame are skipped, and a corrupted frame is dropped.
+    let mut parser = split::FrameParser::default();
+    assert!(parser.push(0x00).is_none());
+    let mut corrupted = frame;
+    corrupted[1] ^= 0x01;
+    for byte in corrupted {
+        assert!(parser.push(byte).is_none());
+    }
+    let matrices = frame.iter().filter_map(|byte| parser.push(*byte));
+    assert!(matrices.eq([remote]));
+
+    let mut raw_matrix = pressed(&[ESCAPE]);
+    split::merge(&remote, &mut raw_matrix);
+    assert!(raw_matrix == pressed(&[ESCAPE, A, L]));
+}
+
+fn split_halves_scan_their_own_columns() {
+    assert!(Side::Left.columns() == (0..SPLIT_LEFT_COLS));
+    assert!(Side::Right.columns() == (SPLIT_LEFT_COLS..NUM_COLS));
+
+    // The right half's columns are wired to the first column pins, and moved back after.
+    let right_cols = NUM_COLS - SPLIT_LEFT_COLS;
+    let scan_mask = Side::Right.scan_mask(&[[true; NUM_ROWS]; NUM_COLS]);
+    assert!(scan_mask[right_cols - 1][0] && !scan_mask[right_cols][0]);
+    assert!(Side::Right.place(&pressed(&[(0, 3)])) == pressed(&[(SPLIT_LEFT_COLS, 3)]));
+
+    // The left half's stay where they are, and nothing past them gets through.
+    assert!(Side::Left.place(&pressed(&[A, L])) == pressed(&[A]));
+}
+
+fn ms_os_descriptor_set_is_consistent() {
+    let set = ms_os_descriptor_set(2);
+    assert_eq!(MS_OS_DESCRIPTOR_SET_LEN, 178);
+
+    // The header, configuration subset, and function subset lengths, and the interface.
+    assert_eq!(u16::from_le_bytes([set[8], set[9]]) as usize, set.len());
+    assert_eq!(u16::from_le_bytes([set[16], set[17]]) as usize, set.len() - 10);
+    assert_eq!(u16::from_le_bytes([set[24], set[25]]) as usize, set.len() - 18);
+    assert_eq!(set[22], 2);
+    assert_eq!(&set[30..36], b"WINUSB");
+}
+
+fn usb_stall_detected_without_frames() {
+    let mut detector = StallDetector::default();
+
+    // Frames keep coming, or the host isn't using the keyboard, so nothing's wrong.
+    for frame in 1..=STALL_TIMEOUT_MS as u16 * 2 {
+        assert!(!detector.tick(true, frame, false));
+        assert!(!detector.tick(false, 0, true));
+    }
+
+    // The frame number stops changing.
+    assert!(!detector.tick(true, 7, false));
+    let stalled_at = (1..=STALL_TIMEOUT_MS).find(|_| detector.tick(true, 7, false));
+    assert_eq!(stalled_at, Some(STALL_TIMEOUT_MS));
+}
+
+fn fault_blinks_most_serious_fault() {
+    let latch = FaultLatch::new();
+    let mut blinker = FaultBlinker::default();
+    assert!(latch.fault().is_none());
+    assert!(!blinker.tick(latch.fault()));
+
+    latch.raise(Fault::FlashWrite);
+    latch.raise(Fault::UsbOverflow);
+    assert_eq!(latch.fault(), Some(Fault::FlashWrite));
+
+    // Count the blinks in one repetition of the pattern, and make sure it then repeats.
+    let period = Fault::FlashWrite.blinks() * BLINK_MS * 2 + PAUSE_MS;
+    let mut blinks = 0;
+    let mut was_lit = false;
+    for ms in 0..period * 2 {
+        let lit = blinker.tick(latch.fault());
+        if ms == period {
+            assert_eq!(blinks, Fault::FlashWrite.blinks());
+        }
+        if lit && !was_lit {
+            blinks += 1;
+        }
+        was_lit = lit;
+    }
+    assert_eq!(blinks, Fault::FlashWrite.blinks() * 2);
+}
+
+fn typing_break_due_after_interval() {
+    const INTERVAL_MS: u64 = 30 * 60 * 1000;
+    let mut reminder = BreakReminder::new(INTERVAL_MS);
+
+    // A key press a minute, which never counts as a break.
+    let mut now_ms = 0;
+    while now_ms < INTERVAL_MS {
+        reminder.update(now_ms, true);
+        assert!(!reminder.is_due(now_ms));
+        now_ms += 60 * 1000;
+    }
+    reminder.update(now_ms, true);
+    assert!(reminder.is_due(now_ms));
+
+    reminder.snooze(now_ms);
+    assert!(!reminder.is_due(now_ms + SNOOZE_MS - 1));
+    assert!(reminder.is_due(now_ms + SNOOZE_MS));
+
+    // Resting ends the session, and the next key press starts a new one.
+    now_ms += REST_MS;
+    reminder.update(now_ms, true);
+    assert!(!reminder.is_due(now_ms));
+}
+
+fn idle_timer_dims_and_plays_away_macro() {
+    const TIMEOUT_MS: u64 = 60 * 1000;
+    let mut timer = IdleTimer::new(IdleSettings { timeout_min: 1, away_macro: Some(2) });
+
+    timer.update(TIMEOUT_MS - 1, false);
+    assert_eq!(timer.state(), IdleState::Active);
+    assert_eq!(timer.backlight_brightness(200), Some(200));
+    assert_eq!(timer.take_away_macro(), None);
+
+    // The away macro plays once, as the keyboard goes idle.
+    timer.update(TIMEOUT_MS, false);
+    assert_eq!(timer.state(), IdleState::Idle);
+    assert_eq!(timer.backlight_brightness(200), Some(50));
+    assert_eq!(timer.take_away_macro(), Some(2));
+    timer.update(2 * TIMEOUT_MS, false);
+    assert_eq!(timer.state(), IdleState::Asleep);
+    assert_eq!(timer.backlight_brightness(200), None);
+    assert_eq!(timer.take_away_macro(), None);
+
+    timer.update(2 * TIMEOUT_MS + 1, true);
+    assert_eq!(timer.state(), IdleState::Active);
+
+    // A timeout of zero never goes idle.
+    timer.set_settings(IdleSettings { timeout_min: 0, away_macro: Some(2) });
+    timer.update(10 * TIMEOUT_MS, false);
+    assert_eq!(timer.state(), IdleState::Active);
+}
+
+fn settings_round_trip_through_flash() {
+    let original = Settings::load();
+
+    for profiles in [[Profile::Gaming, Profile::Typing], [Profile::Typing, Profile::Gaming]] {
+        for (output, config_locked) in [(Output::Secondary, true), (Output::Primary, false)] {
+            let socd_mode = if config_locked { SocdMode::Neutral } else { SocdMode::LastInput };
+            let layout_options = if config_locked { 0x0102_0304 } else { 0 };
+            let rgb = RgbSettings {
+                enabled: !config_locked,
+                brightness: 7,
+                animation: Animation::Reactive,
+            };
+            let analog = AnalogSettings { actuation: 96, rapid_trigger: config_locked as u8 * 16 };
+            let settings = Settings {
+                profiles,
+                output,
+                config_locked,
+                socd_mode,
+                layout_options,
+                rgb,
+                analog,
+                auto_shift: !config_locked,
+                unicode_mode: if config_locked { UnicodeMode::MacOs } else { UnicodeMode::Linux },
+                idle: IdleSettings {
+                    timeout_min: 5,
+                    away_macro: Some(3).filter(|_| config_locked),
+                },
+                panic_action: if config_locked { PanicAction::Halt } else { PanicAction::Reset },
+                default_layer: config_locked as u8 * 3,
+                mod_swaps: ModSwaps {
+                    alt_gui_swapped: config_locked,
+                    caps_as_ctrl: !config_locked,
+                    gui_disabled: config_locked,
+                },
+                game_mode: !config_locked,
+            };
+            assert!(settings.save().is_ok());
+            assert!(Settings::load() == Some(settings));
+        }
+    }
+
+    if let Some(original) = original {
+        assert!(original.save().is_ok());
+    }
+}
+
+fn panic_log_round_trip_through_flash() {
+    let original = PanicLog::load();
+
+    // A long message is cut short, but not in the middle of a character.
+    struct Accents;
+    impl core::fmt::Display for Accents {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            (0..PANIC_LOG_LEN).try_for_each(|_| f.write_str("\u{e9}"))
+        }
+    }
+    let log = PanicLog::new("src/keyboard.rs", 42, Accents);
+    assert!(log.text().starts_with(b"src/keyboard.rs:42: \xc3\xa9"));
+    assert!(core::str::from_utf8(log.text()).is_ok());
+    assert_eq!(log.text().len(), PANIC_LOG_LEN - 1);
+
+    assert!(log.save().is_ok());
+    assert!(PanicLog::load() == Some(log));
+
+    if let Some(original) = original {
+        assert!(original.save().is_ok());
+    }
+}
+
+fn keymap_round_trip_through_flash() {
+    let original = Keymap::load();
+
+    // Enough saves to fill a sector's slots and move on to the next one.
+    let mut keymap = Keymap::default();
+    for i in 0..SLOTS_PER_SECTOR + 2 {
+        let key = if i % 2 == 0 { KeyCode::Tilde } else { KeyCode::Escape };
+        keymap.set_key(0, ESCAPE.0, ESCAPE.1, key);
+        assert!(keymap.save().is_ok());
+        assert!(Keymap::load() == Some(keymap));
+    }
+
+    assert!(Keymap::reset().is_ok());
+    assert!(Keymap::load().is_none());
+
+    if let Some(original) = original {
+        assert!(original.save().is_ok());
+    }
+}
+
+/// A calibration table with the first key reading lower as it's pressed, unlike the default.
+fn inverted_calibration() -> CalibrationTable {
+    let mut payload = [0u8; MAX_BLOCK_SIZE];
+    let len = CalibrationTable::default().serialize(&mut payload);
+    payload[..4].copy_from_slice(&[0x00, 0x0C, 0x00, 0x04]);
+    let table = CalibrationTable::deserialize(CalibrationTable::VERSION, &payload[..len]).unwrap();
+    assert_eq!(table.key(0, 0).travel(1024), 255);
+    table
+}
+
+fn calibration_round_trip_through_flash() {
+    let original = CalibrationTable::load();
+
+    let table = inverted_calibration();
+    assert!(table.save().is_ok());
+    assert!(CalibrationTable::load() == Some(table));
+
+    // Saving over it with the defaults reads back the defaults.
+    assert!(CalibrationTable::default().save().is_ok());
+    assert!(CalibrationTable::load() == Some(CalibrationTable::default()));
+
+    if let Some(original) = original {
+        assert!(original.save().is_ok());
+    }
+}
+
+fn calibration_ignores_bad_block() {
+    let original = CalibrationTable::load();
+
+    let mut block = [0xFFu8; MAX_BLOCK_SIZE];
+    let len = inverted_calibration().encode(&mut block);
+
+    // A flipped bit in the payload fails the CRC, and the keyboard starts with the defaults.
+    block[12] ^= 0x01;
+    assert!(Partition::Calibration.write(0, &block[..len]).is_ok());
+    assert!(CalibrationTable::load().is_none());
+    block[12] ^= 0x01;
+
+    // So does a version this firmware can't read, even with a CRC to match.
+    block[4..6].copy_from_slice(&(CalibrationTable::VERSION + 1).to_le_bytes());
+    let payload_len = u16::from_le_bytes([block[6], block[7]]) as usize;
+    let crc = crc32(&[&block[4..8], &block[12..12 + payload_len]]);
+    block[8..12].copy_from_slice(&crc.to_le_bytes());
+    assert!(Partition::Calibration.write(0, &block[..len]).is_ok());
+    assert!(CalibrationTable::load().is_none());
+    assert!(CalibrationTable::load().unwrap_or_default() == CalibrationTable::default());
+
+    if let Some(original) = original {
+        assert!(original.save().is_ok());
+    }
+}
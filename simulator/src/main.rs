@@ -0,0 +1,391 @@
+//! Runs the firmware's keymap engine on the host, with a virtual keyboard in the terminal.
+//!
+//! Keys are pressed with the mouse: a left click toggles a key between held and released,
+//! and a right click taps it for a single scan. The arrow keys and space do the same from
+//! the keyboard. Each scan goes through the same debounce and report building as the
+//! firmware, and every change to the resulting HID report is logged.
+//!
+//! With `--replay <file>`, a scan trace saved by the firmware (see `scan_trace`) is run
+//! through the same pipeline instead, printing every report it produces.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode as TermKey, KeyEventKind, MouseButton, MouseEventKind},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use key_ripper::{
+    debounce::{Debounce, Debouncer},
+    key_codes::KeyCode,
+    key_mapping,
+    key_scan::KeyScan,
+    keyboard::Keyboard,
+    ms_to_ticks,
+    profile::Profile,
+    scan_trace, NUM_COLS, NUM_ROWS, SCAN_PERIOD_MS,
+};
+
+/// The firmware scans once per `SCAN_PERIOD_MS`, which is also one debounce tick.
+const SCAN_INTERVAL: Duration = Duration::from_millis(SCAN_PERIOD_MS as u64);
+
+/// The width of a key on screen, including the gap to the next key.
+const KEY_WIDTH: u16 = 8;
+/// The height of a key on screen, including the gap to the next row.
+const KEY_HEIGHT: u16 = 2;
+/// The screen row the first row of keys is drawn on.
+const MATRIX_TOP: u16 = 2;
+
+/// The number of report changes kept in the log.
+const HISTORY_LEN: usize = 12;
+
+const MODIFIER_NAMES: [&str; 8] =
+    ["LCtrl", "LShift", "LAlt", "LCmd", "RCtrl", "RShift", "RAlt", "RCmd"];
+
+type Matrix = [[bool; NUM_ROWS]; NUM_COLS];
+
+/// A HID keyboard report, as `(modifier, keycodes)`.
+type Report = (u8, [u8; 6]);
+
+struct Simulator {
+    start: Instant,
+    keyboard: Keyboard,
+    debounce: Debounce<NUM_ROWS, NUM_COLS>,
+
+    /// The keys held down with the mouse or keyboard.
+    held: Matrix,
+    /// Keys to press for the next scan only.
+    tapped: Matrix,
+    /// The debounced matrix from the last scan.
+    scanned: Matrix,
+
+    cursor: (usize, usize),
+    report: Report,
+    history: VecDeque<(Duration, Report)>,
+}
+
+impl Simulator {
+    fn new() -> Self {
+        let profile = Profile::Typing;
+
+        Self {
+            start: Instant::now(),
+            keyboard: Keyboard::new(profile),
+            debounce: Debounce::new(debounce_ticks(profile), modifier_mask()),
+            held: [[false; NUM_ROWS]; NUM_COLS],
+            tapped: [[false; NUM_ROWS]; NUM_COLS],
+            scanned: [[false; NUM_ROWS]; NUM_COLS],
+            cursor: (0, 0),
+            report: (0, [0; 6]),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Run one scan through the firmware pipeline, returning whether anything visible
+    /// changed.
+    fn scan(&mut self) -> bool {
+        let mut raw = self.held;
+        for ((raw_col, tapped_col), mask_col) in
+            raw.iter_mut().zip(&self.tapped).zip(key_mapping::MATRIX_MASK)
+        {
+            for ((raw_key, tapped_key), populated) in
+                raw_col.iter_mut().zip(tapped_col).zip(mask_col)
+            {
+                *raw_key = (*raw_key || *tapped_key) && populated;
+            }
+        }
+        self.tapped = [[false; NUM_ROWS]; NUM_COLS];
+
+        let profile = self.keyboard.profile();
+        let scan = KeyScan::from(self.debounce.report_and_tick(&raw));
+        let report = self.keyboard.report(&scan);
+
+        if self.keyboard.profile() != profile {
+            self.debounce.set_expiration_ticks(debounce_ticks(self.keyboard.profile()));
+        }
+
+        let report = (report.modifier, report.keycodes);
+        let changed = report != self.report || *scan != self.scanned;
+
+        if report != self.report {
+            self.history.push_front((self.start.elapsed(), report));
+            self.history.truncate(HISTORY_LEN);
+        }
+
+        self.report = report;
+        self.scanned = *scan;
+        changed
+    }
+
+    fn toggle(&mut self, (col, row): (usize, usize)) {
+        self.held[col][row] = !self.held[col][row];
+    }
+
+    fn tap(&mut self, (col, row): (usize, usize)) {
+        self.tapped[col][row] = true;
+    }
+
+    fn move_cursor(&mut self, cols: isize, rows: isize) {
+        let (col, row) = self.cursor;
+        self.cursor = (
+            col.saturating_add_signed(cols).min(NUM_COLS - 1),
+            row.saturating_add_signed(rows).min(NUM_ROWS - 1),
+        );
+    }
+
+    /// The layer the keys are currently resolved on, along with its name.
+    fn layer(&self) -> (&'static str, [[KeyCode; NUM_ROWS]; NUM_COLS]) {
+        let name = match self.keyboard.active_layer() {
+            0 => "Normal",
+            key_mapping::FN_LAYER => "Fn",
+            key_mapping::NUM_LAYER if self.keyboard.num_word_active() => "Num Word",
+            _ => "Num",
+        };
+
+        (name, self.keyboard.layer_mapping())
+    }
+
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        queue!(
+            out,
+            terminal::Clear(ClearType::All),
+            cursor::MoveTo(0, 0),
+            Print("key ripper simulator - left click toggles a key, right click taps it, "),
+            Print("arrows and space/t work too, r releases everything, q quits"),
+        )?;
+
+        let (layer_name, layer) = self.layer();
+
+        for (col, (layer_col, mask_col)) in layer.iter().zip(key_mapping::MATRIX_MASK).enumerate() {
+            for (row, (key, populated)) in layer_col.iter().zip(mask_col).enumerate() {
+                if !populated {
+                    continue;
+                }
+
+                let label: String =
+                    format!("{key:?}").chars().take(KEY_WIDTH as usize - 2).collect();
+
+                let (x, y) = key_position(col, row);
+                queue!(out, cursor::MoveTo(x, y))?;
+
+                if self.scanned[col][row] {
+                    queue!(out, SetAttribute(Attribute::Reverse))?;
+                    if !self.held[col][row] {
+                        // Released, but still reported as pressed by the debounce.
+                        queue!(out, SetAttribute(Attribute::Underlined))?;
+                    }
+                }
+
+                let (open, close) = if self.cursor == (col, row) { ('>', '<') } else { ('[', ']') };
+                let width = KEY_WIDTH as usize - 2;
+                queue!(
+                    out,
+                    Print(format!("{open}{label:^width$}{close}")),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            }
+        }
+
+        let info_top = MATRIX_TOP + NUM_ROWS as u16 * KEY_HEIGHT;
+        queue!(
+            out,
+            cursor::MoveTo(0, info_top),
+            Print(format!("Layer: {layer_name:<10} Profile: {:?}", self.keyboard.profile())),
+            cursor::MoveTo(0, info_top + 1),
+            Print(format!("Report: {}", format_report(&self.report))),
+            cursor::MoveTo(0, info_top + 2),
+            Print(format!("        {}", describe_report(&self.report))),
+            cursor::MoveTo(0, info_top + 4),
+            Print("Report log (newest first):"),
+        )?;
+
+        for (line, (time, report)) in self.history.iter().enumerate() {
+            queue!(
+                out,
+                cursor::MoveTo(2, info_top + 5 + line as u16),
+                Print(format!("{:>10.3}s  {}", time.as_secs_f64(), format_report(report)))
+            )?;
+        }
+
+        out.flush()
+    }
+}
+
+/// The number of scans a profile's debounce time lasts for, the same as in the firmware.
+fn debounce_ticks(profile: Profile) -> u8 {
+    ms_to_ticks(profile.settings().debounce_ms.into()) as u8
+}
+
+/// The keys which the debounce lets through unchanged, the same as in the firmware.
+fn modifier_mask() -> Matrix {
+    let mut modifier_mask = [[false; NUM_ROWS]; NUM_COLS];
+    for (col, mapping_col) in modifier_mask.iter_mut().zip(key_mapping::NORMAL_LAYER_MAPPING) {
+        for (key, mapping_key) in col.iter_mut().zip(mapping_col) {
+            *key = mapping_key.is_modifier();
+        }
+    }
+    modifier_mask
+}
+
+/// The top left corner of a key on screen.
+fn key_position(col: usize, row: usize) -> (u16, u16) {
+    (col as u16 * KEY_WIDTH, MATRIX_TOP + row as u16 * KEY_HEIGHT)
+}
+
+/// The key at a position on screen, if there is one.
+fn key_at(x: u16, y: u16) -> Option<(usize, usize)> {
+    if y < MATRIX_TOP
+        || !(y - MATRIX_TOP).is_multiple_of(KEY_HEIGHT)
+        || x % KEY_WIDTH == KEY_WIDTH - 1
+    {
+        return None;
+    }
+
+    let (col, row) = ((x / KEY_WIDTH) as usize, ((y - MATRIX_TOP) / KEY_HEIGHT) as usize);
+    (col < NUM_COLS && row < NUM_ROWS && key_mapping::MATRIX_MASK[col][row]).then_some((col, row))
+}
+
+/// The name of a HID usage, looked up from the keys in the layout.
+fn key_name(code: u8) -> String {
+    [key_mapping::NORMAL_LAYER_MAPPING, key_mapping::FN_LAYER_MAPPING]
+        .iter()
+        .flatten()
+        .flatten()
+        .find(|key| **key as u16 == code as u16 && !key.is_firmware_key())
+        .map_or_else(|| format!("{code:#04x}"), |key| format!("{key:?}"))
+}
+
+fn format_report((modifier, keycodes): &Report) -> String {
+    let keycodes: Vec<_> = keycodes.iter().map(|code| format!("{code:02x}")).collect();
+    format!("{modifier:08b} 00 {}", keycodes.join(" "))
+}
+
+/// The names of the modifiers and keys in a report.
+fn describe_report((modifier, keycodes): &Report) -> String {
+    let modifiers: Vec<_> = MODIFIER_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| modifier & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    let keys: Vec<_> =
+        keycodes.iter().filter(|code| **code != 0).map(|code| key_name(*code)).collect();
+
+    format!("modifiers [{}] keys [{}]", modifiers.join(" "), keys.join(" "))
+}
+
+/// Restores the terminal when the simulator exits, including on panics and errors.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(
+            io::stdout(),
+            terminal::EnterAlternateScreen,
+            event::EnableMouseCapture,
+            cursor::Hide
+        )?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(
+            io::stdout(),
+            cursor::Show,
+            event::DisableMouseCapture,
+            terminal::LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => run_interactive(),
+        [flag, path] if flag == "--replay" => replay(path),
+        _ => {
+            eprintln!("usage: key-ripper-simulator [--replay <scan trace>]");
+            std::process::exit(2);
+        },
+    }
+}
+
+/// Print the reports produced by a scan trace saved by the firmware.
+fn replay(path: &str) -> io::Result<()> {
+    let data = std::fs::read(path)?;
+    let Some(entries) = scan_trace::parse(&data) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a valid scan trace"));
+    };
+
+    let profile = Profile::Typing;
+    let mut debounce = Debounce::new(debounce_ticks(profile), modifier_mask());
+    let mut keyboard = Keyboard::new(profile);
+    let settle_ms = profile.settings().debounce_ms as u32;
+
+    scan_trace::replay(entries, &mut debounce, &mut keyboard, settle_ms, |time_ms, report| {
+        let report = (report.modifier, report.keycodes);
+        println!("{time_ms:>10} ms  {}  {}", format_report(&report), describe_report(&report));
+    });
+
+    Ok(())
+}
+
+fn run_interactive() -> io::Result<()> {
+    let _guard = TerminalGuard::new()?;
+    let mut stdout = io::stdout();
+
+    let mut simulator = Simulator::new();
+    let mut next_scan = Instant::now();
+    simulator.draw(&mut stdout)?;
+
+    loop {
+        let mut redraw = false;
+
+        if event::poll(next_scan.saturating_duration_since(Instant::now()))? {
+            match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => match key.code {
+                    TermKey::Char('q') | TermKey::Esc => return Ok(()),
+                    TermKey::Char('r') => simulator.held = [[false; NUM_ROWS]; NUM_COLS],
+                    TermKey::Char(' ') => simulator.toggle(simulator.cursor),
+                    TermKey::Char('t') => simulator.tap(simulator.cursor),
+                    TermKey::Left => simulator.move_cursor(-1, 0),
+                    TermKey::Right => simulator.move_cursor(1, 0),
+                    TermKey::Up => simulator.move_cursor(0, -1),
+                    TermKey::Down => simulator.move_cursor(0, 1),
+                    _ => continue,
+                },
+                Event::Mouse(mouse) => {
+                    let Some(position) = key_at(mouse.column, mouse.row) else { continue };
+
+                    match mouse.kind {
+                        MouseEventKind::Down(MouseButton::Left) => simulator.toggle(position),
+                        MouseEventKind::Down(MouseButton::Right) => simulator.tap(position),
+                        _ => continue,
+                    }
+                    simulator.cursor = position;
+                },
+                _ => continue,
+            }
+            redraw = true;
+        }
+
+        if Instant::now() >= next_scan {
+            redraw |= simulator.scan();
+            next_scan += SCAN_INTERVAL;
+        }
+
+        if redraw {
+            simulator.draw(&mut stdout)?;
+        }
+    }
+}